@@ -1,11 +1,18 @@
 //! API request handlers
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
     Json,
 };
+use futures_util::StreamExt;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio_util::io::ReaderStream;
 
 use crate::models::*;
 use crate::Orchestrator;
@@ -35,25 +42,25 @@ pub async fn create_template(
     State(orch): State<AppState>,
     Json(req): Json<CreateTemplateRequest>,
 ) -> Result<(StatusCode, Json<TemplateResponse>), (StatusCode, Json<ApiError>)> {
-    let template = Template::new(&req.name, &req.vhdx_path)
+    let mut template = Template::new(&req.name, &req.vhdx_path)
         .with_memory(req.memory_mb)
         .with_cpus(req.cpu_count)
-        .with_gpu(req.gpu_enabled);
-
-    let template_clone = Template {
-        id: template.id.clone(),
-        name: template.name.clone(),
-        vhdx_path: template.vhdx_path.clone(),
-        memory_mb: template.memory_mb,
-        cpu_count: template.cpu_count,
-        gpu_enabled: template.gpu_enabled,
-        installed_software: template.installed_software.clone(),
-        created_at: template.created_at,
-        description: req.description.clone(),
-    };
+        .with_gpu(req.gpu_enabled)
+        .with_probes(req.readiness_probes);
+
+    if let Some(username) = req.guest_username {
+        template = template.with_guest_credential(username, req.guest_password.unwrap_or_default());
+    }
+
+    if let Some(description) = req.description {
+        template = template.with_description(description);
+    }
 
-    orch.register_template(template).map_err(to_api_error)?;
-    Ok((StatusCode::CREATED, Json(template_to_response(template_clone))))
+    // Registration fills in what it read from the VHDX; return what was stored
+    let id = orch.register_template(template).map_err(to_api_error)?;
+    let template = orch.db().get_template(&id).map_err(to_api_error)?
+        .ok_or_else(|| not_found("Template"))?;
+    Ok((StatusCode::CREATED, Json(template_to_response(template))))
 }
 
 pub async fn get_template(
@@ -68,13 +75,66 @@ pub async fn get_template(
 pub async fn delete_template(
     State(orch): State<AppState>,
     Path(name): Path<String>,
+    Query(query): Query<DeleteQuery>,
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let template = orch.get_template(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("Template"))?;
-    orch.db().delete_template(&template.id).map_err(to_api_error)?;
+    blocking(&orch, move |o| o.delete_template(&template.name, query.cascade)).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("Template '{}' deleted", name) }))
 }
 
+pub async fn list_template_versions(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<TemplateVersionResponse>>, (StatusCode, Json<ApiError>)> {
+    let template = orch.get_template(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("Template"))?;
+    let versions = orch.list_template_versions(&name).map_err(to_api_error)?;
+    Ok(Json(versions.into_iter().map(|v| version_to_response(v, template.current_version)).collect()))
+}
+
+pub async fn add_template_version(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Json(req): Json<AddTemplateVersionRequest>,
+) -> Result<(StatusCode, Json<TemplateVersionResponse>), (StatusCode, Json<ApiError>)> {
+    // Hashing a multi-GB VHDX takes a while
+    let (version, template) = blocking(&orch, move |o| {
+        let version = o.add_template_version(&name, &req.vhdx_path, req.notes)?;
+        Ok((version, o.get_template(&name)?))
+    }).await.map_err(to_api_error)?;
+    let current = template.map(|t| t.current_version).unwrap_or_default();
+    Ok((StatusCode::CREATED, Json(version_to_response(version, current))))
+}
+
+pub async fn promote_template(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Json(req): Json<PromoteTemplateRequest>,
+) -> Result<Json<TemplateResponse>, (StatusCode, Json<ApiError>)> {
+    let template = orch.promote_template_version(&name, req.version).map_err(to_api_error)?;
+    Ok(Json(template_to_response(template)))
+}
+
+pub async fn rollback_template(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<TemplateResponse>, (StatusCode, Json<ApiError>)> {
+    let template = orch.rollback_template(&name).map_err(to_api_error)?;
+    Ok(Json(template_to_response(template)))
+}
+
+pub async fn verify_template(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<TemplateCheck>, (StatusCode, Json<ApiError>)> {
+    // Re-hashes the whole VHDX
+    let check = blocking(&orch, move |o| o.verify_template(&name, None, &|_, _| {}))
+        .await
+        .map_err(to_api_error)?;
+    Ok(Json(check))
+}
+
 // === Pools ===
 
 pub async fn list_pools(
@@ -91,22 +151,24 @@ pub async fn create_pool(
     let template = orch.get_template(&req.template_name).map_err(to_api_error)?
         .ok_or_else(|| not_found("Template"))?;
 
-    let pool = VMPool::new(&req.name, &template.id)
+    let mut pool = VMPool::new(&req.name, &template.id)
         .with_count(req.desired_count)
-        .with_warm_count(req.warm_count);
-
-    let pool_clone = VMPool {
-        id: pool.id.clone(),
-        name: pool.name.clone(),
-        template_id: pool.template_id.clone(),
-        desired_count: pool.desired_count,
-        warm_count: pool.warm_count,
-        max_per_host: pool.max_per_host,
-        created_at: pool.created_at,
-    };
+        .with_warm_count(req.warm_count)
+        .with_remediation(req.remediation);
+    if let Some(secs) = req.idle_timeout_secs {
+        pool = pool.with_idle_timeout(secs, req.idle_action);
+    }
+    if let Some(max) = req.max_disk_bytes {
+        pool = pool.with_disk_limit(max);
+    }
+    if let Some(version) = req.template_version {
+        pool = pool.with_template_version(version);
+    }
 
-    orch.create_pool(pool).map_err(to_api_error)?;
-    Ok((StatusCode::CREATED, Json(pool_to_response(pool_clone))))
+    let id = orch.create_pool(pool).map_err(to_api_error)?;
+    let pool = orch.db().get_pool(&id).map_err(to_api_error)?
+        .ok_or_else(|| not_found("Pool"))?;
+    Ok((StatusCode::CREATED, Json(pool_to_response(pool))))
 }
 
 pub async fn get_pool(
@@ -126,45 +188,73 @@ pub async fn get_pool(
         saved_vms: status.saved_vms,
         off_vms: status.off_vms,
         error_vms: status.error_vms,
+        unhealthy_vms: status.unhealthy_vms,
+        idle_vms: status.idle_vms,
+        reclaimed_vms: status.reclaimed_vms,
+        disk_bytes: status.disk_bytes,
+        saved_state_bytes: status.saved_state_bytes,
+        over_disk_limit_vms: status.over_disk_limit_vms,
     }))
 }
 
 pub async fn provision_pool(
     State(orch): State<AppState>,
     Path(name): Path<String>,
+    headers: HeaderMap,
     Json(req): Json<ProvisionRequest>,
-) -> Result<Json<Vec<String>>, (StatusCode, Json<ApiError>)> {
+) -> Result<Json<ProvisionResult>, (StatusCode, Json<ApiError>)> {
     let pool = orch.db().get_pool_by_name(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("Pool"))?;
-    let ids = orch.provision_pool(&pool.id, req.count).map_err(to_api_error)?;
-    Ok(Json(ids))
+
+    let key = req.idempotency_key.or_else(|| {
+        headers.get("idempotency-key")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    });
+    let mut options = ProvisionOptions::new(req.count);
+    options.idempotency_key = key;
+    options.atomic = req.atomic;
+
+    let result = blocking(&orch, move |o| o.provision(&pool.id, &options)).await.map_err(to_api_error)?;
+    Ok(Json(result))
 }
 
-pub async fn prepare_pool(
+pub async fn upgrade_pool(
     State(orch): State<AppState>,
     Path(name): Path<String>,
-) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
+    Json(req): Json<UpgradePoolRequest>,
+) -> Result<Json<UpgradeResult>, (StatusCode, Json<ApiError>)> {
     let pool = orch.db().get_pool_by_name(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("Pool"))?;
 
-    let vms = orch.db().list_vms_by_pool(&pool.id).map_err(to_api_error)?;
-    let mut prepared = 0;
+    let options = UpgradeOptions::new(req.template_version).with_batch_size(req.batch_size);
+    let result = blocking(&orch, move |o| o.upgrade_pool(&pool.id, &options, &|p| tracing::debug!("{}", p)))
+        .await
+        .map_err(to_api_error)?;
+    Ok(Json(result))
+}
 
-    for vm in vms.iter().filter(|v| v.state == VMState::Off) {
-        orch.prepare_vm(&vm.id).map_err(to_api_error)?;
-        prepared += 1;
-    }
+pub async fn prepare_pool(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<PreparedVM>>, (StatusCode, Json<ApiError>)> {
+    let pool = orch.db().get_pool_by_name(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("Pool"))?;
 
-    Ok(Json(ApiSuccess { message: format!("Prepared {} VMs", prepared) }))
+    let prepared = blocking(&orch, move |o| o.prepare_pool(&pool.id, &|p| tracing::debug!("{}", p)))
+        .await
+        .map_err(to_api_error)?;
+    Ok(Json(prepared))
 }
 
 pub async fn delete_pool(
     State(orch): State<AppState>,
     Path(name): Path<String>,
+    Query(query): Query<DeleteQuery>,
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let pool = orch.db().get_pool_by_name(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("Pool"))?;
-    orch.db().delete_pool(&pool.id).map_err(to_api_error)?;
+    blocking(&orch, move |o| o.delete_pool(&pool.id, query.cascade)).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("Pool '{}' deleted", name) }))
 }
 
@@ -194,14 +284,14 @@ pub async fn resume_vm(
         .ok_or_else(|| not_found("VM"))?;
 
     let start = std::time::Instant::now();
-    let ip = orch.resume_vm(&vm.id).map_err(to_api_error)?;
+    let ip = orch.resume_vm_async(&vm.id).await.map_err(to_api_error)?;
     let elapsed = start.elapsed();
 
     Ok(Json(ResumeResponse {
         vm_id: vm.id,
         vm_name: vm.name,
-        ip_address: ip.clone(),
-        mcp_endpoint: format!("http://{}:8080/mcp", ip),
+        ip_address: ip.clone().unwrap_or_default(),
+        mcp_endpoint: format!("http://{}:8080/mcp", ip.as_deref().unwrap_or("0.0.0.0")),
         resume_time_ms: elapsed.as_millis() as u64,
     }))
 }
@@ -212,7 +302,7 @@ pub async fn save_vm(
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let vm = orch.get_vm(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("VM"))?;
-    orch.save_vm(&vm.id).map_err(to_api_error)?;
+    orch.save_vm_async(&vm.id).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("VM '{}' saved", name) }))
 }
 
@@ -222,7 +312,7 @@ pub async fn reset_vm(
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let vm = orch.get_vm(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("VM"))?;
-    orch.reset_vm(&vm.id).map_err(to_api_error)?;
+    blocking(&orch, move |o| o.reset_vm(&vm.id)).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("VM '{}' reset to clean checkpoint", name) }))
 }
 
@@ -232,7 +322,7 @@ pub async fn stop_vm(
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let vm = orch.get_vm(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("VM"))?;
-    orch.stop_vm(&vm.id, true).map_err(to_api_error)?;
+    orch.stop_vm_async(&vm.id, true).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("VM '{}' stopped", name) }))
 }
 
@@ -242,7 +332,7 @@ pub async fn delete_vm(
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let vm = orch.get_vm(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("VM"))?;
-    orch.delete_vm(&vm.id).map_err(to_api_error)?;
+    blocking(&orch, move |o| o.delete_vm(&vm.id)).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("VM '{}' deleted", name) }))
 }
 
@@ -252,10 +342,165 @@ pub async fn prepare_vm(
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let vm = orch.get_vm(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("VM"))?;
-    orch.prepare_vm(&vm.id).map_err(to_api_error)?;
+    blocking(&orch, move |o| o.prepare_vm(&vm.id)).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("VM '{}' prepared", name) }))
 }
 
+pub async fn exec_vm(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Json(req): Json<ExecRequest>,
+) -> Result<Json<ExecResponse>, (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+
+    let start = std::time::Instant::now();
+    let output = orch
+        .exec_vm_async(&vm.id, &req.script, std::time::Duration::from_secs(req.timeout_seconds))
+        .await
+        .map_err(to_api_error)?;
+
+    Ok(Json(ExecResponse {
+        vm_name: vm.name,
+        stdout: output.stdout,
+        stderr: output.stderr,
+        exit_code: output.exit_code,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }))
+}
+
+/// Stream the request body to a staging file, then copy it into the guest
+pub async fn upload_file(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<FileQuery>,
+    body: Body,
+) -> Result<Json<FileTransferResponse>, (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+
+    let staging = orch.staging_path().map_err(to_api_error)?;
+    let result = match receive_body(&orch, body, &staging).await {
+        Ok(()) => {
+            let staging = staging.clone();
+            blocking(&orch, move |o| o.push_file(&vm.id, &staging, &query.path)).await
+        }
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&staging).await;
+
+    Ok(Json(transfer_to_response(result.map_err(to_api_error)?)))
+}
+
+/// Copy a file out of the guest and stream it back; the staged copy is
+/// removed once the response body has been read (or dropped)
+pub async fn download_file(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<FileQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+
+    let staging = orch.staging_path().map_err(to_api_error)?;
+    let target = staging.clone();
+    let pulled = match blocking(&orch, move |o| o.pull_file(&vm.id, &query.path, &target)).await {
+        Ok(transfer) => open_staged(&staging).await
+            .map(|file| (transfer, file))
+            .map_err(crate::Error::from),
+        Err(e) => Err(e),
+    };
+    let (transfer, file) = match pulled {
+        Ok(pulled) => pulled,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&staging).await;
+            return Err(to_api_error(e));
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, transfer.size_bytes.to_string()),
+            (header::HeaderName::from_static("x-checksum-sha256"), transfer.sha256),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ))
+}
+
+/// A staged download that deletes itself once the response is done with it
+struct StagedFile {
+    file: Option<tokio::fs::File>,
+    path: std::path::PathBuf,
+}
+
+async fn open_staged(path: &std::path::Path) -> std::io::Result<StagedFile> {
+    Ok(StagedFile {
+        file: Some(tokio::fs::File::open(path).await?),
+        path: path.to_path_buf(),
+    })
+}
+
+impl AsyncRead for StagedFile {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.file.as_mut() {
+            Some(file) => Pin::new(file).poll_read(cx, buf),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        // Close first: Windows won't delete an open file
+        drop(self.file.take());
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub async fn screenshot_vm(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<ScreenshotQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+    let png = orch.screenshot_async(&vm.id, query.width, query.height).await.map_err(to_api_error)?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], png))
+}
+
+/// Run a slow synchronous orchestrator call on tokio's blocking pool so it
+/// doesn't stall the worker threads serving other requests. Journaled
+/// operations (provision, prepare, reset, delete, release, reconcile) stay
+/// synchronous: a dropped future must not abandon a step halfway, so they are
+/// stopped by cancelling their job instead.
+async fn blocking<T, F>(orch: &AppState, f: F) -> crate::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Orchestrator) -> crate::Result<T> + Send + 'static,
+{
+    let orch = orch.clone();
+    tokio::task::spawn_blocking(move || f(&orch))
+        .await
+        .unwrap_or_else(|e| Err(crate::Error::Other(format!("Background task failed: {}", e))))
+}
+
+async fn receive_body(orch: &Orchestrator, body: Body, path: &std::path::Path) -> crate::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut received = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| crate::Error::Other(format!("Upload failed: {}", e)))?;
+        received += chunk.len() as u64;
+        orch.check_transfer_size(received)?;
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+    Ok(())
+}
+
 // === Acquire/Release ===
 
 pub async fn acquire_vm(
@@ -266,9 +511,16 @@ pub async fn acquire_vm(
         .ok_or_else(|| not_found("Pool"))?;
 
     let start = std::time::Instant::now();
-    let vm = orch.acquire_vm(&pool.id).map_err(to_api_error)?;
+    let vm = orch.acquire_vm_async(&pool.id).await.map_err(to_api_error)?;
     let elapsed = start.elapsed();
 
+    if let Some(interval_ms) = req.record_interval_ms {
+        if !orch.is_recording(&vm.id) {
+            orch.start_recording(&vm.id, std::time::Duration::from_millis(interval_ms))
+                .map_err(to_api_error)?;
+        }
+    }
+
     Ok(Json(ResumeResponse {
         vm_id: vm.id,
         vm_name: vm.name,
@@ -285,17 +537,123 @@ pub async fn release_vm(
 ) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
     let vm = orch.get_vm(&name).map_err(to_api_error)?
         .ok_or_else(|| not_found("VM"))?;
-    orch.release_vm(&vm.id, req.reset).map_err(to_api_error)?;
+    blocking(&orch, move |o| o.release_vm(&vm.id, req.reset)).await.map_err(to_api_error)?;
     Ok(Json(ApiSuccess { message: format!("VM '{}' released", name) }))
 }
 
+pub async fn create_ephemeral(
+    State(orch): State<AppState>,
+    Json(req): Json<EphemeralRequest>,
+) -> Result<(StatusCode, Json<VMResponse>), (StatusCode, Json<ApiError>)> {
+    let mut options = EphemeralOptions::new(req.template, std::time::Duration::from_secs(req.ttl_secs));
+    options.memory_mb = req.memory_mb;
+    options.cpu_count = req.cpus;
+
+    let vm = blocking(&orch, move |o| o.create_ephemeral(&options)).await.map_err(to_api_error)?;
+    Ok((StatusCode::CREATED, Json(vm_to_response(vm))))
+}
+
+pub async fn heartbeat_vm(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+    orch.heartbeat_vm(&vm.id).map_err(to_api_error)?;
+    Ok(Json(ApiSuccess { message: format!("VM '{}' marked active", name) }))
+}
+
+// === Recording ===
+
+pub async fn start_recording(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+    Json(req): Json<RecordingRequest>,
+) -> Result<(StatusCode, Json<ApiSuccess>), (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+    orch.start_recording(&vm.id, std::time::Duration::from_millis(req.interval_ms))
+        .map_err(to_api_error)?;
+    Ok((StatusCode::CREATED, Json(ApiSuccess { message: format!("Recording '{}'", name) })))
+}
+
+pub async fn stop_recording(
+    State(orch): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<TimelineResponse>, (StatusCode, Json<ApiError>)> {
+    let vm = orch.get_vm(&name).map_err(to_api_error)?
+        .ok_or_else(|| not_found("VM"))?;
+    let timeline = blocking(&orch, move |o| o.stop_recording(&vm.id)).await.map_err(to_api_error)?
+        .ok_or_else(|| not_found("Recording"))?;
+    Ok(Json(timeline_to_response(timeline)))
+}
+
+// === Jobs ===
+
+pub async fn list_jobs(State(orch): State<AppState>) -> Json<Vec<Job>> {
+    Json(orch.list_jobs())
+}
+
+pub async fn get_job(
+    State(orch): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, (StatusCode, Json<ApiError>)> {
+    let job = orch.get_job(&id).ok_or_else(|| not_found("Job"))?;
+    Ok(Json(job))
+}
+
+/// Request cancellation; the job's own request returns once it has stopped
+pub async fn cancel_job(
+    State(orch): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, Json<ApiError>)> {
+    let job = orch.cancel_job(&id).map_err(to_api_error)?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
 // === Reconcile ===
 
+/// Drift between the DB, Hyper-V and VM storage; changes nothing
+pub async fn get_drift(
+    State(orch): State<AppState>,
+) -> Result<Json<DriftReport>, (StatusCode, Json<ApiError>)> {
+    let report = blocking(&orch, |o| o.drift_report()).await.map_err(to_api_error)?;
+    Ok(Json(report))
+}
+
 pub async fn reconcile(
     State(orch): State<AppState>,
-) -> Result<Json<ApiSuccess>, (StatusCode, Json<ApiError>)> {
-    orch.reconcile().map_err(to_api_error)?;
-    Ok(Json(ApiSuccess { message: "Reconciled state with Hyper-V".to_string() }))
+    Query(query): Query<ReconcileQuery>,
+) -> Result<Json<DriftReport>, (StatusCode, Json<ApiError>)> {
+    let options = query.into();
+    let report = blocking(&orch, move |o| o.reconcile_with(&options)).await.map_err(to_api_error)?;
+    Ok(Json(report))
+}
+
+// === Events ===
+
+pub async fn list_events(
+    State(orch): State<AppState>,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<EventResponse>>, (StatusCode, Json<ApiError>)> {
+    let pool_id = match &query.pool {
+        Some(name) => Some(
+            orch.db().get_pool_by_name(name).map_err(to_api_error)?
+                .ok_or_else(|| not_found("Pool"))?
+                .id,
+        ),
+        None => None,
+    };
+
+    let filter = EventFilter {
+        vm_name: query.vm,
+        pool_id,
+        kind: query.kind,
+        since_id: query.since,
+        limit: Some(query.limit),
+    };
+    let events = orch.list_events(&filter).map_err(to_api_error)?;
+    Ok(Json(events.into_iter().map(event_to_response).collect()))
 }
 
 // === Helpers ===
@@ -304,10 +662,22 @@ fn to_api_error(e: crate::Error) -> (StatusCode, Json<ApiError>) {
     let status = match &e {
         crate::Error::VMNotFound(_) => StatusCode::NOT_FOUND,
         crate::Error::TemplateNotFound(_) => StatusCode::NOT_FOUND,
+        crate::Error::TemplateVersionNotFound { .. } => StatusCode::NOT_FOUND,
         crate::Error::PoolNotFound(_) => StatusCode::NOT_FOUND,
+        crate::Error::JobNotFound(_) => StatusCode::NOT_FOUND,
+        crate::Error::Cancelled => StatusCode::CONFLICT,
         crate::Error::NoVMAvailable => StatusCode::SERVICE_UNAVAILABLE,
+        crate::Error::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
         crate::Error::InvalidState { .. } => StatusCode::CONFLICT,
+        crate::Error::IdempotencyConflict(_) => StatusCode::CONFLICT,
+        crate::Error::TemplateChecksumMismatch { .. } => StatusCode::CONFLICT,
+        crate::Error::InUse { .. } => StatusCode::CONFLICT,
         crate::Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        crate::Error::ProbeFailed(_) => StatusCode::GATEWAY_TIMEOUT,
+        crate::Error::NoGuestCredential(_) => StatusCode::BAD_REQUEST,
+        crate::Error::InvalidVhdx(_) => StatusCode::BAD_REQUEST,
+        crate::Error::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+        crate::Error::FileTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     };
 
@@ -326,6 +696,7 @@ fn not_found(resource: &str) -> (StatusCode, Json<ApiError>) {
 
 fn template_to_response(t: Template) -> TemplateResponse {
     TemplateResponse {
+        readiness_probes: t.probes(),
         id: t.id,
         name: t.name,
         vhdx_path: t.vhdx_path.to_string_lossy().to_string(),
@@ -333,10 +704,28 @@ fn template_to_response(t: Template) -> TemplateResponse {
         cpu_count: t.cpu_count,
         gpu_enabled: t.gpu_enabled,
         description: t.description,
+        guest_username: t.guest_credential.map(|c| c.username),
+        virtual_size_bytes: t.virtual_size_bytes,
+        disk_type: t.disk_type.map(|d| d.to_string()),
+        current_version: t.current_version,
         created_at: t.created_at.to_rfc3339(),
     }
 }
 
+fn version_to_response(v: TemplateVersion, current: u32) -> TemplateVersionResponse {
+    TemplateVersionResponse {
+        current: v.version == current,
+        version: v.version,
+        vhdx_path: v.vhdx_path.to_string_lossy().to_string(),
+        sha256: v.sha256,
+        size_bytes: v.size_bytes,
+        virtual_size_bytes: v.virtual_size_bytes,
+        disk_type: v.disk_type.map(|d| d.to_string()),
+        notes: v.notes,
+        created_at: v.created_at.to_rfc3339(),
+    }
+}
+
 fn pool_to_response(p: VMPool) -> PoolResponse {
     PoolResponse {
         id: p.id,
@@ -344,10 +733,52 @@ fn pool_to_response(p: VMPool) -> PoolResponse {
         template_id: p.template_id,
         desired_count: p.desired_count,
         warm_count: p.warm_count,
+        remediation: p.remediation.to_string(),
+        idle_timeout_secs: p.idle_timeout_secs,
+        idle_action: p.idle_action.to_string(),
+        max_disk_bytes: p.max_disk_bytes,
+        template_version: p.template_version,
         created_at: p.created_at.to_rfc3339(),
     }
 }
 
+fn transfer_to_response(t: FileTransfer) -> FileTransferResponse {
+    FileTransferResponse {
+        vm_name: t.vm_name,
+        direction: t.direction.to_string(),
+        guest_path: t.guest_path,
+        size_bytes: t.size_bytes,
+        sha256: t.sha256,
+    }
+}
+
+fn timeline_to_response(t: ScreenTimeline) -> TimelineResponse {
+    TimelineResponse {
+        vm_name: t.vm_name,
+        directory: t.directory.to_string_lossy().to_string(),
+        interval_ms: t.interval_ms,
+        started_at: t.started_at.to_rfc3339(),
+        ended_at: t.ended_at.map(|t| t.to_rfc3339()),
+        samples: t.samples,
+        frames: t.frames.into_iter().map(|f| TimelineFrameResponse {
+            captured_at: f.captured_at.to_rfc3339(),
+            path: f.path.to_string_lossy().to_string(),
+        }).collect(),
+    }
+}
+
+fn event_to_response(e: Event) -> EventResponse {
+    EventResponse {
+        id: e.id,
+        kind: e.kind.to_string(),
+        vm_name: e.vm_name,
+        pool_id: e.pool_id,
+        agent_id: e.agent_id,
+        message: e.message,
+        created_at: e.created_at.to_rfc3339(),
+    }
+}
+
 fn vm_to_response(v: VM) -> VMResponse {
     VMResponse {
         id: v.id,
@@ -355,11 +786,19 @@ fn vm_to_response(v: VM) -> VMResponse {
         template_id: v.template_id,
         pool_id: v.pool_id,
         state: v.state.to_string(),
+        health: v.health.to_string(),
         ip_address: v.ip_address,
         memory_mb: v.memory_mb,
         cpu_count: v.cpu_count,
         gpu_enabled: v.gpu_enabled,
         created_at: v.created_at.to_rfc3339(),
         last_resumed_at: v.last_resumed_at.map(|t| t.to_rfc3339()),
+        last_activity_at: v.last_activity_at.map(|t| t.to_rfc3339()),
+        needs_recycle: v.needs_recycle,
+        ephemeral: v.ephemeral,
+        expires_at: v.expires_at.map(|t| t.to_rfc3339()),
+        disk_bytes: v.disk_bytes,
+        saved_state_bytes: v.saved_state_bytes,
+        template_version: v.template_version,
     }
 }
@@ -4,5 +4,5 @@ mod server;
 mod handlers;
 mod types;
 
-pub use server::Server;
+pub use server::{shutdown_signal, Server};
 pub use types::*;
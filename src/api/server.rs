@@ -1,27 +1,37 @@
 //! HTTP server
 
 use axum::{
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Router,
 };
+use std::future::{Future, IntoFuture};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 
 use crate::Orchestrator;
 use super::handlers::{self, AppState};
 
+/// How long running jobs get to clean up once cancelled at the end of a drain
+const CANCEL_GRACE: Duration = Duration::from_secs(10);
+
 /// HTTP API Server
 pub struct Server {
     router: Router,
     addr: SocketAddr,
+    orch: AppState,
+    drain_timeout: Duration,
+    /// Background monitor threads, stopped by the orchestrator's shutdown token
+    monitors: Vec<JoinHandle<()>>,
 }
 
 impl Server {
-    /// Create a new server
-    pub fn new(orchestrator: Orchestrator, addr: SocketAddr) -> Self {
-        let state: AppState = Arc::new(orchestrator);
+    /// Create a new server (takes an owned or already shared orchestrator)
+    pub fn new(orchestrator: impl Into<Arc<Orchestrator>>, addr: SocketAddr) -> Self {
+        let state: AppState = orchestrator.into();
 
         let cors = CorsLayer::new()
             .allow_origin(Any)
@@ -37,6 +47,11 @@ impl Server {
             .route("/api/v1/templates", post(handlers::create_template))
             .route("/api/v1/templates/:name", get(handlers::get_template))
             .route("/api/v1/templates/:name", delete(handlers::delete_template))
+            .route("/api/v1/templates/:name/versions", get(handlers::list_template_versions))
+            .route("/api/v1/templates/:name/versions", post(handlers::add_template_version))
+            .route("/api/v1/templates/:name/promote", post(handlers::promote_template))
+            .route("/api/v1/templates/:name/rollback", post(handlers::rollback_template))
+            .route("/api/v1/templates/:name/verify", post(handlers::verify_template))
 
             // Pools
             .route("/api/v1/pools", get(handlers::list_pools))
@@ -45,6 +60,7 @@ impl Server {
             .route("/api/v1/pools/:name", delete(handlers::delete_pool))
             .route("/api/v1/pools/:name/provision", post(handlers::provision_pool))
             .route("/api/v1/pools/:name/prepare", post(handlers::prepare_pool))
+            .route("/api/v1/pools/:name/upgrade", post(handlers::upgrade_pool))
 
             // VMs
             .route("/api/v1/vms", get(handlers::list_vms))
@@ -56,25 +72,147 @@ impl Server {
             .route("/api/v1/vms/:name/stop", post(handlers::stop_vm))
             .route("/api/v1/vms/:name/prepare", post(handlers::prepare_vm))
             .route("/api/v1/vms/:name/release", post(handlers::release_vm))
+            .route("/api/v1/vms/:name/heartbeat", post(handlers::heartbeat_vm))
+            .route("/api/v1/vms/:name/exec", post(handlers::exec_vm))
+            .route("/api/v1/vms/:name/files", put(handlers::upload_file))
+            .route("/api/v1/vms/:name/files", get(handlers::download_file))
+            .route("/api/v1/vms/:name/screenshot", get(handlers::screenshot_vm))
+            .route("/api/v1/vms/:name/recording", post(handlers::start_recording))
+            .route("/api/v1/vms/:name/recording", delete(handlers::stop_recording))
 
             // Acquire (from pool)
             .route("/api/v1/acquire", post(handlers::acquire_vm))
 
+            // Ephemeral (straight from a template)
+            .route("/api/v1/ephemeral", post(handlers::create_ephemeral))
+
+            // Jobs
+            .route("/api/v1/jobs", get(handlers::list_jobs))
+            .route("/api/v1/jobs/:id", get(handlers::get_job))
+            .route("/api/v1/jobs/:id", delete(handlers::cancel_job))
+
             // Reconcile
             .route("/api/v1/reconcile", post(handlers::reconcile))
 
+            // Events
+            .route("/api/v1/events", get(handlers::list_events))
+            .route("/api/v1/drift", get(handlers::get_drift))
+
             .layer(TraceLayer::new_for_http())
             .layer(cors)
-            .with_state(state);
+            .with_state(state.clone());
+
+        Self {
+            router,
+            addr,
+            orch: state,
+            drain_timeout: Duration::from_secs(30),
+            monitors: Vec::new(),
+        }
+    }
 
-        Self { router, addr }
+    /// How long in-flight requests may run after a shutdown signal before
+    /// their jobs are cancelled
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
     }
 
-    /// Run the server
+    /// Monitor threads (spawned with `shutdown_token()`) to wait for before
+    /// lease state is persisted, so none of them changes it afterwards
+    pub fn with_monitors(mut self, monitors: impl IntoIterator<Item = JoinHandle<()>>) -> Self {
+        self.monitors.extend(monitors);
+        self
+    }
+
+    /// Run the server until SIGINT/SIGTERM (or a Windows console close/shutdown event)
     pub async fn run(self) -> Result<(), std::io::Error> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Run the server until `shutdown` completes, then refuse new acquires,
+    /// stop the monitors, drain in-flight requests and persist lease state
+    pub async fn run_until(self, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<(), std::io::Error> {
         tracing::info!("Starting API server on {}", self.addr);
 
         let listener = tokio::net::TcpListener::bind(self.addr).await?;
-        axum::serve(listener, self.router).await
+        let (draining_tx, draining_rx) = tokio::sync::oneshot::channel();
+        let orch = self.orch.clone();
+        let signal = async move {
+            shutdown.await;
+            tracing::info!("Shutdown requested, draining in-flight requests");
+            orch.begin_shutdown();
+            let _ = draining_tx.send(());
+        };
+
+        let serve = axum::serve(listener, self.router)
+            .with_graceful_shutdown(signal)
+            .into_future();
+        tokio::pin!(serve);
+
+        tokio::select! {
+            result = &mut serve => return result,
+            _ = draining_rx => {}
+        }
+
+        let result = match tokio::time::timeout(self.drain_timeout, &mut serve).await {
+            Ok(result) => result,
+            Err(_) => {
+                let cancelled = self.orch.cancel_all_jobs();
+                tracing::warn!(cancelled, "Drain timed out, cancelling running jobs");
+                tokio::time::timeout(CANCEL_GRACE, &mut serve).await.unwrap_or(Ok(()))
+            }
+        };
+
+        let orch = self.orch.clone();
+        let monitors = self.monitors;
+        let stopped = move || {
+            for monitor in monitors {
+                if monitor.join().is_err() {
+                    tracing::warn!("A background monitor panicked");
+                }
+            }
+            orch.shutdown()
+        };
+        match tokio::task::spawn_blocking(stopped).await {
+            Ok(Ok(leases)) => tracing::info!(leases, "Server stopped; leased VMs keep running"),
+            Ok(Err(e)) => tracing::warn!(error = %e, "Failed to persist lease state"),
+            Err(e) => tracing::warn!(error = %e, "Failed to persist lease state"),
+        }
+        result
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM
+#[cfg(unix)]
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+        let _ = tokio::signal::ctrl_c().await;
+        return;
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Resolves on Ctrl-C, Ctrl-Break, console close or system shutdown
+#[cfg(windows)]
+pub async fn shutdown_signal() {
+    use tokio::signal::windows;
+
+    let (Ok(mut brk), Ok(mut close), Ok(mut shutdown)) =
+        (windows::ctrl_break(), windows::ctrl_close(), windows::ctrl_shutdown())
+    else {
+        let _ = tokio::signal::ctrl_c().await;
+        return;
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = brk.recv() => {}
+        _ = close.recv() => {}
+        _ = shutdown.recv() => {}
     }
 }
@@ -1,5 +1,6 @@
 //! API request/response types
 
+use crate::models::{EventKind, IdleAction, ReadinessProbe, ReconcileOptions, RemediationPolicy};
 use serde::{Deserialize, Serialize};
 
 // === Templates ===
@@ -16,6 +17,13 @@ pub struct CreateTemplateRequest {
     pub gpu_enabled: bool,
     #[serde(default)]
     pub description: Option<String>,
+    #[serde(default)]
+    pub guest_username: Option<String>,
+    #[serde(default)]
+    pub guest_password: Option<String>,
+    /// Ordered readiness probes; defaults to TCP 3389 when empty
+    #[serde(default)]
+    pub readiness_probes: Vec<ReadinessProbe>,
 }
 
 fn default_memory() -> u64 { 4096 }
@@ -30,6 +38,47 @@ pub struct TemplateResponse {
     pub cpu_count: u32,
     pub gpu_enabled: bool,
     pub description: Option<String>,
+    pub guest_username: Option<String>,
+    pub readiness_probes: Vec<ReadinessProbe>,
+    #[serde(default)]
+    pub virtual_size_bytes: Option<u64>,
+    #[serde(default)]
+    pub disk_type: Option<String>,
+    #[serde(default)]
+    pub current_version: u32,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddTemplateVersionRequest {
+    pub vhdx_path: String,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeleteQuery {
+    /// Delete dependent pools and VMs too
+    #[serde(default)]
+    pub cascade: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteTemplateRequest {
+    pub version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TemplateVersionResponse {
+    pub version: u32,
+    pub vhdx_path: String,
+    pub sha256: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub virtual_size_bytes: Option<u64>,
+    pub disk_type: Option<String>,
+    pub notes: Option<String>,
+    /// Whether pools following `latest` use this version
+    pub current: bool,
     pub created_at: String,
 }
 
@@ -43,6 +92,20 @@ pub struct CreatePoolRequest {
     pub desired_count: usize,
     #[serde(default = "default_warm")]
     pub warm_count: usize,
+    /// What to do with VMs that fail health checks
+    #[serde(default)]
+    pub remediation: RemediationPolicy,
+    /// Save or reset Running VMs idle this long (unset = never)
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub idle_action: IdleAction,
+    /// Rebuild VMs on release once their disk files pass this size (unset = no limit)
+    #[serde(default)]
+    pub max_disk_bytes: Option<u64>,
+    /// Template version to pin (unset = follow the current version)
+    #[serde(default)]
+    pub template_version: Option<u32>,
 }
 
 fn default_count() -> usize { 3 }
@@ -55,6 +118,13 @@ pub struct PoolResponse {
     pub template_id: String,
     pub desired_count: usize,
     pub warm_count: usize,
+    pub remediation: String,
+    pub idle_timeout_secs: Option<u64>,
+    pub idle_action: String,
+    #[serde(default)]
+    pub max_disk_bytes: Option<u64>,
+    #[serde(default)]
+    pub template_version: Option<u32>,
     pub created_at: String,
 }
 
@@ -69,16 +139,41 @@ pub struct PoolStatusResponse {
     pub saved_vms: usize,
     pub off_vms: usize,
     pub error_vms: usize,
+    pub unhealthy_vms: usize,
+    pub idle_vms: usize,
+    pub reclaimed_vms: usize,
+    /// Disk and saved-state totals as last measured
+    #[serde(default)]
+    pub disk_bytes: u64,
+    #[serde(default)]
+    pub saved_state_bytes: u64,
+    #[serde(default)]
+    pub over_disk_limit_vms: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProvisionRequest {
     #[serde(default = "default_provision_count")]
     pub count: usize,
+    /// Retrying with the same key returns the original result (the `Idempotency-Key` header also works)
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Delete the whole batch if any VM fails
+    #[serde(default)]
+    pub atomic: bool,
 }
 
 fn default_provision_count() -> usize { 1 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpgradePoolRequest {
+    /// Version to move to and pin (unset = follow the template's current version)
+    #[serde(default)]
+    pub template_version: Option<u32>,
+    #[serde(default = "default_provision_count")]
+    pub batch_size: usize,
+}
+
 // === VMs ===
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -88,12 +183,29 @@ pub struct VMResponse {
     pub template_id: Option<String>,
     pub pool_id: Option<String>,
     pub state: String,
+    pub health: String,
     pub ip_address: Option<String>,
     pub memory_mb: u64,
     pub cpu_count: u32,
     pub gpu_enabled: bool,
     pub created_at: String,
     pub last_resumed_at: Option<String>,
+    pub last_activity_at: Option<String>,
+    /// Turned off after a cancelled operation; re-prepared before reuse
+    #[serde(default)]
+    pub needs_recycle: bool,
+    /// Destroyed on release or at `expires_at`
+    #[serde(default)]
+    pub ephemeral: bool,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    /// Differencing disk plus checkpoint children, as last measured
+    #[serde(default)]
+    pub disk_bytes: Option<u64>,
+    #[serde(default)]
+    pub saved_state_bytes: Option<u64>,
+    #[serde(default)]
+    pub template_version: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -105,9 +217,94 @@ pub struct ResumeResponse {
     pub resume_time_ms: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExecRequest {
+    pub script: String,
+    #[serde(default = "default_exec_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_exec_timeout() -> u64 { 60 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExecResponse {
+    pub vm_name: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileQuery {
+    /// Path inside the guest
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileTransferResponse {
+    pub vm_name: String,
+    pub direction: String,
+    pub guest_path: String,
+    pub size_bytes: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScreenshotQuery {
+    #[serde(default = "default_screenshot_width")]
+    pub width: u32,
+    #[serde(default = "default_screenshot_height")]
+    pub height: u32,
+}
+
+fn default_screenshot_width() -> u32 { 1024 }
+fn default_screenshot_height() -> u32 { 768 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordingRequest {
+    #[serde(default = "default_record_interval")]
+    pub interval_ms: u64,
+}
+
+fn default_record_interval() -> u64 { 1000 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimelineResponse {
+    pub vm_name: String,
+    pub directory: String,
+    pub interval_ms: u64,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub samples: usize,
+    pub frames: Vec<TimelineFrameResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimelineFrameResponse {
+    pub captured_at: String,
+    pub path: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AcquireVMRequest {
     pub pool_name: String,
+    /// Record the console for this lease at the given interval
+    #[serde(default)]
+    pub record_interval_ms: Option<u64>,
+}
+
+/// Create a one-shot VM straight from a template
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EphemeralRequest {
+    pub template: String,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub cpus: Option<u32>,
+    /// Seconds until the VM is destroyed if not released first
+    #[serde(alias = "ttl")]
+    pub ttl_secs: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -148,6 +345,60 @@ pub struct AgentResponse {
     pub error_message: Option<String>,
 }
 
+// === Events ===
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EventQuery {
+    /// Only events for this VM
+    pub vm: Option<String>,
+    /// Only events for this pool
+    pub pool: Option<String>,
+    /// Only events of this kind (e.g. VMIdleReclaimed)
+    pub kind: Option<EventKind>,
+    /// Only events after this id (for polling)
+    pub since: Option<i64>,
+    #[serde(default = "default_event_limit")]
+    pub limit: usize,
+}
+
+fn default_event_limit() -> usize { 100 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventResponse {
+    pub id: i64,
+    pub kind: String,
+    pub vm_name: Option<String>,
+    pub pool_id: Option<String>,
+    pub agent_id: Option<String>,
+    pub message: String,
+    pub created_at: String,
+}
+
+// === Reconcile ===
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReconcileQuery {
+    /// Only report drift, change nothing
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Add unknown pool-named VMs to their pool
+    #[serde(default)]
+    pub adopt: bool,
+    /// Remove unknown VMs and orphan directories
+    #[serde(default)]
+    pub delete_orphans: bool,
+}
+
+impl From<ReconcileQuery> for ReconcileOptions {
+    fn from(q: ReconcileQuery) -> Self {
+        Self {
+            dry_run: q.dry_run,
+            adopt: q.adopt,
+            delete_orphans: q.delete_orphans,
+        }
+    }
+}
+
 // === Generic ===
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -180,12 +431,24 @@ mod tests {
         assert_eq!(req.memory_mb, 4096); // default
         assert_eq!(req.cpu_count, 2); // default
         assert!(!req.gpu_enabled);
+        assert!(req.readiness_probes.is_empty());
+    }
+
+    #[test]
+    fn test_create_template_request_probes() {
+        let json = r#"{"name": "headless", "vhdx_path": "C:\\h.vhdx", "readiness_probes": [{"type": "tcp", "port": 22, "timeout_secs": 30}, {"type": "guest_script", "script": "Test-Path C:\\ready"}]}"#;
+        let req: CreateTemplateRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.readiness_probes.len(), 2);
+        assert_eq!(req.readiness_probes[0].to_string(), "tcp:22");
     }
 
     #[test]
     fn test_create_pool_request_defaults() {
         let json = r#"{"name": "agents", "template_name": "win11"}"#;
         let req: CreatePoolRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.remediation, RemediationPolicy::Notify);
+        assert!(req.idle_timeout_secs.is_none());
+        assert_eq!(req.idle_action, IdleAction::Save);
         
         assert_eq!(req.desired_count, 3); // default
         assert_eq!(req.warm_count, 1); // default
@@ -211,6 +474,36 @@ mod tests {
         let json = r#"{"pool_name": "agents"}"#;
         let req: AcquireVMRequest = serde_json::from_str(json).unwrap();
         assert_eq!(req.pool_name, "agents");
+        assert!(req.record_interval_ms.is_none());
+    }
+
+    #[test]
+    fn test_recording_request_defaults() {
+        let req: RecordingRequest = serde_json::from_str("{}").unwrap();
+        assert_eq!(req.interval_ms, 1000);
+    }
+
+    #[test]
+    fn test_exec_request_defaults() {
+        let json = r#"{"script": "Get-Process"}"#;
+        let req: ExecRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.script, "Get-Process");
+        assert_eq!(req.timeout_seconds, 60); // default
+    }
+
+    #[test]
+    fn test_screenshot_query_defaults() {
+        let q: ScreenshotQuery = serde_json::from_str("{}").unwrap();
+        assert_eq!((q.width, q.height), (1024, 768));
+    }
+
+    #[test]
+    fn test_ephemeral_request() {
+        let json = r#"{"template": "win11", "ttl": 600}"#;
+        let req: EphemeralRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.template, "win11");
+        assert_eq!(req.ttl_secs, 600);
+        assert!(req.memory_mb.is_none() && req.cpus.is_none());
     }
 
     #[test]
@@ -219,4 +512,27 @@ mod tests {
         let req: ReleaseVMRequest = serde_json::from_str(json).unwrap();
         assert!(!req.reset); // default false
     }
+
+    #[test]
+    fn test_event_query_defaults() {
+        let q: EventQuery = serde_json::from_str(r#"{"vm": "agents-0"}"#).unwrap();
+        assert_eq!(q.vm.as_deref(), Some("agents-0"));
+        assert_eq!(q.limit, 100);
+        assert!(q.since.is_none());
+    }
+
+    #[test]
+    fn test_reconcile_query_defaults() {
+        let query: ReconcileQuery = serde_json::from_str("{}").unwrap();
+        let options = ReconcileOptions::from(query);
+        assert!(!options.dry_run && !options.adopt && !options.delete_orphans);
+    }
+
+    #[test]
+    fn test_provision_request_defaults() {
+        let req: ProvisionRequest = serde_json::from_str("{}").unwrap();
+        assert_eq!(req.count, 1);
+        assert!(req.idempotency_key.is_none());
+        assert!(!req.atomic);
+    }
 }
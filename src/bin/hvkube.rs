@@ -2,9 +2,13 @@
 
 use clap::{Parser, Subcommand};
 use hyperv_kube::models::*;
+use hyperv_kube::monitor::{ExpiryReaper, HealthMonitor, IdleReclaimer, Reconciler};
 use hyperv_kube::{Orchestrator, OrchestratorConfig, Result, Server};
+use hyperv_kube::vhdx::DiskChain;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tabled::{Table, Tabled};
 
 #[derive(Parser)]
@@ -16,6 +20,10 @@ struct Cli {
     #[arg(long, global = true, default_value = r"C:\HyperVKube")]
     data_dir: PathBuf,
 
+    /// VMs to provision or prepare at the same time
+    #[arg(long, global = true, default_value = "4")]
+    parallel: usize,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -37,8 +45,29 @@ enum Commands {
         #[command(subcommand)]
         action: VmAction,
     },
-    /// Sync state with Hyper-V
-    Reconcile,
+    /// Sync state with Hyper-V and report drift
+    Reconcile {
+        /// Only report drift, change nothing
+        #[arg(long)]
+        dry_run: bool,
+        /// Add unknown VMs named like a pool member to that pool
+        #[arg(long)]
+        adopt: bool,
+        /// Remove unknown VMs and directories that belong to no VM
+        #[arg(long)]
+        delete_orphans: bool,
+    },
+    /// Finish operations interrupted by a crash (rolled back or forward)
+    Recover,
+    /// Show the event log (health changes, remediation)
+    Events {
+        /// Only events for this VM
+        #[arg(long)]
+        vm: Option<String>,
+        /// Number of most recent events to show
+        #[arg(short = 'n', long, default_value = "50")]
+        limit: usize,
+    },
     /// Start HTTP API server
     Serve {
         /// Host to bind to
@@ -47,6 +76,27 @@ enum Commands {
         /// Port to listen on
         #[arg(short, long, default_value = "8080")]
         port: u16,
+        /// Record every lease's console at this interval (milliseconds)
+        #[arg(long)]
+        record_interval_ms: Option<u64>,
+        /// Seconds between VM health checks (0 disables the monitor)
+        #[arg(long, default_value = "30")]
+        health_interval_secs: u64,
+        /// Consecutive failed checks before a VM is marked unhealthy
+        #[arg(long, default_value = "3")]
+        health_failures: u32,
+        /// Seconds between idle VM sweeps (0 disables reclamation)
+        #[arg(long, default_value = "60")]
+        idle_check_secs: u64,
+        /// Seconds between sweeps for ephemeral VMs past their TTL (always on)
+        #[arg(long, default_value = "30")]
+        expiry_check_secs: u64,
+        /// Seconds between reconcile passes against Hyper-V (0 disables)
+        #[arg(long, default_value = "300")]
+        reconcile_interval_secs: u64,
+        /// Seconds in-flight requests get to finish on shutdown before their jobs are cancelled
+        #[arg(long, default_value = "30")]
+        drain_timeout_secs: u64,
     },
 }
 
@@ -69,13 +119,88 @@ enum TemplateAction {
         /// Enable GPU
         #[arg(long)]
         gpu: bool,
+        /// Guest username for PowerShell Direct
+        #[arg(long)]
+        guest_user: Option<String>,
+        /// Guest password for PowerShell Direct
+        #[arg(long)]
+        guest_password: Option<String>,
+        /// Readiness probe, repeatable and checked in order
+        /// (heartbeat, tcp:PORT, http:PORT/PATH[=STATUS][~BODY], script:PS; suffix @SECS for a timeout)
+        #[arg(long = "probe")]
+        probes: Vec<ReadinessProbe>,
     },
     /// List templates
     List,
-    /// Delete a template
+    /// Delete a template (refused while pools or VMs use it)
     Delete {
         /// Template name
         name: String,
+        /// Also delete the pools and VMs built on it
+        #[arg(long)]
+        cascade: bool,
+    },
+    /// Add a VHDX as the template's next version (not used until promoted)
+    AddVersion {
+        /// Template name
+        name: String,
+        /// Path to VHDX file
+        #[arg(short, long)]
+        vhdx: PathBuf,
+        /// What changed in this version
+        #[arg(long)]
+        notes: Option<String>,
+        /// Promote it right away
+        #[arg(long)]
+        promote: bool,
+    },
+    /// List a template's versions
+    Versions {
+        /// Template name
+        name: String,
+    },
+    /// Make a version the one pools following latest provision from
+    Promote {
+        /// Template name
+        name: String,
+        /// Version number
+        version: u32,
+    },
+    /// Promote the version before the current one
+    Rollback {
+        /// Template name
+        name: String,
+    },
+    /// Bake a new version from a build spec (base VHDX, steps, sysprep)
+    Build {
+        /// Build spec (YAML)
+        #[arg(short, long)]
+        file: PathBuf,
+    },
+    /// Re-hash a version's VHDX against the checksum recorded when it was added
+    Verify {
+        /// Template name
+        name: String,
+        /// Version to check (default: current)
+        #[arg(long)]
+        version: Option<u32>,
+    },
+    /// Turn a VM's disk into a new template (the VM is shut down first)
+    Capture {
+        /// VM name
+        vm: String,
+        /// New template name
+        #[arg(short, long)]
+        name: String,
+        /// Merge a copy and leave the VM as it is (must be Off)
+        #[arg(long)]
+        keep_source: bool,
+        /// Merged VHDX (default: <name>.vhdx next to the VM's template)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// What this template is for
+        #[arg(long)]
+        notes: Option<String>,
     },
 }
 
@@ -92,6 +217,21 @@ enum PoolAction {
         /// Number of VMs
         #[arg(short, long, default_value = "3")]
         count: usize,
+        /// What to do with unhealthy VMs (notify, reset, rebuild)
+        #[arg(long, default_value = "notify")]
+        remediation: RemediationPolicy,
+        /// Reclaim Running VMs idle this many seconds
+        #[arg(long)]
+        idle_timeout_secs: Option<u64>,
+        /// How to reclaim idle VMs (save, reset)
+        #[arg(long, default_value = "save")]
+        idle_action: IdleAction,
+        /// Rebuild VMs on release once their own disk files pass this many GB
+        #[arg(long)]
+        max_disk_gb: Option<u64>,
+        /// Pin a template version (default: follow the current version)
+        #[arg(long)]
+        template_version: Option<u32>,
     },
     /// List pools
     List,
@@ -107,19 +247,36 @@ enum PoolAction {
         /// Number of VMs to create
         #[arg(short, long, default_value = "1")]
         count: usize,
+        /// Re-running with the same key shows the first run's result instead of provisioning again
+        #[arg(long)]
+        idempotency_key: Option<String>,
+        /// Keep going when a VM fails instead of deleting the whole batch
+        #[arg(long)]
+        partial: bool,
     },
     /// Prepare all VMs in pool (boot, checkpoint, save)
     Prepare {
         /// Pool name
         name: String,
     },
-    /// Delete a pool
-    Delete {
+    /// Replace the pool's VMs with ones from another template version, a batch at a time
+    Upgrade {
         /// Pool name
         name: String,
-        /// Also delete VMs
+        /// Version to move to and pin (default: the template's current version, unpinned)
         #[arg(long)]
-        delete_vms: bool,
+        template_version: Option<u32>,
+        /// New VMs brought up before each round of old ones is deleted
+        #[arg(long, default_value = "1")]
+        batch_size: usize,
+    },
+    /// Delete a pool (refused while it has VMs)
+    Delete {
+        /// Pool name
+        name: String,
+        /// Also delete its VMs
+        #[arg(long, alias = "delete-vms")]
+        cascade: bool,
     },
 }
 
@@ -136,6 +293,11 @@ enum VmAction {
         /// VM name
         name: String,
     },
+    /// Show the VM's differencing-disk chain down to the base disk
+    DiskChain {
+        /// VM name
+        name: String,
+    },
     /// Resume a saved VM (fast!)
     Resume {
         /// VM name
@@ -174,6 +336,52 @@ enum VmAction {
         /// VM name
         name: String,
     },
+    /// Create and boot a one-shot VM from a template (destroyed on delete or by `serve` after the TTL)
+    Ephemeral {
+        /// Template name
+        #[arg(short, long)]
+        template: String,
+        /// Memory in MB (defaults to the template's)
+        #[arg(short, long)]
+        memory: Option<u64>,
+        /// CPU count (defaults to the template's)
+        #[arg(short, long)]
+        cpus: Option<u32>,
+        /// Seconds until the VM is destroyed
+        #[arg(long, default_value = "3600")]
+        ttl_secs: u64,
+    },
+    /// Run a PowerShell script inside the guest
+    Exec {
+        /// VM name
+        name: String,
+        /// Script to run
+        script: String,
+        /// Timeout in seconds
+        #[arg(short, long, default_value = "60")]
+        timeout: u64,
+    },
+    /// Copy files between host and guest (use VM:PATH for the guest side)
+    Cp {
+        /// Source (host path or VM:PATH)
+        source: String,
+        /// Destination (host path or VM:PATH)
+        destination: String,
+    },
+    /// Capture the VM console as PNG
+    Screenshot {
+        /// VM name
+        name: String,
+        /// Output file
+        #[arg(short, long, default_value = "screenshot.png")]
+        output: PathBuf,
+        /// Width in pixels
+        #[arg(long, default_value = "1024")]
+        width: u32,
+        /// Height in pixels
+        #[arg(long, default_value = "768")]
+        height: u32,
+    },
 }
 
 // Table display structs
@@ -187,6 +395,28 @@ struct TemplateRow {
     cpus: u32,
     #[tabled(rename = "GPU")]
     gpu: String,
+    #[tabled(rename = "Ready When")]
+    probes: String,
+    #[tabled(rename = "Version")]
+    version: String,
+    #[tabled(rename = "Disk")]
+    disk: String,
+    #[tabled(rename = "VHDX")]
+    vhdx: String,
+}
+
+#[derive(Tabled)]
+struct TemplateVersionRow {
+    #[tabled(rename = "Version")]
+    version: String,
+    #[tabled(rename = "SHA-256")]
+    sha256: String,
+    #[tabled(rename = "Size")]
+    size: String,
+    #[tabled(rename = "Notes")]
+    notes: String,
+    #[tabled(rename = "Created")]
+    created: String,
     #[tabled(rename = "VHDX")]
     vhdx: String,
 }
@@ -201,6 +431,8 @@ struct PoolRow {
     desired: usize,
     #[tabled(rename = "Warm")]
     warm: usize,
+    #[tabled(rename = "Remediation")]
+    remediation: String,
 }
 
 #[derive(Tabled)]
@@ -209,6 +441,8 @@ struct VMRow {
     name: String,
     #[tabled(rename = "State")]
     state: String,
+    #[tabled(rename = "Health")]
+    health: String,
     #[tabled(rename = "Pool")]
     pool: String,
     #[tabled(rename = "IP")]
@@ -217,6 +451,32 @@ struct VMRow {
     memory: String,
 }
 
+#[derive(Tabled)]
+struct EventRow {
+    #[tabled(rename = "Time")]
+    time: String,
+    #[tabled(rename = "Kind")]
+    kind: String,
+    #[tabled(rename = "VM")]
+    vm: String,
+    #[tabled(rename = "Message")]
+    message: String,
+}
+
+/// First Ctrl-C cancels running operations so they can clean up; a second one
+/// (or one with nothing to cancel) exits immediately
+fn cancel_on_ctrl_c(orch: Arc<Orchestrator>) {
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            let cancelled = orch.cancel_all_jobs();
+            if cancelled == 0 {
+                std::process::exit(130);
+            }
+            eprintln!("Cancelling {} operation(s), press Ctrl-C again to exit now...", cancelled);
+        }
+    });
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging
@@ -229,24 +489,91 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    let record_interval = match &cli.command {
+        Commands::Serve { record_interval_ms, .. } => record_interval_ms.map(Duration::from_millis),
+        _ => None,
+    };
+
     let config = OrchestratorConfig {
         vm_storage_path: cli.data_dir.join("VMs"),
         db_path: cli.data_dir.join("state.db"),
+        record_interval,
+        max_parallel: cli.parallel,
         ..Default::default()
     };
 
-    let orch = Orchestrator::with_config(config)?;
+    let orch = Arc::new(Orchestrator::with_config(config)?);
+    if !matches!(cli.command, Commands::Serve { .. }) {
+        cancel_on_ctrl_c(orch.clone());
+    }
 
     match cli.command {
         Commands::Template { action } => handle_template(&orch, action)?,
         Commands::Pool { action } => handle_pool(&orch, action)?,
         Commands::Vm { action } => handle_vm(&orch, action)?,
-        Commands::Reconcile => {
+        Commands::Reconcile { dry_run, adopt, delete_orphans } => {
             println!("Reconciling state with Hyper-V...");
-            orch.reconcile()?;
-            println!("Done.");
+            let report = orch.reconcile_with(&ReconcileOptions { dry_run, adopt, delete_orphans })?;
+            if report.is_clean() {
+                println!("No drift.");
+                return Ok(());
+            }
+
+            println!("Drift:");
+            for item in &report.items {
+                println!("  {}", item);
+            }
+            if !report.actions.is_empty() {
+                println!();
+                println!("Actions:");
+                for action in &report.actions {
+                    println!("  {}", action);
+                }
+            }
+        }
+        Commands::Recover => {
+            let recovered = orch.recover_operations()?;
+            if recovered.is_empty() {
+                println!("No interrupted operations.");
+            }
+            for op in recovered {
+                println!("{} {} on {}: {}", op.id, op.kind, op.vm_name, op.status);
+            }
+        }
+        Commands::Events { vm, limit } => {
+            let events = orch.list_events(&EventFilter {
+                vm_name: vm,
+                limit: Some(limit),
+                ..Default::default()
+            })?;
+            if events.is_empty() {
+                println!("No events.");
+                return Ok(());
+            }
+
+            let rows: Vec<EventRow> = events
+                .into_iter()
+                .map(|e| EventRow {
+                    time: e.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    kind: e.kind.to_string(),
+                    vm: e.vm_name.unwrap_or_else(|| "-".to_string()),
+                    message: e.message,
+                })
+                .collect();
+
+            println!("{}", Table::new(rows));
         }
-        Commands::Serve { host, port } => {
+        Commands::Serve {
+            host,
+            port,
+            health_interval_secs,
+            health_failures,
+            idle_check_secs,
+            expiry_check_secs,
+            reconcile_interval_secs,
+            drain_timeout_secs,
+            ..
+        } => {
             let addr: SocketAddr = format!("{}:{}", host, port).parse()
                 .expect("Invalid address");
 
@@ -263,11 +590,52 @@ async fn main() -> Result<()> {
             println!("  GET  /api/v1/vms                List VMs");
             println!("  POST /api/v1/vms/:name/resume   Resume VM (fast!)");
             println!("  POST /api/v1/vms/:name/save     Save VM state");
+            println!("  POST /api/v1/vms/:name/exec     Run script in guest");
+            println!("  PUT  /api/v1/vms/:name/files    Upload file to guest");
+            println!("  GET  /api/v1/vms/:name/files    Download file from guest");
+            println!("  GET  /api/v1/vms/:name/screenshot  Console screenshot (PNG)");
+            println!("  POST /api/v1/vms/:name/recording   Start console recording");
+            println!("  DELETE /api/v1/vms/:name/recording Stop recording, get timeline");
             println!("  POST /api/v1/acquire            Acquire VM from pool");
+            println!("  POST /api/v1/ephemeral          Create a one-shot VM from a template");
             println!("  POST /api/v1/vms/:name/release  Release VM to pool");
+            println!("  POST /api/v1/vms/:name/heartbeat  Keep a lease from being reclaimed");
+            println!("  GET  /api/v1/events             Event log (health, remediation, drift)");
+            println!("  GET  /api/v1/drift              Drift between DB and Hyper-V");
+            println!("  POST /api/v1/reconcile          Reconcile now");
+            println!("  GET  /api/v1/jobs               Running and recent jobs");
+            println!("  DELETE /api/v1/jobs/:id         Cancel a job");
             println!();
 
-            let server = Server::new(orch, addr);
+            // Nothing else is running yet, so anything unfinished was interrupted
+            for op in orch.recover_operations()? {
+                println!("Recovered interrupted {} on {}: {}", op.kind, op.vm_name, op.status);
+            }
+            let reattached = orch.reattach_leases()?;
+            if !reattached.is_empty() {
+                println!("Re-attached {} lease(s) from the previous run", reattached.len());
+            }
+
+            let stop = orch.shutdown_token();
+            let mut monitors = Vec::new();
+            if health_interval_secs > 0 {
+                monitors.push(
+                    HealthMonitor::new(orch.clone(), Duration::from_secs(health_interval_secs))
+                        .with_failure_threshold(health_failures)
+                        .spawn(stop.clone()),
+                );
+            }
+            if idle_check_secs > 0 {
+                monitors.push(IdleReclaimer::new(orch.clone(), Duration::from_secs(idle_check_secs)).spawn(stop.clone()));
+            }
+            monitors.push(ExpiryReaper::new(orch.clone(), Duration::from_secs(expiry_check_secs)).spawn(stop.clone()));
+            if reconcile_interval_secs > 0 {
+                monitors.push(Reconciler::new(orch.clone(), Duration::from_secs(reconcile_interval_secs)).spawn(stop));
+            }
+
+            let server = Server::new(orch, addr)
+                .with_drain_timeout(Duration::from_secs(drain_timeout_secs))
+                .with_monitors(monitors);
             server.run().await.map_err(|e| hyperv_kube::Error::Other(e.to_string()))?;
         }
     }
@@ -283,14 +651,22 @@ fn handle_template(orch: &Orchestrator, action: TemplateAction) -> Result<()> {
             memory,
             cpus,
             gpu,
+            guest_user,
+            guest_password,
+            probes,
         } => {
-            let template = Template::new(&name, &vhdx)
+            let mut template = Template::new(&name, &vhdx)
                 .with_memory(memory)
                 .with_cpus(cpus)
-                .with_gpu(gpu);
+                .with_gpu(gpu)
+                .with_probes(probes);
 
-            let id = orch.register_template(template)?;
-            println!("Template registered: {} ({})", name, id);
+            if let Some(user) = guest_user {
+                template = template.with_guest_credential(user, guest_password.unwrap_or_default());
+            }
+
+            let id = orch.register_template_with_progress(template, &print_hash_progress("Hashing"))?;
+            println!("Template registered: {} ({}), VHDX now read-only", name, id);
         }
         TemplateAction::List => {
             let templates = orch.list_templates()?;
@@ -306,20 +682,116 @@ fn handle_template(orch: &Orchestrator, action: TemplateAction) -> Result<()> {
                     memory: format!("{}MB", t.memory_mb),
                     cpus: t.cpu_count,
                     gpu: if t.gpu_enabled { "Yes" } else { "No" }.to_string(),
+                    probes: t.probes().iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", "),
+                    version: format!("v{}", t.current_version),
+                    disk: match (t.disk_type, t.virtual_size_bytes) {
+                        (Some(kind), Some(size)) => format!("{} {}GB", kind, size >> 30),
+                        _ => "-".to_string(),
+                    },
                     vhdx: t.vhdx_path.to_string_lossy().to_string(),
                 })
                 .collect();
 
             println!("{}", Table::new(rows));
         }
-        TemplateAction::Delete { name } => {
-            if let Some(t) = orch.get_template(&name)? {
-                orch.db().delete_template(&t.id)?;
-                println!("Template deleted: {}", name);
-            } else {
-                println!("Template not found: {}", name);
+        TemplateAction::Delete { name, cascade } => {
+            orch.delete_template(&name, cascade)?;
+            println!("Template deleted: {} (VHDX files kept)", name);
+        }
+        TemplateAction::AddVersion { name, vhdx, notes, promote } => {
+            let version = orch.add_template_version(&name, &vhdx, notes)?;
+            println!("Added {} v{} (sha256 {})", name, version.version, version.sha256.as_deref().unwrap_or("-"));
+            if promote {
+                orch.promote_template_version(&name, version.version)?;
+                println!("Promoted {} to v{}", name, version.version);
             }
         }
+        TemplateAction::Versions { name } => {
+            let template = orch
+                .get_template(&name)?
+                .ok_or_else(|| hyperv_kube::Error::TemplateNotFound(name.clone()))?;
+            let rows: Vec<TemplateVersionRow> = orch
+                .list_template_versions(&name)?
+                .into_iter()
+                .map(|v| TemplateVersionRow {
+                    version: if v.version == template.current_version {
+                        format!("v{} *", v.version)
+                    } else {
+                        format!("v{}", v.version)
+                    },
+                    sha256: v.sha256.as_deref().map(|h| h[..12.min(h.len())].to_string()).unwrap_or_else(|| "-".to_string()),
+                    size: v.size_bytes.map(|b| format!("{}MB", b >> 20)).unwrap_or_else(|| "-".to_string()),
+                    notes: v.notes.unwrap_or_default(),
+                    created: v.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                    vhdx: v.vhdx_path.to_string_lossy().to_string(),
+                })
+                .collect();
+            println!("{}", Table::new(rows));
+            println!("* current; pools without a pinned version use it");
+        }
+        TemplateAction::Promote { name, version } => {
+            let template = orch.promote_template_version(&name, version)?;
+            println!("{} now at v{}", name, template.current_version);
+        }
+        TemplateAction::Rollback { name } => {
+            let template = orch.rollback_template(&name)?;
+            println!("{} rolled back to v{}", name, template.current_version);
+        }
+        TemplateAction::Build { file } => {
+            let spec = BuildSpec::from_file(&file)?;
+            let result = orch.build_template(&spec, &|line| println!("  {}", line))?;
+            let version = &result.version;
+            println!();
+            println!("Built {} v{}: {}", result.template, version.version, version.vhdx_path.display());
+            println!("  sha256: {}", version.sha256.as_deref().unwrap_or("-"));
+            if let Some(log) = &version.build_log {
+                println!("  log:    {}", log.display());
+            }
+            if !result.promoted {
+                println!("Not promoted; run `hvkube template promote {} {}` to use it", result.template, version.version);
+            }
+        }
+        TemplateAction::Verify { name, version } => {
+            let check = orch.verify_template(&name, version, &print_hash_progress("Hashing"))?;
+            println!("{} v{}: {}", check.template, check.version, check.vhdx_path.display());
+            println!("  sha256: {}", check.actual_sha256);
+            if !check.read_only {
+                println!("  warning: the VHDX is writable; every pool disk depends on it staying unchanged");
+            }
+            match &check.expected_sha256 {
+                None => {
+                    println!("UNVERIFIED: no checksum was recorded for this version, so there was nothing to compare with.");
+                    println!("Recorded the current hash; later checks compare against it.");
+                }
+                Some(_) if check.is_intact() => println!("OK"),
+                Some(expected) => {
+                    println!("MISMATCH: expected {} ({} bytes)", expected, check.expected_size.unwrap_or_default());
+                    println!("Provisioning from this version is refused until the image is restored.");
+                    std::process::exit(1);
+                }
+            }
+        }
+        TemplateAction::Capture { vm, name, keep_source, output, notes } => {
+            let source = orch
+                .get_vm(&vm)?
+                .ok_or_else(|| hyperv_kube::Error::VMNotFound(vm.clone()))?;
+            let mut options = CaptureOptions::new();
+            if keep_source {
+                options = options.keep_source();
+            }
+            if let Some(path) = output {
+                options = options.with_output(path);
+            }
+            if let Some(notes) = notes {
+                options = options.with_notes(notes);
+            }
+
+            println!("Capturing {} (this merges the whole disk chain)...", vm);
+            let template = orch.capture_template(&source.id, &name, &options)?;
+            let version = orch.list_template_versions(&name)?.into_iter().next();
+            println!("Template {} registered from {}: {}", name, vm, template.vhdx_path.display());
+            println!("  sha256: {}", version.as_ref().and_then(|v| v.sha256.as_deref()).unwrap_or("-"));
+        }
     }
     Ok(())
 }
@@ -330,12 +802,28 @@ fn handle_pool(orch: &Orchestrator, action: PoolAction) -> Result<()> {
             name,
             template,
             count,
+            remediation,
+            idle_timeout_secs,
+            idle_action,
+            max_disk_gb,
+            template_version,
         } => {
             let tmpl = orch
                 .get_template(&template)?
                 .ok_or_else(|| hyperv_kube::Error::TemplateNotFound(template.clone()))?;
 
-            let pool = VMPool::new(&name, &tmpl.id).with_count(count);
+            let mut pool = VMPool::new(&name, &tmpl.id)
+                .with_count(count)
+                .with_remediation(remediation);
+            if let Some(secs) = idle_timeout_secs {
+                pool = pool.with_idle_timeout(secs, idle_action);
+            }
+            if let Some(gb) = max_disk_gb {
+                pool = pool.with_disk_limit(gb << 30);
+            }
+            if let Some(version) = template_version {
+                pool = pool.with_template_version(version);
+            }
             let id = orch.create_pool(pool)?;
             println!("Pool created: {} ({})", name, id);
         }
@@ -355,11 +843,15 @@ fn handle_pool(orch: &Orchestrator, action: PoolAction) -> Result<()> {
                         .find(|t| t.id == p.template_id)
                         .map(|t| t.name.clone())
                         .unwrap_or_else(|| "?".to_string());
+                    let pin = p.template_version
+                        .map(|v| format!("v{}", v))
+                        .unwrap_or_else(|| "latest".to_string());
                     PoolRow {
                         name: p.name.clone(),
-                        template: tmpl_name,
+                        template: format!("{} ({})", tmpl_name, pin),
                         desired: p.desired_count,
                         warm: p.warm_count,
+                        remediation: p.remediation.to_string(),
                     }
                 })
                 .collect();
@@ -380,21 +872,35 @@ fn handle_pool(orch: &Orchestrator, action: PoolAction) -> Result<()> {
             println!("  Saved:   {}", status.saved_vms);
             println!("  Off:     {}", status.off_vms);
             println!("  Error:   {}", status.error_vms);
+            println!("  Unhealthy: {}", status.unhealthy_vms);
+            println!("  Idle:    {} (reclaimed so far: {})", status.idle_vms, status.reclaimed_vms);
+            println!(
+                "  Disk:    {}MB, saved state {}MB ({} over limit)",
+                status.disk_bytes >> 20,
+                status.saved_state_bytes >> 20,
+                status.over_disk_limit_vms
+            );
         }
-        PoolAction::Provision { name, count } => {
+        PoolAction::Provision { name, count, idempotency_key, partial } => {
             let pool = orch
                 .db()
                 .get_pool_by_name(&name)?
                 .ok_or_else(|| hyperv_kube::Error::PoolNotFound(name.clone()))?;
 
             println!("Provisioning {} VMs for pool {}...", count, name);
-            let ids = orch.provision_pool(&pool.id, count)?;
-            println!("Created {} VMs:", ids.len());
-            for id in ids {
-                if let Some(vm) = orch.db().get_vm(&id)? {
-                    println!("  - {}", vm.name);
+            let mut options = ProvisionOptions::new(count);
+            options.idempotency_key = idempotency_key;
+            options.atomic = !partial;
+
+            let result = orch.provision_with_progress(&pool.id, &options, &|p| println!("  {}", p))?;
+            println!();
+            for vm in &result.vms {
+                match &vm.error {
+                    Some(error) => println!("  - {}: {} ({})", vm.name, vm.outcome, error),
+                    None => println!("  - {}: {}", vm.name, vm.outcome),
                 }
             }
+            println!("Created {} of {} VMs.", result.created_ids().len(), count);
         }
         PoolAction::Prepare { name } => {
             let pool = orch
@@ -411,27 +917,44 @@ fn handle_pool(orch: &Orchestrator, action: PoolAction) -> Result<()> {
             }
 
             println!("Preparing {} VMs in pool {}...", off_vms.len(), name);
-            for vm in off_vms {
-                println!("Preparing {}...", vm.name);
-                orch.prepare_vm(&vm.id)?;
-            }
-            println!("Done.");
+            let prepared = orch.prepare_pool(&pool.id, &|p| println!("  {}", p))?;
+            let failed = prepared.iter().filter(|p| p.error.is_some()).count();
+            println!("Prepared {} of {} VMs.", prepared.len() - failed, prepared.len());
         }
-        PoolAction::Delete { name, delete_vms } => {
+        PoolAction::Upgrade { name, template_version, batch_size } => {
             let pool = orch
                 .db()
                 .get_pool_by_name(&name)?
                 .ok_or_else(|| hyperv_kube::Error::PoolNotFound(name.clone()))?;
 
-            if delete_vms {
-                let vms = orch.db().list_vms_by_pool(&pool.id)?;
-                for vm in vms {
-                    println!("Deleting VM {}...", vm.name);
-                    orch.delete_vm(&vm.id)?;
-                }
+            let options = UpgradeOptions::new(template_version).with_batch_size(batch_size);
+            let result = orch.upgrade_pool(&pool.id, &options, &|p| println!("  {}", p))?;
+            println!();
+            println!(
+                "Pool {} -> v{}: {} new, {} replaced.",
+                name,
+                result.target_version,
+                result.created.len(),
+                result.replaced.len()
+            );
+            if !result.pending.is_empty() {
+                println!("Still leased (run again once released): {}", result.pending.join(", "));
+            }
+            if let Some(reason) = &result.paused {
+                println!("Paused: {}", reason);
+                std::process::exit(1);
             }
+        }
+        PoolAction::Delete { name, cascade } => {
+            let pool = orch
+                .db()
+                .get_pool_by_name(&name)?
+                .ok_or_else(|| hyperv_kube::Error::PoolNotFound(name.clone()))?;
 
-            orch.db().delete_pool(&pool.id)?;
+            if cascade {
+                println!("Deleting pool {} and its VMs...", name);
+            }
+            orch.delete_pool(&pool.id, cascade)?;
             println!("Pool deleted: {}", name);
         }
     }
@@ -469,6 +992,7 @@ fn handle_vm(orch: &Orchestrator, action: VmAction) -> Result<()> {
                     VMRow {
                         name: v.name.clone(),
                         state: v.state.to_string(),
+                        health: v.health.to_string(),
                         pool: pool_name,
                         ip: v.ip_address.clone().unwrap_or_else(|| "-".to_string()),
                         memory: format!("{}MB", v.memory_mb),
@@ -491,11 +1015,40 @@ fn handle_vm(orch: &Orchestrator, action: VmAction) -> Result<()> {
             println!("  CPUs:     {}", vm.cpu_count);
             println!("  GPU:      {}", if vm.gpu_enabled { "Yes" } else { "No" });
             println!("  VHDX:     {}", vm.vhdx_path.display());
+            if let Some(version) = vm.template_version {
+                println!("  Template: v{}", version);
+            }
+            if let Some(bytes) = vm.disk_bytes {
+                println!("  Disk:     {}MB (saved state {}MB)", bytes >> 20, vm.saved_state_bytes.unwrap_or(0) >> 20);
+            }
             println!("  Created:  {}", vm.created_at);
             if let Some(t) = vm.last_resumed_at {
                 println!("  Resumed:  {}", t);
             }
         }
+        VmAction::DiskChain { name } => {
+            let vm = orch
+                .get_vm(&name)?
+                .ok_or_else(|| hyperv_kube::Error::VMNotFound(name.clone()))?;
+
+            let chain = DiskChain::walk(&vm.vhdx_path);
+            for (depth, link) in chain.links.iter().enumerate() {
+                println!(
+                    "{}{} ({} {}GB)",
+                    "  ".repeat(depth),
+                    link.path.display(),
+                    link.disk_type,
+                    link.virtual_size >> 30
+                );
+            }
+            match &chain.problem {
+                Some(problem) => {
+                    println!("BROKEN: {}", problem);
+                    std::process::exit(1);
+                }
+                None => println!("OK"),
+            }
+        }
         VmAction::Resume { name } => {
             let vm = orch
                 .get_vm(&name)?
@@ -505,7 +1058,10 @@ fn handle_vm(orch: &Orchestrator, action: VmAction) -> Result<()> {
             let start = std::time::Instant::now();
             let ip = orch.resume_vm(&vm.id)?;
             let elapsed = start.elapsed();
-            println!("VM ready in {:.2}s at {}", elapsed.as_secs_f64(), ip);
+            match ip {
+                Some(ip) => println!("VM ready in {:.2}s at {}", elapsed.as_secs_f64(), ip),
+                None => println!("VM ready in {:.2}s", elapsed.as_secs_f64()),
+            }
         }
         VmAction::Save { name } => {
             let vm = orch
@@ -560,6 +1116,85 @@ fn handle_vm(orch: &Orchestrator, action: VmAction) -> Result<()> {
             orch.prepare_vm(&vm.id)?;
             println!("Done. VM is ready for fast resume.");
         }
+        VmAction::Ephemeral { template, memory, cpus, ttl_secs } => {
+            let mut options = EphemeralOptions::new(&template, Duration::from_secs(ttl_secs));
+            options.memory_mb = memory;
+            options.cpu_count = cpus;
+
+            println!("Creating ephemeral VM from {}...", template);
+            let vm = orch.create_ephemeral(&options)?;
+            println!("{} ready at {}", vm.name, vm.ip_address.as_deref().unwrap_or("-"));
+            if let Some(expires_at) = vm.expires_at {
+                println!("Expires at {}", expires_at.format("%Y-%m-%d %H:%M:%S UTC"));
+            }
+        }
+        VmAction::Exec { name, script, timeout } => {
+            let vm = orch
+                .get_vm(&name)?
+                .ok_or_else(|| hyperv_kube::Error::VMNotFound(name.clone()))?;
+
+            let output = orch.exec_vm(&vm.id, &script, Duration::from_secs(timeout))?;
+            print!("{}", output.stdout);
+            eprint!("{}", output.stderr);
+            if output.exit_code != 0 {
+                std::process::exit(output.exit_code);
+            }
+        }
+        VmAction::Cp { source, destination } => {
+            let transfer = match (parse_guest_path(&source), parse_guest_path(&destination)) {
+                (None, Some((vm_name, guest_path))) => {
+                    let vm = orch
+                        .get_vm(vm_name)?
+                        .ok_or_else(|| hyperv_kube::Error::VMNotFound(vm_name.to_string()))?;
+                    orch.push_file(&vm.id, std::path::Path::new(&source), guest_path)?
+                }
+                (Some((vm_name, guest_path)), None) => {
+                    let vm = orch
+                        .get_vm(vm_name)?
+                        .ok_or_else(|| hyperv_kube::Error::VMNotFound(vm_name.to_string()))?;
+                    orch.pull_file(&vm.id, guest_path, std::path::Path::new(&destination))?
+                }
+                _ => {
+                    return Err(hyperv_kube::Error::Other(
+                        "Exactly one of source/destination must be VM:PATH".to_string(),
+                    ))
+                }
+            };
+            println!("Copied {} bytes (sha256 {})", transfer.size_bytes, transfer.sha256);
+        }
+        VmAction::Screenshot { name, output, width, height } => {
+            let vm = orch
+                .get_vm(&name)?
+                .ok_or_else(|| hyperv_kube::Error::VMNotFound(name.clone()))?;
+
+            let png = orch.screenshot(&vm.id, width, height)?;
+            std::fs::write(&output, png)?;
+            println!("Saved {}", output.display());
+        }
     }
     Ok(())
 }
+
+/// Prints "<label> 10%", "20%"... on one line while a file is hashed
+fn print_hash_progress(label: &str) -> impl Fn(u64, u64) + Sync + '_ {
+    let shown = std::sync::atomic::AtomicU64::new(0);
+    move |done, total| {
+        let tenths = (done * 10).checked_div(total).unwrap_or(10);
+        if shown.fetch_max(tenths, std::sync::atomic::Ordering::Relaxed) < tenths {
+            print!("\r{} {}%", label, tenths * 10);
+            let _ = std::io::Write::flush(&mut std::io::stdout());
+            if tenths == 10 {
+                println!();
+            }
+        }
+    }
+}
+
+/// Split `VM:PATH` into its parts; single-letter prefixes are drive letters, not VMs
+fn parse_guest_path(spec: &str) -> Option<(&str, &str)> {
+    let (vm, path) = spec.split_once(':')?;
+    if vm.len() < 2 || path.is_empty() {
+        return None;
+    }
+    Some((vm, path))
+}
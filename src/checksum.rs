@@ -0,0 +1,85 @@
+//! File checksums
+
+use crate::Result;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+/// Receives (bytes hashed so far, file size) while a file is hashed
+pub type HashProgress<'a> = &'a (dyn Fn(u64, u64) + Sync);
+
+/// Stream a file through SHA-256, returning (size in bytes, lowercase hex digest)
+pub fn sha256_file(path: impl AsRef<Path>) -> Result<(u64, String)> {
+    sha256_file_with_progress(path, &|_, _| {})
+}
+
+/// `sha256_file`, reporting progress after every chunk
+pub fn sha256_file_with_progress(path: impl AsRef<Path>, on_progress: HashProgress<'_>) -> Result<(u64, String)> {
+    let mut file = std::fs::File::open(path)?;
+    let total = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    let mut size = 0u64;
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+        on_progress(size, total);
+    }
+
+    Ok((size, to_hex(&hasher.finalize())))
+}
+
+/// SHA-256 of an in-memory buffer as lowercase hex
+pub fn sha256_bytes(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_sha256_bytes() {
+        assert_eq!(sha256_bytes(b"abc"), ABC_SHA256);
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("abc.txt");
+        std::fs::write(&path, "abc").unwrap();
+
+        let (size, digest) = sha256_file(&path).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(digest, ABC_SHA256);
+    }
+
+    #[test]
+    fn test_sha256_file_progress() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("big.bin");
+        std::fs::write(&path, vec![7u8; 2 * 1024 * 1024 + 5]).unwrap();
+
+        let seen = std::sync::Mutex::new(Vec::new());
+        let (size, _) = sha256_file_with_progress(&path, &|done, total| seen.lock().unwrap().push((done, total))).unwrap();
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen.last(), Some(&(size, size)));
+    }
+
+    #[test]
+    fn test_sha256_missing_file() {
+        assert!(sha256_file("/nonexistent/file.bin").is_err());
+    }
+}
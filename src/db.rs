@@ -1,11 +1,105 @@
 //! SQLite state storage
 
 use crate::models::*;
-use crate::Result;
+use crate::{secret, Result};
 use rusqlite::{params, Connection, OptionalExtension};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+/// Bumped for migrations `add_column` can't express; stored in `PRAGMA user_version`
+const SCHEMA_VERSION: i64 = 1;
+
+/// Tables with foreign keys, in creation order. Pools and VMs keep the rows
+/// they depend on from being deleted; agents outlive their pool and VM.
+const REFERENCING_TABLES: [(&str, &str); 4] = [
+    (
+        "template_versions",
+        r#"
+        template_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        vhdx_path TEXT NOT NULL,
+        sha256 TEXT,
+        size_bytes INTEGER,
+        virtual_size_bytes INTEGER,
+        disk_type TEXT,
+        notes TEXT,
+        created_at TEXT NOT NULL,
+        build_log TEXT,
+        PRIMARY KEY (template_id, version),
+        FOREIGN KEY (template_id) REFERENCES templates(id) ON DELETE CASCADE
+        "#,
+    ),
+    (
+        "pools",
+        r#"
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        template_id TEXT NOT NULL,
+        desired_count INTEGER NOT NULL,
+        warm_count INTEGER NOT NULL,
+        max_per_host INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        remediation TEXT,
+        idle_timeout_secs INTEGER,
+        idle_action TEXT,
+        max_disk_bytes INTEGER,
+        template_version INTEGER,
+        next_index INTEGER NOT NULL DEFAULT 0,
+        FOREIGN KEY (template_id) REFERENCES templates(id) ON DELETE RESTRICT
+        "#,
+    ),
+    (
+        "vms",
+        r#"
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        template_id TEXT,
+        pool_id TEXT,
+        state TEXT NOT NULL,
+        vhdx_path TEXT NOT NULL,
+        ip_address TEXT,
+        memory_mb INTEGER NOT NULL,
+        cpu_count INTEGER NOT NULL,
+        gpu_enabled INTEGER NOT NULL,
+        current_agent_id TEXT,
+        created_at TEXT NOT NULL,
+        last_resumed_at TEXT,
+        error_message TEXT,
+        health TEXT,
+        last_activity_at TEXT,
+        needs_recycle INTEGER NOT NULL DEFAULT 0,
+        ephemeral INTEGER NOT NULL DEFAULT 0,
+        expires_at TEXT,
+        disk_bytes INTEGER,
+        saved_state_bytes INTEGER,
+        template_version INTEGER,
+        lease_id TEXT,
+        leased_at TEXT,
+        FOREIGN KEY (template_id) REFERENCES templates(id) ON DELETE RESTRICT,
+        FOREIGN KEY (pool_id) REFERENCES pools(id) ON DELETE RESTRICT
+        "#,
+    ),
+    (
+        "agents",
+        r#"
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        pool_id TEXT,
+        vm_id TEXT,
+        status TEXT NOT NULL,
+        task TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        scheduled_at TEXT,
+        started_at TEXT,
+        completed_at TEXT,
+        result TEXT,
+        error_message TEXT,
+        FOREIGN KEY (pool_id) REFERENCES pools(id) ON DELETE SET NULL,
+        FOREIGN KEY (vm_id) REFERENCES vms(id) ON DELETE SET NULL
+        "#,
+    ),
+];
+
 /// Database for state storage
 pub struct Database {
     conn: Arc<Mutex<Connection>>,
@@ -45,71 +139,176 @@ impl Database {
                 gpu_enabled INTEGER NOT NULL,
                 installed_software TEXT,
                 description TEXT,
-                created_at TEXT NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS pools (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE,
-                template_id TEXT NOT NULL,
-                desired_count INTEGER NOT NULL,
-                warm_count INTEGER NOT NULL,
-                max_per_host INTEGER NOT NULL,
                 created_at TEXT NOT NULL,
-                FOREIGN KEY (template_id) REFERENCES templates(id)
+                guest_username TEXT,
+                guest_password TEXT,
+                readiness_probes TEXT,
+                virtual_size_bytes INTEGER,
+                disk_type TEXT,
+                current_version INTEGER NOT NULL DEFAULT 1
             );
 
-            CREATE TABLE IF NOT EXISTS vms (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE,
-                template_id TEXT,
+            CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                vm_id TEXT,
+                vm_name TEXT,
                 pool_id TEXT,
-                state TEXT NOT NULL,
-                vhdx_path TEXT NOT NULL,
-                ip_address TEXT,
-                memory_mb INTEGER NOT NULL,
-                cpu_count INTEGER NOT NULL,
-                gpu_enabled INTEGER NOT NULL,
-                current_agent_id TEXT,
-                created_at TEXT NOT NULL,
-                last_resumed_at TEXT,
-                error_message TEXT,
-                FOREIGN KEY (template_id) REFERENCES templates(id),
-                FOREIGN KEY (pool_id) REFERENCES pools(id)
+                agent_id TEXT,
+                message TEXT NOT NULL,
+                created_at TEXT NOT NULL
             );
 
-            CREATE TABLE IF NOT EXISTS agents (
+            CREATE TABLE IF NOT EXISTS operations (
                 id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                pool_id TEXT,
+                kind TEXT NOT NULL,
                 vm_id TEXT,
+                vm_name TEXT NOT NULL,
+                params TEXT NOT NULL,
+                completed_steps TEXT NOT NULL,
+                current_step TEXT,
                 status TEXT NOT NULL,
-                task TEXT NOT NULL,
+                error TEXT,
                 created_at TEXT NOT NULL,
-                scheduled_at TEXT,
-                started_at TEXT,
-                completed_at TEXT,
+                updated_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS provision_requests (
+                idempotency_key TEXT PRIMARY KEY,
+                pool_id TEXT NOT NULL,
                 result TEXT,
-                error_message TEXT,
-                FOREIGN KEY (pool_id) REFERENCES pools(id),
-                FOREIGN KEY (vm_id) REFERENCES vms(id)
+                created_at TEXT NOT NULL
             );
+            "#,
+        )?;
+        for (table, columns) in REFERENCING_TABLES {
+            conn.execute_batch(&format!("CREATE TABLE IF NOT EXISTS {} ({});", table, columns))?;
+        }
+
+        // Columns added after the initial schema
+        Self::add_column(&conn, "templates", "guest_username", "TEXT")?;
+        Self::add_column(&conn, "templates", "guest_password", "TEXT")?;
+        Self::add_column(&conn, "templates", "readiness_probes", "TEXT")?;
+        Self::add_column(&conn, "templates", "virtual_size_bytes", "INTEGER")?;
+        Self::add_column(&conn, "templates", "disk_type", "TEXT")?;
+        Self::add_column(&conn, "pools", "remediation", "TEXT")?;
+        Self::add_column(&conn, "vms", "health", "TEXT")?;
+        Self::add_column(&conn, "pools", "idle_timeout_secs", "INTEGER")?;
+        Self::add_column(&conn, "pools", "idle_action", "TEXT")?;
+        Self::add_column(&conn, "vms", "last_activity_at", "TEXT")?;
+        Self::add_column(&conn, "pools", "next_index", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column(&conn, "vms", "needs_recycle", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column(&conn, "vms", "ephemeral", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column(&conn, "vms", "expires_at", "TEXT")?;
+        Self::add_column(&conn, "vms", "disk_bytes", "INTEGER")?;
+        Self::add_column(&conn, "vms", "saved_state_bytes", "INTEGER")?;
+        Self::add_column(&conn, "pools", "max_disk_bytes", "INTEGER")?;
+        Self::add_column(&conn, "templates", "current_version", "INTEGER NOT NULL DEFAULT 1")?;
+        Self::add_column(&conn, "pools", "template_version", "INTEGER")?;
+        Self::add_column(&conn, "vms", "template_version", "INTEGER")?;
+        Self::add_column(&conn, "template_versions", "build_log", "TEXT")?;
+        Self::add_column(&conn, "vms", "lease_id", "TEXT")?;
+        Self::add_column(&conn, "vms", "leased_at", "TEXT")?;
+
+        // Templates registered before versioning become their own version 1,
+        // and the VMs made from them come from that version
+        conn.execute_batch(
+            r#"INSERT INTO template_versions (template_id, version, vhdx_path, virtual_size_bytes, disk_type, created_at)
+               SELECT id, current_version, vhdx_path, virtual_size_bytes, disk_type, created_at FROM templates
+               WHERE id NOT IN (SELECT template_id FROM template_versions);
+               UPDATE vms SET template_version = 1 WHERE template_version IS NULL AND template_id IS NOT NULL;"#,
+        )?;
+        Self::protect_guest_passwords(&conn)?;
 
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            Self::add_delete_rules(&conn)?;
+        }
+        conn.execute_batch(&format!(
+            r#"
             CREATE INDEX IF NOT EXISTS idx_vms_pool ON vms(pool_id);
             CREATE INDEX IF NOT EXISTS idx_vms_state ON vms(state);
             CREATE INDEX IF NOT EXISTS idx_agents_status ON agents(status);
+            CREATE INDEX IF NOT EXISTS idx_events_vm ON events(vm_name);
+            CREATE INDEX IF NOT EXISTS idx_operations_status ON operations(status);
+            PRAGMA user_version = {};
+            PRAGMA foreign_keys = ON;
             "#,
-        )?;
+            SCHEMA_VERSION
+        ))?;
+        Ok(())
+    }
+
+    /// Rebuild the tables with foreign keys so they carry their ON DELETE rules
+    /// (SQLite can't alter constraints in place). Enforcement is off meanwhile,
+    /// and references left dangling while it wasn't enforced are cleared first.
+    fn add_delete_rules(conn: &Connection) -> Result<()> {
+        let mut sql = String::from(
+            r#"PRAGMA foreign_keys = OFF;
+            BEGIN;
+            DELETE FROM template_versions WHERE template_id NOT IN (SELECT id FROM templates);
+            UPDATE vms SET template_id = NULL WHERE template_id NOT IN (SELECT id FROM templates);
+            UPDATE vms SET pool_id = NULL WHERE pool_id NOT IN (SELECT id FROM pools);
+            UPDATE agents SET pool_id = NULL WHERE pool_id NOT IN (SELECT id FROM pools);
+            UPDATE agents SET vm_id = NULL WHERE vm_id NOT IN (SELECT id FROM vms);
+            "#,
+        );
+        for (table, columns) in REFERENCING_TABLES {
+            let names = conn
+                .prepare(&format!("PRAGMA table_info({})", table))?
+                .query_map([], |row| row.get::<_, String>(1))?
+                .collect::<std::result::Result<Vec<_>, _>>()?
+                .join(", ");
+            sql.push_str(&format!(
+                "CREATE TABLE {table}_new ({columns});
+                INSERT INTO {table}_new ({names}) SELECT {names} FROM {table};
+                DROP TABLE {table};
+                ALTER TABLE {table}_new RENAME TO {table};
+                "
+            ));
+        }
+        sql.push_str("COMMIT;");
+        conn.execute_batch(&sql)?;
+        Ok(())
+    }
+
+    /// Protect guest passwords stored before they were kept encrypted
+    fn protect_guest_passwords(conn: &Connection) -> Result<()> {
+        let stored = conn
+            .prepare("SELECT id, guest_password FROM templates WHERE guest_password IS NOT NULL")?
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (id, password) in stored.into_iter().filter(|(_, p)| !secret::is_protected(p)) {
+            let protected = secret::protect(&password)?;
+            if protected != password {
+                conn.execute("UPDATE templates SET guest_password = ?1 WHERE id = ?2", params![protected, id])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a column to an existing table unless it's already there
+    fn add_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+        let exists = conn
+            .prepare(&format!("PRAGMA table_info({})", table))?
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .iter()
+            .any(|c| c == column);
+        if !exists {
+            conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+        }
         Ok(())
     }
 
     // ===== Templates =====
 
     pub fn insert_template(&self, t: &Template) -> Result<()> {
+        let password = t.guest_credential.as_ref().map(|c| secret::protect(&c.password)).transpose()?;
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            r#"INSERT INTO templates (id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+            r#"INSERT INTO templates (id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at, guest_username, guest_password, readiness_probes, virtual_size_bytes, disk_type, current_version)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)"#,
             params![
                 t.id,
                 t.name,
@@ -120,6 +319,12 @@ impl Database {
                 serde_json::to_string(&t.installed_software)?,
                 t.description,
                 t.created_at.to_rfc3339(),
+                t.guest_credential.as_ref().map(|c| c.username.as_str()),
+                password,
+                serde_json::to_string(&t.readiness_probes)?,
+                t.virtual_size_bytes,
+                t.disk_type.map(|d| d.to_string()),
+                t.current_version,
             ],
         )?;
         Ok(())
@@ -128,82 +333,153 @@ impl Database {
     pub fn get_template(&self, id: &str) -> Result<Option<Template>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at FROM templates WHERE id = ?1",
+            "SELECT id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at, guest_username, guest_password, readiness_probes, virtual_size_bytes, disk_type, current_version FROM templates WHERE id = ?1",
             params![id],
-            |row| {
-                let software_json: String = row.get(6)?;
-                Ok(Template {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    vhdx_path: row.get::<_, String>(2)?.into(),
-                    memory_mb: row.get(3)?,
-                    cpu_count: row.get(4)?,
-                    gpu_enabled: row.get::<_, i32>(5)? != 0,
-                    installed_software: serde_json::from_str(&software_json).unwrap_or_default(),
-                    description: row.get(7)?,
-                    created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?).unwrap().with_timezone(&chrono::Utc),
-                })
-            },
+            Self::row_to_template,
         ).optional().map_err(Into::into)
     }
 
     pub fn get_template_by_name(&self, name: &str) -> Result<Option<Template>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at FROM templates WHERE name = ?1",
+            "SELECT id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at, guest_username, guest_password, readiness_probes, virtual_size_bytes, disk_type, current_version FROM templates WHERE name = ?1",
             params![name],
-            |row| {
-                let software_json: String = row.get(6)?;
-                Ok(Template {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    vhdx_path: row.get::<_, String>(2)?.into(),
-                    memory_mb: row.get(3)?,
-                    cpu_count: row.get(4)?,
-                    gpu_enabled: row.get::<_, i32>(5)? != 0,
-                    installed_software: serde_json::from_str(&software_json).unwrap_or_default(),
-                    description: row.get(7)?,
-                    created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?).unwrap().with_timezone(&chrono::Utc),
-                })
-            },
+            Self::row_to_template,
         ).optional().map_err(Into::into)
     }
 
     pub fn list_templates(&self) -> Result<Vec<Template>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at FROM templates ORDER BY name"
-        )?;
-        let templates = stmt.query_map([], |row| {
-            let software_json: String = row.get(6)?;
-            Ok(Template {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                vhdx_path: row.get::<_, String>(2)?.into(),
-                memory_mb: row.get(3)?,
-                cpu_count: row.get(4)?,
-                gpu_enabled: row.get::<_, i32>(5)? != 0,
-                installed_software: serde_json::from_str(&software_json).unwrap_or_default(),
-                description: row.get(7)?,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?).unwrap().with_timezone(&chrono::Utc),
-            })
-        })?.collect::<std::result::Result<Vec<_>, _>>()?;
+            "SELECT id, name, vhdx_path, memory_mb, cpu_count, gpu_enabled, installed_software, description, created_at, guest_username, guest_password, readiness_probes, virtual_size_bytes, disk_type, current_version FROM templates ORDER BY name"
+        )?;
+        let templates = stmt.query_map([], Self::row_to_template)?.collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(templates)
     }
 
+    fn row_to_template(row: &rusqlite::Row) -> rusqlite::Result<Template> {
+        let software_json: String = row.get(6)?;
+        let guest_username: Option<String> = row.get(9)?;
+        let guest_password = row.get::<_, Option<String>>(10)?
+            .map(|p| secret::unprotect(&p))
+            .transpose()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(10, rusqlite::types::Type::Text, Box::new(e)))?;
+        let probes_json: Option<String> = row.get(11)?;
+        let disk_type: Option<String> = row.get(13)?;
+        Ok(Template {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            vhdx_path: row.get::<_, String>(2)?.into(),
+            memory_mb: row.get(3)?,
+            cpu_count: row.get(4)?,
+            gpu_enabled: row.get::<_, i32>(5)? != 0,
+            installed_software: serde_json::from_str(&software_json).unwrap_or_default(),
+            description: row.get(7)?,
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?).unwrap().with_timezone(&chrono::Utc),
+            guest_credential: guest_username.map(|u| GuestCredential::new(u, guest_password.unwrap_or_default())),
+            readiness_probes: probes_json.and_then(|j| serde_json::from_str(&j).ok()).unwrap_or_default(),
+            virtual_size_bytes: row.get(12)?,
+            disk_type: disk_type.and_then(|d| d.parse().ok()),
+            current_version: row.get(14)?,
+        })
+    }
+
+    /// Delete a template and its versions; fails while pools or VMs use it
     pub fn delete_template(&self, id: &str) -> Result<bool> {
         let conn = self.conn.lock().unwrap();
         let rows = conn.execute("DELETE FROM templates WHERE id = ?1", params![id])?;
         Ok(rows > 0)
     }
 
+    pub fn insert_template_version(&self, v: &TemplateVersion) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT INTO template_versions (template_id, version, vhdx_path, sha256, size_bytes, virtual_size_bytes, disk_type, notes, created_at, build_log)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"#,
+            params![
+                v.template_id,
+                v.version,
+                v.vhdx_path.to_string_lossy(),
+                v.sha256,
+                v.size_bytes,
+                v.virtual_size_bytes,
+                v.disk_type.map(|d| d.to_string()),
+                v.notes,
+                v.created_at.to_rfc3339(),
+                v.build_log.as_ref().map(|p| p.to_string_lossy().to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_template_version(&self, template_id: &str, version: u32) -> Result<Option<TemplateVersion>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT template_id, version, vhdx_path, sha256, size_bytes, virtual_size_bytes, disk_type, notes, created_at, build_log FROM template_versions WHERE template_id = ?1 AND version = ?2",
+            params![template_id, version],
+            Self::row_to_template_version,
+        ).optional().map_err(Into::into)
+    }
+
+    /// Versions of a template, oldest first
+    pub fn list_template_versions(&self, template_id: &str) -> Result<Vec<TemplateVersion>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT template_id, version, vhdx_path, sha256, size_bytes, virtual_size_bytes, disk_type, notes, created_at, build_log FROM template_versions WHERE template_id = ?1 ORDER BY version"
+        )?;
+        let versions = stmt.query_map(params![template_id], Self::row_to_template_version)?.collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(versions)
+    }
+
+    /// Record the checksum of a version registered before checksums were kept
+    pub fn set_template_version_checksum(&self, template_id: &str, version: u32, sha256: &str, size_bytes: u64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE template_versions SET sha256 = ?1, size_bytes = ?2 WHERE template_id = ?3 AND version = ?4",
+            params![sha256, size_bytes, template_id, version],
+        )?;
+        Ok(())
+    }
+
+    /// Point a template at one of its versions
+    pub fn set_template_current_version(&self, v: &TemplateVersion) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE templates SET current_version = ?1, vhdx_path = ?2, virtual_size_bytes = ?3, disk_type = ?4 WHERE id = ?5",
+            params![
+                v.version,
+                v.vhdx_path.to_string_lossy(),
+                v.virtual_size_bytes,
+                v.disk_type.map(|d| d.to_string()),
+                v.template_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn row_to_template_version(row: &rusqlite::Row) -> rusqlite::Result<TemplateVersion> {
+        let disk_type: Option<String> = row.get(6)?;
+        Ok(TemplateVersion {
+            template_id: row.get(0)?,
+            version: row.get(1)?,
+            vhdx_path: row.get::<_, String>(2)?.into(),
+            sha256: row.get(3)?,
+            size_bytes: row.get(4)?,
+            virtual_size_bytes: row.get(5)?,
+            disk_type: disk_type.and_then(|d| d.parse().ok()),
+            notes: row.get(7)?,
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?).unwrap().with_timezone(&chrono::Utc),
+            build_log: row.get::<_, Option<String>>(9)?.map(PathBuf::from),
+        })
+    }
+
     // ===== Pools =====
 
     pub fn insert_pool(&self, p: &VMPool) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            r#"INSERT INTO pools (id, name, template_id, desired_count, warm_count, max_per_host, created_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+            r#"INSERT INTO pools (id, name, template_id, desired_count, warm_count, max_per_host, created_at, remediation, idle_timeout_secs, idle_action, max_disk_bytes, template_version)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"#,
             params![
                 p.id,
                 p.name,
@@ -212,6 +488,11 @@ impl Database {
                 p.warm_count,
                 p.max_per_host,
                 p.created_at.to_rfc3339(),
+                p.remediation.to_string(),
+                p.idle_timeout_secs,
+                p.idle_action.to_string(),
+                p.max_disk_bytes,
+                p.template_version,
             ],
         )?;
         Ok(())
@@ -220,56 +501,117 @@ impl Database {
     pub fn get_pool(&self, id: &str) -> Result<Option<VMPool>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at FROM pools WHERE id = ?1",
+            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at, remediation, idle_timeout_secs, idle_action, max_disk_bytes, template_version FROM pools WHERE id = ?1",
             params![id],
-            |row| Ok(VMPool {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                template_id: row.get(2)?,
-                desired_count: row.get::<_, i64>(3)? as usize,
-                warm_count: row.get::<_, i64>(4)? as usize,
-                max_per_host: row.get::<_, i64>(5)? as usize,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?).unwrap().with_timezone(&chrono::Utc),
-            }),
+            Self::row_to_pool,
         ).optional().map_err(Into::into)
     }
 
     pub fn get_pool_by_name(&self, name: &str) -> Result<Option<VMPool>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at FROM pools WHERE name = ?1",
+            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at, remediation, idle_timeout_secs, idle_action, max_disk_bytes, template_version FROM pools WHERE name = ?1",
             params![name],
-            |row| Ok(VMPool {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                template_id: row.get(2)?,
-                desired_count: row.get::<_, i64>(3)? as usize,
-                warm_count: row.get::<_, i64>(4)? as usize,
-                max_per_host: row.get::<_, i64>(5)? as usize,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?).unwrap().with_timezone(&chrono::Utc),
-            }),
+            Self::row_to_pool,
         ).optional().map_err(Into::into)
     }
 
     pub fn list_pools(&self) -> Result<Vec<VMPool>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at FROM pools ORDER BY name"
-        )?;
-        let pools = stmt.query_map([], |row| {
-            Ok(VMPool {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                template_id: row.get(2)?,
-                desired_count: row.get::<_, i64>(3)? as usize,
-                warm_count: row.get::<_, i64>(4)? as usize,
-                max_per_host: row.get::<_, i64>(5)? as usize,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?).unwrap().with_timezone(&chrono::Utc),
-            })
-        })?.collect::<std::result::Result<Vec<_>, _>>()?;
+            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at, remediation, idle_timeout_secs, idle_action, max_disk_bytes, template_version FROM pools ORDER BY name"
+        )?;
+        let pools = stmt.query_map([], Self::row_to_pool)?.collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(pools)
+    }
+
+    pub fn update_pool_remediation(&self, id: &str, policy: RemediationPolicy) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE pools SET remediation = ?1 WHERE id = ?2",
+            params![policy.to_string(), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_pool_idle(&self, id: &str, timeout_secs: Option<u64>, action: IdleAction) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE pools SET idle_timeout_secs = ?1, idle_action = ?2 WHERE id = ?3",
+            params![timeout_secs, action.to_string(), id],
+        )?;
+        Ok(())
+    }
+
+    /// Reserve the next VM index for a pool. Indexes only move forward, so a
+    /// deleted VM's name is never handed out again.
+    pub fn allocate_vm_index(&self, pool_id: &str) -> Result<u32> {
+        let conn = self.conn.lock().unwrap();
+        let tx = conn.unchecked_transaction()?;
+
+        let (pool_name, next): (String, u32) = tx.query_row(
+            "SELECT name, next_index FROM pools WHERE id = ?1",
+            params![pool_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?.ok_or_else(|| crate::Error::PoolNotFound(pool_id.to_string()))?;
+
+        // Pools created before the allocator existed start past their highest VM
+        let prefix = format!("{}-", pool_name);
+        let mut stmt = tx.prepare("SELECT name FROM vms WHERE pool_id = ?1")?;
+        let past_used = stmt.query_map(params![pool_id], |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .iter()
+            .filter_map(|n| n.strip_prefix(&prefix)?.parse::<u32>().ok())
+            .map(|i| i + 1)
+            .max()
+            .unwrap_or(0);
+        drop(stmt);
+
+        let index = next.max(past_used);
+        tx.execute("UPDATE pools SET next_index = ?1 WHERE id = ?2", params![index + 1, pool_id])?;
+        tx.commit()?;
+        Ok(index)
+    }
+
+    fn row_to_pool(row: &rusqlite::Row) -> rusqlite::Result<VMPool> {
+        let remediation: Option<String> = row.get(7)?;
+        let idle_action: Option<String> = row.get(9)?;
+        Ok(VMPool {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            template_id: row.get(2)?,
+            desired_count: row.get::<_, i64>(3)? as usize,
+            warm_count: row.get::<_, i64>(4)? as usize,
+            max_per_host: row.get::<_, i64>(5)? as usize,
+            remediation: remediation.and_then(|r| r.parse().ok()).unwrap_or_default(),
+            idle_timeout_secs: row.get(8)?,
+            idle_action: idle_action.and_then(|a| a.parse().ok()).unwrap_or_default(),
+            max_disk_bytes: row.get(10)?,
+            template_version: row.get(11)?,
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?).unwrap().with_timezone(&chrono::Utc),
+        })
+    }
+
+    /// Pin a pool to a template version, or unpin it with None
+    pub fn update_pool_template_version(&self, id: &str, version: Option<u32>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE pools SET template_version = ?1 WHERE id = ?2",
+            params![version, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_pools_by_template(&self, template_id: &str) -> Result<Vec<VMPool>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, template_id, desired_count, warm_count, max_per_host, created_at, remediation, idle_timeout_secs, idle_action, max_disk_bytes, template_version FROM pools WHERE template_id = ?1 ORDER BY name"
+        )?;
+        let pools = stmt.query_map(params![template_id], Self::row_to_pool)?.collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(pools)
     }
 
+    /// Delete a pool; fails while VMs belong to it
     pub fn delete_pool(&self, id: &str) -> Result<bool> {
         let conn = self.conn.lock().unwrap();
         let rows = conn.execute("DELETE FROM pools WHERE id = ?1", params![id])?;
@@ -281,8 +623,8 @@ impl Database {
     pub fn insert_vm(&self, vm: &VM) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            r#"INSERT INTO vms (id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)"#,
+            r#"INSERT INTO vms (id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24)"#,
             params![
                 vm.id,
                 vm.name,
@@ -298,6 +640,16 @@ impl Database {
                 vm.created_at.to_rfc3339(),
                 vm.last_resumed_at.map(|t| t.to_rfc3339()),
                 vm.error_message,
+                format!("{:?}", vm.health),
+                vm.last_activity_at.map(|t| t.to_rfc3339()),
+                vm.needs_recycle as i32,
+                vm.ephemeral as i32,
+                vm.expires_at.map(|t| t.to_rfc3339()),
+                vm.disk_bytes,
+                vm.saved_state_bytes,
+                vm.template_version,
+                vm.lease_id,
+                vm.leased_at.map(|t| t.to_rfc3339()),
             ],
         )?;
         Ok(())
@@ -306,7 +658,7 @@ impl Database {
     pub fn get_vm(&self, id: &str) -> Result<Option<VM>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message FROM vms WHERE id = ?1",
+            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at FROM vms WHERE id = ?1",
             params![id],
             Self::row_to_vm,
         ).optional().map_err(Into::into)
@@ -315,7 +667,7 @@ impl Database {
     pub fn get_vm_by_name(&self, name: &str) -> Result<Option<VM>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message FROM vms WHERE name = ?1",
+            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at FROM vms WHERE name = ?1",
             params![name],
             Self::row_to_vm,
         ).optional().map_err(Into::into)
@@ -324,16 +676,25 @@ impl Database {
     pub fn list_vms(&self) -> Result<Vec<VM>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message FROM vms ORDER BY name"
+            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at FROM vms ORDER BY name"
         )?;
         let vms = stmt.query_map([], Self::row_to_vm)?.collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(vms)
     }
 
+    pub fn list_vms_by_template(&self, template_id: &str) -> Result<Vec<VM>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at FROM vms WHERE template_id = ?1 ORDER BY name"
+        )?;
+        let vms = stmt.query_map(params![template_id], Self::row_to_vm)?.collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(vms)
+    }
+
     pub fn list_vms_by_pool(&self, pool_id: &str) -> Result<Vec<VM>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message FROM vms WHERE pool_id = ?1 ORDER BY name"
+            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at FROM vms WHERE pool_id = ?1 ORDER BY name"
         )?;
         let vms = stmt.query_map(params![pool_id], Self::row_to_vm)?.collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(vms)
@@ -342,7 +703,7 @@ impl Database {
     pub fn find_available_vm_in_pool(&self, pool_id: &str) -> Result<Option<VM>> {
         let conn = self.conn.lock().unwrap();
         conn.query_row(
-            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message FROM vms WHERE pool_id = ?1 AND state = 'Saved' AND current_agent_id IS NULL LIMIT 1",
+            "SELECT id, name, template_id, pool_id, state, vhdx_path, ip_address, memory_mb, cpu_count, gpu_enabled, current_agent_id, created_at, last_resumed_at, error_message, health, last_activity_at, needs_recycle, ephemeral, expires_at, disk_bytes, saved_state_bytes, template_version, lease_id, leased_at FROM vms WHERE pool_id = ?1 AND state = 'Saved' AND current_agent_id IS NULL AND lease_id IS NULL LIMIT 1",
             params![pool_id],
             Self::row_to_vm,
         ).optional().map_err(Into::into)
@@ -375,10 +736,98 @@ impl Database {
         Ok(())
     }
 
+    /// Claim a free VM for the acquire leasing it as `lease_id`; false if
+    /// someone else already holds it
+    pub fn claim_vm(&self, id: &str, lease_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let rows = conn.execute(
+            "UPDATE vms SET lease_id = ?1, leased_at = NULL WHERE id = ?2 AND lease_id IS NULL AND current_agent_id IS NULL",
+            params![lease_id, id],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Drop an acquire's claim unless it already became a lease
+    pub fn release_vm_claim(&self, id: &str, lease_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE vms SET lease_id = NULL WHERE id = ?1 AND lease_id = ?2 AND leased_at IS NULL",
+            params![id, lease_id],
+        )?;
+        Ok(())
+    }
+
+    /// Turn an acquire's claim into a lease; false if the claim was lost
+    pub fn grant_vm_lease(&self, id: &str, lease_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let rows = conn.execute(
+            "UPDATE vms SET leased_at = ?1 WHERE id = ?2 AND lease_id = ?3 AND leased_at IS NULL",
+            params![chrono::Utc::now().to_rfc3339(), id, lease_id],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Clear every claim left by an acquire that never finished; returns how many
+    pub fn release_acquire_claims(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let released = conn.execute(
+            "UPDATE vms SET lease_id = NULL WHERE lease_id IS NOT NULL AND leased_at IS NULL",
+            [],
+        )?;
+        Ok(released)
+    }
+
+    /// Free a VM from whoever holds it, agent or lease
+    pub fn release_vm_lease(&self, id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE vms SET current_agent_id = NULL, lease_id = NULL, leased_at = NULL WHERE id = ?1",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_vm_health(&self, id: &str, health: VMHealth) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE vms SET health = ?1 WHERE id = ?2",
+            params![format!("{:?}", health), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_vm_recycle(&self, id: &str, needs_recycle: bool) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE vms SET needs_recycle = ?1 WHERE id = ?2",
+            params![needs_recycle as i32, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_vm_disk_usage(&self, id: &str, disk_bytes: Option<u64>, saved_state_bytes: Option<u64>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE vms SET disk_bytes = ?1, saved_state_bytes = ?2 WHERE id = ?3",
+            params![disk_bytes, saved_state_bytes, id],
+        )?;
+        Ok(())
+    }
+
+    /// Record activity on a VM (acquire, heartbeat, exec, transfer)
+    pub fn touch_vm(&self, id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE vms SET last_activity_at = ?1 WHERE id = ?2",
+            params![chrono::Utc::now().to_rfc3339(), id],
+        )?;
+        Ok(())
+    }
+
     pub fn update_vm_resumed(&self, id: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "UPDATE vms SET last_resumed_at = ?1 WHERE id = ?2",
+            "UPDATE vms SET last_resumed_at = ?1, last_activity_at = ?1 WHERE id = ?2",
             params![chrono::Utc::now().to_rfc3339(), id],
         )?;
         Ok(())
@@ -400,6 +849,14 @@ impl Database {
             _ => VMState::Error,
         };
         let last_resumed: Option<String> = row.get(12)?;
+        let last_activity: Option<String> = row.get(15)?;
+        let expires: Option<String> = row.get(18)?;
+        let leased: Option<String> = row.get(23)?;
+        let health = match row.get::<_, Option<String>>(14)?.as_deref() {
+            Some("Healthy") => VMHealth::Healthy,
+            Some("Unhealthy") => VMHealth::Unhealthy,
+            _ => VMHealth::Unknown,
+        };
         Ok(VM {
             id: row.get(0)?,
             name: row.get(1)?,
@@ -415,6 +872,16 @@ impl Database {
             created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(11)?).unwrap().with_timezone(&chrono::Utc),
             last_resumed_at: last_resumed.map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&chrono::Utc)),
             error_message: row.get(13)?,
+            health,
+            last_activity_at: last_activity.map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&chrono::Utc)),
+            needs_recycle: row.get::<_, i32>(16)? != 0,
+            ephemeral: row.get::<_, i32>(17)? != 0,
+            expires_at: expires.map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&chrono::Utc)),
+            disk_bytes: row.get(19)?,
+            saved_state_bytes: row.get(20)?,
+            template_version: row.get(21)?,
+            lease_id: row.get(22)?,
+            leased_at: leased.map(|s| chrono::DateTime::parse_from_rfc3339(&s).unwrap().with_timezone(&chrono::Utc)),
         })
     }
 
@@ -479,6 +946,16 @@ impl Database {
         Ok(())
     }
 
+    /// Mark an agent failed with a reason
+    pub fn fail_agent(&self, id: &str, message: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE agents SET status = 'Failed', error_message = ?1, completed_at = ?2 WHERE id = ?3",
+            params![message, chrono::Utc::now().to_rfc3339(), id],
+        )?;
+        Ok(())
+    }
+
     pub fn update_agent_vm(&self, agent_id: &str, vm_id: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
@@ -488,6 +965,15 @@ impl Database {
         Ok(())
     }
 
+    pub fn update_agent_result(&self, agent_id: &str, result: &AgentResult) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE agents SET result = ?1 WHERE id = ?2",
+            params![serde_json::to_string(result)?, agent_id],
+        )?;
+        Ok(())
+    }
+
     fn row_to_agent(row: &rusqlite::Row) -> rusqlite::Result<Agent> {
         let status_str: String = row.get(4)?;
         let status = match status_str.as_str() {
@@ -520,6 +1006,217 @@ impl Database {
             error_message: row.get(11)?,
         })
     }
+
+    // ===== Events =====
+
+    pub fn insert_event(&self, e: &Event) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT INTO events (kind, vm_id, vm_name, pool_id, agent_id, message, created_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+            params![
+                e.kind.to_string(),
+                e.vm_id,
+                e.vm_name,
+                e.pool_id,
+                e.agent_id,
+                e.message,
+                e.created_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Oldest first. The limit keeps the newest events, or with `since_id` the
+    /// ones right after it, so a follower pages forward without gaps.
+    pub fn list_events(&self, filter: &EventFilter) -> Result<Vec<Event>> {
+        let conn = self.conn.lock().unwrap();
+        let order = if filter.since_id.is_some() { "ASC" } else { "DESC" };
+        let mut stmt = conn.prepare(&format!(
+            r#"SELECT id, kind, vm_id, vm_name, pool_id, agent_id, message, created_at FROM events
+               WHERE (?1 IS NULL OR vm_name = ?1) AND (?2 IS NULL OR pool_id = ?2) AND (?5 IS NULL OR kind = ?5) AND id > ?3
+               ORDER BY id {} LIMIT ?4"#,
+            order
+        ))?;
+        let mut events = stmt.query_map(
+            params![
+                filter.vm_name,
+                filter.pool_id,
+                filter.since_id.unwrap_or(0),
+                filter.limit.map(|l| l as i64).unwrap_or(-1),
+                filter.kind.map(|k| k.to_string()),
+            ],
+            Self::row_to_event,
+        )?.collect::<std::result::Result<Vec<_>, _>>()?;
+        if filter.since_id.is_none() {
+            events.reverse();
+        }
+        Ok(events)
+    }
+
+    pub fn count_events(&self, kind: EventKind, pool_id: &str) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM events WHERE kind = ?1 AND pool_id = ?2",
+            params![kind.to_string(), pool_id],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn row_to_event(row: &rusqlite::Row) -> rusqlite::Result<Event> {
+        let kind: String = row.get(1)?;
+        Ok(Event {
+            id: row.get(0)?,
+            kind: kind.parse().map_err(|e: String| {
+                rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, e.into())
+            })?,
+            vm_id: row.get(2)?,
+            vm_name: row.get(3)?,
+            pool_id: row.get(4)?,
+            agent_id: row.get(5)?,
+            message: row.get(6)?,
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(7)?).unwrap().with_timezone(&chrono::Utc),
+        })
+    }
+
+    // ===== Provision Requests =====
+
+    /// Claim an idempotency key; false if it was already claimed
+    pub fn insert_provision_request(&self, key: &str, pool_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let rows = conn.execute(
+            "INSERT OR IGNORE INTO provision_requests (idempotency_key, pool_id, created_at) VALUES (?1, ?2, ?3)",
+            params![key, pool_id, chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(rows > 0)
+    }
+
+    /// Pool the key was used for, and the result once the request finished
+    pub fn get_provision_request(&self, key: &str) -> Result<Option<(String, Option<ProvisionResult>)>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT pool_id, result FROM provision_requests WHERE idempotency_key = ?1",
+            params![key],
+            |row| {
+                let result: Option<String> = row.get(1)?;
+                Ok((row.get(0)?, result.and_then(|r| serde_json::from_str(&r).ok())))
+            },
+        ).optional().map_err(Into::into)
+    }
+
+    pub fn complete_provision_request(&self, key: &str, result: &ProvisionResult) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE provision_requests SET result = ?1 WHERE idempotency_key = ?2",
+            params![serde_json::to_string(result)?, key],
+        )?;
+        Ok(())
+    }
+
+    /// Keys claimed by requests that never finished, with their pool IDs
+    pub fn list_unfinished_provision_requests(&self) -> Result<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT idempotency_key, pool_id FROM provision_requests WHERE result IS NULL ORDER BY created_at"
+        )?;
+        let keys = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(keys)
+    }
+
+    pub fn delete_provision_request(&self, key: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM provision_requests WHERE idempotency_key = ?1", params![key])?;
+        Ok(())
+    }
+
+    // ===== Operations =====
+
+    pub fn insert_operation(&self, op: &Operation) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT INTO operations (id, kind, vm_id, vm_name, params, completed_steps, current_step, status, error, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"#,
+            params![
+                op.id,
+                op.kind.to_string(),
+                op.vm_id,
+                op.vm_name,
+                op.params.to_string(),
+                serde_json::to_string(&op.completed_steps)?,
+                op.current_step,
+                op.status.to_string(),
+                op.error,
+                op.created_at.to_rfc3339(),
+                op.updated_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Persist an operation's progress
+    pub fn update_operation(&self, op: &Operation) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"UPDATE operations SET vm_id = ?2, completed_steps = ?3, current_step = ?4, status = ?5, error = ?6, updated_at = ?7
+               WHERE id = ?1"#,
+            params![
+                op.id,
+                op.vm_id,
+                serde_json::to_string(&op.completed_steps)?,
+                op.current_step,
+                op.status.to_string(),
+                op.error,
+                op.updated_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_operation(&self, id: &str) -> Result<Option<Operation>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, kind, vm_id, vm_name, params, completed_steps, current_step, status, error, created_at, updated_at FROM operations WHERE id = ?1"
+        )?;
+        stmt.query_row([id], Self::row_to_operation).optional().map_err(Into::into)
+    }
+
+    /// Operations, oldest first, optionally only those with `status`
+    pub fn list_operations(&self, status: Option<OperationStatus>) -> Result<Vec<Operation>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT id, kind, vm_id, vm_name, params, completed_steps, current_step, status, error, created_at, updated_at FROM operations
+               WHERE (?1 IS NULL OR status = ?1) ORDER BY created_at"#
+        )?;
+        let ops = stmt.query_map(params![status.map(|s| s.to_string())], Self::row_to_operation)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ops)
+    }
+
+    fn row_to_operation(row: &rusqlite::Row) -> rusqlite::Result<Operation> {
+        let kind: String = row.get(1)?;
+        let status: String = row.get(7)?;
+        let params: String = row.get(4)?;
+        let steps: String = row.get(5)?;
+        Ok(Operation {
+            id: row.get(0)?,
+            kind: kind.parse().map_err(|e: String| {
+                rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, e.into())
+            })?,
+            vm_id: row.get(2)?,
+            vm_name: row.get(3)?,
+            params: serde_json::from_str(&params).unwrap_or_default(),
+            completed_steps: serde_json::from_str(&steps).unwrap_or_default(),
+            current_step: row.get(6)?,
+            status: status.parse().map_err(|e: String| {
+                rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Text, e.into())
+            })?,
+            error: row.get(8)?,
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?).unwrap().with_timezone(&chrono::Utc),
+            updated_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?).unwrap().with_timezone(&chrono::Utc),
+        })
+    }
 }
 
 
@@ -544,6 +1241,16 @@ mod tests {
         
         let loaded = db.get_template(&template.id).unwrap().unwrap();
         assert_eq!(loaded.name, "win11");
+        assert!(loaded.disk_type.is_none());
+
+        let mut sized = Template::new("win11-sized", r"C:\templates\win11.vhdx");
+        sized.virtual_size_bytes = Some(64 << 30);
+        sized.disk_type = Some(crate::vhdx::DiskType::Dynamic);
+        db.insert_template(&sized).unwrap();
+        let loaded = db.get_template(&sized.id).unwrap().unwrap();
+        assert_eq!(loaded.virtual_size_bytes, Some(64 << 30));
+        assert_eq!(loaded.disk_type, Some(crate::vhdx::DiskType::Dynamic));
+        db.delete_template(&sized.id).unwrap();
         
         let templates = db.list_templates().unwrap();
         assert_eq!(templates.len(), 1);
@@ -555,6 +1262,210 @@ mod tests {
         assert!(db.get_template(&template.id).unwrap().is_none());
     }
 
+    #[test]
+    fn test_template_guest_credential() {
+        let db = Database::in_memory().unwrap();
+
+        let template = Template::new("win11", r"C:\templates\win11.vhdx")
+            .with_guest_credential("Administrator", "s3cret");
+        db.insert_template(&template).unwrap();
+
+        let loaded = db.get_template(&template.id).unwrap().unwrap();
+        let cred = loaded.guest_credential.unwrap();
+        assert_eq!(cred.username, "Administrator");
+        assert_eq!(cred.password, "s3cret");
+
+        // Encrypted at rest where DPAPI is available
+        let raw: String = db.conn.lock().unwrap()
+            .query_row("SELECT guest_password FROM templates WHERE id = ?1", params![template.id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(secret::is_protected(&raw), cfg!(windows));
+    }
+
+    #[test]
+    fn test_schema_upgrade_adds_columns() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("old.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE templates (id TEXT PRIMARY KEY, name TEXT NOT NULL UNIQUE, vhdx_path TEXT NOT NULL, memory_mb INTEGER NOT NULL, cpu_count INTEGER NOT NULL, gpu_enabled INTEGER NOT NULL, installed_software TEXT, description TEXT, created_at TEXT NOT NULL);"
+            ).unwrap();
+        }
+
+        let db = Database::open(&path).unwrap();
+        let template = Template::new("win11", r"C:\t.vhdx").with_guest_credential("user", "pw");
+        db.insert_template(&template).unwrap();
+        assert!(db.get_template(&template.id).unwrap().unwrap().guest_credential.is_some());
+    }
+
+    #[test]
+    fn test_template_readiness_probes() {
+        let db = Database::in_memory().unwrap();
+
+        let probes = vec!["heartbeat".parse().unwrap(), "http:8080/health=200@30".parse().unwrap()];
+        let template = Template::new("win11", r"C:\t.vhdx").with_probes(probes.clone());
+        db.insert_template(&template).unwrap();
+
+        let loaded = db.get_template_by_name("win11").unwrap().unwrap();
+        assert_eq!(loaded.readiness_probes, probes);
+    }
+
+    #[test]
+    fn test_template_versions() {
+        let db = Database::in_memory().unwrap();
+        let template = Template::new("win11", r"C:\t\v1.vhdx");
+        db.insert_template(&template).unwrap();
+        db.insert_template_version(&TemplateVersion::current_of(&template)).unwrap();
+
+        let mut v2 = TemplateVersion::new(&template.id, 2, r"C:\t\v2.vhdx").with_notes("patched");
+        v2.sha256 = Some("ab".repeat(32));
+        v2.disk_type = Some(crate::vhdx::DiskType::Dynamic);
+        db.insert_template_version(&v2).unwrap();
+        assert!(db.insert_template_version(&v2).is_err());
+
+        let versions = db.list_template_versions(&template.id).unwrap();
+        assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), vec![1, 2]);
+        let loaded = db.get_template_version(&template.id, 2).unwrap().unwrap();
+        assert_eq!(loaded.notes.as_deref(), Some("patched"));
+        assert_eq!(loaded.sha256, v2.sha256);
+        assert!(db.get_template_version(&template.id, 3).unwrap().is_none());
+
+        db.set_template_version_checksum(&template.id, 1, &"cd".repeat(32), 42).unwrap();
+        let v1 = db.get_template_version(&template.id, 1).unwrap().unwrap();
+        assert_eq!((v1.sha256, v1.size_bytes), (Some("cd".repeat(32)), Some(42)));
+
+        db.set_template_current_version(&loaded).unwrap();
+        let current = db.get_template(&template.id).unwrap().unwrap();
+        assert_eq!(current.current_version, 2);
+        assert_eq!(current.vhdx_path, PathBuf::from(r"C:\t\v2.vhdx"));
+        assert_eq!(current.disk_type, Some(crate::vhdx::DiskType::Dynamic));
+
+        db.delete_template(&template.id).unwrap();
+        assert!(db.list_template_versions(&template.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schema_upgrade_backfills_versions() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("old.db");
+        let template = Template::new("win11", r"C:\t.vhdx");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE templates (id TEXT PRIMARY KEY, name TEXT NOT NULL UNIQUE, vhdx_path TEXT NOT NULL, memory_mb INTEGER NOT NULL, cpu_count INTEGER NOT NULL, gpu_enabled INTEGER NOT NULL, installed_software TEXT, description TEXT, created_at TEXT NOT NULL);"
+            ).unwrap();
+            conn.execute(
+                "INSERT INTO templates VALUES (?1, 'win11', ?2, 4096, 2, 0, '[]', NULL, ?3)",
+                params![template.id, r"C:\t.vhdx", template.created_at.to_rfc3339()],
+            ).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE vms (id TEXT PRIMARY KEY, name TEXT NOT NULL UNIQUE, template_id TEXT, pool_id TEXT, state TEXT NOT NULL, vhdx_path TEXT NOT NULL, ip_address TEXT, memory_mb INTEGER NOT NULL, cpu_count INTEGER NOT NULL, gpu_enabled INTEGER NOT NULL, current_agent_id TEXT, created_at TEXT NOT NULL, last_resumed_at TEXT, error_message TEXT);"
+            ).unwrap();
+            for (id, template_id) in [("vm-1", Some(template.id.as_str())), ("vm-2", None)] {
+                conn.execute(
+                    "INSERT INTO vms VALUES (?1, ?1, ?2, NULL, 'Saved', 'C:\\vms\\d.vhdx', NULL, 4096, 2, 0, NULL, ?3, NULL, NULL)",
+                    params![id, template_id, template.created_at.to_rfc3339()],
+                ).unwrap();
+            }
+        }
+
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.get_template(&template.id).unwrap().unwrap().current_version, 1);
+        assert_eq!(db.get_vm("vm-1").unwrap().unwrap().template_version, Some(1));
+        assert_eq!(db.get_vm("vm-2").unwrap().unwrap().template_version, None);
+        let versions = db.list_template_versions(&template.id).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].vhdx_path, PathBuf::from(r"C:\t.vhdx"));
+
+        // Reopening doesn't duplicate
+        drop(db);
+        assert_eq!(Database::open(&path).unwrap().list_template_versions(&template.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_delete_rules() {
+        let db = Database::in_memory().unwrap();
+        let template = Template::new("win11", r"C:\t.vhdx");
+        db.insert_template(&template).unwrap();
+        db.insert_template_version(&TemplateVersion::current_of(&template)).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        db.insert_pool(&pool).unwrap();
+        let mut vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\agents-0.vhdx"), 4096, 2);
+        vm.template_id = Some(template.id.clone());
+        vm.pool_id = Some(pool.id.clone());
+        db.insert_vm(&vm).unwrap();
+        let mut agent = Agent::new("agent", Task::new("build")).with_pool(&pool.id);
+        agent.vm_id = Some(vm.id.clone());
+        db.insert_agent(&agent).unwrap();
+
+        assert_eq!(db.list_pools_by_template(&template.id).unwrap().len(), 1);
+        assert_eq!(db.list_vms_by_template(&template.id).unwrap().len(), 1);
+        assert!(db.delete_template(&template.id).is_err());
+        assert!(db.delete_pool(&pool.id).is_err());
+        let mut dangling = vm.clone();
+        dangling.id = "vm-2".to_string();
+        dangling.name = "agents-9".to_string();
+        dangling.pool_id = Some("no-such-pool".to_string());
+        assert!(db.insert_vm(&dangling).is_err());
+
+        // Agents keep their history without the VM and pool
+        db.delete_vm(&vm.id).unwrap();
+        db.delete_pool(&pool.id).unwrap();
+        let agent = db.get_agent(&agent.id).unwrap().unwrap();
+        assert!(agent.vm_id.is_none() && agent.pool_id.is_none());
+
+        db.delete_template(&template.id).unwrap();
+        assert!(db.list_template_versions(&template.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schema_upgrade_adds_delete_rules() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("old.db");
+        let template = Template::new("win11", r"C:\t.vhdx");
+        let vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\agents-0.vhdx"), 4096, 2);
+        {
+            let db = Database::open(&path).unwrap();
+            db.insert_template(&template).unwrap();
+            db.insert_vm(&vm).unwrap();
+            let task = serde_json::to_string(&Task::new("build")).unwrap();
+            let created_at = chrono::Utc::now().to_rfc3339();
+            let conn = db.conn.lock().unwrap();
+            conn.execute_batch("PRAGMA foreign_keys = OFF; PRAGMA user_version = 0;").unwrap();
+            // Tables as they were before ON DELETE rules, with a reference left dangling
+            conn.execute_batch(
+                "DROP TABLE agents;
+                 CREATE TABLE agents (id TEXT PRIMARY KEY, name TEXT NOT NULL, pool_id TEXT, vm_id TEXT, status TEXT NOT NULL, task TEXT NOT NULL, created_at TEXT NOT NULL, scheduled_at TEXT, started_at TEXT, completed_at TEXT, result TEXT, error_message TEXT, FOREIGN KEY (pool_id) REFERENCES pools(id), FOREIGN KEY (vm_id) REFERENCES vms(id));"
+            ).unwrap();
+            conn.execute(
+                "INSERT INTO agents (id, name, vm_id, status, task, created_at) VALUES ('current', 'agent', ?1, 'Pending', ?2, ?3)",
+                params![vm.id, task, created_at],
+            ).unwrap();
+            conn.execute(
+                "INSERT INTO agents (id, name, vm_id, status, task, created_at) VALUES ('stale', 'agent', 'deleted-vm', 'Pending', ?1, ?2)",
+                params![task, created_at],
+            ).unwrap();
+        }
+
+        let db = Database::open(&path).unwrap();
+        let version: i64 = db.conn.lock().unwrap().query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        assert_eq!(db.list_templates().unwrap().len(), 1);
+        let vm_of = |id: &str| -> Option<String> {
+            db.conn.lock().unwrap()
+                .query_row("SELECT vm_id FROM agents WHERE id = ?1", params![id], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(vm_of("current"), Some(vm.id.clone()));
+        assert!(vm_of("stale").is_none());
+
+        // Deleting the VM no longer trips over the agent that ran on it
+        db.delete_vm(&vm.id).unwrap();
+        assert_eq!(db.list_agents().unwrap().len(), 2);
+        assert!(db.list_agents().unwrap().iter().all(|a| a.vm_id.is_none()));
+    }
+
     #[test]
     fn test_pool_crud() {
         let db = Database::in_memory().unwrap();
@@ -573,6 +1484,22 @@ mod tests {
         
         let by_name = db.get_pool_by_name("agents").unwrap().unwrap();
         assert_eq!(by_name.id, pool.id);
+        assert_eq!(by_name.remediation, RemediationPolicy::Notify);
+
+        db.update_pool_remediation(&pool.id, RemediationPolicy::Rebuild).unwrap();
+        assert_eq!(db.get_pool(&pool.id).unwrap().unwrap().remediation, RemediationPolicy::Rebuild);
+
+        db.update_pool_idle(&pool.id, Some(600), IdleAction::Reset).unwrap();
+        let loaded = db.get_pool(&pool.id).unwrap().unwrap();
+        assert_eq!(loaded.idle_timeout_secs, Some(600));
+        assert_eq!(loaded.idle_action, IdleAction::Reset);
+        assert!(loaded.template_version.is_none());
+
+        let pinned = VMPool::new("pinned", &template.id).with_template_version(2);
+        db.insert_pool(&pinned).unwrap();
+        assert_eq!(db.get_pool_by_name("pinned").unwrap().unwrap().template_version, Some(2));
+        db.update_pool_template_version(&pinned.id, None).unwrap();
+        assert!(db.get_pool(&pinned.id).unwrap().unwrap().template_version.is_none());
     }
 
     #[test]
@@ -600,6 +1527,34 @@ mod tests {
         
         let by_name = db.get_vm_by_name("test-vm-1").unwrap().unwrap();
         assert_eq!(by_name.id, vm.id);
+
+        assert_eq!(by_name.health, VMHealth::Unknown);
+        assert!(by_name.last_activity_at.is_none());
+        db.touch_vm(&vm.id).unwrap();
+        assert!(db.get_vm(&vm.id).unwrap().unwrap().last_activity_at.is_some());
+
+        db.update_vm_health(&vm.id, VMHealth::Unhealthy).unwrap();
+        assert_eq!(db.get_vm(&vm.id).unwrap().unwrap().health, VMHealth::Unhealthy);
+
+        assert!(by_name.disk_bytes.is_none());
+        db.update_vm_disk_usage(&vm.id, Some(3 << 30), Some(4 << 30)).unwrap();
+        let measured = db.get_vm(&vm.id).unwrap().unwrap();
+        assert_eq!((measured.disk_bytes, measured.saved_state_bytes), (Some(3 << 30), Some(4 << 30)));
+    }
+
+    #[test]
+    fn test_ephemeral_vm_roundtrip() {
+        let db = Database::in_memory().unwrap();
+
+        let mut vm = VM::new("eph-1".to_string(), PathBuf::from(r"C:\vms\eph.vhdx"), 2048, 1);
+        vm.ephemeral = true;
+        vm.expires_at = Some(chrono::Utc::now() + chrono::Duration::seconds(600));
+        db.insert_vm(&vm).unwrap();
+
+        let loaded = db.get_vm(&vm.id).unwrap().unwrap();
+        assert!(loaded.ephemeral);
+        assert_eq!(loaded.expires_at.map(|t| t.timestamp()), vm.expires_at.map(|t| t.timestamp()));
+        assert!(loaded.pool_id.is_none());
     }
 
     #[test]
@@ -659,6 +1614,75 @@ mod tests {
         assert!(available.is_none());
     }
 
+    #[test]
+    fn test_update_agent_result() {
+        let db = Database::in_memory().unwrap();
+
+        let agent = Agent::new("agent", Task::new("workflow"));
+        db.insert_agent(&agent).unwrap();
+
+        let result = AgentResult {
+            success: true,
+            output: serde_json::json!({"ok": true}),
+            screenshots: vec![],
+            duration_seconds: 12,
+            timeline: Some(ScreenTimeline::new("agents-0", "/tmp/rec", 1000)),
+        };
+        db.update_agent_result(&agent.id, &result).unwrap();
+
+        let loaded = db.get_agent(&agent.id).unwrap().unwrap().result.unwrap();
+        assert_eq!(loaded.duration_seconds, 12);
+        assert_eq!(loaded.timeline.unwrap().vm_name, "agents-0");
+    }
+
+    #[test]
+    fn test_events() {
+        let db = Database::in_memory().unwrap();
+
+        let vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\a.vhdx"), 4096, 2);
+        let first = db.insert_event(&Event::new(EventKind::VMUnhealthy, "heartbeat lost").for_vm(&vm)).unwrap();
+        db.insert_event(&Event::new(EventKind::VMRecovered, "healthy again").for_vm(&vm)).unwrap();
+        db.insert_event(&Event::new(EventKind::VMUnhealthy, "other vm")).unwrap();
+
+        let all = db.list_events(&EventFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].id, first);
+        assert_eq!(all[0].kind, EventKind::VMUnhealthy);
+
+        let for_vm = db.list_events(&EventFilter {
+            vm_name: Some("agents-0".to_string()),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(for_vm.len(), 2);
+
+        let newer = db.list_events(&EventFilter { since_id: Some(first), ..Default::default() }).unwrap();
+        assert_eq!(newer.len(), 2);
+        // Paging forward takes the next events, not the newest
+        let next = db.list_events(&EventFilter { since_id: Some(first), limit: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(next[0].message, "healthy again");
+
+        let latest = db.list_events(&EventFilter { limit: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].message, "other vm");
+
+        let recovered = db.list_events(&EventFilter { kind: Some(EventKind::VMRecovered), ..Default::default() }).unwrap();
+        assert_eq!(recovered.len(), 1);
+    }
+
+    #[test]
+    fn test_fail_agent() {
+        let db = Database::in_memory().unwrap();
+
+        let agent = Agent::new("agent", Task::new("workflow"));
+        db.insert_agent(&agent).unwrap();
+        db.fail_agent(&agent.id, "VM unhealthy").unwrap();
+
+        let loaded = db.get_agent(&agent.id).unwrap().unwrap();
+        assert_eq!(loaded.status, AgentStatus::Failed);
+        assert_eq!(loaded.error_message.as_deref(), Some("VM unhealthy"));
+        assert!(loaded.completed_at.is_some());
+    }
+
     #[test]
     fn test_get_nonexistent_template() {
         let db = Database::in_memory().unwrap();
@@ -772,6 +1796,30 @@ mod tests {
         // assert_eq!(loaded.error_message, Some("Crashed".to_string()));
     }
 
+    #[test]
+    fn test_vm_claim_becomes_lease() {
+        let db = Database::in_memory().unwrap();
+        let vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\agents-0.vhdx"), 4096, 2);
+        db.insert_vm(&vm).unwrap();
+
+        assert!(db.claim_vm(&vm.id, "lease-1").unwrap());
+        assert!(!db.claim_vm(&vm.id, "lease-2").unwrap());
+        assert!(!db.grant_vm_lease(&vm.id, "lease-2").unwrap());
+        assert!(db.grant_vm_lease(&vm.id, "lease-1").unwrap());
+
+        // The lease survives the acquire's cleanup and a restart's claim sweep,
+        // and never shows up as an agent
+        db.release_vm_claim(&vm.id, "lease-1").unwrap();
+        assert_eq!(db.release_acquire_claims().unwrap(), 0);
+        let leased = db.get_vm(&vm.id).unwrap().unwrap();
+        assert_eq!(leased.lease_id.as_deref(), Some("lease-1"));
+        assert!(leased.leased_at.is_some() && leased.current_agent_id.is_none());
+
+        db.release_vm_lease(&vm.id).unwrap();
+        let released = db.get_vm(&vm.id).unwrap().unwrap();
+        assert!(!released.is_leased() && released.leased_at.is_none());
+    }
+
     #[test]
     fn test_list_vms_empty() {
         let db = Database::in_memory().unwrap();
@@ -795,4 +1843,69 @@ mod tests {
         let pools = db.list_pools().unwrap();
         assert_eq!(pools.len(), 2);
     }
+
+    #[test]
+    fn test_operation_journal_rows() {
+        let db = Database::in_memory().unwrap();
+
+        let mut op = Operation::new(OperationKind::Provision, "agents-0", serde_json::json!({"parent": "C:\\t.vhdx"}));
+        db.insert_operation(&op).unwrap();
+
+        op.completed_steps.push("create_disk".to_string());
+        op.current_step = Some("create_vm".to_string());
+        op.vm_id = Some("vm-1".to_string());
+        db.update_operation(&op).unwrap();
+
+        let loaded = db.get_operation(&op.id).unwrap().unwrap();
+        assert_eq!(loaded.kind, OperationKind::Provision);
+        assert_eq!(loaded.completed_steps, vec!["create_disk"]);
+        assert_eq!(loaded.current_step.as_deref(), Some("create_vm"));
+        assert_eq!(loaded.params["parent"], "C:\\t.vhdx");
+        assert_eq!(db.list_operations(Some(OperationStatus::Running)).unwrap().len(), 1);
+
+        op.status = OperationStatus::Completed;
+        db.update_operation(&op).unwrap();
+        assert!(db.list_operations(Some(OperationStatus::Running)).unwrap().is_empty());
+        assert_eq!(db.list_operations(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_allocate_vm_index() {
+        let db = Database::in_memory().unwrap();
+        let template = Template::new("win11", r"C:\templates\win11.vhdx");
+        db.insert_template(&template).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        db.insert_pool(&pool).unwrap();
+
+        // agents-1 was deleted; its index must not come back
+        for name in ["agents-0", "agents-2"] {
+            let mut vm = VM::new(name.to_string(), PathBuf::from(r"C:\vms\x.vhdx"), 4096, 2);
+            vm.pool_id = Some(pool.id.clone());
+            db.insert_vm(&vm).unwrap();
+        }
+
+        assert_eq!(db.allocate_vm_index(&pool.id).unwrap(), 3);
+        assert_eq!(db.allocate_vm_index(&pool.id).unwrap(), 4);
+        assert!(db.allocate_vm_index("missing").is_err());
+    }
+
+    #[test]
+    fn test_provision_requests() {
+        let db = Database::in_memory().unwrap();
+
+        assert!(db.insert_provision_request("key-1", "pool-1").unwrap());
+        assert!(!db.insert_provision_request("key-1", "pool-1").unwrap());
+        assert_eq!(db.get_provision_request("key-1").unwrap(), Some(("pool-1".to_string(), None)));
+
+        let result = ProvisionResult::new("pool-1", Some("key-1".to_string()));
+        db.complete_provision_request("key-1", &result).unwrap();
+        let (_, stored) = db.get_provision_request("key-1").unwrap().unwrap();
+        assert_eq!(stored.unwrap().pool_id, "pool-1");
+
+        db.insert_provision_request("key-2", "pool-1").unwrap();
+        assert_eq!(db.list_unfinished_provision_requests().unwrap(), [("key-2".to_string(), "pool-1".to_string())]);
+
+        db.delete_provision_request("key-1").unwrap();
+        assert!(db.get_provision_request("key-1").unwrap().is_none());
+    }
 }
@@ -16,6 +16,15 @@ pub enum Error {
     #[error("Template not found: {0}")]
     TemplateNotFound(String),
 
+    #[error("Template {template} has no version {version}")]
+    TemplateVersionNotFound { template: String, version: u32 },
+
+    #[error("Template {template} v{version} no longer matches its checksum")]
+    TemplateChecksumMismatch { template: String, version: u32 },
+
+    #[error("{resource} is still used by {}; delete those first or cascade", .dependents.join(", "))]
+    InUse { resource: String, dependents: Vec<String> },
+
     #[error("Pool not found: {0}")]
     PoolNotFound(String),
 
@@ -28,15 +37,36 @@ pub enum Error {
     #[error("Timeout waiting for VM")]
     Timeout,
 
+    #[error("Readiness probe failed: {0}")]
+    ProbeFailed(String),
+
     #[error("VM has no IP address")]
     NoIP,
 
     #[error("Guest not responding")]
     GuestNotResponding,
 
+    #[error("No guest credentials configured for template: {0}")]
+    NoGuestCredential(String),
+
     #[error("Insufficient resources: need {required}MB, have {available}MB")]
     InsufficientMemory { required: u64, available: u64 },
 
+    #[error("File too large: {size} bytes exceeds limit of {limit} bytes")]
+    FileTooLarge { size: u64, limit: u64 },
+
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("Job not found: {0}")]
+    JobNotFound(String),
+
+    #[error("Idempotency key conflict: {0}")]
+    IdempotencyConflict(String),
+
     #[error("Database error: {0}")]
     Database(#[from] rusqlite::Error),
 
@@ -46,9 +76,15 @@ pub enum Error {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[error("Invalid VHDX: {0}")]
+    InvalidVhdx(String),
+
     #[error("Parse error: {0}")]
     Parse(String),
 
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
     #[error("Hyper-V not available - enable Hyper-V feature")]
     HyperVNotAvailable,
 
@@ -72,6 +108,12 @@ mod tests {
 
         let e = Error::NoVMAvailable;
         assert_eq!(e.to_string(), "No VM available in pool");
+
+        let e = Error::NoGuestCredential("win11".to_string());
+        assert_eq!(e.to_string(), "No guest credentials configured for template: win11");
+
+        let e = Error::ProbeFailed("tcp:3389".to_string());
+        assert_eq!(e.to_string(), "Readiness probe failed: tcp:3389");
     }
 
     #[test]
@@ -94,6 +136,13 @@ mod tests {
         assert!(e.to_string().contains("4096"));
     }
 
+    #[test]
+    fn test_error_file_too_large() {
+        let e = Error::FileTooLarge { size: 2048, limit: 1024 };
+        assert!(e.to_string().contains("2048"));
+        assert!(e.to_string().contains("1024"));
+    }
+
     #[test]
     fn test_error_debug() {
         let e = Error::Timeout;
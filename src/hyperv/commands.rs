@@ -1,10 +1,22 @@
 //! PowerShell wrappers for Hyper-V commands
 
+use crate::models::{GuestCredential, ProbeKind, ReadinessProbe};
+use crate::runtime::block_on;
 use crate::{Error, Result};
+use base64::Engine;
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::time::{Duration, Instant};
 
+/// Connect/read timeout for a single network probe attempt
+const PROBE_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Upper bound for a single guest script probe attempt
+const GUEST_SCRIPT_TIMEOUT: Duration = Duration::from_secs(30);
+/// A guest counts as settled once its CPU stays below this...
+pub const SETTLE_CPU_PERCENT: u32 = 15;
+/// ...for this many consecutive one-second samples
+const SETTLE_SAMPLES: usize = 3;
+
 /// VM information from Hyper-V
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HyperVInfo {
@@ -32,6 +44,55 @@ impl HyperVInfo {
     }
 }
 
+/// A checkpoint (snapshot) of a VM
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointInfo {
+    #[serde(rename = "VMName")]
+    pub vm_name: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+}
+
+/// A VM's attached disk and its differencing parent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VMDiskInfo {
+    #[serde(rename = "VMName")]
+    pub vm_name: String,
+    #[serde(rename = "Path")]
+    pub path: String,
+    #[serde(rename = "ParentPath")]
+    pub parent_path: Option<String>,
+}
+
+/// A VM's first IPv4 address as reported by its network adapter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VMAddressInfo {
+    #[serde(rename = "VMName")]
+    pub vm_name: String,
+    #[serde(rename = "IPv4")]
+    pub ipv4: Option<String>,
+}
+
+/// Total size of a VM's saved-state files in its configuration directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedStateInfo {
+    #[serde(rename = "VMName")]
+    pub vm_name: String,
+    #[serde(rename = "Bytes")]
+    pub bytes: u64,
+}
+
+/// Output of a script run inside the guest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestOutput {
+    #[serde(rename = "Stdout")]
+    pub stdout: String,
+    #[serde(rename = "Stderr")]
+    pub stderr: String,
+    #[serde(rename = "ExitCode")]
+    pub exit_code: i32,
+}
+
 /// Hyper-V operations
 pub struct HyperV;
 
@@ -47,26 +108,62 @@ impl HyperV {
         let output = powershell(
             r#"Get-VM | Select-Object Name, State, MemoryAssigned, @{N='Uptime';E={$_.Uptime.ToString()}}, Id | ConvertTo-Json -Compress"#,
         )?;
+        parse_json_list(&output)
+    }
 
-        if output.trim().is_empty() {
-            return Ok(vec![]);
-        }
+    /// List checkpoints of all VMs
+    pub fn list_checkpoints() -> Result<Vec<CheckpointInfo>> {
+        let output = powershell(
+            r#"Get-VMSnapshot -VMName * -ErrorAction SilentlyContinue | Select-Object VMName, Name | ConvertTo-Json -Compress"#,
+        )?;
+        parse_json_list(&output)
+    }
 
-        // Handle single vs array JSON
-        if output.trim().starts_with('[') {
-            Ok(serde_json::from_str(&output)?)
-        } else {
-            let single: HyperVInfo = serde_json::from_str(&output)?;
-            Ok(vec![single])
-        }
+    /// List every VM's attached disks with their differencing parents
+    pub fn list_vm_disks() -> Result<Vec<VMDiskInfo>> {
+        let output = powershell(
+            r#"Get-VM | Get-VMHardDiskDrive | ForEach-Object {
+                $vhd = Get-VHD -Path $_.Path -ErrorAction SilentlyContinue
+                [pscustomobject]@{ VMName = $_.VMName; Path = $_.Path; ParentPath = $vhd.ParentPath }
+            } | ConvertTo-Json -Compress"#,
+        )?;
+        parse_json_list(&output)
+    }
+
+    /// Size of every VM's `.vmrs`/`.bin`/`.vsv` files (memory and device state)
+    pub fn list_saved_state_sizes() -> Result<Vec<SavedStateInfo>> {
+        let output = powershell(
+            r#"Get-VM | ForEach-Object {
+                $vm = $_
+                $files = Get-ChildItem -Path $vm.ConfigurationLocation -Recurse -File -ErrorAction SilentlyContinue |
+                    Where-Object { $_.BaseName -eq $vm.Id -and $_.Extension -in '.vmrs', '.bin', '.vsv' }
+                [pscustomobject]@{ VMName = $vm.Name; Bytes = [int64]($files | Measure-Object Length -Sum).Sum }
+            } | ConvertTo-Json -Compress"#,
+        )?;
+        parse_json_list(&output)
+    }
+
+    /// List the first IPv4 address of every VM
+    pub fn list_vm_addresses() -> Result<Vec<VMAddressInfo>> {
+        let output = powershell(
+            r#"Get-VMNetworkAdapter -VMName * | ForEach-Object {
+                $ip = $_.IPAddresses | Where-Object { $_ -match '^\d+\.\d+\.\d+\.\d+$' } | Select-Object -First 1
+                [pscustomobject]@{ VMName = $_.VMName; IPv4 = $ip }
+            } | ConvertTo-Json -Compress"#,
+        )?;
+        parse_json_list(&output)
     }
 
     /// Get VM by name
     pub fn get_vm(name: &str) -> Result<Option<HyperVInfo>> {
-        let output = powershell(&format!(
+        block_on(Self::get_vm_async(name))
+    }
+
+    pub async fn get_vm_async(name: &str) -> Result<Option<HyperVInfo>> {
+        let output = powershell_async(&format!(
             r#"Get-VM -Name '{}' -ErrorAction SilentlyContinue | Select-Object Name, State, MemoryAssigned, @{{N='Uptime';E={{$_.Uptime.ToString()}}}}, Id | ConvertTo-Json -Compress"#,
             escape_ps(name)
-        ))?;
+        )).await?;
 
         if output.trim().is_empty() {
             return Ok(None);
@@ -100,38 +197,78 @@ impl HyperV {
         Ok(())
     }
 
-    /// Create differencing disk (COW clone from parent)
-    pub fn create_differencing_disk(parent_path: &str, child_path: &str) -> Result<()> {
-        powershell(&format!(
-            "New-VHD -Path '{}' -ParentPath '{}' -Differencing",
-            escape_ps(child_path),
-            escape_ps(parent_path)
-        ))?;
-        Ok(())
-    }
-
     /// Start VM (resumes if saved, cold boots if off)
     pub fn start_vm(name: &str) -> Result<()> {
-        powershell(&format!("Start-VM -Name '{}'", escape_ps(name)))?;
+        block_on(Self::start_vm_async(name))
+    }
+
+    pub async fn start_vm_async(name: &str) -> Result<()> {
+        powershell_async(&format!("Start-VM -Name '{}'", escape_ps(name))).await?;
         Ok(())
     }
 
     /// Save VM state to disk (fast resume later)
     pub fn save_vm(name: &str) -> Result<()> {
-        powershell(&format!("Save-VM -Name '{}'", escape_ps(name)))?;
+        block_on(Self::save_vm_async(name))
+    }
+
+    pub async fn save_vm_async(name: &str) -> Result<()> {
+        powershell_async(&format!("Save-VM -Name '{}'", escape_ps(name))).await?;
         Ok(())
     }
 
     /// Stop VM (graceful shutdown)
     pub fn stop_vm(name: &str, force: bool) -> Result<()> {
+        block_on(Self::stop_vm_async(name, force))
+    }
+
+    pub async fn stop_vm_async(name: &str, force: bool) -> Result<()> {
         let force_flag = if force { " -Force" } else { "" };
-        powershell(&format!("Stop-VM -Name '{}'{}", escape_ps(name), force_flag))?;
+        powershell_async(&format!("Stop-VM -Name '{}'{}", escape_ps(name), force_flag)).await?;
+        Ok(())
+    }
+
+    /// Wait for a guest that is shutting itself down (e.g. sysprep) to reach Off
+    pub fn wait_for_off(name: &str, timeout: Duration) -> Result<()> {
+        block_on(Self::wait_for_off_async(name, timeout))
+    }
+
+    pub async fn wait_for_off_async(name: &str, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            match Self::get_vm_async(name).await? {
+                Some(info) if info.state == 2 => return Ok(()),
+                Some(_) => {}
+                None => return Err(Error::VMNotFound(name.to_string())),
+            }
+            if start.elapsed() > timeout {
+                return Err(Error::Timeout);
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+
+    /// Merge a disk and its parents into a new standalone dynamic VHDX
+    pub fn convert_vhd(source: &str, destination: &str) -> Result<()> {
+        block_on(Self::convert_vhd_async(source, destination))
+    }
+
+    pub async fn convert_vhd_async(source: &str, destination: &str) -> Result<()> {
+        powershell_async(&format!(
+            "Convert-VHD -Path '{}' -DestinationPath '{}' -VHDType Dynamic",
+            escape_ps(source),
+            escape_ps(destination)
+        )).await?;
         Ok(())
     }
 
     /// Turn off VM immediately (like pulling power)
     pub fn turn_off_vm(name: &str) -> Result<()> {
-        powershell(&format!("Stop-VM -Name '{}' -TurnOff -Force", escape_ps(name)))?;
+        block_on(Self::turn_off_vm_async(name))
+    }
+
+    pub async fn turn_off_vm_async(name: &str) -> Result<()> {
+        powershell_async(&format!("Stop-VM -Name '{}' -TurnOff -Force", escape_ps(name))).await?;
         Ok(())
     }
 
@@ -141,6 +278,12 @@ impl HyperV {
         Ok(())
     }
 
+    /// Discard a saved VM's memory state, leaving it Off
+    pub fn remove_saved_state(name: &str) -> Result<()> {
+        powershell(&format!("Remove-VMSavedState -VMName '{}'", escape_ps(name)))?;
+        Ok(())
+    }
+
     /// Create checkpoint (snapshot)
     pub fn create_checkpoint(vm_name: &str, checkpoint_name: &str) -> Result<()> {
         powershell(&format!(
@@ -151,6 +294,16 @@ impl HyperV {
         Ok(())
     }
 
+    /// Delete a checkpoint if it exists
+    pub fn remove_checkpoint(vm_name: &str, checkpoint_name: &str) -> Result<()> {
+        powershell(&format!(
+            "Get-VMSnapshot -VMName '{}' -Name '{}' -ErrorAction SilentlyContinue | Remove-VMSnapshot",
+            escape_ps(vm_name),
+            escape_ps(checkpoint_name)
+        ))?;
+        Ok(())
+    }
+
     /// Restore to checkpoint
     pub fn restore_checkpoint(vm_name: &str, checkpoint_name: &str) -> Result<()> {
         powershell(&format!(
@@ -163,10 +316,14 @@ impl HyperV {
 
     /// Get VM IP address(es)
     pub fn get_vm_ip(name: &str) -> Result<Option<String>> {
-        let output = powershell(&format!(
+        block_on(Self::get_vm_ip_async(name))
+    }
+
+    pub async fn get_vm_ip_async(name: &str) -> Result<Option<String>> {
+        let output = powershell_async(&format!(
             r#"(Get-VMNetworkAdapter -VMName '{}').IPAddresses | Where-Object {{ $_ -match '^\d+\.\d+\.\d+\.\d+$' }} | Select-Object -First 1"#,
             escape_ps(name)
-        ))?;
+        )).await?;
 
         let ip = output.trim();
         if ip.is_empty() {
@@ -176,8 +333,30 @@ impl HyperV {
         }
     }
 
-    /// Wait for VM to be running and have an IP
+    /// Wait for VM to be running, have an IP and answer on RDP
     pub fn wait_for_ready(name: &str, timeout: Duration) -> Result<String> {
+        Self::wait_for_probes(name, &ReadinessProbe::defaults(), None, timeout)?
+            .ok_or(Error::NoIP)
+    }
+
+    /// Wait for VM to be running, then for each probe to pass in order
+    pub fn wait_for_probes(
+        name: &str,
+        probes: &[ReadinessProbe],
+        credential: Option<&GuestCredential>,
+        timeout: Duration,
+    ) -> Result<Option<String>> {
+        block_on(Self::wait_for_probes_async(name, probes, credential, timeout))
+    }
+
+    /// Async `wait_for_probes`; dropping the future stops polling and kills
+    /// any PowerShell still running
+    pub async fn wait_for_probes_async(
+        name: &str,
+        probes: &[ReadinessProbe],
+        credential: Option<&GuestCredential>,
+        timeout: Duration,
+    ) -> Result<Option<String>> {
         let start = Instant::now();
 
         loop {
@@ -185,34 +364,142 @@ impl HyperV {
                 return Err(Error::Timeout);
             }
 
-            // Check VM state
-            if let Some(info) = Self::get_vm(name)? {
-                if info.state != 3 {
-                    // Not running yet
-                    std::thread::sleep(Duration::from_millis(500));
-                    continue;
-                }
-            } else {
-                return Err(Error::VMNotFound(name.to_string()));
+            match Self::get_vm_async(name).await? {
+                Some(info) if info.state == 3 => break,
+                Some(_) => tokio::time::sleep(Duration::from_millis(500)).await,
+                None => return Err(Error::VMNotFound(name.to_string())),
             }
+        }
 
-            // Check for IP
-            if let Some(ip) = Self::get_vm_ip(name)? {
-                // Try TCP connect to RDP port
-                if let Ok(_) = std::net::TcpStream::connect_timeout(
-                    &format!("{}:3389", ip).parse().unwrap(),
-                    Duration::from_secs(2),
-                ) {
-                    return Ok(ip);
+        let mut ip = None;
+        for probe in probes {
+            let probe_start = Instant::now();
+            loop {
+                if start.elapsed() > timeout {
+                    return Err(Error::Timeout);
+                }
+                if probe.timeout().is_some_and(|t| probe_start.elapsed() > t) {
+                    return Err(Error::ProbeFailed(probe.to_string()));
+                }
+
+                if probe.needs_ip() && ip.is_none() {
+                    ip = Self::get_vm_ip_async(name).await?;
+                }
+                if Self::check_probe_async(name, ip.as_deref(), probe, credential).await? {
+                    tracing::debug!(vm = %name, probe = %probe, "Probe passed");
+                    break;
                 }
+
+                tokio::time::sleep(Duration::from_millis(500)).await;
             }
+        }
 
-            std::thread::sleep(Duration::from_millis(500));
+        if ip.is_none() {
+            ip = Self::get_vm_ip_async(name).await?;
         }
+        Ok(ip)
+    }
+
+    /// Run a single probe attempt against a VM
+    pub fn check_probe(
+        name: &str,
+        ip: Option<&str>,
+        probe: &ReadinessProbe,
+        credential: Option<&GuestCredential>,
+    ) -> Result<bool> {
+        block_on(Self::check_probe_async(name, ip, probe, credential))
+    }
+
+    pub async fn check_probe_async(
+        name: &str,
+        ip: Option<&str>,
+        probe: &ReadinessProbe,
+        credential: Option<&GuestCredential>,
+    ) -> Result<bool> {
+        match &probe.kind {
+            ProbeKind::Heartbeat => Self::heartbeat_ok_async(name).await,
+            ProbeKind::Tcp { port } => {
+                let Some(addr) = ip.and_then(|ip| socket_addr(ip, *port)) else {
+                    return Ok(false);
+                };
+                let connect = tokio::net::TcpStream::connect(addr);
+                Ok(matches!(tokio::time::timeout(PROBE_ATTEMPT_TIMEOUT, connect).await, Ok(Ok(_))))
+            }
+            ProbeKind::Http { port, path, expected_status, expected_body } => {
+                let Some(addr) = ip.and_then(|ip| socket_addr(ip, *port)) else {
+                    return Ok(false);
+                };
+                Ok(match http_get(&addr, path, PROBE_ATTEMPT_TIMEOUT).await {
+                    Ok((status, body)) => {
+                        status == *expected_status
+                            && expected_body.as_ref().is_none_or(|b| body.contains(b.as_str()))
+                    }
+                    Err(_) => false,
+                })
+            }
+            ProbeKind::GuestScript { script } => {
+                let credential = credential.ok_or_else(|| Error::NoGuestCredential(name.to_string()))?;
+                let timeout = probe.timeout().unwrap_or(GUEST_SCRIPT_TIMEOUT).min(GUEST_SCRIPT_TIMEOUT);
+                // PowerShell Direct fails until the guest has booted far enough
+                Ok(Self::invoke_in_guest_async(name, credential, script, timeout).await
+                    .is_ok_and(|out| out.exit_code == 0))
+            }
+        }
+    }
+
+    /// Whether the heartbeat integration service currently reports OK
+    pub fn heartbeat_ok(name: &str) -> Result<bool> {
+        block_on(Self::heartbeat_ok_async(name))
+    }
+
+    pub async fn heartbeat_ok_async(name: &str) -> Result<bool> {
+        let output = powershell_async(&format!(
+            r#"(Get-VMIntegrationService -VMName '{}' -Name 'Heartbeat' -ErrorAction SilentlyContinue).PrimaryStatusDescription"#,
+            escape_ps(name)
+        )).await?;
+        Ok(output.trim() == "OK")
+    }
+
+    /// CPU usage (percent of assigned cores) Hyper-V reports for a VM
+    pub fn get_vm_cpu_usage(name: &str) -> Result<u32> {
+        block_on(Self::get_vm_cpu_usage_async(name))
+    }
+
+    pub async fn get_vm_cpu_usage_async(name: &str) -> Result<u32> {
+        let output = powershell_async(&format!("(Get-VM -Name '{}').CPUUsage", escape_ps(name))).await?;
+        output
+            .trim()
+            .parse()
+            .map_err(|_| Error::Parse(format!("Failed to parse CPU usage: {}", output.trim())))
+    }
+
+    /// Wait for a freshly booted guest to stop churning (first-boot services,
+    /// updates). Returns false if it was still busy when `timeout` ran out.
+    pub fn wait_for_settle(name: &str, threshold: u32, timeout: Duration) -> Result<bool> {
+        block_on(Self::wait_for_settle_async(name, threshold, timeout))
+    }
+
+    pub async fn wait_for_settle_async(name: &str, threshold: u32, timeout: Duration) -> Result<bool> {
+        let start = Instant::now();
+        let mut samples = Vec::new();
+
+        while start.elapsed() < timeout {
+            samples.push(Self::get_vm_cpu_usage_async(name).await?);
+            if is_settled(&samples, threshold, SETTLE_SAMPLES) {
+                return Ok(true);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        Ok(false)
     }
 
     /// Wait for guest heartbeat (integration services)
     pub fn wait_for_heartbeat(name: &str, timeout: Duration) -> Result<()> {
+        block_on(Self::wait_for_heartbeat_async(name, timeout))
+    }
+
+    pub async fn wait_for_heartbeat_async(name: &str, timeout: Duration) -> Result<()> {
         let start = Instant::now();
 
         loop {
@@ -220,19 +507,190 @@ impl HyperV {
                 return Err(Error::GuestNotResponding);
             }
 
-            let output = powershell(&format!(
-                r#"(Get-VMIntegrationService -VMName '{}' -Name 'Heartbeat' -ErrorAction SilentlyContinue).PrimaryStatusDescription"#,
-                escape_ps(name)
-            ))?;
-
-            if output.trim() == "OK" {
+            if Self::heartbeat_ok_async(name).await? {
                 return Ok(());
             }
 
-            std::thread::sleep(Duration::from_millis(500));
+            tokio::time::sleep(Duration::from_millis(500)).await;
         }
     }
 
+    /// Run a script inside the guest via PowerShell Direct (Invoke-Command -VMName)
+    pub fn invoke_in_guest(
+        name: &str,
+        credential: &GuestCredential,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<GuestOutput> {
+        block_on(Self::invoke_in_guest_async(name, credential, script, timeout))
+    }
+
+    pub async fn invoke_in_guest_async(
+        name: &str,
+        credential: &GuestCredential,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<GuestOutput> {
+        let output = run_with_timeout(
+            guest_powershell_command(
+                &format!(
+                    r#"
+            $ErrorActionPreference = 'Stop'
+            {}
+            $result = Invoke-Command -VMName '{}' -Credential $credential -ArgumentList '{}' -ScriptBlock {{
+                param($Script)
+                $global:LASTEXITCODE = 0
+                $failed = $false
+                try {{
+                    $output = @(& ([scriptblock]::Create($Script)) 2>&1)
+                    $failed = -not $?
+                }} catch {{
+                    $output = @($_)
+                    $failed = $true
+                }}
+                $errors = @($output | Where-Object {{ $_ -is [System.Management.Automation.ErrorRecord] }})
+                $stdout = @($output | Where-Object {{ $_ -isnot [System.Management.Automation.ErrorRecord] }})
+                $code = if ($LASTEXITCODE) {{ $LASTEXITCODE }} elseif ($failed -or $errors.Count) {{ 1 }} else {{ 0 }}
+                [pscustomobject]@{{ Stdout = ($stdout | Out-String); Stderr = ($errors | Out-String); ExitCode = $code }}
+            }}
+            $result | Select-Object Stdout, Stderr, ExitCode | ConvertTo-Json -Compress
+            "#,
+                    credential_ps(credential),
+                    escape_ps(name),
+                    escape_ps(script)
+                ),
+                credential,
+            ),
+            timeout,
+        )
+        .await?;
+
+        Ok(serde_json::from_str(output.trim())?)
+    }
+
+    /// Enable the Guest Service Interface (required by Copy-VMFile)
+    pub fn enable_guest_services(name: &str) -> Result<()> {
+        powershell(&format!(
+            "Enable-VMIntegrationService -VMName '{}' -Name 'Guest Service Interface'",
+            escape_ps(name)
+        ))?;
+        Ok(())
+    }
+
+    /// Copy a host file into the guest (Copy-VMFile)
+    pub fn copy_to_guest(name: &str, source: &str, destination: &str) -> Result<()> {
+        block_on(Self::copy_to_guest_async(name, source, destination))
+    }
+
+    pub async fn copy_to_guest_async(name: &str, source: &str, destination: &str) -> Result<()> {
+        powershell_async(&format!(
+            "Copy-VMFile -Name '{}' -SourcePath '{}' -DestinationPath '{}' -FileSource Host -CreateFullPath -Force",
+            escape_ps(name),
+            escape_ps(source),
+            escape_ps(destination)
+        )).await?;
+        Ok(())
+    }
+
+    /// Size in bytes of a file in the guest, over PowerShell Direct
+    pub fn guest_file_size(name: &str, credential: &GuestCredential, path: &str) -> Result<u64> {
+        block_on(Self::guest_file_size_async(name, credential, path))
+    }
+
+    pub async fn guest_file_size_async(name: &str, credential: &GuestCredential, path: &str) -> Result<u64> {
+        let output = run_async(guest_powershell_command(
+            &format!(
+                r#"
+            $ErrorActionPreference = 'Stop'
+            {}
+            Invoke-Command -VMName '{}' -Credential $credential -ArgumentList '{}' -ScriptBlock {{
+                param($Path)
+                (Get-Item -LiteralPath $Path).Length
+            }}
+            "#,
+                credential_ps(credential),
+                escape_ps(name),
+                escape_ps(path)
+            ),
+            credential,
+        ))
+        .await?;
+        output
+            .trim()
+            .parse()
+            .map_err(|_| Error::Parse(format!("Failed to parse file size: {}", output.trim())))
+    }
+
+    /// Copy a guest file out to the host over a PowerShell Direct session
+    pub fn copy_from_guest(
+        name: &str,
+        credential: &GuestCredential,
+        source: &str,
+        destination: &str,
+    ) -> Result<()> {
+        block_on(Self::copy_from_guest_async(name, credential, source, destination))
+    }
+
+    /// Async `copy_from_guest`; dropping the future kills the session
+    pub async fn copy_from_guest_async(
+        name: &str,
+        credential: &GuestCredential,
+        source: &str,
+        destination: &str,
+    ) -> Result<()> {
+        run_async(guest_powershell_command(
+            &format!(
+                r#"
+            $ErrorActionPreference = 'Stop'
+            {}
+            $session = New-PSSession -VMName '{}' -Credential $credential
+            try {{
+                Copy-Item -FromSession $session -Path '{}' -Destination '{}' -Force
+            }} finally {{
+                Remove-PSSession $session
+            }}
+            "#,
+                credential_ps(credential),
+                escape_ps(name),
+                escape_ps(source),
+                escape_ps(destination)
+            ),
+            credential,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Grab the console framebuffer as raw RGB565 (GetVirtualSystemThumbnailImage)
+    pub fn capture_screenshot(name: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+        block_on(Self::capture_screenshot_async(name, width, height))
+    }
+
+    pub async fn capture_screenshot_async(name: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+        let output = powershell_async(&format!(
+            r#"
+            $ErrorActionPreference = 'Stop'
+            $ns = 'root\virtualization\v2'
+            $vmms = Get-CimInstance -Namespace $ns -ClassName Msvm_VirtualSystemManagementService
+            $vm = Get-CimInstance -Namespace $ns -ClassName Msvm_ComputerSystem | Where-Object {{ $_.ElementName -eq '{}' }}
+            $settings = Get-CimAssociatedInstance -InputObject $vm -ResultClassName Msvm_VirtualSystemSettingData |
+                Where-Object {{ $_.VirtualSystemType -eq 'Microsoft:Hyper-V:System:Realized' }}
+            $result = Invoke-CimMethod -InputObject $vmms -MethodName GetVirtualSystemThumbnailImage -Arguments @{{
+                TargetSystem = $settings; WidthPixels = [uint16]{}; HeightPixels = [uint16]{}
+            }}
+            if ($result.ReturnValue -ne 0) {{ throw "GetVirtualSystemThumbnailImage returned $($result.ReturnValue)" }}
+            [Convert]::ToBase64String([byte[]]$result.ImageData)
+            "#,
+            escape_ps(name),
+            width,
+            height
+        )).await?;
+
+        base64::engine::general_purpose::STANDARD
+            .decode(output.trim())
+            .map_err(|e| Error::Parse(format!("Invalid thumbnail data: {}", e)))
+    }
+
     /// Enable enhanced session mode
     pub fn enable_enhanced_session(name: &str) -> Result<()> {
         powershell(&format!(
@@ -288,26 +746,21 @@ impl HyperV {
 
     /// Wait for terminator MCP agent to be healthy (port 8080)
     pub fn wait_for_terminator(ip: &str, timeout: Duration) -> Result<()> {
-        use std::io::{Read, Write};
         let start = Instant::now();
-        let addr: std::net::SocketAddr = format!("{}:8080", ip).parse()
-            .map_err(|_| Error::Parse(format!("Invalid IP: {}", ip)))?;
+        let probe = ReadinessProbe::new(ProbeKind::Http {
+            port: 8080,
+            path: "/health".to_string(),
+            expected_status: 200,
+            expected_body: None,
+        });
 
         loop {
             if start.elapsed() > timeout {
                 return Err(Error::GuestNotResponding);
             }
 
-            if let Ok(mut stream) = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(2)) {
-                let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
-                let _ = stream.write_all(b"GET /health HTTP/1.0\r\nHost: localhost\r\n\r\n");
-                let mut buf = [0u8; 512];
-                if let Ok(n) = stream.read(&mut buf) {
-                    let response = String::from_utf8_lossy(&buf[..n]);
-                    if response.contains("200") || response.contains("healthy") {
-                        return Ok(());
-                    }
-                }
+            if Self::check_probe("", Some(ip), &probe, None)? {
+                return Ok(());
             }
 
             std::thread::sleep(Duration::from_millis(300));
@@ -315,19 +768,57 @@ impl HyperV {
     }
 }
 
+/// Build a PowerShell invocation
+fn powershell_command(script: &str) -> Command {
+    let mut cmd = Command::new("powershell");
+    cmd.args([
+        "-NoProfile",
+        "-NonInteractive",
+        "-ExecutionPolicy",
+        "Bypass",
+        "-Command",
+        script,
+    ]);
+    cmd
+}
+
+/// Build a PowerShell invocation for a script that starts with `credential_ps`.
+/// The password goes in the child's environment, never on its command line.
+fn guest_powershell_command(script: &str, credential: &GuestCredential) -> Command {
+    let mut cmd = powershell_command(script);
+    cmd.env(GUEST_PASSWORD_ENV, &credential.password);
+    cmd
+}
+
 /// Execute PowerShell command
 fn powershell(script: &str) -> Result<String> {
-    let output = Command::new("powershell")
-        .args([
-            "-NoProfile",
-            "-NonInteractive",
-            "-ExecutionPolicy",
-            "Bypass",
-            "-Command",
-            script,
-        ])
-        .output()?;
+    let output = powershell_command(script).output()?;
+    check_output(output)
+}
+
+/// Execute PowerShell command without blocking the runtime. Dropping the
+/// future kills the process.
+async fn powershell_async(script: &str) -> Result<String> {
+    run_async(powershell_command(script)).await
+}
+
+async fn run_async(cmd: Command) -> Result<String> {
+    let output = tokio::process::Command::from(cmd)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    check_output(output)
+}
+
+/// Run a command, killing it if it runs past `timeout`
+async fn run_with_timeout(cmd: Command, timeout: Duration) -> Result<String> {
+    tokio::time::timeout(timeout, run_async(cmd))
+        .await
+        .map_err(|_| Error::Timeout)?
+}
 
+fn check_output(output: std::process::Output) -> Result<String> {
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         let stdout = String::from_utf8_lossy(&output.stdout);
@@ -342,6 +833,68 @@ fn powershell(script: &str) -> Result<String> {
     Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
 
+/// Environment variable carrying the guest password to `credential_ps`
+const GUEST_PASSWORD_ENV: &str = "HYPERV_KUBE_GUEST_PASSWORD";
+
+/// PowerShell lines that build `$credential` for PowerShell Direct. The
+/// password is read from (and then cleared out of) the environment set by
+/// `guest_powershell_command`, so it never shows up in the process list.
+fn credential_ps(credential: &GuestCredential) -> String {
+    format!(
+        "$password = ConvertTo-SecureString $env:{env} -AsPlainText -Force; Remove-Item Env:{env}; $credential = New-Object System.Management.Automation.PSCredential('{}', $password)",
+        escape_ps(&credential.username),
+        env = GUEST_PASSWORD_ENV
+    )
+}
+
+/// Parse ConvertTo-Json output, which is empty, an object or an array
+fn parse_json_list<T: serde::de::DeserializeOwned>(output: &str) -> Result<Vec<T>> {
+    let output = output.trim();
+    if output.is_empty() {
+        Ok(vec![])
+    } else if output.starts_with('[') {
+        Ok(serde_json::from_str(output)?)
+    } else {
+        Ok(vec![serde_json::from_str(output)?])
+    }
+}
+
+/// Whether the last `needed` CPU samples are all below `threshold`
+pub fn is_settled(samples: &[u32], threshold: u32, needed: usize) -> bool {
+    samples.len() >= needed && samples[samples.len() - needed..].iter().all(|&c| c < threshold)
+}
+
+fn socket_addr(ip: &str, port: u16) -> Option<std::net::SocketAddr> {
+    let ip: std::net::IpAddr = ip.parse().ok()?;
+    Some(std::net::SocketAddr::new(ip, port))
+}
+
+/// Minimal HTTP/1.0 GET returning status code and body
+async fn http_get(addr: &std::net::SocketAddr, path: &str, timeout: Duration) -> std::io::Result<(u16, String)> {
+    let buf = tokio::time::timeout(timeout, async {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.write_all(format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr.ip()).as_bytes()).await?;
+
+        let mut buf = Vec::new();
+        stream.take(64 * 1024).read_to_end(&mut buf).await?;
+        Ok::<_, std::io::Error>(buf)
+    })
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "HTTP probe timed out"))??;
+    let response = String::from_utf8_lossy(&buf);
+
+    let status = response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed HTTP response"))?;
+    let body = response.split_once("\r\n\r\n").map(|(_, b)| b.to_string()).unwrap_or_default();
+    Ok((status, body))
+}
+
 /// Escape string for PowerShell
 fn escape_ps(s: &str) -> String {
     s.replace("'", "''")
@@ -356,4 +909,130 @@ mod tests {
         assert_eq!(escape_ps("test"), "test");
         assert_eq!(escape_ps("test's"), "test''s");
     }
+
+    #[test]
+    fn test_credential_ps_escapes() {
+        let cred = GuestCredential::new("Admin", "it's");
+        let ps = credential_ps(&cred);
+        assert!(ps.contains("'Admin'"));
+        assert!(!ps.contains("it's") && !ps.contains("it''s"));
+        assert!(ps.contains(GUEST_PASSWORD_ENV));
+
+        let cmd = guest_powershell_command(&ps, &cred);
+        assert!(cmd.get_args().all(|arg| !arg.to_string_lossy().contains("it's")));
+        assert!(cmd
+            .get_envs()
+            .any(|(k, v)| k == GUEST_PASSWORD_ENV && v == Some(std::ffi::OsStr::new("it's"))));
+    }
+
+    #[test]
+    fn test_guest_output_parse() {
+        let json = r#"{"Stdout":"hello\r\n","Stderr":"","ExitCode":0}"#;
+        let out: GuestOutput = serde_json::from_str(json).unwrap();
+        assert_eq!(out.stdout, "hello\r\n");
+        assert!(out.stderr.is_empty());
+        assert_eq!(out.exit_code, 0);
+    }
+
+    #[test]
+    fn test_is_settled() {
+        assert!(!is_settled(&[], 15, 3));
+        assert!(!is_settled(&[90, 5, 5], 15, 3));
+        assert!(is_settled(&[90, 40, 5, 3, 10], 15, 3));
+        assert!(!is_settled(&[5, 5, 20], 15, 3));
+    }
+
+    #[test]
+    fn test_parse_json_list() {
+        let none: Vec<CheckpointInfo> = parse_json_list("  \r\n").unwrap();
+        assert!(none.is_empty());
+
+        let one: Vec<CheckpointInfo> = parse_json_list(r#"{"VMName":"agents-0","Name":"clean"}"#).unwrap();
+        assert_eq!(one[0].name, "clean");
+
+        let many: Vec<VMDiskInfo> = parse_json_list(
+            r#"[{"VMName":"a","Path":"C:\\a.vhdx","ParentPath":"C:\\t.vhdx"},{"VMName":"b","Path":"C:\\b.vhdx","ParentPath":null}]"#,
+        ).unwrap();
+        assert_eq!(many.len(), 2);
+        assert!(many[1].parent_path.is_none());
+    }
+
+    fn serve_once(response: &'static str) -> std::net::SocketAddr {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http_get() {
+        let addr = serve_once("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 8\r\n\r\nstarting");
+        let (status, body) = http_get(&addr, "/health", Duration::from_secs(2)).await.unwrap();
+        assert_eq!(status, 503);
+        assert_eq!(body, "starting");
+    }
+
+    #[test]
+    fn test_check_probe_http() {
+        let http = |port, body: Option<&str>| ReadinessProbe::new(ProbeKind::Http {
+            port,
+            path: "/health".to_string(),
+            expected_status: 200,
+            expected_body: body.map(String::from),
+        });
+
+        let addr = serve_once("HTTP/1.0 200 OK\r\n\r\n{\"status\":\"healthy\"}");
+        assert!(HyperV::check_probe("vm", Some("127.0.0.1"), &http(addr.port(), Some("healthy")), None).unwrap());
+
+        let addr = serve_once("HTTP/1.0 200 OK\r\n\r\n{\"status\":\"degraded\"}");
+        assert!(!HyperV::check_probe("vm", Some("127.0.0.1"), &http(addr.port(), Some("healthy")), None).unwrap());
+
+        // No IP yet means not ready rather than an error
+        assert!(!HyperV::check_probe("vm", None, &http(8080, None), None).unwrap());
+    }
+
+    #[test]
+    fn test_check_probe_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = ReadinessProbe::new(ProbeKind::Tcp { port });
+        assert!(HyperV::check_probe("vm", Some("127.0.0.1"), &probe, None).unwrap());
+
+        drop(listener);
+        assert!(!HyperV::check_probe("vm", Some("127.0.0.1"), &probe, None).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_probe_stops_waiting() {
+        // Accepts but never answers, so the probe would wait out its own timeout
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = ReadinessProbe::new(ProbeKind::Http {
+            port,
+            path: "/health".to_string(),
+            expected_status: 200,
+            expected_body: None,
+        });
+
+        let start = Instant::now();
+        let attempt = HyperV::check_probe_async("vm", Some("127.0.0.1"), &probe, None);
+        assert!(tokio::time::timeout(Duration::from_millis(100), attempt).await.is_err());
+        assert!(start.elapsed() < PROBE_ATTEMPT_TIMEOUT);
+        drop(listener);
+    }
+
+    #[test]
+    fn test_check_probe_script_needs_credential() {
+        let probe = ReadinessProbe::new(ProbeKind::GuestScript { script: "exit 0".to_string() });
+        let result = HyperV::check_probe("vm", None, &probe, None);
+        assert!(matches!(result, Err(Error::NoGuestCredential(_))));
+    }
 }
@@ -1,5 +1,7 @@
 //! Hyper-V backend via PowerShell
 
 mod commands;
+mod screenshot;
 
 pub use commands::*;
+pub use screenshot::*;
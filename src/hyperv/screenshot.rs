@@ -0,0 +1,111 @@
+//! Console thumbnail decoding (RGB565 -> PNG)
+
+use crate::{Error, Result};
+
+/// Largest thumbnail width or height asked of Hyper-V (8K)
+pub const MAX_SCREENSHOT_DIMENSION: u32 = 7680;
+
+/// Reject thumbnail sizes Hyper-V can't produce before asking it for one
+pub fn check_screenshot_size(width: u32, height: u32) -> Result<()> {
+    let valid = 1..=MAX_SCREENSHOT_DIMENSION;
+    if !valid.contains(&width) || !valid.contains(&height) {
+        return Err(Error::InvalidArgument(format!(
+            "screenshot size {}x{} must be within 1..={} on each side",
+            width, height, MAX_SCREENSHOT_DIMENSION
+        )));
+    }
+    Ok(())
+}
+
+/// Expand a little-endian RGB565 buffer to packed RGB8
+pub fn rgb565_to_rgb8(data: &[u8]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(data.len() / 2 * 3);
+    for px in data.chunks_exact(2) {
+        let v = u16::from_le_bytes([px[0], px[1]]);
+        let r = ((v >> 11) & 0x1f) as u8;
+        let g = ((v >> 5) & 0x3f) as u8;
+        let b = (v & 0x1f) as u8;
+        // Replicate high bits into the low bits so 0x1f maps to 0xff
+        rgb.push((r << 3) | (r >> 2));
+        rgb.push((g << 2) | (g >> 4));
+        rgb.push((b << 3) | (b >> 2));
+    }
+    rgb
+}
+
+/// Encode a raw RGB565 thumbnail as PNG
+pub fn rgb565_to_png(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let expected = width as usize * height as usize * 2;
+    if data.len() < expected {
+        return Err(Error::Parse(format!(
+            "Thumbnail too short: got {} bytes, expected {} for {}x{}",
+            data.len(),
+            expected,
+            width,
+            height
+        )));
+    }
+
+    let rgb = rgb565_to_rgb8(&data[..expected]);
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| Error::Other(format!("PNG encode failed: {}", e)))?;
+        writer
+            .write_image_data(&rgb)
+            .map_err(|e| Error::Other(format!("PNG encode failed: {}", e)))?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_screenshot_size() {
+        assert!(check_screenshot_size(1024, 768).is_ok());
+        assert!(check_screenshot_size(1, MAX_SCREENSHOT_DIMENSION).is_ok());
+        for (w, h) in [(0, 768), (1024, 0), (MAX_SCREENSHOT_DIMENSION + 1, 768), (u32::MAX, u32::MAX)] {
+            assert!(matches!(check_screenshot_size(w, h), Err(Error::InvalidArgument(_))), "{}x{}", w, h);
+        }
+    }
+
+    #[test]
+    fn test_rgb565_primaries() {
+        let red = 0xf800u16.to_le_bytes();
+        let green = 0x07e0u16.to_le_bytes();
+        let blue = 0x001fu16.to_le_bytes();
+        let black = 0x0000u16.to_le_bytes();
+        let data = [red, green, blue, black].concat();
+
+        let rgb = rgb565_to_rgb8(&data);
+        assert_eq!(rgb, vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_rgb565_to_png_roundtrip() {
+        let (w, h) = (4u32, 3u32);
+        let data: Vec<u8> = (0..w * h).flat_map(|_| 0xffffu16.to_le_bytes()).collect();
+
+        let png_bytes = rgb565_to_png(&data, w, h).unwrap();
+        assert_eq!(&png_bytes[..8], b"\x89PNG\r\n\x1a\n");
+
+        let decoder = png::Decoder::new(png_bytes.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (w, h));
+        assert!(buf[..info.buffer_size()].iter().all(|&b| b == 255));
+    }
+
+    #[test]
+    fn test_rgb565_to_png_short_buffer() {
+        let result = rgb565_to_png(&[0u8; 10], 4, 4);
+        assert!(matches!(result.unwrap_err(), Error::Parse(_)));
+    }
+}
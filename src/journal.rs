@@ -0,0 +1,304 @@
+//! Write-ahead journal for multi-step operations
+//!
+//! Each step's intent is written before it runs and its completion after, so
+//! an operation interrupted by a crash can be rolled back or forward on the
+//! next start.
+
+use crate::db::Database;
+use crate::models::*;
+use crate::Result;
+use chrono::Utc;
+
+/// The steps of one operation, with a way to undo each of them.
+///
+/// Both `run` and `undo` must be safe to repeat, and `undo` must tolerate a
+/// step that only partly happened.
+pub trait StepExecutor {
+    /// Step names, in execution order
+    fn steps(&self) -> Vec<&'static str>;
+
+    fn run(&mut self, step: &str) -> Result<()>;
+
+    fn undo(&mut self, step: &str) -> Result<()>;
+}
+
+/// Runs operations through the journal
+pub struct Journal<'a> {
+    db: &'a Database,
+}
+
+impl<'a> Journal<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Self { db }
+    }
+
+    /// Run every step of `op`. On failure, roll-back operations undo what they
+    /// did; roll-forward operations are left Failed for a retry.
+    pub fn run(&self, op: &mut Operation, exec: &mut dyn StepExecutor) -> Result<()> {
+        self.db.insert_operation(op)?;
+        self.run_steps(op, exec)
+    }
+
+    /// Finish an operation left Running by a crash
+    pub fn recover(&self, op: &mut Operation, exec: &mut dyn StepExecutor) -> Result<OperationStatus> {
+        let all_done = exec.steps().iter().all(|s| op.is_done(s));
+        if all_done {
+            self.finish(op, OperationStatus::Completed, None)?;
+            return Ok(op.status);
+        }
+
+        match op.kind.recovery() {
+            Recovery::RollBack => {
+                let error = op.error.clone().unwrap_or_else(|| "interrupted".to_string());
+                self.roll_back(op, exec, error)?;
+            }
+            Recovery::RollForward => {
+                // Status is recorded by run_steps either way
+                let _ = self.run_steps(op, exec);
+            }
+        }
+        Ok(op.status)
+    }
+
+    /// Operations that were never finished
+    pub fn pending(&self) -> Result<Vec<Operation>> {
+        self.db.list_operations(Some(OperationStatus::Running))
+    }
+
+    fn run_steps(&self, op: &mut Operation, exec: &mut dyn StepExecutor) -> Result<()> {
+        for step in exec.steps() {
+            if op.is_done(step) {
+                continue;
+            }
+
+            self.start_step(op, step)?;
+            if let Err(e) = exec.run(step) {
+                tracing::warn!(op = %op.id, kind = %op.kind, vm = %op.vm_name, step, error = %e, "Operation step failed");
+                match op.kind.recovery() {
+                    Recovery::RollBack => self.roll_back(op, exec, e.to_string())?,
+                    Recovery::RollForward => self.finish(op, OperationStatus::Failed, Some(e.to_string()))?,
+                }
+                return Err(e);
+            }
+            self.complete_step(op, step)?;
+        }
+
+        self.finish(op, OperationStatus::Completed, None)
+    }
+
+    /// Undo the interrupted step (it may have partly happened), then completed steps in reverse
+    fn roll_back(&self, op: &mut Operation, exec: &mut dyn StepExecutor, error: String) -> Result<()> {
+        let mut to_undo: Vec<String> = op.completed_steps.clone();
+        to_undo.extend(op.current_step.clone());
+
+        for step in to_undo.iter().rev() {
+            if let Err(e) = exec.undo(step) {
+                tracing::error!(op = %op.id, vm = %op.vm_name, step = %step, error = %e, "Rollback step failed");
+                return self.finish(op, OperationStatus::Failed, Some(format!("{}; rollback of {} failed: {}", error, step, e)));
+            }
+            op.completed_steps.retain(|s| s != step);
+            op.current_step = None;
+            self.save(op)?;
+        }
+
+        self.finish(op, OperationStatus::RolledBack, Some(error))
+    }
+
+    fn start_step(&self, op: &mut Operation, step: &str) -> Result<()> {
+        op.current_step = Some(step.to_string());
+        self.save(op)
+    }
+
+    fn complete_step(&self, op: &mut Operation, step: &str) -> Result<()> {
+        op.completed_steps.push(step.to_string());
+        op.current_step = None;
+        self.save(op)
+    }
+
+    fn finish(&self, op: &mut Operation, status: OperationStatus, error: Option<String>) -> Result<()> {
+        op.status = status;
+        op.error = error;
+        op.current_step = None;
+        self.save(op)
+    }
+
+    fn save(&self, op: &mut Operation) -> Result<()> {
+        op.updated_at = Utc::now();
+        self.db.update_operation(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    const STEPS: [&str; 4] = ["create_disk", "create_vm", "configure", "insert_record"];
+
+    /// Records what ran and can fail (or "crash") at a given step
+    #[derive(Default)]
+    struct FakeSteps {
+        log: Vec<String>,
+        fail_at: Option<&'static str>,
+        fail_undo_at: Option<&'static str>,
+    }
+
+    impl StepExecutor for FakeSteps {
+        fn steps(&self) -> Vec<&'static str> {
+            STEPS.to_vec()
+        }
+
+        fn run(&mut self, step: &str) -> Result<()> {
+            self.log.push(format!("run {}", step));
+            if self.fail_at == Some(step) {
+                return Err(Error::PowerShell(format!("{} failed", step)));
+            }
+            Ok(())
+        }
+
+        fn undo(&mut self, step: &str) -> Result<()> {
+            self.log.push(format!("undo {}", step));
+            if self.fail_undo_at == Some(step) {
+                return Err(Error::PowerShell(format!("undo {} failed", step)));
+            }
+            Ok(())
+        }
+    }
+
+    /// Journal the first `k` steps and the intent of step `k`, then stop as a crash would
+    fn crash_before_finishing(db: &Database, kind: OperationKind, k: usize) -> Operation {
+        let journal = Journal::new(db);
+        let mut op = Operation::new(kind, "agents-0", serde_json::Value::Null);
+        db.insert_operation(&op).unwrap();
+        for step in &STEPS[..k] {
+            journal.start_step(&mut op, step).unwrap();
+            journal.complete_step(&mut op, step).unwrap();
+        }
+        if k < STEPS.len() {
+            journal.start_step(&mut op, STEPS[k]).unwrap();
+        }
+        op
+    }
+
+    #[test]
+    fn test_run_completes() {
+        let db = Database::in_memory().unwrap();
+        let mut exec = FakeSteps::default();
+        let mut op = Operation::new(OperationKind::Provision, "agents-0", serde_json::Value::Null);
+
+        Journal::new(&db).run(&mut op, &mut exec).unwrap();
+        let stored = db.get_operation(&op.id).unwrap().unwrap();
+        assert_eq!(stored.status, OperationStatus::Completed);
+        assert_eq!(stored.completed_steps, STEPS);
+        assert!(Journal::new(&db).pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failure_at_each_step_rolls_back() {
+        for (k, step) in STEPS.iter().enumerate() {
+            let db = Database::in_memory().unwrap();
+            let mut exec = FakeSteps { fail_at: Some(step), ..Default::default() };
+            let mut op = Operation::new(OperationKind::Provision, "agents-0", serde_json::Value::Null);
+
+            assert!(Journal::new(&db).run(&mut op, &mut exec).is_err());
+
+            let mut expected: Vec<String> = STEPS[..=k].iter().map(|s| format!("run {}", s)).collect();
+            expected.extend(STEPS[..=k].iter().rev().map(|s| format!("undo {}", s)));
+            assert_eq!(exec.log, expected, "failing at {}", step);
+
+            let stored = db.get_operation(&op.id).unwrap().unwrap();
+            assert_eq!(stored.status, OperationStatus::RolledBack);
+            assert!(stored.completed_steps.is_empty());
+            assert!(stored.error.unwrap().contains(step));
+        }
+    }
+
+    #[test]
+    fn test_failure_in_roll_forward_kind_is_left_failed() {
+        let db = Database::in_memory().unwrap();
+        let mut exec = FakeSteps { fail_at: Some("configure"), ..Default::default() };
+        let mut op = Operation::new(OperationKind::Delete, "agents-0", serde_json::Value::Null);
+
+        assert!(Journal::new(&db).run(&mut op, &mut exec).is_err());
+        assert!(!exec.log.iter().any(|l| l.starts_with("undo")));
+        let stored = db.get_operation(&op.id).unwrap().unwrap();
+        assert_eq!(stored.status, OperationStatus::Failed);
+        assert_eq!(stored.completed_steps, ["create_disk", "create_vm"]);
+    }
+
+    #[test]
+    fn test_failed_rollback_is_recorded() {
+        let db = Database::in_memory().unwrap();
+        let mut exec = FakeSteps { fail_at: Some("configure"), fail_undo_at: Some("create_vm"), ..Default::default() };
+        let mut op = Operation::new(OperationKind::Provision, "agents-0", serde_json::Value::Null);
+
+        assert!(Journal::new(&db).run(&mut op, &mut exec).is_err());
+        let stored = db.get_operation(&op.id).unwrap().unwrap();
+        assert_eq!(stored.status, OperationStatus::Failed);
+        assert_eq!(stored.completed_steps, ["create_disk", "create_vm"]);
+        assert!(stored.error.unwrap().contains("rollback of create_vm failed"));
+    }
+
+    #[test]
+    fn test_crash_at_each_step_rolls_back_on_recovery() {
+        for k in 0..STEPS.len() {
+            let db = Database::in_memory().unwrap();
+            let op = crash_before_finishing(&db, OperationKind::Provision, k);
+
+            let journal = Journal::new(&db);
+            let mut pending = journal.pending().unwrap();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].id, op.id);
+
+            let mut exec = FakeSteps::default();
+            let status = journal.recover(&mut pending[0], &mut exec).unwrap();
+            assert_eq!(status, OperationStatus::RolledBack);
+
+            // The interrupted step is undone too, since it may have partly happened
+            let expected: Vec<String> = STEPS[..=k].iter().rev().map(|s| format!("undo {}", s)).collect();
+            assert_eq!(exec.log, expected, "crash at {}", STEPS[k]);
+            assert!(journal.pending().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_crash_at_each_step_rolls_forward_on_recovery() {
+        for k in 0..STEPS.len() {
+            let db = Database::in_memory().unwrap();
+            crash_before_finishing(&db, OperationKind::Delete, k);
+
+            let journal = Journal::new(&db);
+            let mut op = journal.pending().unwrap().remove(0);
+            let mut exec = FakeSteps::default();
+            assert_eq!(journal.recover(&mut op, &mut exec).unwrap(), OperationStatus::Completed);
+
+            let expected: Vec<String> = STEPS[k..].iter().map(|s| format!("run {}", s)).collect();
+            assert_eq!(exec.log, expected, "crash at {}", STEPS[k]);
+            assert_eq!(db.get_operation(&op.id).unwrap().unwrap().completed_steps, STEPS);
+        }
+    }
+
+    #[test]
+    fn test_crash_after_last_step_completes_on_recovery() {
+        let db = Database::in_memory().unwrap();
+        crash_before_finishing(&db, OperationKind::Provision, STEPS.len());
+
+        let journal = Journal::new(&db);
+        let mut op = journal.pending().unwrap().remove(0);
+        let mut exec = FakeSteps::default();
+        assert_eq!(journal.recover(&mut op, &mut exec).unwrap(), OperationStatus::Completed);
+        assert!(exec.log.is_empty());
+    }
+
+    #[test]
+    fn test_failed_roll_forward_stays_failed() {
+        let db = Database::in_memory().unwrap();
+        crash_before_finishing(&db, OperationKind::Reset, 1);
+
+        let journal = Journal::new(&db);
+        let mut op = journal.pending().unwrap().remove(0);
+        let mut exec = FakeSteps { fail_at: Some("configure"), ..Default::default() };
+        assert_eq!(journal.recover(&mut op, &mut exec).unwrap(), OperationStatus::Failed);
+        assert!(journal.pending().unwrap().is_empty());
+    }
+}
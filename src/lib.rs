@@ -41,11 +41,19 @@
 //! ```
 
 pub mod api;
+pub mod checksum;
 pub mod db;
 pub mod error;
 pub mod hyperv;
+pub mod journal;
 pub mod models;
+pub mod monitor;
 pub mod orchestrator;
+pub mod reconcile;
+pub mod recorder;
+pub mod runtime;
+pub mod secret;
+pub mod vhdx;
 
 pub use api::Server;
 pub use error::{Error, Result};
@@ -3,6 +3,8 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::ScreenTimeline;
+
 /// Status of an agent/task
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AgentStatus {
@@ -136,6 +138,9 @@ pub struct AgentResult {
     pub screenshots: Vec<String>,
     /// Duration in seconds
     pub duration_seconds: u64,
+    /// Host-side console recording of the lease
+    #[serde(default)]
+    pub timeline: Option<ScreenTimeline>,
 }
 
 #[cfg(test)]
@@ -198,6 +203,7 @@ mod tests {
             output: serde_json::json!({"status": "done"}),
             screenshots: vec!["s1.png".into(), "s2.png".into()],
             duration_seconds: 45,
+            timeline: None,
         };
         
         assert!(result.success);
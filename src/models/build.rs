@@ -0,0 +1,220 @@
+//! Template build model - baking a golden image from a base VHDX and a list of steps
+
+use super::{GuestCredential, TemplateVersion};
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Sysprep in the background; the guest shuts itself down when done
+pub const SYSPREP_SCRIPT: &str = r#"Start-Process -FilePath "$env:WINDIR\System32\Sysprep\sysprep.exe" -ArgumentList '/generalize', '/oobe', '/shutdown', '/quiet'"#;
+
+/// A `build.yaml`: how to turn a base image into a new template version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildSpec {
+    /// Template the result becomes a version of (registered if it doesn't exist)
+    pub template: String,
+    /// Image the build VM boots from; it is never written to
+    pub base: PathBuf,
+    /// Merged VHDX; defaults to `<template>-v<N>.vhdx` next to the base
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    #[serde(default = "default_memory")]
+    pub memory_mb: u64,
+    #[serde(default = "default_cpus")]
+    pub cpu_count: u32,
+    /// Guest login for PowerShell Direct; defaults to the template's
+    #[serde(default)]
+    pub guest_user: Option<String>,
+    #[serde(default)]
+    pub guest_password: Option<String>,
+    /// Run sysprep /generalize instead of a plain shutdown
+    #[serde(default)]
+    pub generalize: bool,
+    /// Make the new version current once registered
+    #[serde(default)]
+    pub promote: bool,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Limit for each step, boot and shutdown
+    #[serde(default = "default_step_timeout")]
+    pub step_timeout_secs: u64,
+    /// Written as `- script: ...` rather than YAML tags
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub steps: Vec<BuildStep>,
+}
+
+fn default_memory() -> u64 { 4096 }
+fn default_cpus() -> u32 { 2 }
+fn default_step_timeout() -> u64 { 1800 }
+
+/// One step of a build, run in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStep {
+    /// PowerShell run in the guest; a non-zero exit fails the build
+    Script(String),
+    /// Host file copied into the guest
+    Copy { from: PathBuf, to: String },
+    /// Shut the guest down and boot it again
+    Reboot,
+    /// Turn the Windows Update service on or off
+    WindowsUpdate(bool),
+}
+
+impl BuildStep {
+    /// PowerShell this step runs in the guest, if any
+    pub fn guest_script(&self) -> Option<String> {
+        match self {
+            BuildStep::Script(script) => Some(script.clone()),
+            BuildStep::WindowsUpdate(true) => Some(
+                "Set-Service -Name wuauserv -StartupType Manual; Start-Service -Name wuauserv".to_string(),
+            ),
+            BuildStep::WindowsUpdate(false) => Some(
+                "Stop-Service -Name wuauserv -Force; Set-Service -Name wuauserv -StartupType Disabled".to_string(),
+            ),
+            BuildStep::Copy { .. } | BuildStep::Reboot => None,
+        }
+    }
+}
+
+impl std::fmt::Display for BuildStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildStep::Script(script) => {
+                let first = script.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim();
+                write!(f, "script: {}", first)
+            }
+            BuildStep::Copy { from, to } => write!(f, "copy {} -> {}", from.display(), to),
+            BuildStep::Reboot => write!(f, "reboot"),
+            BuildStep::WindowsUpdate(true) => write!(f, "enable Windows Update"),
+            BuildStep::WindowsUpdate(false) => write!(f, "disable Windows Update"),
+        }
+    }
+}
+
+impl BuildSpec {
+    pub fn parse(yaml: &str) -> Result<Self> {
+        let spec: Self = serde_yaml::from_str(yaml).map_err(|e| Error::Parse(format!("build spec: {}", e)))?;
+        if spec.template.trim().is_empty() {
+            return Err(Error::Parse("build spec: template is empty".to_string()));
+        }
+        Ok(spec)
+    }
+
+    /// Read a spec; relative paths in it are relative to the file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut spec = Self::parse(&std::fs::read_to_string(path)?)?;
+        if let Some(dir) = path.parent() {
+            spec.resolve_paths(dir);
+        }
+        Ok(spec)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |p: &mut PathBuf| {
+            if p.is_relative() {
+                *p = dir.join(&*p);
+            }
+        };
+        resolve(&mut self.base);
+        if let Some(output) = &mut self.output {
+            resolve(output);
+        }
+        for step in &mut self.steps {
+            if let BuildStep::Copy { from, .. } = step {
+                resolve(from);
+            }
+        }
+    }
+
+    pub fn credential(&self) -> Option<GuestCredential> {
+        self.guest_user.as_ref()
+            .map(|u| GuestCredential::new(u, self.guest_password.clone().unwrap_or_default()))
+    }
+
+    /// Whether any part of the build runs inside the guest
+    pub fn needs_guest_session(&self) -> bool {
+        self.generalize || self.steps.iter().any(|s| s.guest_script().is_some())
+    }
+
+    /// Where the merged VHDX goes when it becomes `version`
+    pub fn output_path(&self, version: u32) -> PathBuf {
+        self.output.clone().unwrap_or_else(|| {
+            self.base.with_file_name(format!("{}-v{}.vhdx", self.template, version))
+        })
+    }
+
+    pub fn step_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.step_timeout_secs)
+    }
+}
+
+/// Outcome of a successful build
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildResult {
+    pub template: String,
+    pub version: TemplateVersion,
+    pub promoted: bool,
+}
+
+/// Build log next to the output VHDX
+pub fn build_log_path(output: &Path) -> PathBuf {
+    output.with_extension("build.log")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+template: win11-dev
+base: base/win11.vhdx
+memory_mb: 8192
+guest_user: Administrator
+guest_password: s3cret
+generalize: true
+steps:
+  - windows_update: false
+  - copy:
+      from: files/setup.exe
+      to: C:\Temp\setup.exe
+  - script: |
+      C:\Temp\setup.exe /quiet
+      Remove-Item C:\Temp\setup.exe
+  - reboot
+"#;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = BuildSpec::parse(SPEC).unwrap();
+        assert_eq!(spec.template, "win11-dev");
+        assert_eq!(spec.memory_mb, 8192);
+        assert_eq!(spec.cpu_count, 2);
+        assert_eq!(spec.steps.len(), 4);
+        assert_eq!(spec.steps[0], BuildStep::WindowsUpdate(false));
+        assert_eq!(spec.steps[3], BuildStep::Reboot);
+        assert_eq!(spec.steps[2].to_string(), r"script: C:\Temp\setup.exe /quiet");
+        assert!(spec.needs_guest_session());
+        assert_eq!(spec.credential().unwrap().username, "Administrator");
+        assert_eq!(spec.output_path(3), PathBuf::from("base/win11-dev-v3.vhdx"));
+
+        assert!(BuildSpec::parse("template: x\nbase: b.vhdx\nsteps: [{ shutdown: true }]").is_err());
+        assert!(BuildSpec::parse("template: ''\nbase: b.vhdx").is_err());
+        let bare = BuildSpec::parse("template: x\nbase: b.vhdx\nsteps: [reboot]").unwrap();
+        assert!(!bare.needs_guest_session());
+    }
+
+    #[test]
+    fn test_spec_paths_relative_to_file() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("build.yaml");
+        std::fs::write(&path, SPEC).unwrap();
+
+        let spec = BuildSpec::from_file(&path).unwrap();
+        assert_eq!(spec.base, tmp.path().join("base/win11.vhdx"));
+        assert!(matches!(&spec.steps[1], BuildStep::Copy { from, .. } if *from == tmp.path().join("files/setup.exe")));
+        assert_eq!(build_log_path(&spec.output_path(2)), tmp.path().join("base/win11-dev-v2.build.log"));
+    }
+}
@@ -0,0 +1,50 @@
+//! Template capture model - turning an existing VM's disk into a template
+
+use std::path::PathBuf;
+
+/// How to capture a VM as a template
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureOptions {
+    /// Merge a copy of the active disk and leave the VM as it is; the VM must
+    /// already be Off (a Saved VM's disk is only crash-consistent). Otherwise
+    /// the VM is shut down first.
+    pub keep_source: bool,
+    /// Merged VHDX; defaults to `<name>.vhdx` next to the VM's template
+    pub output: Option<PathBuf>,
+    pub notes: Option<String>,
+}
+
+impl CaptureOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keep_source(mut self) -> Self {
+        self.keep_source = true;
+        self
+    }
+
+    pub fn with_output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output = Some(path.into());
+        self
+    }
+
+    pub fn with_notes(mut self, notes: impl Into<String>) -> Self {
+        self.notes = Some(notes.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_options_builder() {
+        let options = CaptureOptions::new().keep_source().with_output(r"C:\Templates\custom.vhdx").with_notes("tuned");
+        assert!(options.keep_source);
+        assert_eq!(options.output, Some(PathBuf::from(r"C:\Templates\custom.vhdx")));
+        assert_eq!(options.notes.as_deref(), Some("tuned"));
+        assert!(!CaptureOptions::new().keep_source);
+    }
+}
@@ -0,0 +1,163 @@
+//! Drift model - differences between the database, Hyper-V and the disk
+
+use super::VMState;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// One difference found by the reconciler
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    /// DB state differs from Hyper-V
+    StateMismatch {
+        vm_name: String,
+        db_state: VMState,
+        actual_state: VMState,
+    },
+    /// DB row with no Hyper-V VM behind it
+    MissingFromHyperV { vm_name: String },
+    /// Hyper-V VM named like one of our pools but not in the DB
+    UnknownVM {
+        vm_name: String,
+        pool_name: String,
+        vhdx_path: Option<PathBuf>,
+    },
+    /// Directory under the VM storage path that belongs to no VM
+    OrphanDirectory { path: PathBuf },
+    /// Differencing disk whose parent is missing or isn't the template VHDX
+    BrokenParent {
+        vm_name: String,
+        vhdx_path: PathBuf,
+        expected_parent: Option<PathBuf>,
+        actual_parent: Option<PathBuf>,
+    },
+    /// Parent locator chain that can't be followed to a base disk
+    BrokenChain {
+        vm_name: String,
+        vhdx_path: PathBuf,
+        problem: String,
+    },
+    /// Prepared VM without its `clean` checkpoint (reset will fail)
+    MissingCheckpoint { vm_name: String, checkpoint: String },
+    /// Recorded IP differs from what Hyper-V reports
+    IpMismatch {
+        vm_name: String,
+        db_ip: Option<String>,
+        actual_ip: Option<String>,
+    },
+}
+
+impl Drift {
+    pub fn vm_name(&self) -> Option<&str> {
+        match self {
+            Drift::StateMismatch { vm_name, .. }
+            | Drift::MissingFromHyperV { vm_name }
+            | Drift::UnknownVM { vm_name, .. }
+            | Drift::BrokenParent { vm_name, .. }
+            | Drift::BrokenChain { vm_name, .. }
+            | Drift::MissingCheckpoint { vm_name, .. }
+            | Drift::IpMismatch { vm_name, .. } => Some(vm_name),
+            Drift::OrphanDirectory { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let opt = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string()).unwrap_or_else(|| "none".to_string());
+        match self {
+            Drift::StateMismatch { vm_name, db_state, actual_state } => {
+                write!(f, "{}: DB says {}, Hyper-V says {}", vm_name, db_state, actual_state)
+            }
+            Drift::MissingFromHyperV { vm_name } => write!(f, "{}: not found in Hyper-V", vm_name),
+            Drift::UnknownVM { vm_name, pool_name, .. } => {
+                write!(f, "{}: Hyper-V VM matches pool {} but is not in the DB", vm_name, pool_name)
+            }
+            Drift::OrphanDirectory { path } => write!(f, "{}: directory has no VM", path.display()),
+            Drift::BrokenParent { vm_name, expected_parent, actual_parent, .. } => write!(
+                f,
+                "{}: disk parent is {}, expected {}",
+                vm_name,
+                opt(actual_parent),
+                opt(expected_parent)
+            ),
+            Drift::BrokenChain { vm_name, problem, .. } => write!(f, "{}: broken disk chain: {}", vm_name, problem),
+            Drift::MissingCheckpoint { vm_name, checkpoint } => {
+                write!(f, "{}: missing '{}' checkpoint", vm_name, checkpoint)
+            }
+            Drift::IpMismatch { vm_name, db_ip, actual_ip } => write!(
+                f,
+                "{}: DB IP {}, Hyper-V IP {}",
+                vm_name,
+                db_ip.as_deref().unwrap_or("none"),
+                actual_ip.as_deref().unwrap_or("none")
+            ),
+        }
+    }
+}
+
+/// What the reconciler may change beyond syncing DB state
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReconcileOptions {
+    /// Only report, change nothing
+    pub dry_run: bool,
+    /// Insert unknown pool-named Hyper-V VMs into the DB
+    pub adopt: bool,
+    /// Delete unknown VMs (unless adopted) and orphan directories
+    pub delete_orphans: bool,
+}
+
+/// Result of a reconcile pass
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub items: Vec<Drift>,
+    /// Changes made (empty on a dry run)
+    pub actions: Vec<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl DriftReport {
+    pub fn new(items: Vec<Drift>) -> Self {
+        Self {
+            items,
+            actions: vec![],
+            checked_at: Utc::now(),
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift_display() {
+        let d = Drift::StateMismatch {
+            vm_name: "agents-0".to_string(),
+            db_state: VMState::Saved,
+            actual_state: VMState::Off,
+        };
+        assert_eq!(d.to_string(), "agents-0: DB says Saved, Hyper-V says Off");
+        assert_eq!(d.vm_name(), Some("agents-0"));
+
+        let d = Drift::OrphanDirectory { path: PathBuf::from("agents-9") };
+        assert!(d.vm_name().is_none());
+        assert!(d.to_string().contains("has no VM"));
+    }
+
+    #[test]
+    fn test_drift_serialization() {
+        let d = Drift::MissingCheckpoint {
+            vm_name: "agents-1".to_string(),
+            checkpoint: "clean".to_string(),
+        };
+        let json = serde_json::to_string(&d).unwrap();
+        assert!(json.contains(r#""kind":"missing_checkpoint""#));
+        assert_eq!(serde_json::from_str::<Drift>(&json).unwrap(), d);
+    }
+}
@@ -0,0 +1,160 @@
+//! Event model - notable things the orchestrator did or observed
+
+use super::{VMPool, VM};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Kind of event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    /// Health checks started failing
+    VMUnhealthy,
+    /// Health checks pass again
+    VMRecovered,
+    /// Unhealthy VM was reset to its clean checkpoint
+    VMReset,
+    /// Unhealthy VM was replaced
+    VMRebuilt,
+    /// Automatic remediation failed
+    RemediationFailed,
+    /// Running VM saved or reset after sitting idle
+    VMIdleReclaimed,
+    /// Reconciler found a difference between DB, Hyper-V and disk
+    DriftDetected,
+    /// A job was cancelled and its VM turned off for recycling
+    OperationCancelled,
+    /// A lease survived a server restart and is tracked again
+    LeaseReattached,
+    /// A leased VM was no longer running after a server restart
+    LeaseLost,
+    /// An ephemeral VM outlived its TTL and was destroyed
+    EphemeralExpired,
+    /// A VM's disk grew past its pool's limit
+    DiskLimitExceeded,
+    /// A rolling upgrade stopped because a new VM failed
+    UpgradePaused,
+    /// A template version had no checksum, so its current hash was recorded unverified
+    TemplateChecksumRecorded,
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "VMUnhealthy" => Ok(EventKind::VMUnhealthy),
+            "VMRecovered" => Ok(EventKind::VMRecovered),
+            "VMReset" => Ok(EventKind::VMReset),
+            "VMRebuilt" => Ok(EventKind::VMRebuilt),
+            "RemediationFailed" => Ok(EventKind::RemediationFailed),
+            "VMIdleReclaimed" => Ok(EventKind::VMIdleReclaimed),
+            "DriftDetected" => Ok(EventKind::DriftDetected),
+            "OperationCancelled" => Ok(EventKind::OperationCancelled),
+            "LeaseReattached" => Ok(EventKind::LeaseReattached),
+            "LeaseLost" => Ok(EventKind::LeaseLost),
+            "EphemeralExpired" => Ok(EventKind::EphemeralExpired),
+            "DiskLimitExceeded" => Ok(EventKind::DiskLimitExceeded),
+            "UpgradePaused" => Ok(EventKind::UpgradePaused),
+            "TemplateChecksumRecorded" => Ok(EventKind::TemplateChecksumRecorded),
+            _ => Err(format!("Unknown event kind: {}", s)),
+        }
+    }
+}
+
+/// An entry in the event log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Sequence number (assigned by the database)
+    pub id: i64,
+    pub kind: EventKind,
+    pub vm_id: Option<String>,
+    pub vm_name: Option<String>,
+    pub pool_id: Option<String>,
+    /// Agent holding the VM when the event happened
+    pub agent_id: Option<String>,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, message: impl Into<String>) -> Self {
+        Self {
+            id: 0,
+            kind,
+            vm_id: None,
+            vm_name: None,
+            pool_id: None,
+            agent_id: None,
+            message: message.into(),
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Attach VM, pool and lease holder details
+    pub fn for_vm(mut self, vm: &VM) -> Self {
+        self.vm_id = Some(vm.id.clone());
+        self.vm_name = Some(vm.name.clone());
+        self.pool_id = vm.pool_id.clone();
+        self.agent_id = vm.current_agent_id.clone();
+        self
+    }
+
+    pub fn for_pool(mut self, pool: &VMPool) -> Self {
+        self.pool_id = Some(pool.id.clone());
+        self
+    }
+}
+
+/// Filter for listing events
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub vm_name: Option<String>,
+    pub pool_id: Option<String>,
+    pub kind: Option<EventKind>,
+    /// Only events with a larger id
+    pub since_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_event_for_vm() {
+        let mut vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\a.vhdx"), 4096, 2);
+        vm.pool_id = Some("pool-1".to_string());
+        vm.current_agent_id = Some("agent-1".to_string());
+
+        let event = Event::new(EventKind::VMUnhealthy, "heartbeat lost").for_vm(&vm);
+        assert_eq!(event.vm_name.as_deref(), Some("agents-0"));
+        assert_eq!(event.pool_id.as_deref(), Some("pool-1"));
+        assert_eq!(event.agent_id.as_deref(), Some("agent-1"));
+        assert_eq!(event.id, 0);
+    }
+
+    #[test]
+    fn test_event_kind_roundtrip() {
+        for kind in [
+            EventKind::VMUnhealthy,
+            EventKind::VMRecovered,
+            EventKind::VMReset,
+            EventKind::VMRebuilt,
+            EventKind::RemediationFailed,
+            EventKind::VMIdleReclaimed,
+            EventKind::DriftDetected,
+            EventKind::UpgradePaused,
+            EventKind::TemplateChecksumRecorded,
+        ] {
+            assert_eq!(kind.to_string().parse::<EventKind>().unwrap(), kind);
+        }
+        assert!("Bogus".parse::<EventKind>().is_err());
+    }
+}
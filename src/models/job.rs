@@ -0,0 +1,114 @@
+//! Job model - long-running operations that can be cancelled
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Kind of cancellable operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobKind {
+    Provision,
+    Prepare,
+    Resume,
+    Reset,
+    /// Script run inside the guest
+    Exec,
+    /// Rolling replacement of a pool's VMs
+    Upgrade,
+    /// Baking a template version from a build spec
+    Build,
+    /// Merging a VM's disk into a new template
+    Capture,
+    /// File copy into or out of a guest
+    Transfer,
+}
+
+impl std::fmt::Display for JobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Job status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A long-running operation, tracked in memory while the server runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    /// VM or pool name
+    pub target: String,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    pub fn new(kind: JobKind, target: impl Into<String>) -> Self {
+        Self {
+            id: format!("job-{}", Uuid::new_v4()),
+            kind,
+            target: target.into(),
+            status: JobStatus::Running,
+            error: None,
+            started_at: Utc::now(),
+            finished_at: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.status != JobStatus::Running
+    }
+
+    /// Record how the job ended. Any failure after cancellation was requested
+    /// counts as Cancelled, since it is usually the cancellation surfacing.
+    pub fn finish<T>(&mut self, result: &crate::Result<T>, cancelled: bool) {
+        self.status = match result {
+            Ok(_) => JobStatus::Completed,
+            Err(crate::Error::Cancelled) => JobStatus::Cancelled,
+            Err(_) if cancelled => JobStatus::Cancelled,
+            Err(_) => JobStatus::Failed,
+        };
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        self.finished_at = Some(Utc::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_finish() {
+        let mut job = Job::new(JobKind::Prepare, "agents-0");
+        assert!(!job.is_finished());
+
+        job.finish(&Ok(()), false);
+        assert_eq!(job.status, JobStatus::Completed);
+        assert!(job.finished_at.is_some());
+
+        let mut job = Job::new(JobKind::Resume, "agents-0");
+        job.finish::<()>(&Err(crate::Error::Timeout), false);
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("Timeout waiting for VM"));
+
+        // An error racing the cancellation still counts as cancelled
+        let mut job = Job::new(JobKind::Exec, "agents-0");
+        job.finish::<()>(&Err(crate::Error::Timeout), true);
+        assert_eq!(job.status, JobStatus::Cancelled);
+    }
+}
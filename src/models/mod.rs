@@ -4,8 +4,30 @@ mod vm;
 mod pool;
 mod template;
 mod agent;
+mod transfer;
+mod recording;
+mod probe;
+mod event;
+mod drift;
+mod operation;
+mod provision;
+mod job;
+mod upgrade;
+mod build;
+mod capture;
 
 pub use vm::*;
 pub use pool::*;
 pub use template::*;
 pub use agent::*;
+pub use transfer::*;
+pub use recording::*;
+pub use probe::*;
+pub use event::*;
+pub use drift::*;
+pub use operation::*;
+pub use provision::*;
+pub use job::*;
+pub use upgrade::*;
+pub use build::*;
+pub use capture::*;
@@ -0,0 +1,160 @@
+//! Operation model - journal entries for multi-step VM operations
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Kind of journaled operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OperationKind {
+    Provision,
+    Delete,
+    Reset,
+    Prepare,
+    /// A template build VM
+    Build,
+}
+
+impl OperationKind {
+    /// What to do with an operation interrupted by a crash
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            // Half-built VMs are worth less than a clean slate
+            OperationKind::Provision | OperationKind::Prepare | OperationKind::Build => Recovery::RollBack,
+            // The caller already asked for the VM to go away / be clean
+            OperationKind::Delete | OperationKind::Reset => Recovery::RollForward,
+        }
+    }
+}
+
+impl std::fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::str::FromStr for OperationKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Provision" => Ok(OperationKind::Provision),
+            "Delete" => Ok(OperationKind::Delete),
+            "Reset" => Ok(OperationKind::Reset),
+            "Prepare" => Ok(OperationKind::Prepare),
+            "Build" => Ok(OperationKind::Build),
+            _ => Err(format!("Unknown operation kind: {}", s)),
+        }
+    }
+}
+
+/// How an interrupted operation is finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Undo completed steps in reverse order
+    RollBack,
+    /// Re-run the interrupted step and everything after it
+    RollForward,
+}
+
+/// Operation status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OperationStatus {
+    /// In progress, or interrupted if nothing is running it
+    Running,
+    Completed,
+    RolledBack,
+    /// Stopped with an error and could not be undone
+    Failed,
+}
+
+impl std::fmt::Display for OperationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::str::FromStr for OperationStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Running" => Ok(OperationStatus::Running),
+            "Completed" => Ok(OperationStatus::Completed),
+            "RolledBack" => Ok(OperationStatus::RolledBack),
+            "Failed" => Ok(OperationStatus::Failed),
+            _ => Err(format!("Unknown operation status: {}", s)),
+        }
+    }
+}
+
+/// A journaled multi-step operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: String,
+    pub kind: OperationKind,
+    pub vm_id: Option<String>,
+    pub vm_name: String,
+    /// Everything needed to resume or undo the operation
+    pub params: serde_json::Value,
+    /// Steps that finished, in order
+    pub completed_steps: Vec<String>,
+    /// Step that was started but not finished (the intent)
+    pub current_step: Option<String>,
+    pub status: OperationStatus,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Operation {
+    pub fn new(kind: OperationKind, vm_name: impl Into<String>, params: serde_json::Value) -> Self {
+        let now = Utc::now();
+        Self {
+            id: format!("op-{}", Uuid::new_v4()),
+            kind,
+            vm_id: None,
+            vm_name: vm_name.into(),
+            params,
+            completed_steps: vec![],
+            current_step: None,
+            status: OperationStatus::Running,
+            error: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn with_vm_id(mut self, vm_id: impl Into<String>) -> Self {
+        self.vm_id = Some(vm_id.into());
+        self
+    }
+
+    pub fn is_done(&self, step: &str) -> bool {
+        self.completed_steps.iter().any(|s| s == step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_kind_recovery() {
+        assert_eq!(OperationKind::Provision.recovery(), Recovery::RollBack);
+        assert_eq!(OperationKind::Prepare.recovery(), Recovery::RollBack);
+        assert_eq!(OperationKind::Build.recovery(), Recovery::RollBack);
+        assert_eq!(OperationKind::Delete.recovery(), Recovery::RollForward);
+        assert_eq!(OperationKind::Reset.recovery(), Recovery::RollForward);
+    }
+
+    #[test]
+    fn test_operation_roundtrip_strings() {
+        for kind in [OperationKind::Provision, OperationKind::Delete, OperationKind::Reset, OperationKind::Prepare, OperationKind::Build] {
+            assert_eq!(kind.to_string().parse::<OperationKind>().unwrap(), kind);
+        }
+        for status in [OperationStatus::Running, OperationStatus::Completed, OperationStatus::RolledBack, OperationStatus::Failed] {
+            assert_eq!(status.to_string().parse::<OperationStatus>().unwrap(), status);
+        }
+    }
+}
@@ -3,6 +3,74 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+/// What the health monitor does with an unhealthy VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemediationPolicy {
+    /// Mark it unhealthy and emit an event for the lease holder
+    #[default]
+    Notify,
+    /// Fail the lease, restore the clean checkpoint and re-prepare
+    Reset,
+    /// Fail the lease, delete the VM and provision a replacement
+    Rebuild,
+}
+
+impl std::fmt::Display for RemediationPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemediationPolicy::Notify => write!(f, "notify"),
+            RemediationPolicy::Reset => write!(f, "reset"),
+            RemediationPolicy::Rebuild => write!(f, "rebuild"),
+        }
+    }
+}
+
+impl std::str::FromStr for RemediationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "notify" => Ok(RemediationPolicy::Notify),
+            "reset" => Ok(RemediationPolicy::Reset),
+            "rebuild" => Ok(RemediationPolicy::Rebuild),
+            _ => Err(format!("Unknown remediation policy: {}", s)),
+        }
+    }
+}
+
+/// What idle reclamation does with a Running VM nobody is using
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    /// Save state so the VM can be resumed again
+    #[default]
+    Save,
+    /// Restore the clean checkpoint and re-prepare
+    Reset,
+}
+
+impl std::fmt::Display for IdleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdleAction::Save => write!(f, "save"),
+            IdleAction::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+impl std::str::FromStr for IdleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "save" => Ok(IdleAction::Save),
+            "reset" => Ok(IdleAction::Reset),
+            _ => Err(format!("Unknown idle action: {}", s)),
+        }
+    }
+}
+
 /// A pool of VMs from the same template
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VMPool {
@@ -18,6 +86,21 @@ pub struct VMPool {
     pub warm_count: usize,
     /// Maximum VMs per host
     pub max_per_host: usize,
+    /// Response to VMs failing health checks
+    #[serde(default)]
+    pub remediation: RemediationPolicy,
+    /// Reclaim Running VMs idle for this many seconds (None = never)
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// How idle VMs are reclaimed
+    #[serde(default)]
+    pub idle_action: IdleAction,
+    /// Largest a VM's own disk files may grow before it is rebuilt on release
+    #[serde(default)]
+    pub max_disk_bytes: Option<u64>,
+    /// Template version to provision from (None = the template's current version)
+    #[serde(default)]
+    pub template_version: Option<u32>,
     /// Creation time
     pub created_at: DateTime<Utc>,
 }
@@ -31,6 +114,11 @@ impl VMPool {
             desired_count: 3,
             warm_count: 1,
             max_per_host: 10,
+            remediation: RemediationPolicy::Notify,
+            idle_timeout_secs: None,
+            idle_action: IdleAction::Save,
+            max_disk_bytes: None,
+            template_version: None,
             created_at: Utc::now(),
         }
     }
@@ -49,6 +137,37 @@ impl VMPool {
         self.max_per_host = max;
         self
     }
+
+    pub fn with_remediation(mut self, policy: RemediationPolicy) -> Self {
+        self.remediation = policy;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, secs: u64, action: IdleAction) -> Self {
+        self.idle_timeout_secs = Some(secs);
+        self.idle_action = action;
+        self
+    }
+
+    pub fn with_disk_limit(mut self, max_bytes: u64) -> Self {
+        self.max_disk_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Pin the pool to one template version instead of following `latest`
+    pub fn with_template_version(mut self, version: u32) -> Self {
+        self.template_version = Some(version);
+        self
+    }
+
+    /// Whether a VM with `disk_bytes` of its own disk files is over the limit
+    pub fn disk_over_limit(&self, disk_bytes: u64) -> bool {
+        self.max_disk_bytes.is_some_and(|max| disk_bytes > max)
+    }
+
+    pub fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.idle_timeout_secs.map(std::time::Duration::from_secs)
+    }
 }
 
 /// Pool status summary
@@ -63,6 +182,16 @@ pub struct PoolStatus {
     pub saved_vms: usize,
     pub off_vms: usize,
     pub error_vms: usize,
+    pub unhealthy_vms: usize,
+    /// Running VMs with no lease holder
+    pub idle_vms: usize,
+    /// VMs reclaimed for idleness so far
+    pub reclaimed_vms: usize,
+    /// Total of the VMs' own disk files, as last measured
+    pub disk_bytes: u64,
+    pub saved_state_bytes: u64,
+    /// VMs whose disk is past `max_disk_bytes`
+    pub over_disk_limit_vms: usize,
 }
 
 #[cfg(test)]
@@ -78,6 +207,17 @@ mod tests {
         assert_eq!(p.desired_count, 3);
         assert_eq!(p.warm_count, 1);
         assert_eq!(p.max_per_host, 10);
+        assert_eq!(p.remediation, RemediationPolicy::Notify);
+        assert!(p.idle_timeout().is_none());
+    }
+
+    #[test]
+    fn test_pool_idle_timeout() {
+        let p = VMPool::new("agents", "tmpl-1").with_idle_timeout(900, IdleAction::Reset);
+        assert_eq!(p.idle_timeout(), Some(std::time::Duration::from_secs(900)));
+        assert_eq!(p.idle_action, IdleAction::Reset);
+        assert_eq!("SAVE".parse::<IdleAction>().unwrap(), IdleAction::Save);
+        assert!("hibernate".parse::<IdleAction>().is_err());
     }
 
     #[test]
@@ -92,6 +232,24 @@ mod tests {
         assert_eq!(p.max_per_host, 5);
     }
 
+    #[test]
+    fn test_pool_disk_limit() {
+        let p = VMPool::new("agents", "tmpl-1");
+        assert!(!p.disk_over_limit(u64::MAX));
+
+        let p = p.with_disk_limit(20 << 30);
+        assert!(!p.disk_over_limit(20 << 30));
+        assert!(p.disk_over_limit((20 << 30) + 1));
+    }
+
+    #[test]
+    fn test_remediation_policy_parse() {
+        assert_eq!("reset".parse::<RemediationPolicy>().unwrap(), RemediationPolicy::Reset);
+        assert_eq!("Rebuild".parse::<RemediationPolicy>().unwrap(), RemediationPolicy::Rebuild);
+        assert!("reboot".parse::<RemediationPolicy>().is_err());
+        assert_eq!(serde_json::to_string(&RemediationPolicy::Notify).unwrap(), "\"notify\"");
+    }
+
     #[test]
     fn test_pool_serialization() {
         let p = VMPool::new("test", "tmpl-1");
@@ -114,6 +272,12 @@ mod tests {
             saved_vms: 3,
             off_vms: 1,
             error_vms: 0,
+            unhealthy_vms: 0,
+            idle_vms: 0,
+            reclaimed_vms: 0,
+            disk_bytes: 0,
+            saved_state_bytes: 0,
+            over_disk_limit_vms: 0,
         };
         
         assert_eq!(status.running_vms + status.saved_vms + status.off_vms + status.error_vms, 5);
@@ -0,0 +1,198 @@
+//! Readiness probes - how to tell a guest is ready for work
+
+use serde::{Deserialize, Serialize};
+
+/// What a readiness probe checks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProbeKind {
+    /// Hyper-V heartbeat integration service reports OK
+    Heartbeat,
+    /// TCP connect to a guest port succeeds
+    Tcp { port: u16 },
+    /// HTTP GET returns the expected status (and body substring, if set)
+    Http {
+        port: u16,
+        path: String,
+        #[serde(default = "default_http_status")]
+        expected_status: u16,
+        #[serde(default)]
+        expected_body: Option<String>,
+    },
+    /// PowerShell Direct script exits with code 0
+    GuestScript { script: String },
+}
+
+fn default_http_status() -> u16 { 200 }
+
+/// A single step in a template's readiness check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadinessProbe {
+    #[serde(flatten)]
+    pub kind: ProbeKind,
+    /// How long to keep retrying this probe (bounded by the overall ready timeout if unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+impl ReadinessProbe {
+    pub fn new(kind: ProbeKind) -> Self {
+        Self {
+            kind,
+            timeout_secs: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, secs: u64) -> Self {
+        self.timeout_secs = Some(secs);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.timeout_secs.map(std::time::Duration::from_secs)
+    }
+
+    /// Probes used when a template doesn't declare any (RDP answering)
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::new(ProbeKind::Tcp { port: 3389 })]
+    }
+
+    /// Whether this probe talks to the guest over the network
+    pub fn needs_ip(&self) -> bool {
+        matches!(self.kind, ProbeKind::Tcp { .. } | ProbeKind::Http { .. })
+    }
+}
+
+impl std::fmt::Display for ReadinessProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ProbeKind::Heartbeat => write!(f, "heartbeat"),
+            ProbeKind::Tcp { port } => write!(f, "tcp:{}", port),
+            ProbeKind::Http { port, path, expected_status, expected_body } => {
+                write!(f, "http:{}{}={}", port, path, expected_status)?;
+                if let Some(body) = expected_body {
+                    write!(f, "~{}", body)?;
+                }
+                Ok(())
+            }
+            ProbeKind::GuestScript { script } => write!(f, "script:{}", script),
+        }
+    }
+}
+
+/// Parse the CLI form: `heartbeat`, `tcp:3389`, `http:8080/health[=200][~healthy]`,
+/// `script:<powershell>`, each optionally suffixed with `@<timeout secs>`
+impl std::str::FromStr for ReadinessProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, timeout) = match s.rsplit_once('@') {
+            Some((spec, secs)) if secs.parse::<u64>().is_ok() => (spec, secs.parse::<u64>().ok()),
+            _ => (s, None),
+        };
+
+        let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+        let kind = match kind {
+            "heartbeat" => ProbeKind::Heartbeat,
+            "tcp" => ProbeKind::Tcp {
+                port: arg.parse().map_err(|_| format!("Invalid TCP port: {}", arg))?,
+            },
+            "http" => {
+                let (target, expected_body) = match arg.split_once('~') {
+                    Some((t, body)) => (t, Some(body.to_string())),
+                    None => (arg, None),
+                };
+                let (target, expected_status) = match target.split_once('=') {
+                    Some((t, status)) => (t, status.parse().map_err(|_| format!("Invalid HTTP status: {}", status))?),
+                    None => (target, default_http_status()),
+                };
+                let (port, path) = match target.find('/') {
+                    Some(i) => (&target[..i], &target[i..]),
+                    None => (target, "/"),
+                };
+                ProbeKind::Http {
+                    port: port.parse().map_err(|_| format!("Invalid HTTP port: {}", port))?,
+                    path: path.to_string(),
+                    expected_status,
+                    expected_body,
+                }
+            }
+            "script" if !arg.is_empty() => ProbeKind::GuestScript { script: arg.to_string() },
+            _ => return Err(format!("Unknown probe: {}", s)),
+        };
+
+        let mut probe = ReadinessProbe::new(kind);
+        probe.timeout_secs = timeout;
+        Ok(probe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_defaults() {
+        let probes = ReadinessProbe::defaults();
+        assert_eq!(probes.len(), 1);
+        assert_eq!(probes[0].kind, ProbeKind::Tcp { port: 3389 });
+        assert!(probes[0].timeout().is_none());
+        assert!(probes[0].needs_ip());
+    }
+
+    #[test]
+    fn test_probe_parse() {
+        let p: ReadinessProbe = "heartbeat".parse().unwrap();
+        assert_eq!(p.kind, ProbeKind::Heartbeat);
+        assert!(!p.needs_ip());
+
+        let p: ReadinessProbe = "tcp:5985@30".parse().unwrap();
+        assert_eq!(p.kind, ProbeKind::Tcp { port: 5985 });
+        assert_eq!(p.timeout(), Some(std::time::Duration::from_secs(30)));
+
+        let p: ReadinessProbe = "http:9000/ready=204~ok".parse().unwrap();
+        assert_eq!(
+            p.kind,
+            ProbeKind::Http {
+                port: 9000,
+                path: "/ready".to_string(),
+                expected_status: 204,
+                expected_body: Some("ok".to_string()),
+            }
+        );
+
+        let p: ReadinessProbe = "http:8080".parse().unwrap();
+        assert!(matches!(p.kind, ProbeKind::Http { ref path, expected_status: 200, .. } if path == "/"));
+
+        let p: ReadinessProbe = "script:Get-Service sshd | Where Status -eq Running".parse().unwrap();
+        assert!(matches!(p.kind, ProbeKind::GuestScript { .. }));
+    }
+
+    #[test]
+    fn test_probe_parse_errors() {
+        assert!("tcp:abc".parse::<ReadinessProbe>().is_err());
+        assert!("ping".parse::<ReadinessProbe>().is_err());
+        assert!("script:".parse::<ReadinessProbe>().is_err());
+    }
+
+    #[test]
+    fn test_probe_display_roundtrip() {
+        for spec in ["heartbeat", "tcp:3389", "http:8080/health=200~healthy"] {
+            let p: ReadinessProbe = spec.parse().unwrap();
+            assert_eq!(p.to_string(), spec);
+        }
+    }
+
+    #[test]
+    fn test_probe_serialization() {
+        let json = r#"[{"type":"heartbeat"},{"type":"http","port":8080,"path":"/health","timeout_secs":30}]"#;
+        let probes: Vec<ReadinessProbe> = serde_json::from_str(json).unwrap();
+        assert!(probes[0].timeout().is_none());
+        assert_eq!(probes[1].timeout_secs, Some(30));
+        assert!(matches!(probes[1].kind, ProbeKind::Http { expected_status: 200, .. }));
+
+        let out = serde_json::to_string(&probes).unwrap();
+        assert!(out.contains(r#""type":"heartbeat""#));
+        assert!(!out.contains(r#"{"type":"heartbeat","timeout_secs""#));
+    }
+}
@@ -0,0 +1,246 @@
+//! Provisioning model - batch requests and per-VM outcomes
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How to provision a batch of pool VMs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProvisionOptions {
+    pub count: usize,
+    /// Repeating a request with the same key returns the first result instead of provisioning again
+    pub idempotency_key: Option<String>,
+    /// All or nothing: if any VM fails, delete the ones already created in this batch
+    pub atomic: bool,
+}
+
+impl ProvisionOptions {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub fn atomic(mut self) -> Self {
+        self.atomic = true;
+        self
+    }
+}
+
+/// A one-shot VM straight from a template, outside any pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EphemeralOptions {
+    /// Template name
+    pub template: String,
+    /// Overrides the template's memory
+    pub memory_mb: Option<u64>,
+    /// Overrides the template's CPU count
+    pub cpu_count: Option<u32>,
+    /// Destroyed after this long even if never released
+    pub ttl: Duration,
+}
+
+impl EphemeralOptions {
+    pub fn new(template: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            template: template.into(),
+            memory_mb: None,
+            cpu_count: None,
+            ttl,
+        }
+    }
+
+    pub fn with_memory(mut self, mb: u64) -> Self {
+        self.memory_mb = Some(mb);
+        self
+    }
+
+    pub fn with_cpus(mut self, count: u32) -> Self {
+        self.cpu_count = Some(count);
+        self
+    }
+}
+
+/// What happened to one VM of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisionOutcome {
+    Created,
+    /// Failed; its own partial disk/VM were cleaned up
+    Failed,
+    /// Created, then deleted because another VM in an atomic batch failed
+    RolledBack,
+    /// Not created because the request was cancelled first
+    Cancelled,
+}
+
+impl std::fmt::Display for ProvisionOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProvisionOutcome::Created => write!(f, "created"),
+            ProvisionOutcome::Failed => write!(f, "failed"),
+            ProvisionOutcome::RolledBack => write!(f, "rolled back"),
+            ProvisionOutcome::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvisionedVM {
+    pub name: String,
+    pub vm_id: Option<String>,
+    pub outcome: ProvisionOutcome,
+    pub error: Option<String>,
+}
+
+/// Result of a provision request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvisionResult {
+    pub pool_id: String,
+    pub idempotency_key: Option<String>,
+    pub vms: Vec<ProvisionedVM>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ProvisionResult {
+    pub fn new(pool_id: impl Into<String>, idempotency_key: Option<String>) -> Self {
+        Self {
+            pool_id: pool_id.into(),
+            idempotency_key,
+            vms: vec![],
+            created_at: Utc::now(),
+        }
+    }
+
+    /// IDs of VMs that exist after the request
+    pub fn created_ids(&self) -> Vec<String> {
+        self.vms.iter()
+            .filter(|v| v.outcome == ProvisionOutcome::Created)
+            .filter_map(|v| v.vm_id.clone())
+            .collect()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &ProvisionedVM> {
+        self.vms.iter().filter(|v| v.outcome == ProvisionOutcome::Failed)
+    }
+
+    /// The request was cancelled before every VM was created
+    pub fn is_cancelled(&self) -> bool {
+        self.vms.iter().any(|v| v.outcome == ProvisionOutcome::Cancelled)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.vms.iter().all(|v| v.outcome == ProvisionOutcome::Created)
+    }
+}
+
+/// Result of preparing (boot, checkpoint, save) a pool's VMs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreparedVM {
+    pub name: String,
+    pub vm_id: String,
+    /// None if the VM is Saved and ready
+    pub error: Option<String>,
+}
+
+/// Where one VM of a batch is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressStatus {
+    /// Working on `step`
+    Running,
+    Done,
+    Failed,
+}
+
+/// Per-VM progress of a batch provision or prepare
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    pub vm_name: String,
+    pub step: String,
+    pub status: ProgressStatus,
+    pub error: Option<String>,
+}
+
+impl Progress {
+    pub fn running(vm_name: &str, step: &str) -> Self {
+        Self {
+            vm_name: vm_name.to_string(),
+            step: step.to_string(),
+            status: ProgressStatus::Running,
+            error: None,
+        }
+    }
+
+    pub fn finished(vm_name: &str, step: &str, result: &crate::Result<()>) -> Self {
+        Self {
+            vm_name: vm_name.to_string(),
+            step: step.to_string(),
+            status: if result.is_ok() { ProgressStatus::Done } else { ProgressStatus::Failed },
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.status, &self.error) {
+            (ProgressStatus::Running, _) => write!(f, "{}: {}...", self.vm_name, self.step),
+            (ProgressStatus::Done, _) => write!(f, "{}: {} done", self.vm_name, self.step),
+            (ProgressStatus::Failed, Some(e)) => write!(f, "{}: {} failed: {}", self.vm_name, self.step, e),
+            (ProgressStatus::Failed, None) => write!(f, "{}: {} failed", self.vm_name, self.step),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm(name: &str, outcome: ProvisionOutcome) -> ProvisionedVM {
+        ProvisionedVM {
+            name: name.to_string(),
+            vm_id: Some(format!("vm-{}", name)),
+            outcome,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_provision_result_summary() {
+        let mut result = ProvisionResult::new("pool-1", None);
+        assert!(result.is_success());
+
+        result.vms.push(vm("agents-0", ProvisionOutcome::Created));
+        result.vms.push(vm("agents-1", ProvisionOutcome::Failed));
+        result.vms.push(vm("agents-2", ProvisionOutcome::RolledBack));
+
+        assert!(!result.is_success());
+        assert_eq!(result.created_ids(), vec!["vm-agents-0"]);
+        assert_eq!(result.failures().count(), 1);
+    }
+
+    #[test]
+    fn test_provision_options_builder() {
+        let opts = ProvisionOptions::new(3).with_idempotency_key("abc").atomic();
+        assert_eq!(opts.count, 3);
+        assert_eq!(opts.idempotency_key.as_deref(), Some("abc"));
+        assert!(opts.atomic);
+    }
+
+    #[test]
+    fn test_progress_display() {
+        assert_eq!(Progress::running("agents-0", "create_vm").to_string(), "agents-0: create_vm...");
+        assert_eq!(Progress::finished("agents-0", "prepare", &Ok(())).to_string(), "agents-0: prepare done");
+
+        let failed = Progress::finished("agents-1", "provision", &Err(crate::Error::Timeout));
+        assert_eq!(failed.status, ProgressStatus::Failed);
+        assert_eq!(failed.to_string(), "agents-1: provision failed: Timeout waiting for VM");
+    }
+}
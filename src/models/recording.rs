@@ -0,0 +1,83 @@
+//! Screen timeline model - host-side console recordings
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A deduplicated sequence of console frames captured during a lease
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenTimeline {
+    /// VM that was recorded
+    pub vm_name: String,
+    /// Directory holding the frame PNGs and timeline.json
+    pub directory: PathBuf,
+    /// Sampling interval in milliseconds
+    pub interval_ms: u64,
+    /// When recording started
+    pub started_at: DateTime<Utc>,
+    /// When recording stopped
+    pub ended_at: Option<DateTime<Utc>>,
+    /// Number of samples taken (including unchanged ones)
+    pub samples: usize,
+    /// Distinct frames, in capture order
+    pub frames: Vec<TimelineFrame>,
+}
+
+impl ScreenTimeline {
+    pub fn new(vm_name: impl Into<String>, directory: impl Into<PathBuf>, interval_ms: u64) -> Self {
+        Self {
+            vm_name: vm_name.into(),
+            directory: directory.into(),
+            interval_ms,
+            started_at: Utc::now(),
+            ended_at: None,
+            samples: 0,
+            frames: vec![],
+        }
+    }
+
+    /// Recording length in seconds
+    pub fn duration_seconds(&self) -> u64 {
+        let end = self.ended_at.unwrap_or_else(Utc::now);
+        (end - self.started_at).num_seconds().max(0) as u64
+    }
+}
+
+/// A single distinct frame in a timeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineFrame {
+    /// When the frame was first seen
+    pub captured_at: DateTime<Utc>,
+    /// PNG file on the host
+    pub path: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline_new() {
+        let t = ScreenTimeline::new("agents-0", r"C:\HyperVKube\VMs\agents-0\recordings\1", 1000);
+        assert_eq!(t.vm_name, "agents-0");
+        assert_eq!(t.interval_ms, 1000);
+        assert_eq!(t.samples, 0);
+        assert!(t.frames.is_empty());
+        assert!(t.ended_at.is_none());
+    }
+
+    #[test]
+    fn test_timeline_serialization() {
+        let mut t = ScreenTimeline::new("agents-0", "/tmp/rec", 500);
+        t.frames.push(TimelineFrame {
+            captured_at: Utc::now(),
+            path: "/tmp/rec/frame-00000.png".into(),
+        });
+        t.ended_at = Some(Utc::now());
+
+        let json = serde_json::to_string(&t).unwrap();
+        let parsed: ScreenTimeline = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.frames.len(), 1);
+        assert_eq!(parsed.duration_seconds(), t.duration_seconds());
+    }
+}
@@ -1,6 +1,8 @@
 //! Template model - golden images for VM creation
 
 use chrono::{DateTime, Utc};
+use super::ReadinessProbe;
+use crate::vhdx::DiskType;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -25,6 +27,24 @@ pub struct Template {
     pub created_at: DateTime<Utc>,
     /// Description
     pub description: Option<String>,
+    /// Guest login used for PowerShell Direct
+    #[serde(default)]
+    pub guest_credential: Option<GuestCredential>,
+    /// Ordered readiness checks; empty means the defaults (RDP answering)
+    #[serde(default)]
+    pub readiness_probes: Vec<ReadinessProbe>,
+    /// Size the guest sees, read from the VHDX at registration
+    #[serde(default)]
+    pub virtual_size_bytes: Option<u64>,
+    #[serde(default)]
+    pub disk_type: Option<DiskType>,
+    /// Version pools following `latest` provision from; the fields above mirror it
+    #[serde(default = "first_version")]
+    pub current_version: u32,
+}
+
+fn first_version() -> u32 {
+    1
 }
 
 impl Template {
@@ -39,6 +59,11 @@ impl Template {
             installed_software: vec![],
             created_at: Utc::now(),
             description: None,
+            guest_credential: None,
+            readiness_probes: vec![],
+            virtual_size_bytes: None,
+            disk_type: None,
+            current_version: 1,
         }
     }
 
@@ -66,6 +91,147 @@ impl Template {
         self.description = Some(desc.into());
         self
     }
+
+    pub fn with_guest_credential(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.guest_credential = Some(GuestCredential::new(username, password));
+        self
+    }
+
+    pub fn with_probes(mut self, probes: Vec<ReadinessProbe>) -> Self {
+        self.readiness_probes = probes;
+        self
+    }
+
+    /// Probes to run when waiting for a VM from this template
+    pub fn probes(&self) -> Vec<ReadinessProbe> {
+        if self.readiness_probes.is_empty() {
+            ReadinessProbe::defaults()
+        } else {
+            self.readiness_probes.clone()
+        }
+    }
+
+    /// This template with the disk of `version`, for provisioning a pinned pool
+    pub fn at_version(&self, version: &TemplateVersion) -> Template {
+        Template {
+            vhdx_path: version.vhdx_path.clone(),
+            virtual_size_bytes: version.virtual_size_bytes,
+            disk_type: version.disk_type,
+            current_version: version.version,
+            ..self.clone()
+        }
+    }
+}
+
+/// An immutable disk image of a template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateVersion {
+    pub template_id: String,
+    /// 1, 2, ... in registration order
+    pub version: u32,
+    pub vhdx_path: PathBuf,
+    /// SHA-256 of the VHDX when the version was added
+    pub sha256: Option<String>,
+    /// File size on the host
+    pub size_bytes: Option<u64>,
+    pub virtual_size_bytes: Option<u64>,
+    pub disk_type: Option<DiskType>,
+    pub notes: Option<String>,
+    /// Log of the build that produced it, for baked versions
+    #[serde(default)]
+    pub build_log: Option<PathBuf>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TemplateVersion {
+    pub fn new(template_id: impl Into<String>, version: u32, vhdx_path: impl Into<PathBuf>) -> Self {
+        Self {
+            template_id: template_id.into(),
+            version,
+            vhdx_path: vhdx_path.into(),
+            sha256: None,
+            size_bytes: None,
+            virtual_size_bytes: None,
+            disk_type: None,
+            notes: None,
+            build_log: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// The version a template currently points at, from the template's own fields
+    pub fn current_of(template: &Template) -> Self {
+        Self {
+            virtual_size_bytes: template.virtual_size_bytes,
+            disk_type: template.disk_type,
+            created_at: template.created_at,
+            ..Self::new(&template.id, template.current_version, &template.vhdx_path)
+        }
+    }
+
+    pub fn with_notes(mut self, notes: impl Into<String>) -> Self {
+        self.notes = Some(notes.into());
+        self
+    }
+}
+
+/// Outcome of re-hashing a template version's VHDX
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateCheck {
+    pub template: String,
+    pub version: u32,
+    pub vhdx_path: PathBuf,
+    /// Recorded when the version was added; None if it predates checksums
+    pub expected_sha256: Option<String>,
+    pub actual_sha256: String,
+    pub expected_size: Option<u64>,
+    pub actual_size: u64,
+    /// Whether the file is still protected against writes
+    pub read_only: bool,
+}
+
+impl TemplateCheck {
+    /// The file is what was registered (or nothing was recorded to compare with)
+    pub fn is_intact(&self) -> bool {
+        self.expected_sha256.as_ref().is_none_or(|s| *s == self.actual_sha256)
+            && self.expected_size.is_none_or(|s| s == self.actual_size)
+    }
+
+    /// The file matched a checksum recorded before this check
+    pub fn is_verified(&self) -> bool {
+        self.expected_sha256.is_some() && self.is_intact()
+    }
+}
+
+/// Guest account used for PowerShell Direct sessions
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuestCredential {
+    pub username: String,
+    /// Never serialized; kept DPAPI-protected in the DB (see [`crate::secret`])
+    #[serde(skip_serializing, default)]
+    pub password: String,
+}
+
+impl GuestCredential {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for GuestCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuestCredential")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
 }
 
 /// Builder for template registration
@@ -135,6 +301,51 @@ mod tests {
         assert_eq!(parsed.id, t.id);
     }
 
+    #[test]
+    fn test_template_guest_credential() {
+        let t = Template::new("win11", r"C:\test.vhdx")
+            .with_guest_credential("Administrator", "s3cret");
+
+        let cred = t.guest_credential.as_ref().unwrap();
+        assert_eq!(cred.username, "Administrator");
+        assert_eq!(cred.password, "s3cret");
+        assert!(!format!("{:?}", t).contains("s3cret"));
+        assert!(!serde_json::to_string(&t).unwrap().contains("s3cret"));
+
+        // Older serialized templates have no credential
+        let json = r#"{"id":"tmpl-1","name":"old","vhdx_path":"C:\\old.vhdx","memory_mb":4096,"cpu_count":2,"gpu_enabled":false,"installed_software":[],"created_at":"2024-01-01T00:00:00Z","description":null}"#;
+        let parsed: Template = serde_json::from_str(json).unwrap();
+        assert!(parsed.guest_credential.is_none());
+        assert!(parsed.readiness_probes.is_empty());
+    }
+
+    #[test]
+    fn test_template_probes() {
+        let t = Template::new("win11", r"C:\test.vhdx");
+        assert_eq!(t.probes(), ReadinessProbe::defaults());
+
+        let probes = vec!["heartbeat".parse().unwrap(), "http:9000/health".parse().unwrap()];
+        let t = t.with_probes(probes.clone());
+        assert_eq!(t.probes(), probes);
+    }
+
+    #[test]
+    fn test_template_at_version() {
+        let t = Template::new("win11", r"C:\t\v1.vhdx").with_memory(8192);
+        assert_eq!(t.current_version, 1);
+        let mut v3 = TemplateVersion::new(&t.id, 3, r"C:\t\v3.vhdx").with_notes("KB5034441");
+        v3.disk_type = Some(DiskType::Dynamic);
+
+        let pinned = t.at_version(&v3);
+        assert_eq!(pinned.vhdx_path, PathBuf::from(r"C:\t\v3.vhdx"));
+        assert_eq!(pinned.current_version, 3);
+        assert_eq!(pinned.disk_type, Some(DiskType::Dynamic));
+        assert_eq!(pinned.memory_mb, 8192);
+
+        let current = TemplateVersion::current_of(&t);
+        assert_eq!((current.version, current.vhdx_path), (1, t.vhdx_path));
+    }
+
     #[test]
     fn test_template_config() {
         let cfg = TemplateConfig::new("win11", r"C:\test.vhdx");
@@ -0,0 +1,64 @@
+//! File transfer model
+
+use serde::{Deserialize, Serialize};
+
+/// Direction of a host <-> guest file copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    /// Host file pushed into the guest
+    ToGuest,
+    /// Guest file pulled out to the host
+    FromGuest,
+}
+
+impl std::fmt::Display for TransferDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferDirection::ToGuest => write!(f, "ToGuest"),
+            TransferDirection::FromGuest => write!(f, "FromGuest"),
+        }
+    }
+}
+
+/// Result of a completed file copy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransfer {
+    /// VM the file was copied to/from
+    pub vm_name: String,
+    /// Copy direction
+    pub direction: TransferDirection,
+    /// Path inside the guest
+    pub guest_path: String,
+    /// Size of the transferred file
+    pub size_bytes: u64,
+    /// SHA-256 of the transferred file (lowercase hex)
+    pub sha256: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_direction_display() {
+        assert_eq!(TransferDirection::ToGuest.to_string(), "ToGuest");
+        assert_eq!(TransferDirection::FromGuest.to_string(), "FromGuest");
+    }
+
+    #[test]
+    fn test_file_transfer_serialization() {
+        let t = FileTransfer {
+            vm_name: "agents-0".to_string(),
+            direction: TransferDirection::ToGuest,
+            guest_path: r"C:\Temp\input.csv".to_string(),
+            size_bytes: 42,
+            sha256: "ab".repeat(32),
+        };
+
+        let json = serde_json::to_string(&t).unwrap();
+        assert!(json.contains("\"direction\":\"ToGuest\""));
+
+        let parsed: FileTransfer = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.size_bytes, 42);
+    }
+}
@@ -0,0 +1,159 @@
+//! Rolling upgrade model - moving a pool's VMs to another template version
+
+use super::{VMState, VM};
+use serde::{Deserialize, Serialize};
+
+/// How to move a pool to another template version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeOptions {
+    /// Version to pin the pool to; None follows the template's current version
+    pub template_version: Option<u32>,
+    /// New VMs provisioned and prepared per round, and old ones retired after them
+    pub batch_size: usize,
+}
+
+impl UpgradeOptions {
+    pub fn new(template_version: Option<u32>) -> Self {
+        Self {
+            template_version,
+            batch_size: 1,
+        }
+    }
+
+    pub fn with_batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+}
+
+/// Outcome of a rolling upgrade
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpgradeResult {
+    pub pool_id: String,
+    pub target_version: u32,
+    /// New VMs that passed readiness
+    pub created: Vec<String>,
+    /// Old VMs deleted
+    pub replaced: Vec<String>,
+    /// Old VMs left alone because they are leased or busy; run again once released
+    pub pending: Vec<String>,
+    /// Why the upgrade stopped early, if it did
+    pub paused: Option<String>,
+}
+
+impl UpgradeResult {
+    pub fn new(pool_id: impl Into<String>, target_version: u32) -> Self {
+        Self {
+            pool_id: pool_id.into(),
+            target_version,
+            created: vec![],
+            replaced: vec![],
+            pending: vec![],
+            paused: None,
+        }
+    }
+
+    /// Every VM of the pool is on the target version
+    pub fn is_complete(&self) -> bool {
+        self.paused.is_none() && self.pending.is_empty()
+    }
+}
+
+/// Whether a VM was created from another version than `target`.
+/// VMs from before template versions existed came from version 1.
+pub fn is_outdated(vm: &VM, target: u32) -> bool {
+    vm.template_version.unwrap_or(1) != target
+}
+
+/// New VMs to provision this round: one per idle outdated VM up to `batch_size`,
+/// never taking the pool past `desired_count + batch_size`
+pub fn upgrade_batch(pool_size: usize, desired_count: usize, batch_size: usize, outdated_idle: usize) -> usize {
+    let room = (desired_count + batch_size).saturating_sub(pool_size);
+    batch_size.min(outdated_idle).min(room)
+}
+
+/// Up to `limit` outdated, unleased VMs that can go without leaving fewer
+/// than `warm_count` VMs available. VMs that aren't available anyway go first.
+pub fn pick_retirees(vms: &[VM], target: u32, warm_count: usize, limit: usize) -> Vec<&VM> {
+    let is_available = |v: &VM| v.state == VMState::Saved && v.current_agent_id.is_none();
+    let mut available = vms.iter().filter(|v| is_available(v)).count();
+
+    let mut candidates: Vec<&VM> = vms.iter()
+        .filter(|v| is_outdated(v, target) && v.current_agent_id.is_none())
+        .collect();
+    candidates.sort_by_key(|v| is_available(v));
+
+    let mut picked = Vec::new();
+    for vm in candidates {
+        if picked.len() == limit {
+            break;
+        }
+        if is_available(vm) {
+            if available <= warm_count {
+                break;
+            }
+            available -= 1;
+        }
+        picked.push(vm);
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn vm(name: &str, version: Option<u32>, state: VMState) -> VM {
+        let mut vm = VM::new(name.to_string(), PathBuf::from(r"C:\vms\disk.vhdx"), 4096, 2);
+        vm.template_version = version;
+        vm.state = state;
+        vm
+    }
+
+    fn names(vms: Vec<&VM>) -> Vec<&str> {
+        vms.into_iter().map(|v| v.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_is_outdated() {
+        assert!(!is_outdated(&vm("a", None, VMState::Saved), 1));
+        assert!(is_outdated(&vm("a", None, VMState::Saved), 2));
+        assert!(!is_outdated(&vm("a", Some(2), VMState::Saved), 2));
+    }
+
+    #[test]
+    fn test_pick_retirees_keeps_warm_count() {
+        let mut leased = vm("old-2", Some(1), VMState::Running);
+        leased.current_agent_id = Some("agent-1".to_string());
+        let vms = vec![
+            vm("old-0", Some(1), VMState::Saved),
+            vm("old-1", Some(1), VMState::Off),
+            leased,
+            vm("new-0", Some(2), VMState::Saved),
+        ];
+
+        // Off VMs go first; one Saved old VM can go while new-0 stays available
+        assert_eq!(names(pick_retirees(&vms, 2, 1, 5)), ["old-1", "old-0"]);
+        assert_eq!(names(pick_retirees(&vms, 2, 1, 1)), ["old-1"]);
+        assert_eq!(names(pick_retirees(&vms, 2, 2, 5)), ["old-1"]);
+        assert!(pick_retirees(&vms, 1, 0, 5).iter().all(|v| v.name == "new-0"));
+    }
+
+    #[test]
+    fn test_upgrade_options() {
+        let options = UpgradeOptions::new(Some(3)).with_batch_size(0);
+        assert_eq!(options.batch_size, 1);
+        assert!(UpgradeResult::new("pool-1", 3).is_complete());
+    }
+
+    #[test]
+    fn test_upgrade_batch_caps_pool_size() {
+        assert_eq!(upgrade_batch(3, 3, 2, 3), 2);
+        assert_eq!(upgrade_batch(3, 3, 2, 1), 1);
+        // New VMs from earlier rounds still waiting to replace old ones
+        assert_eq!(upgrade_batch(4, 3, 2, 3), 1);
+        assert_eq!(upgrade_batch(5, 3, 2, 3), 0);
+        assert_eq!(upgrade_batch(9, 3, 2, 3), 0);
+    }
+}
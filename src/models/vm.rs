@@ -43,6 +43,26 @@ impl VMState {
     }
 }
 
+/// Outcome of the most recent health check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum VMHealth {
+    /// Not checked since the last state change
+    #[default]
+    Unknown,
+    Healthy,
+    Unhealthy,
+}
+
+impl std::fmt::Display for VMHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VMHealth::Unknown => write!(f, "Unknown"),
+            VMHealth::Healthy => write!(f, "Healthy"),
+            VMHealth::Unhealthy => write!(f, "Unhealthy"),
+        }
+    }
+}
+
 /// A VM instance
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VM {
@@ -74,6 +94,36 @@ pub struct VM {
     pub last_resumed_at: Option<DateTime<Utc>>,
     /// Error message if in error state
     pub error_message: Option<String>,
+    /// Latest health check result
+    #[serde(default)]
+    pub health: VMHealth,
+    /// Last acquire, heartbeat, exec or file transfer
+    #[serde(default)]
+    pub last_activity_at: Option<DateTime<Utc>>,
+    /// An operation on it was cancelled; it must be reset and prepared before reuse
+    #[serde(default)]
+    pub needs_recycle: bool,
+    /// One-shot VM outside any pool, destroyed on release or expiry
+    #[serde(default)]
+    pub ephemeral: bool,
+    /// When an ephemeral VM is destroyed regardless of use
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Size of the VM's own disk files (differencing disk and checkpoint children)
+    #[serde(default)]
+    pub disk_bytes: Option<u64>,
+    /// Size of its saved-state files (`.vmrs`, `.bin`, `.vsv`)
+    #[serde(default)]
+    pub saved_state_bytes: Option<u64>,
+    /// Template version its disk was created from
+    #[serde(default)]
+    pub template_version: Option<u32>,
+    /// Lease handed out by an acquire, or claimed by one still resuming the VM
+    #[serde(default)]
+    pub lease_id: Option<String>,
+    /// When the acquire finished and the claim became a lease
+    #[serde(default)]
+    pub leased_at: Option<DateTime<Utc>>,
 }
 
 impl VM {
@@ -93,11 +143,39 @@ impl VM {
             created_at: Utc::now(),
             last_resumed_at: None,
             error_message: None,
+            health: VMHealth::Unknown,
+            last_activity_at: None,
+            needs_recycle: false,
+            ephemeral: false,
+            expires_at: None,
+            disk_bytes: None,
+            saved_state_bytes: None,
+            template_version: None,
+            lease_id: None,
+            leased_at: None,
         }
     }
 
+    /// Ephemeral VM past its TTL
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.ephemeral && self.expires_at.is_some_and(|t| t <= now)
+    }
+
     pub fn is_available(&self) -> bool {
-        self.state == VMState::Saved && self.current_agent_id.is_none()
+        self.state == VMState::Saved && !self.is_leased()
+    }
+
+    /// Held by an agent or by an acquire (finished or not)
+    pub fn is_leased(&self) -> bool {
+        self.current_agent_id.is_some() || self.lease_id.is_some()
+    }
+
+    /// Time since the last recorded activity (falls back to resume, then creation)
+    pub fn idle_for(&self, now: DateTime<Utc>) -> std::time::Duration {
+        let since = self.last_activity_at
+            .or(self.last_resumed_at)
+            .unwrap_or(self.created_at);
+        (now - since).to_std().unwrap_or_default()
     }
 }
 
@@ -188,6 +266,7 @@ mod tests {
         assert_eq!(vm.memory_mb, 4096);
         assert_eq!(vm.cpu_count, 2);
         assert!(vm.ip_address.is_none());
+        assert_eq!(vm.health, VMHealth::Unknown);
     }
 
     #[test]
@@ -211,6 +290,36 @@ mod tests {
         assert!(!vm.is_available());
     }
 
+    #[test]
+    fn test_vm_idle_for() {
+        let mut vm = VM::new("test-vm".to_string(), PathBuf::from(r"C:\test.vhdx"), 4096, 2);
+        let now = vm.created_at + chrono::Duration::seconds(600);
+        assert_eq!(vm.idle_for(now).as_secs(), 600);
+
+        vm.last_resumed_at = Some(vm.created_at + chrono::Duration::seconds(300));
+        assert_eq!(vm.idle_for(now).as_secs(), 300);
+
+        vm.last_activity_at = Some(vm.created_at + chrono::Duration::seconds(590));
+        assert_eq!(vm.idle_for(now).as_secs(), 10);
+
+        // Clock skew never goes negative
+        assert_eq!(vm.idle_for(vm.created_at).as_secs(), 0);
+    }
+
+    #[test]
+    fn test_vm_is_expired() {
+        let mut vm = VM::new("eph-1".to_string(), PathBuf::from(r"C:\vms\eph.vhdx"), 4096, 2);
+        let later = vm.created_at + chrono::Duration::seconds(60);
+        vm.expires_at = Some(later);
+
+        // Pool VMs never expire
+        assert!(!vm.is_expired(later));
+
+        vm.ephemeral = true;
+        assert!(!vm.is_expired(vm.created_at));
+        assert!(vm.is_expired(later));
+    }
+
     #[test]
     fn test_vm_config_builder() {
         let config = VMConfig::new("my-vm")
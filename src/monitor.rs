@@ -0,0 +1,362 @@
+//! Background monitors: VM health checks, idle reclamation, ephemeral VM
+//! expiry and reconciliation
+//!
+//! Each runs on its own thread until the token passed to `spawn` is
+//! cancelled, which the server does on shutdown.
+
+use crate::hyperv::HyperV;
+use crate::models::*;
+use crate::{runtime, Orchestrator, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Outcome of checking one VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheck {
+    Healthy,
+    Unhealthy(String),
+    /// Not in a state we check (off, errored, or mid-operation)
+    Skipped,
+}
+
+/// Periodically checks Saved and Running VMs and applies pool remediation,
+/// and measures every VM's disk usage
+pub struct HealthMonitor {
+    orch: Arc<Orchestrator>,
+    interval: Duration,
+    /// Consecutive failures before a VM is marked unhealthy
+    failure_threshold: u32,
+    failures: HashMap<String, u32>,
+}
+
+impl HealthMonitor {
+    pub fn new(orch: Arc<Orchestrator>, interval: Duration) -> Self {
+        Self {
+            orch,
+            interval,
+            failure_threshold: 3,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold.max(1);
+        self
+    }
+
+    /// Run the monitor loop on a background thread until `cancel` fires
+    pub fn spawn(mut self, cancel: CancellationToken) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            if let Err(e) = self.sweep(&cancel) {
+                tracing::warn!(error = %e, "Health sweep failed");
+            }
+            if wait_or_cancel(&cancel, self.interval) {
+                break;
+            }
+        })
+    }
+
+    /// Check every VM once, stopping early if `cancel` fires
+    pub fn sweep(&mut self, cancel: &CancellationToken) -> Result<()> {
+        for vm in self.orch.list_vms()? {
+            if cancel.is_cancelled() {
+                return Ok(());
+            }
+            let check = match self.check_vm(&vm) {
+                Ok(check) => check,
+                Err(e) => HealthCheck::Unhealthy(format!("health check errored: {}", e)),
+            };
+            if let Err(e) = self.record(&vm, check) {
+                tracing::warn!(vm = %vm.name, error = %e, "Failed to apply health check result");
+            }
+        }
+        match self.orch.check_disk_usage() {
+            Ok(over) if !over.is_empty() => tracing::info!(count = over.len(), "VMs over their pool disk limit"),
+            Ok(_) => {}
+            Err(e) => tracing::warn!(error = %e, "Disk usage check failed"),
+        }
+        Ok(())
+    }
+
+    /// Check a single VM against Hyper-V, its heartbeat and its template's probes
+    pub fn check_vm(&self, vm: &VM) -> Result<HealthCheck> {
+        if self.orch.is_busy(&vm.id) || !matches!(vm.state, VMState::Running | VMState::Saved) {
+            return Ok(HealthCheck::Skipped);
+        }
+
+        let Some(info) = HyperV::get_vm(&vm.name)? else {
+            return Ok(HealthCheck::Unhealthy("VM missing from Hyper-V".to_string()));
+        };
+        let actual = VMState::from_hyperv_state(info.state);
+        if actual != vm.state {
+            return Ok(HealthCheck::Unhealthy(format!(
+                "expected {}, Hyper-V reports {}",
+                vm.state,
+                info.state_str()
+            )));
+        }
+        if vm.state == VMState::Saved {
+            return Ok(HealthCheck::Healthy);
+        }
+
+        if !HyperV::heartbeat_ok(&vm.name)? {
+            return Ok(HealthCheck::Unhealthy("heartbeat lost".to_string()));
+        }
+
+        let (probes, credential) = self.orch.readiness_probes(vm)?;
+        let ip = HyperV::get_vm_ip(&vm.name)?;
+        if probes.iter().any(|p| p.needs_ip()) && ip.is_none() {
+            return Ok(HealthCheck::Unhealthy("no IPv4 address".to_string()));
+        }
+        if ip.is_some() && ip != vm.ip_address {
+            self.orch.db().update_vm_ip(&vm.id, ip.as_deref())?;
+        }
+
+        for probe in &probes {
+            if !HyperV::check_probe(&vm.name, ip.as_deref(), probe, credential.as_ref())? {
+                return Ok(HealthCheck::Unhealthy(format!("probe {} failed", probe)));
+            }
+        }
+
+        Ok(HealthCheck::Healthy)
+    }
+
+    /// Apply a check result, only marking unhealthy after repeated failures
+    pub fn record(&mut self, vm: &VM, check: HealthCheck) -> Result<()> {
+        match check {
+            HealthCheck::Skipped => Ok(()),
+            HealthCheck::Healthy => {
+                self.failures.remove(&vm.id);
+                self.orch.mark_healthy(&vm.id)
+            }
+            HealthCheck::Unhealthy(reason) => {
+                let count = self.failures.entry(vm.id.clone()).or_insert(0);
+                *count += 1;
+                tracing::debug!(vm = %vm.name, failures = *count, reason = %reason, "Health check failed");
+                if *count < self.failure_threshold {
+                    return Ok(());
+                }
+                self.failures.remove(&vm.id);
+                self.orch.mark_unhealthy(&vm.id, &reason)
+            }
+        }
+    }
+}
+
+/// Periodically saves or resets Running VMs nobody has touched for a while
+pub struct IdleReclaimer {
+    orch: Arc<Orchestrator>,
+    interval: Duration,
+}
+
+impl IdleReclaimer {
+    pub fn new(orch: Arc<Orchestrator>, interval: Duration) -> Self {
+        Self { orch, interval }
+    }
+
+    /// Run the reclaim loop on a background thread until `cancel` fires
+    pub fn spawn(self, cancel: CancellationToken) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            match self.orch.reclaim_idle() {
+                Ok(reclaimed) if !reclaimed.is_empty() => {
+                    tracing::info!(count = reclaimed.len(), "Reclaimed idle VMs");
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!(error = %e, "Idle reclamation failed"),
+            }
+            if wait_or_cancel(&cancel, self.interval) {
+                break;
+            }
+        })
+    }
+}
+
+/// Periodically destroys ephemeral VMs past their TTL. Runs whether or not
+/// idle reclamation is enabled, since a TTL is a promise to the caller.
+pub struct ExpiryReaper {
+    orch: Arc<Orchestrator>,
+    interval: Duration,
+}
+
+impl ExpiryReaper {
+    pub fn new(orch: Arc<Orchestrator>, interval: Duration) -> Self {
+        Self { orch, interval: interval.max(Duration::from_secs(1)) }
+    }
+
+    /// Run the expiry loop on a background thread until `cancel` fires
+    pub fn spawn(self, cancel: CancellationToken) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            match self.orch.reap_expired() {
+                Ok(reaped) if !reaped.is_empty() => {
+                    tracing::info!(count = reaped.len(), "Destroyed expired ephemeral VMs");
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!(error = %e, "Ephemeral VM expiry failed"),
+            }
+            if wait_or_cancel(&cancel, self.interval) {
+                break;
+            }
+        })
+    }
+}
+
+/// Periodically reconciles the DB with Hyper-V, logging each new drift once
+pub struct Reconciler {
+    orch: Arc<Orchestrator>,
+    interval: Duration,
+    options: ReconcileOptions,
+    /// Drift already reported, so a persistent problem doesn't flood the event log
+    seen: HashSet<String>,
+}
+
+impl Reconciler {
+    pub fn new(orch: Arc<Orchestrator>, interval: Duration) -> Self {
+        Self {
+            orch,
+            interval,
+            options: ReconcileOptions::default(),
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn with_options(mut self, options: ReconcileOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Run the reconcile loop on a background thread until `cancel` fires
+    pub fn spawn(mut self, cancel: CancellationToken) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            match self.orch.reconcile_with(&self.options) {
+                Ok(report) => {
+                    if let Err(e) = self.record(&report) {
+                        tracing::warn!(error = %e, "Failed to record drift");
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "Reconcile failed"),
+            }
+            if wait_or_cancel(&cancel, self.interval) {
+                break;
+            }
+        })
+    }
+
+    /// Emit a DriftDetected event for each item not seen on the previous pass
+    pub fn record(&mut self, report: &DriftReport) -> Result<usize> {
+        let current: HashSet<String> = report.items.iter().map(|d| d.to_string()).collect();
+        let mut emitted = 0;
+        for item in &report.items {
+            let key = item.to_string();
+            if self.seen.contains(&key) {
+                continue;
+            }
+            let mut event = Event::new(EventKind::DriftDetected, key);
+            event.vm_name = item.vm_name().map(String::from);
+            self.orch.record_event(event)?;
+            emitted += 1;
+        }
+        self.seen = current;
+        Ok(emitted)
+    }
+}
+
+/// Sleep for `interval`; true if `cancel` fired first (or already had)
+fn wait_or_cancel(cancel: &CancellationToken, interval: Duration) -> bool {
+    runtime::block_on(tokio::time::timeout(interval, cancel.cancelled())).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OrchestratorConfig;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn setup() -> (Arc<Orchestrator>, VM, TempDir) {
+        let tmp = TempDir::new().unwrap();
+        let config = OrchestratorConfig {
+            vm_storage_path: tmp.path().join("vms"),
+            db_path: tmp.path().join("test.db"),
+            ..Default::default()
+        };
+        let orch = Orchestrator::with_config(config).unwrap();
+
+        let vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\a.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        (Arc::new(orch), vm, tmp)
+    }
+
+    #[test]
+    fn test_monitors_stop_when_cancelled() {
+        let (orch, _vm, _tmp) = setup();
+        let hour = Duration::from_secs(3600);
+        let cancel = CancellationToken::new();
+        let handles = [
+            HealthMonitor::new(orch.clone(), hour).spawn(cancel.clone()),
+            IdleReclaimer::new(orch.clone(), hour).spawn(cancel.clone()),
+            ExpiryReaper::new(orch.clone(), hour).spawn(cancel.clone()),
+            Reconciler::new(orch.clone(), hour).spawn(cancel.clone()),
+        ];
+
+        // Each finishes its first pass, then wakes from its hour-long wait
+        cancel.cancel();
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !handles.iter().all(|h| h.is_finished()) {
+            assert!(std::time::Instant::now() < deadline, "monitor still running after cancel");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_record_waits_for_threshold() {
+        let (orch, vm, _tmp) = setup();
+        let mut monitor = HealthMonitor::new(orch.clone(), Duration::from_secs(30))
+            .with_failure_threshold(2);
+
+        let failing = HealthCheck::Unhealthy("heartbeat lost".to_string());
+        monitor.record(&vm, failing.clone()).unwrap();
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().health, VMHealth::Unknown);
+
+        // A passing check resets the streak
+        monitor.record(&vm, HealthCheck::Healthy).unwrap();
+        monitor.record(&vm, failing.clone()).unwrap();
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().health, VMHealth::Healthy);
+
+        monitor.record(&vm, failing).unwrap();
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().health, VMHealth::Unhealthy);
+
+        let events = orch.list_events(&EventFilter::default()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::VMUnhealthy);
+    }
+
+    #[test]
+    fn test_check_skips_off_vms() {
+        let (orch, vm, _tmp) = setup();
+        let monitor = HealthMonitor::new(orch, Duration::from_secs(30));
+        assert_eq!(monitor.check_vm(&vm).unwrap(), HealthCheck::Skipped);
+    }
+
+    #[test]
+    fn test_reconciler_reports_new_drift_once() {
+        let (orch, _vm, _tmp) = setup();
+        let mut reconciler = Reconciler::new(orch.clone(), Duration::from_secs(300));
+
+        let missing = Drift::MissingFromHyperV { vm_name: "agents-0".to_string() };
+        let report = DriftReport::new(vec![missing.clone()]);
+        assert_eq!(reconciler.record(&report).unwrap(), 1);
+        assert_eq!(reconciler.record(&report).unwrap(), 0);
+
+        // Once fixed and back again, it's reported again
+        reconciler.record(&DriftReport::new(vec![])).unwrap();
+        assert_eq!(reconciler.record(&report).unwrap(), 1);
+
+        let filter = EventFilter { kind: Some(EventKind::DriftDetected), ..Default::default() };
+        let events = orch.list_events(&filter).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].vm_name.as_deref(), Some("agents-0"));
+    }
+}
@@ -1,11 +1,32 @@
 //! VM orchestration and lifecycle management
+//!
+//! The lease path (acquire, resume, save, stop, exec, screenshot) has
+//! `*_async` methods that never block a runtime worker. Journaled operations
+//! (provision, prepare, reset, delete, release, reconcile) are synchronous so
+//! each step runs to completion or rollback; async callers run them on the
+//! blocking pool and stop them by cancelling their job.
 
+use crate::checksum::{self, HashProgress};
 use crate::db::Database;
-use crate::hyperv::HyperV;
+use crate::hyperv::{self, GuestOutput, HyperV};
+use crate::journal::{Journal, StepExecutor};
 use crate::models::*;
+use crate::reconcile::{self, HostSnapshot};
+use crate::recorder::ScreenRecorder;
+use crate::runtime;
+use crate::vhdx;
 use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+/// Finished jobs kept for inspection before the oldest are forgotten
+const MAX_FINISHED_JOBS: usize = 100;
 
 /// Configuration for the orchestrator
 pub struct OrchestratorConfig {
@@ -17,6 +38,16 @@ pub struct OrchestratorConfig {
     pub switch_name: String,
     /// Timeout for VM ready check
     pub ready_timeout: Duration,
+    /// Largest file accepted for host <-> guest copies
+    pub max_transfer_bytes: u64,
+    /// Record every lease's console at this interval (None = only on request)
+    pub record_interval: Option<Duration>,
+    /// VMs provisioned or prepared at the same time
+    pub max_parallel: usize,
+    /// How long to wait for a first-booted guest to go quiet before checkpointing
+    pub settle_timeout: Duration,
+    /// Longest a host <-> guest file copy may take before it's killed
+    pub transfer_timeout: Duration,
 }
 
 impl Default for OrchestratorConfig {
@@ -26,14 +57,122 @@ impl Default for OrchestratorConfig {
             db_path: PathBuf::from(r"C:\HyperVKube\state.db"),
             switch_name: "Default Switch".to_string(),
             ready_timeout: Duration::from_secs(120),
+            max_transfer_bytes: 4 * 1024 * 1024 * 1024,
+            record_interval: None,
+            max_parallel: 4,
+            settle_timeout: Duration::from_secs(60),
+            transfer_timeout: Duration::from_secs(30 * 60),
         }
     }
 }
 
+/// Receives per-VM progress from batch operations (called from worker threads)
+pub type ProgressCallback<'a> = &'a (dyn Fn(&Progress) + Sync);
+
 /// Main orchestrator for VM management
 pub struct Orchestrator {
     db: Database,
     config: OrchestratorConfig,
+    /// Active console recorders, keyed by VM id
+    recorders: Mutex<HashMap<String, ScreenRecorder>>,
+    /// VMs with a lifecycle operation in flight (id -> nesting depth)
+    busy: Mutex<HashMap<String, usize>>,
+    /// Running and recently finished cancellable operations, keyed by job id
+    jobs: Mutex<HashMap<String, JobEntry>>,
+    /// Cancelled once the server starts shutting down; new acquires are
+    /// refused and background monitors stop
+    shutting_down: CancellationToken,
+    /// Template files whose checksum matched, with the size and mtime they had then
+    verified: Mutex<HashMap<PathBuf, (u64, SystemTime)>>,
+}
+
+struct JobEntry {
+    job: Job,
+    cancel: CancellationToken,
+}
+
+/// Tracks a job for as long as its operation runs
+struct JobGuard<'a> {
+    jobs: &'a Mutex<HashMap<String, JobEntry>>,
+    id: String,
+    cancel: CancellationToken,
+}
+
+impl JobGuard<'_> {
+    fn finish<T>(&self, result: &Result<T>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(entry) = jobs.get_mut(&self.id) {
+            entry.job.finish(result, self.cancel.is_cancelled());
+        }
+
+        let mut finished: Vec<(chrono::DateTime<chrono::Utc>, String)> = jobs.values()
+            .filter(|e| e.job.is_finished())
+            .map(|e| (e.job.started_at, e.job.id.clone()))
+            .collect();
+        if finished.len() > MAX_FINISHED_JOBS {
+            finished.sort();
+            for (_, id) in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+                jobs.remove(id);
+            }
+        }
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        // Still running here means the future driving it was dropped
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(entry) = jobs.get_mut(&self.id).filter(|e| !e.job.is_finished()) {
+            entry.job.finish::<()>(&Err(Error::Cancelled), true);
+        }
+    }
+}
+
+/// Marks a VM busy for as long as it's alive
+struct BusyGuard<'a> {
+    busy: &'a Mutex<HashMap<String, usize>>,
+    vm_id: String,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        let mut busy = self.busy.lock().unwrap();
+        if let Some(depth) = busy.get_mut(&self.vm_id) {
+            *depth -= 1;
+            if *depth == 0 {
+                busy.remove(&self.vm_id);
+            }
+        }
+    }
+}
+
+/// An acquire's hold on a Saved VM while it resumes; released on drop
+/// unless `into_lease` handed it to the caller
+struct ClaimGuard<'a> {
+    db: &'a Database,
+    vm_id: String,
+    lease_id: String,
+}
+
+impl ClaimGuard<'_> {
+    /// Turn the claim into a lease that outlives the acquire (and a restart)
+    fn into_lease(self) -> Result<()> {
+        if !self.db.grant_vm_lease(&self.vm_id, &self.lease_id)? {
+            return Err(Error::InvalidState {
+                current: "claim released".to_string(),
+                expected: "claimed".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ClaimGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.release_vm_claim(&self.vm_id, &self.lease_id) {
+            tracing::warn!(vm = %self.vm_id, error = %e, "Failed to release acquire claim");
+        }
+    }
 }
 
 impl Orchestrator {
@@ -50,7 +189,15 @@ impl Orchestrator {
 
         let db = Database::open(&config.db_path)?;
 
-        Ok(Self { db, config })
+        Ok(Self {
+            db,
+            config,
+            recorders: Mutex::new(HashMap::new()),
+            busy: Mutex::new(HashMap::new()),
+            jobs: Mutex::new(HashMap::new()),
+            shutting_down: CancellationToken::new(),
+            verified: Mutex::new(HashMap::new()),
+        })
     }
 
     /// Get database reference
@@ -58,483 +205,3921 @@ impl Orchestrator {
         &self.db
     }
 
+    /// Get configuration
+    pub fn config(&self) -> &OrchestratorConfig {
+        &self.config
+    }
+
     // ===== Template Operations =====
 
-    /// Register a template (golden image)
+    /// Register a template (golden image) after checking its VHDX is well-formed.
+    /// The VHDX becomes version 1; its checksum is recorded and the file made read-only.
     pub fn register_template(&self, template: Template) -> Result<String> {
-        // Verify VHDX exists
-        if !template.vhdx_path.exists() {
-            return Err(Error::Other(format!(
-                "Template VHDX not found: {:?}",
-                template.vhdx_path
-            )));
-        }
+        self.register_template_with_progress(template, &|_, _| {})
+    }
+
+    /// `register_template`, reporting progress while the VHDX is hashed
+    pub fn register_template_with_progress(&self, template: Template, on_hash: HashProgress<'_>) -> Result<String> {
+        self.register_template_with(template, None, None, on_hash)
+    }
+
+    fn register_template_with(
+        &self,
+        mut template: Template,
+        notes: Option<String>,
+        build_log: Option<PathBuf>,
+        on_hash: HashProgress<'_>,
+    ) -> Result<String> {
+        let mut version = self.new_template_version(&template.id, 1, &template.vhdx_path, &template.name, on_hash)?;
+        version.notes = notes;
+        version.build_log = build_log;
+        template.virtual_size_bytes = version.virtual_size_bytes;
+        template.disk_type = version.disk_type;
+        template.current_version = 1;
 
         let id = template.id.clone();
         self.db.insert_template(&template)?;
+        self.db.insert_template_version(&version)?;
         tracing::info!(template = %template.name, id = %id, "Template registered");
         Ok(id)
     }
 
-    /// List all templates
-    pub fn list_templates(&self) -> Result<Vec<Template>> {
-        self.db.list_templates()
-    }
-
-    /// Get template by name
-    pub fn get_template(&self, name: &str) -> Result<Option<Template>> {
-        self.db.get_template_by_name(name)
+    /// Add a VHDX as the next version of a template. Pools following `latest`
+    /// keep using the current version until this one is promoted.
+    pub fn add_template_version(
+        &self,
+        name: &str,
+        vhdx_path: impl Into<PathBuf>,
+        notes: Option<String>,
+    ) -> Result<TemplateVersion> {
+        let template = self.template_by_name(name)?;
+        self.add_version(&template, &vhdx_path.into(), notes, None)
     }
 
-    // ===== Pool Operations =====
-
-    /// Create a VM pool
-    pub fn create_pool(&self, pool: VMPool) -> Result<String> {
-        // Verify template exists
-        if self.db.get_template(&pool.template_id)?.is_none() {
-            return Err(Error::TemplateNotFound(pool.template_id.clone()));
+    fn add_version(
+        &self,
+        template: &Template,
+        vhdx_path: &Path,
+        notes: Option<String>,
+        build_log: Option<PathBuf>,
+    ) -> Result<TemplateVersion> {
+        let absolute = |p: &Path| std::path::absolute(p).unwrap_or_else(|_| p.to_path_buf());
+        let versions = self.db.list_template_versions(&template.id)?;
+        if let Some(existing) = versions.iter().find(|v| absolute(&v.vhdx_path) == absolute(vhdx_path)) {
+            return Err(Error::Other(format!(
+                "{:?} is already {} v{}", vhdx_path, template.name, existing.version
+            )));
         }
 
-        let id = pool.id.clone();
-        self.db.insert_pool(&pool)?;
-        tracing::info!(pool = %pool.name, id = %id, "Pool created");
-        Ok(id)
+        let next = self.next_template_version(template)?;
+        let mut version = self.new_template_version(&template.id, next, vhdx_path, &template.name, &|_, _| {})?;
+        version.notes = notes;
+        version.build_log = build_log;
+        self.db.insert_template_version(&version)?;
+        tracing::info!(template = %template.name, version = next, "Template version added");
+        Ok(version)
     }
 
-    /// List all pools
-    pub fn list_pools(&self) -> Result<Vec<VMPool>> {
-        self.db.list_pools()
+    fn next_template_version(&self, template: &Template) -> Result<u32> {
+        let newest = self.db.list_template_versions(&template.id)?
+            .iter()
+            .map(|v| v.version)
+            .max();
+        Ok(newest.unwrap_or(template.current_version) + 1)
     }
 
-    /// Get pool status
-    pub fn get_pool_status(&self, pool_id: &str) -> Result<PoolStatus> {
-        let pool = self.db.get_pool(pool_id)?
-            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
-
-        let vms = self.db.list_vms_by_pool(pool_id)?;
+    /// Versions of a template, oldest first
+    pub fn list_template_versions(&self, name: &str) -> Result<Vec<TemplateVersion>> {
+        let template = self.template_by_name(name)?;
+        self.db.list_template_versions(&template.id)
+    }
 
-        Ok(PoolStatus {
-            id: pool.id,
-            name: pool.name,
-            template_id: pool.template_id,
-            desired_count: pool.desired_count,
-            total_vms: vms.len(),
-            running_vms: vms.iter().filter(|v| v.state == VMState::Running).count(),
-            saved_vms: vms.iter().filter(|v| v.state == VMState::Saved).count(),
-            off_vms: vms.iter().filter(|v| v.state == VMState::Off).count(),
-            error_vms: vms.iter().filter(|v| v.state == VMState::Error).count(),
-        })
+    /// Make `version` the one pools following `latest` provision from
+    pub fn promote_template_version(&self, name: &str, version: u32) -> Result<Template> {
+        let template = self.template_by_name(name)?;
+        let target = self.db.get_template_version(&template.id, version)?
+            .ok_or_else(|| Error::TemplateVersionNotFound { template: name.to_string(), version })?;
+        if !target.vhdx_path.exists() {
+            return Err(Error::Other(format!(
+                "VHDX of {} v{} not found: {:?}",
+                name, version, target.vhdx_path
+            )));
+        }
+        self.db.set_template_current_version(&target)?;
+        tracing::info!(template = %name, from = template.current_version, to = version, "Template version promoted");
+        Ok(template.at_version(&target))
     }
 
-    /// Provision VMs for a pool (create and prepare them)
-    pub fn provision_pool(&self, pool_id: &str, count: usize) -> Result<Vec<String>> {
-        let pool = self.db.get_pool(pool_id)?
-            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
+    /// Go back to the newest version older than the current one
+    pub fn rollback_template(&self, name: &str) -> Result<Template> {
+        let template = self.template_by_name(name)?;
+        let previous = self.db.list_template_versions(&template.id)?
+            .into_iter()
+            .map(|v| v.version)
+            .filter(|v| *v < template.current_version)
+            .max()
+            .ok_or_else(|| Error::Other(format!(
+                "{} has no version before v{}",
+                name, template.current_version
+            )))?;
+        self.promote_template_version(name, previous)
+    }
 
+    /// The template as a pool provisions it: at its pinned version, or the current one
+    pub fn pool_template(&self, pool: &VMPool) -> Result<Template> {
         let template = self.db.get_template(&pool.template_id)?
             .ok_or_else(|| Error::TemplateNotFound(pool.template_id.clone()))?;
-
-        let existing = self.db.list_vms_by_pool(pool_id)?;
-        let start_index = existing.len();
-
-        let mut created_ids = Vec::new();
-
-        for i in 0..count {
-            let vm_name = format!("{}-{}", pool.name, start_index + i);
-            let vm_dir = self.config.vm_storage_path.join(&vm_name);
-            std::fs::create_dir_all(&vm_dir)?;
-
-            let vhdx_path = vm_dir.join("disk.vhdx");
-
-            tracing::info!(vm = %vm_name, "Creating differencing disk");
-            HyperV::create_differencing_disk(
-                template.vhdx_path.to_str().unwrap(),
-                vhdx_path.to_str().unwrap(),
-            )?;
-
-            tracing::info!(vm = %vm_name, "Creating VM");
-            HyperV::create_vm(
-                &vm_name,
-                vhdx_path.to_str().unwrap(),
-                template.memory_mb,
-                template.cpu_count,
-            )?;
-
-            // Configure network
-            HyperV::set_network_adapter(&vm_name, &self.config.switch_name)?;
-
-            // Enable enhanced session
-            let _ = HyperV::enable_enhanced_session(&vm_name);
-
-            // Add GPU if template has it
-            if template.gpu_enabled {
-                let _ = HyperV::add_gpu(&vm_name);
+        match pool.template_version {
+            Some(version) if version != template.current_version => {
+                let pinned = self.db.get_template_version(&template.id, version)?
+                    .ok_or_else(|| Error::TemplateVersionNotFound { template: template.name.clone(), version })?;
+                Ok(template.at_version(&pinned))
             }
+            _ => Ok(template),
+        }
+    }
 
-            // Create VM record
-            let mut vm = VM::new(vm_name.clone(), vhdx_path, template.memory_mb, template.cpu_count);
-            vm.template_id = Some(template.id.clone());
-            vm.pool_id = Some(pool.id.clone());
-            vm.gpu_enabled = template.gpu_enabled;
+    fn template_by_name(&self, name: &str) -> Result<Template> {
+        self.db.get_template_by_name(name)?
+            .ok_or_else(|| Error::TemplateNotFound(name.to_string()))
+    }
 
-            self.db.insert_vm(&vm)?;
-            created_ids.push(vm.id.clone());
+    /// Check a VHDX and describe it as version `version` of a template.
+    /// Every pool disk reads through it, so the file is made read-only.
+    /// A disk with an unreplayed log is refused: Hyper-V would write to it on
+    /// first open, after the checksum was taken and the file locked.
+    fn new_template_version(
+        &self,
+        template_id: &str,
+        version: u32,
+        vhdx_path: &Path,
+        name: &str,
+        on_hash: HashProgress<'_>,
+    ) -> Result<TemplateVersion> {
+        if !vhdx_path.exists() {
+            return Err(Error::Other(format!("Template VHDX not found: {:?}", vhdx_path)));
+        }
 
-            tracing::info!(vm = %vm_name, "VM created (not yet booted)");
+        let disk = vhdx::inspect(vhdx_path)?;
+        if disk.has_pending_log() {
+            return Err(Error::InvalidVhdx(format!(
+                "{} has an unreplayed log; mount and dismount it once (Mount-VHD, Dismount-VHD) to replay it",
+                vhdx_path.display()
+            )));
+        }
+        let (size_bytes, sha256) = checksum::sha256_file_with_progress(vhdx_path, on_hash)?;
+        if let Err(e) = set_read_only(vhdx_path) {
+            tracing::warn!(template = %name, version, error = %e, "Couldn't make template VHDX read-only");
         }
 
-        Ok(created_ids)
+        let mut v = TemplateVersion::new(template_id, version, vhdx_path);
+        v.sha256 = Some(sha256);
+        v.size_bytes = Some(size_bytes);
+        v.virtual_size_bytes = Some(disk.virtual_size);
+        v.disk_type = Some(disk.disk_type);
+        Ok(v)
     }
 
-    /// Boot a VM, create checkpoint, and save state (makes it ready for fast resume)
-    pub fn prepare_vm(&self, vm_id: &str) -> Result<()> {
-        let vm = self.db.get_vm(vm_id)?
-            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+    /// Re-hash a template version's VHDX (the current one by default) and
+    /// compare it with the checksum recorded when it was added. Versions from
+    /// before checksums were kept get theirs recorded now.
+    pub fn verify_template(&self, name: &str, version: Option<u32>, on_hash: HashProgress<'_>) -> Result<TemplateCheck> {
+        let template = self.template_by_name(name)?;
+        let number = version.unwrap_or(template.current_version);
+        let v = self.db.get_template_version(&template.id, number)?
+            .ok_or_else(|| Error::TemplateVersionNotFound { template: name.to_string(), version: number })?;
+        self.check_template_version(name, &v, on_hash)
+    }
 
-        tracing::info!(vm = %vm.name, "Starting VM for first boot");
-        HyperV::start_vm(&vm.name)?;
-        self.db.update_vm_state(vm_id, VMState::Running)?;
+    fn check_template_version(&self, name: &str, v: &TemplateVersion, on_hash: HashProgress<'_>) -> Result<TemplateCheck> {
+        let metadata = std::fs::metadata(&v.vhdx_path)?;
+        let stamp = (metadata.len(), metadata.modified()?);
+        let (actual_size, actual_sha256) = checksum::sha256_file_with_progress(&v.vhdx_path, on_hash)?;
+        if v.sha256.is_none() {
+            // Nothing to compare with: whatever is on disk now becomes the reference
+            self.db.set_template_version_checksum(&v.template_id, v.version, &actual_sha256, actual_size)?;
+            self.record_event(Event::new(EventKind::TemplateChecksumRecorded, format!(
+                "{} v{} had no checksum; recorded {} without verifying it",
+                name, v.version, actual_sha256
+            )))?;
+        }
 
-        tracing::info!(vm = %vm.name, "Waiting for VM to be ready");
-        let ip = HyperV::wait_for_ready(&vm.name, self.config.ready_timeout)?;
-        self.db.update_vm_ip(vm_id, Some(&ip))?;
+        let check = TemplateCheck {
+            template: name.to_string(),
+            version: v.version,
+            vhdx_path: v.vhdx_path.clone(),
+            expected_sha256: v.sha256.clone(),
+            actual_sha256,
+            expected_size: v.size_bytes,
+            actual_size,
+            read_only: metadata.permissions().readonly(),
+        };
+        let mut verified = self.verified.lock().unwrap();
+        if check.is_intact() {
+            verified.insert(v.vhdx_path.clone(), stamp);
+        } else {
+            verified.remove(&v.vhdx_path);
+            tracing::error!(template = %name, version = v.version, path = ?v.vhdx_path, "Template VHDX no longer matches its checksum");
+        }
+        Ok(check)
+    }
 
-        // Wait a bit more for Windows to settle
-        std::thread::sleep(Duration::from_secs(10));
+    /// Refuse to build VMs on a template image that changed since it was added.
+    /// Re-hashing is skipped while the file keeps the size and mtime of its last good check.
+    fn ensure_template_intact(&self, template: &Template) -> Result<()> {
+        let Some(v) = self.db.get_template_version(&template.id, template.current_version)? else {
+            return Err(Error::TemplateVersionNotFound {
+                template: template.name.clone(),
+                version: template.current_version,
+            });
+        };
+        let metadata = std::fs::metadata(&v.vhdx_path)?;
+        let stamp = (metadata.len(), metadata.modified()?);
+        if self.verified.lock().unwrap().get(&v.vhdx_path) == Some(&stamp) {
+            return Ok(());
+        }
 
-        tracing::info!(vm = %vm.name, "Creating clean checkpoint");
-        HyperV::create_checkpoint(&vm.name, "clean")?;
+        if !self.check_template_version(&template.name, &v, &|_, _| {})?.is_intact() {
+            return Err(Error::TemplateChecksumMismatch {
+                template: template.name.clone(),
+                version: v.version,
+            });
+        }
+        Ok(())
+    }
 
-        tracing::info!(vm = %vm.name, "Saving VM state");
-        HyperV::save_vm(&vm.name)?;
-        self.db.update_vm_state(vm_id, VMState::Saved)?;
+    /// Delete a template and its versions. Pools and VMs built on it are torn
+    /// down first with `cascade`; otherwise they make this an error. The VHDX
+    /// files are left on disk.
+    pub fn delete_template(&self, name: &str, cascade: bool) -> Result<()> {
+        let template = self.template_by_name(name)?;
+        let pools = self.db.list_pools_by_template(&template.id)?;
+        let vms = self.db.list_vms_by_template(&template.id)?;
+        if !pools.is_empty() || !vms.is_empty() {
+            if !cascade {
+                let dependents = pools.iter().map(|p| format!("pool {}", p.name))
+                    .chain(vms.iter().filter(|v| v.pool_id.is_none()).map(|v| format!("VM {}", v.name)))
+                    .collect();
+                return Err(Error::InUse { resource: format!("Template {}", name), dependents });
+            }
+            refuse_leased(&vms)?;
+            for pool in &pools {
+                self.delete_pool(&pool.id, true)?;
+            }
+            for vm in self.db.list_vms_by_template(&template.id)? {
+                self.delete_vm(&vm.id)?;
+            }
+        }
 
-        tracing::info!(vm = %vm.name, "VM ready for fast resume");
+        let versions = self.db.list_template_versions(&template.id)?;
+        self.db.delete_template(&template.id)?;
+        let mut verified = self.verified.lock().unwrap();
+        for v in &versions {
+            verified.remove(&v.vhdx_path);
+        }
+        tracing::info!(template = %name, cascade, "Template deleted");
         Ok(())
     }
 
-    // ===== VM Operations =====
-
-    /// List all VMs
-    pub fn list_vms(&self) -> Result<Vec<VM>> {
-        self.db.list_vms()
+    /// List all templates
+    pub fn list_templates(&self) -> Result<Vec<Template>> {
+        self.db.list_templates()
     }
 
-    /// Get VM by name
-    pub fn get_vm(&self, name: &str) -> Result<Option<VM>> {
-        self.db.get_vm_by_name(name)
+    /// Get template by name
+    pub fn get_template(&self, name: &str) -> Result<Option<Template>> {
+        self.db.get_template_by_name(name)
     }
 
-    /// Resume a saved VM (fast, 2-5 seconds)
-    pub fn resume_vm(&self, vm_id: &str) -> Result<String> {
-        let vm = self.db.get_vm(vm_id)?
-            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+    // ===== Template Builds =====
 
-        if vm.state != VMState::Saved {
-            return Err(Error::InvalidState {
-                current: vm.state.to_string(),
-                expected: "Saved".to_string(),
-            });
+    /// Bake a new template version: boot a VM on a differencing disk over
+    /// `spec.base`, run the steps, shut it down (through sysprep if asked),
+    /// merge the disk into a standalone VHDX and register it. The build VM is
+    /// journaled like a provision, so it's removed either way, even after a
+    /// crash; the log is kept next to the output.
+    pub fn build_template(&self, spec: &BuildSpec, on_log: &(dyn Fn(&str) + Sync)) -> Result<BuildResult> {
+        let existing = self.db.get_template_by_name(&spec.template)?;
+        let credential = spec.credential()
+            .or_else(|| existing.as_ref().and_then(|t| t.guest_credential.clone()));
+        if spec.needs_guest_session() && credential.is_none() {
+            return Err(Error::NoGuestCredential(spec.template.clone()));
+        }
+        if !spec.base.exists() {
+            return Err(Error::Other(format!("Base VHDX not found: {:?}", spec.base)));
+        }
+        let next = match &existing {
+            Some(t) => self.next_template_version(t)?,
+            None => 1,
+        };
+        let output = spec.output_path(next);
+        if output.exists() {
+            return Err(Error::Other(format!("Build output already exists: {:?}", output)));
         }
 
-        let start = std::time::Instant::now();
-        tracing::info!(vm = %vm.name, "Resuming VM");
-
-        HyperV::start_vm(&vm.name)?;
-        self.db.update_vm_state(vm_id, VMState::Running)?;
-        self.db.update_vm_resumed(vm_id)?;
+        let log_path = build_log_path(&output);
+        let mut log = BuildLog::create(&log_path, on_log)?;
+        let vm_name = format!("build-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        log.line(&format!("Building {} v{} on {}", spec.template, next, vm_name));
 
-        // Wait for ready
-        let ip = HyperV::wait_for_ready(&vm.name, Duration::from_secs(30))?;
-        self.db.update_vm_ip(vm_id, Some(&ip))?;
+        let job = self.start_job(JobKind::Build, &vm_name);
+        let disk = self.config.vm_storage_path.join(&vm_name).join("disk.vhdx");
+        let vm = VM::new(vm_name, disk, spec.memory_mb, spec.cpu_count);
+        let mut steps = BuildSteps {
+            inner: OperationSteps::new(self, OperationKind::Build, vm)
+                .with_parent(spec.base.clone())
+                .with_output(output.clone())
+                .with_cancel(&job.cancel),
+            spec,
+            credential: credential.as_ref(),
+            log: &mut log,
+        };
+        let result = steps.inner.operation()
+            .and_then(|mut op| Journal::new(&self.db).run(&mut op, &mut steps));
+        job.finish(&result);
+        if let Err(e) = result {
+            log.line(&format!("Build failed: {}", e));
+            return Err(e);
+        }
 
-        let elapsed = start.elapsed();
-        tracing::info!(vm = %vm.name, elapsed_ms = elapsed.as_millis(), ip = %ip, "VM resumed");
+        let version = match &existing {
+            Some(template) => self.add_version(template, &output, spec.notes.clone(), Some(log_path))?,
+            None => {
+                let mut template = Template::new(&spec.template, &output)
+                    .with_memory(spec.memory_mb)
+                    .with_cpus(spec.cpu_count);
+                template.guest_credential = credential;
+                let id = self.register_template_with(template, spec.notes.clone(), Some(log_path), &|_, _| {})?;
+                self.db.get_template_version(&id, 1)?
+                    .ok_or_else(|| Error::TemplateVersionNotFound { template: spec.template.clone(), version: 1 })?
+            }
+        };
+        let promoted = match &existing {
+            Some(_) if spec.promote => {
+                self.promote_template_version(&spec.template, version.version)?;
+                true
+            }
+            Some(_) => false,
+            None => true,
+        };
+        log.line(&format!(
+            "Registered {} v{}{}",
+            spec.template,
+            version.version,
+            if promoted { " (current)" } else { "" }
+        ));
 
-        Ok(ip)
+        Ok(BuildResult {
+            template: spec.template.clone(),
+            version,
+            promoted,
+        })
     }
 
-    /// Save VM state (for fast resume later)
-    pub fn save_vm(&self, vm_id: &str) -> Result<()> {
-        let vm = self.db.get_vm(vm_id)?
-            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+    /// Boot the build VM, run the steps, shut it down and merge its disk into `output`
+    fn run_build(
+        &self,
+        spec: &BuildSpec,
+        vm: &VM,
+        output: &Path,
+        credential: Option<&GuestCredential>,
+        log: &mut BuildLog<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let timeout = spec.step_timeout();
+        let guest = || credential.ok_or_else(|| Error::NoGuestCredential(spec.template.clone()));
+        let vm_name = vm.name.as_str();
+        let disk_path = vm.vhdx_path.to_string_lossy();
 
-        if vm.state != VMState::Running {
-            return Err(Error::InvalidState {
-                current: vm.state.to_string(),
-                expected: "Running".to_string(),
-            });
+        // A step can take up to `timeout`, so each one races `cancel`
+        boot_build_vm(vm_name, timeout, log, cancel)?;
+
+        for (i, step) in spec.steps.iter().enumerate() {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            log.line(&format!("[{}/{}] {}", i + 1, spec.steps.len(), step));
+            match step {
+                BuildStep::Copy { from, to } => {
+                    let from = from.to_string_lossy();
+                    runtime::block_on(until_cancelled(cancel, HyperV::copy_to_guest_async(vm_name, &from, to)))?;
+                }
+                BuildStep::Reboot => {
+                    runtime::block_on(until_cancelled(cancel, HyperV::stop_vm_async(vm_name, false)))?;
+                    boot_build_vm(vm_name, timeout, log, cancel)?;
+                }
+                _ => {
+                    let script = step.guest_script().unwrap_or_default();
+                    let invoke = HyperV::invoke_in_guest_async(vm_name, guest()?, &script, timeout);
+                    let output = runtime::block_on(until_cancelled(cancel, invoke))?;
+                    log.line(&output.stdout);
+                    log.line(&output.stderr);
+                    if output.exit_code != 0 {
+                        return Err(Error::Other(format!(
+                            "Step {} ({}) exited with {}",
+                            i + 1,
+                            step,
+                            output.exit_code
+                        )));
+                    }
+                }
+            }
         }
 
-        tracing::info!(vm = %vm.name, "Saving VM state");
-        HyperV::save_vm(&vm.name)?;
-        self.db.update_vm_state(vm_id, VMState::Saved)?;
-        self.db.update_vm_agent(vm_id, None)?;
+        if spec.generalize {
+            log.line("Generalizing with sysprep");
+            runtime::block_on(until_cancelled(cancel, async {
+                HyperV::invoke_in_guest_async(vm_name, guest()?, SYSPREP_SCRIPT, timeout).await?;
+                HyperV::wait_for_off_async(vm_name, timeout).await
+            }))?;
+        } else {
+            log.line("Shutting down");
+            runtime::block_on(until_cancelled(cancel, HyperV::stop_vm_async(vm_name, false)))?;
+        }
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
 
+        log.line(&format!("Merging into {}", output.display()));
+        let output_path = output.to_string_lossy();
+        runtime::block_on(until_cancelled(cancel, HyperV::convert_vhd_async(&disk_path, &output_path)))?;
+        if vhdx::inspect(output)?.disk_type == vhdx::DiskType::Differencing {
+            return Err(Error::InvalidVhdx(format!("{} still has a parent after merging", output.display())));
+        }
         Ok(())
     }
 
-    /// Reset VM to clean checkpoint
-    pub fn reset_vm(&self, vm_id: &str) -> Result<()> {
+    // ===== Template Capture =====
+
+    /// Turn a VM's current disk into a new template: shut the VM down, merge
+    /// its differencing chain into a standalone VHDX, checksum and register it.
+    /// The new template inherits the source template's login and probes.
+    pub fn capture_template(&self, vm_id: &str, name: &str, options: &CaptureOptions) -> Result<Template> {
+        if self.db.get_template_by_name(name)?.is_some() {
+            return Err(Error::Other(format!("Template already exists: {}", name)));
+        }
+        let _busy = self.mark_busy(vm_id);
         let vm = self.db.get_vm(vm_id)?
             .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+        // A Saved VM's disk misses whatever the guest hadn't flushed
+        if options.keep_source && vm.state != VMState::Off {
+            return Err(Error::InvalidState {
+                current: vm.state.to_string(),
+                expected: "Off".to_string(),
+            });
+        }
+        if !options.keep_source && vm.is_leased() {
+            return Err(Error::InvalidState {
+                current: "leased".to_string(),
+                expected: "unleased".to_string(),
+            });
+        }
 
-        tracing::info!(vm = %vm.name, "Resetting VM to clean checkpoint");
-
-        // Stop if running
-        if vm.state == VMState::Running {
-            HyperV::turn_off_vm(&vm.name)?;
+        let source = match &vm.template_id {
+            Some(id) => self.db.get_template(id)?,
+            None => None,
+        };
+        let output = options.output.clone().unwrap_or_else(|| {
+            let dir = source.as_ref()
+                .and_then(|t| t.vhdx_path.parent().map(Path::to_path_buf))
+                .unwrap_or_else(|| self.config.vm_storage_path.clone());
+            dir.join(format!("{}.vhdx", name))
+        });
+        if output.exists() {
+            return Err(Error::Other(format!("Capture output already exists: {:?}", output)));
         }
 
-        HyperV::restore_checkpoint(&vm.name, "clean")?;
-        self.db.update_vm_state(vm_id, VMState::Off)?;
-        self.db.update_vm_agent(vm_id, None)?;
-        self.db.update_vm_ip(vm_id, None)?;
+        tracing::info!(vm = %vm.name, template = %name, keep_source = options.keep_source, "Capturing VM as template");
+        let job = self.start_job(JobKind::Capture, &vm.name);
+        let result = self.run_capture(&vm, &output, options.keep_source, &job.cancel);
+        job.finish(&result);
+        if let Err(e) = result {
+            if output.exists() {
+                std::fs::remove_file(&output)?;
+            }
+            return Err(e);
+        }
 
-        Ok(())
+        let mut template = Template::new(name, &output)
+            .with_memory(vm.memory_mb)
+            .with_cpus(vm.cpu_count);
+        if let Some(source) = source {
+            template.guest_credential = source.guest_credential;
+            template.readiness_probes = source.readiness_probes;
+            template.description = Some(format!("Captured from {}", vm.name));
+        }
+        self.register_template_with(template, options.notes.clone(), None, &|_, _| {})?;
+        self.template_by_name(name)
     }
 
-    /// Stop VM
-    pub fn stop_vm(&self, vm_id: &str, force: bool) -> Result<()> {
-        let vm = self.db.get_vm(vm_id)?
-            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
-
-        tracing::info!(vm = %vm.name, force = force, "Stopping VM");
+    fn run_capture(&self, vm: &VM, output: &Path, keep_source: bool, cancel: &CancellationToken) -> Result<()> {
+        // With checkpoints the VM writes to an .avhdx child rather than its own disk
+        let disk = HyperV::list_vm_disks()?
+            .into_iter()
+            .find(|d| d.vm_name == vm.name)
+            .map(|d| PathBuf::from(d.path))
+            .unwrap_or_else(|| vm.vhdx_path.clone());
+        let merge = |from: &Path, to: &Path| {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let (from, to) = (from.to_string_lossy(), to.to_string_lossy());
+            runtime::block_on(until_cancelled(cancel, HyperV::convert_vhd_async(&from, &to)))
+        };
 
-        if force {
-            HyperV::turn_off_vm(&vm.name)?;
+        if keep_source {
+            merge_copy(&disk, output, merge)?;
         } else {
-            HyperV::stop_vm(&vm.name, true)?;
+            self.shut_down_for_capture(vm, cancel)?;
+            merge(&disk, output)?;
         }
 
-        self.db.update_vm_state(vm_id, VMState::Off)?;
+        if vhdx::inspect(output)?.disk_type == vhdx::DiskType::Differencing {
+            return Err(Error::InvalidVhdx(format!("{} still has a parent after merging", output.display())));
+        }
         Ok(())
     }
 
-    /// Delete VM completely
-    pub fn delete_vm(&self, vm_id: &str) -> Result<()> {
-        let vm = self.db.get_vm(vm_id)?
-            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
-
-        tracing::info!(vm = %vm.name, "Deleting VM");
-
-        // Stop if running
-        if vm.state == VMState::Running || vm.state == VMState::Saved {
-            let _ = HyperV::turn_off_vm(&vm.name);
+    /// Bring a VM to Off through a guest shutdown so its disk is consistent.
+    /// Saved and paused VMs are resumed first rather than having their memory discarded.
+    fn shut_down_for_capture(&self, vm: &VM, cancel: &CancellationToken) -> Result<()> {
+        match vm.state {
+            VMState::Off => return Ok(()),
+            VMState::Running => {}
+            _ => {
+                runtime::block_on(until_cancelled(cancel, async {
+                    HyperV::start_vm_async(&vm.name).await?;
+                    HyperV::wait_for_heartbeat_async(&vm.name, self.config.ready_timeout).await
+                }))?;
+            }
         }
+        runtime::block_on(until_cancelled(cancel, HyperV::stop_vm_async(&vm.name, true)))?;
+        self.db.update_vm_state(&vm.id, VMState::Off)?;
+        self.db.update_vm_ip(&vm.id, None)?;
+        Ok(())
+    }
 
-        // Remove from Hyper-V
-        let _ = HyperV::remove_vm(&vm.name);
+    // ===== Pool Operations =====
 
-        // Delete VHDX
-        if vm.vhdx_path.exists() {
-            std::fs::remove_file(&vm.vhdx_path)?;
+    /// Create a VM pool
+    pub fn create_pool(&self, pool: VMPool) -> Result<String> {
+        // Verify template (and pinned version) exists
+        let template = self.db.get_template(&pool.template_id)?
+            .ok_or_else(|| Error::TemplateNotFound(pool.template_id.clone()))?;
+        if let Some(version) = pool.template_version {
+            if self.db.get_template_version(&template.id, version)?.is_none() {
+                return Err(Error::TemplateVersionNotFound { template: template.name, version });
+            }
         }
 
-        // Delete VM directory
-        if let Some(parent) = vm.vhdx_path.parent() {
-            let _ = std::fs::remove_dir_all(parent);
-        }
+        let id = pool.id.clone();
+        self.db.insert_pool(&pool)?;
+        tracing::info!(pool = %pool.name, id = %id, "Pool created");
+        Ok(id)
+    }
 
-        // Remove from DB
-        self.db.delete_vm(vm_id)?;
+    /// Delete a pool. Its VMs are deleted first with `cascade`; otherwise
+    /// having any is an error. Leased VMs are never deleted from under an agent.
+    pub fn delete_pool(&self, pool_id: &str, cascade: bool) -> Result<()> {
+        let pool = self.db.get_pool(pool_id)?
+            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
+        let vms = self.db.list_vms_by_pool(&pool.id)?;
+        if !vms.is_empty() {
+            if !cascade {
+                return Err(Error::InUse {
+                    resource: format!("Pool {}", pool.name),
+                    dependents: vms.iter().map(|v| format!("VM {}", v.name)).collect(),
+                });
+            }
+            refuse_leased(&vms)?;
+            for vm in &vms {
+                self.delete_vm(&vm.id)?;
+            }
+        }
 
+        self.db.delete_pool(&pool.id)?;
+        tracing::info!(pool = %pool.name, cascade, "Pool deleted");
         Ok(())
     }
 
-    /// Open VM console
-    pub fn open_console(&self, vm_id: &str) -> Result<()> {
+    /// List all pools
+    pub fn list_pools(&self) -> Result<Vec<VMPool>> {
+        self.db.list_pools()
+    }
+
+    /// Get pool status
+    pub fn get_pool_status(&self, pool_id: &str) -> Result<PoolStatus> {
+        let pool = self.db.get_pool(pool_id)?
+            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
+
+        let vms = self.db.list_vms_by_pool(pool_id)?;
+
+        Ok(PoolStatus {
+            id: pool.id.clone(),
+            name: pool.name.clone(),
+            template_id: pool.template_id.clone(),
+            desired_count: pool.desired_count,
+            total_vms: vms.len(),
+            running_vms: vms.iter().filter(|v| v.state == VMState::Running).count(),
+            saved_vms: vms.iter().filter(|v| v.state == VMState::Saved).count(),
+            off_vms: vms.iter().filter(|v| v.state == VMState::Off).count(),
+            error_vms: vms.iter().filter(|v| v.state == VMState::Error).count(),
+            unhealthy_vms: vms.iter().filter(|v| v.health == VMHealth::Unhealthy).count(),
+            idle_vms: vms.iter().filter(|v| v.state == VMState::Running && v.current_agent_id.is_none()).count(),
+            reclaimed_vms: self.db.count_events(EventKind::VMIdleReclaimed, pool_id)?,
+            disk_bytes: vms.iter().filter_map(|v| v.disk_bytes).sum(),
+            saved_state_bytes: vms.iter().filter_map(|v| v.saved_state_bytes).sum(),
+            over_disk_limit_vms: vms.iter().filter(|v| v.disk_bytes.is_some_and(|b| pool.disk_over_limit(b))).count(),
+        })
+    }
+
+    /// Provision VMs for a pool (create them, not yet booted). All or nothing:
+    /// if one VM fails, the ones created before it are deleted again.
+    pub fn provision_pool(&self, pool_id: &str, count: usize) -> Result<Vec<String>> {
+        let result = self.provision(pool_id, &ProvisionOptions::new(count).atomic())?;
+        if result.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if let Some(failed) = result.failures().next() {
+            return Err(Error::Other(format!(
+                "Failed to provision {}: {}",
+                failed.name,
+                failed.error.as_deref().unwrap_or("unknown error")
+            )));
+        }
+        Ok(result.created_ids())
+    }
+
+    /// Provision a batch of VMs, reporting what happened to each one
+    pub fn provision(&self, pool_id: &str, options: &ProvisionOptions) -> Result<ProvisionResult> {
+        self.provision_with_progress(pool_id, options, &|_| {})
+    }
+
+    /// Provision a batch of VMs on up to `max_parallel` workers
+    pub fn provision_with_progress(
+        &self,
+        pool_id: &str,
+        options: &ProvisionOptions,
+        on_progress: ProgressCallback<'_>,
+    ) -> Result<ProvisionResult> {
+        let pool = self.db.get_pool(pool_id)?
+            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
+
+        let template = self.pool_template(&pool)?;
+        self.ensure_template_intact(&template)?;
+
+        let job = self.start_job(JobKind::Provision, &pool.name);
+        let result = self.provision_idempotent(&pool, &template, options, on_progress, &job.cancel);
+        match &result {
+            Ok(partial) if partial.is_cancelled() => job.finish::<()>(&Err(Error::Cancelled)),
+            _ => job.finish(&result),
+        }
+        result
+    }
+
+    fn provision_idempotent(
+        &self,
+        pool: &VMPool,
+        template: &Template,
+        options: &ProvisionOptions,
+        on_progress: ProgressCallback<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<ProvisionResult> {
+        let Some(key) = options.idempotency_key.as_deref() else {
+            return self.provision_batch(pool, template, options, on_progress, cancel);
+        };
+
+        if !self.db.insert_provision_request(key, &pool.id)? {
+            let (owner, result) = self.db.get_provision_request(key)?
+                .ok_or_else(|| Error::IdempotencyConflict(key.to_string()))?;
+            if owner != pool.id {
+                return Err(Error::IdempotencyConflict(format!("{} was used for another pool", key)));
+            }
+            return result.ok_or_else(|| Error::IdempotencyConflict(format!("{} is still in progress", key)));
+        }
+
+        match self.provision_batch(pool, template, options, on_progress, cancel) {
+            Ok(result) => {
+                self.db.complete_provision_request(key, &result)?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.db.delete_provision_request(key)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn provision_batch(
+        &self,
+        pool: &VMPool,
+        template: &Template,
+        options: &ProvisionOptions,
+        on_progress: ProgressCallback<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<ProvisionResult> {
+        let mut result = ProvisionResult::new(&pool.id, options.idempotency_key.clone());
+
+        // Names are reserved up front so parallel workers never race for an index
+        let names = (0..options.count)
+            .map(|_| Ok(format!("{}-{}", pool.name, self.db.allocate_vm_index(&pool.id)?)))
+            .collect::<Result<Vec<String>>>()?;
+
+        if names.is_empty() {
+            return Ok(result);
+        }
+
+        // Sized like prepare, which usually follows and boots the same VMs
+        let workers = self.memory_workers(template.memory_mb)?;
+        let stop = AtomicBool::new(false);
+        let outcomes = fan_out(&names, workers, &stop, |vm_name| {
+            let entry = self.provision_one(template, pool, vm_name, on_progress, cancel);
+            if cancel.is_cancelled() || (options.atomic && entry.outcome == ProvisionOutcome::Failed) {
+                stop.store(true, Ordering::SeqCst);
+            }
+            entry
+        });
+        // Names skipped after an atomic batch failed are simply left unused;
+        // after a cancel they are reported so the caller sees the whole batch
+        result.vms = names.iter().zip(outcomes)
+            .filter_map(|(name, entry)| match entry {
+                Some(entry) => Some(entry),
+                None if cancel.is_cancelled() => Some(ProvisionedVM {
+                    name: name.clone(),
+                    vm_id: None,
+                    outcome: ProvisionOutcome::Cancelled,
+                    error: None,
+                }),
+                None => None,
+            })
+            .collect();
+
+        if options.atomic && !result.is_success() {
+            for entry in result.vms.iter_mut().filter(|v| v.outcome == ProvisionOutcome::Created) {
+                let Some(vm_id) = entry.vm_id.clone() else { continue };
+                match self.delete_vm(&vm_id) {
+                    Ok(()) => entry.outcome = ProvisionOutcome::RolledBack,
+                    Err(e) => {
+                        tracing::error!(vm = %entry.name, error = %e, "Failed to roll back provisioned VM");
+                        entry.error = Some(format!("rollback failed: {}", e));
+                    }
+                }
+            }
+        }
+
+        // Each cancelled VM was already rolled back by its journal
+        Ok(result)
+    }
+
+    fn provision_one(
+        &self,
+        template: &Template,
+        pool: &VMPool,
+        vm_name: &str,
+        on_progress: ProgressCallback<'_>,
+        cancel: &CancellationToken,
+    ) -> ProvisionedVM {
+        let vhdx_path = self.config.vm_storage_path.join(vm_name).join("disk.vhdx");
+        let mut vm = VM::new(vm_name.to_string(), vhdx_path, template.memory_mb, template.cpu_count);
+        vm.template_id = Some(template.id.clone());
+        vm.template_version = Some(template.current_version);
+        vm.pool_id = Some(pool.id.clone());
+        vm.gpu_enabled = template.gpu_enabled;
+        let vm_id = vm.id.clone();
+
+        // A failed VM's own disk and Hyper-V VM are undone by the journal
+        let mut steps = OperationSteps::new(self, OperationKind::Provision, vm)
+            .with_parent(template.vhdx_path.clone())
+            .with_progress(on_progress)
+            .with_cancel(cancel);
+        let outcome = self.run_journaled(&mut steps);
+        on_progress(&Progress::finished(vm_name, "provision", &outcome));
+
+        match outcome {
+            Ok(()) => {
+                tracing::info!(vm = %vm_name, "VM created (not yet booted)");
+                ProvisionedVM {
+                    name: vm_name.to_string(),
+                    vm_id: Some(vm_id),
+                    outcome: ProvisionOutcome::Created,
+                    error: None,
+                }
+            }
+            Err(Error::Cancelled) => ProvisionedVM {
+                name: vm_name.to_string(),
+                vm_id: None,
+                outcome: ProvisionOutcome::Cancelled,
+                error: None,
+            },
+            Err(e) => {
+                tracing::warn!(vm = %vm_name, error = %e, "Failed to provision VM");
+                ProvisionedVM {
+                    name: vm_name.to_string(),
+                    vm_id: None,
+                    outcome: ProvisionOutcome::Failed,
+                    error: Some(e.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Prepare every Off VM in a pool, several at a time as host memory allows
+    pub fn prepare_pool(&self, pool_id: &str, on_progress: ProgressCallback<'_>) -> Result<Vec<PreparedVM>> {
+        let pool = self.db.get_pool(pool_id)?
+            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
+
+        let vms: Vec<VM> = self.db.list_vms_by_pool(&pool.id)?
+            .into_iter()
+            .filter(|v| v.state == VMState::Off)
+            .collect();
+        if vms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let largest = vms.iter().map(|v| v.memory_mb).max().unwrap_or(0);
+        let workers = self.memory_workers(largest)?;
+        tracing::info!(pool = %pool.name, vms = vms.len(), workers, "Preparing pool");
+
+        let job = self.start_job(JobKind::Prepare, &pool.name);
+        let stop = AtomicBool::new(false);
+        let outcomes = fan_out(&vms, workers, &stop, |vm| {
+            let outcome = self.prepare_vm_with(vm, on_progress, &job.cancel);
+            on_progress(&Progress::finished(&vm.name, "prepare", &outcome));
+            if job.cancel.is_cancelled() {
+                stop.store(true, Ordering::SeqCst);
+            }
+            PreparedVM {
+                name: vm.name.clone(),
+                vm_id: vm.id.clone(),
+                error: outcome.err().map(|e| e.to_string()),
+            }
+        });
+
+        let result = if job.cancel.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(outcomes.into_iter().flatten().collect())
+        };
+        job.finish(&result);
+        result
+    }
+
+    /// How many VMs of `memory_mb` to work on at once: `max_parallel`, fewer
+    /// if host memory is short
+    fn memory_workers(&self, memory_mb: u64) -> Result<usize> {
+        let memory_mb = memory_mb.max(1);
+        match HyperV::get_host_available_memory_mb() {
+            Ok(available) if available < memory_mb => Err(Error::InsufficientMemory {
+                required: memory_mb,
+                available,
+            }),
+            Ok(available) => Ok(((available / memory_mb) as usize).clamp(1, self.config.max_parallel.max(1))),
+            Err(e) => {
+                tracing::warn!(error = %e, "Couldn't read host memory, using max_parallel");
+                Ok(self.config.max_parallel.max(1))
+            }
+        }
+    }
+
+    /// Move a pool to another template version, replacing its VMs a batch at a time.
+    ///
+    /// Each round provisions and prepares new VMs before deleting as many idle old
+    /// ones, never leaving fewer than `warm_count` available. A new VM failing to
+    /// provision or become ready pauses the upgrade; running it again carries on.
+    /// Leased old VMs are left for a later run.
+    pub fn upgrade_pool(
+        &self,
+        pool_id: &str,
+        options: &UpgradeOptions,
+        on_progress: ProgressCallback<'_>,
+    ) -> Result<UpgradeResult> {
+        let mut pool = self.db.get_pool(pool_id)?
+            .ok_or_else(|| Error::PoolNotFound(pool_id.to_string()))?;
+        pool.template_version = options.template_version;
+        let target = self.pool_template(&pool)?.current_version;
+
+        // New VMs, including ones provisioned outside the upgrade, come from the target
+        self.db.update_pool_template_version(&pool.id, options.template_version)?;
+        tracing::info!(pool = %pool.name, version = target, "Upgrading pool");
+
+        let job = self.start_job(JobKind::Upgrade, &pool.name);
+        let result = self.upgrade_rounds(&pool, target, options.batch_size.max(1), on_progress, &job.cancel);
+        job.finish(&result);
+        result
+    }
+
+    fn upgrade_rounds(
+        &self,
+        pool: &VMPool,
+        target: u32,
+        batch_size: usize,
+        on_progress: ProgressCallback<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<UpgradeResult> {
+        let mut result = UpgradeResult::new(&pool.id, target);
+        loop {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let vms = self.db.list_vms_by_pool(&pool.id)?;
+            let (idle, leased): (Vec<&VM>, Vec<&VM>) = vms.iter()
+                .filter(|v| is_outdated(v, target))
+                .partition(|v| !v.is_leased() && !self.is_busy(&v.id));
+            result.pending = leased.iter().map(|v| v.name.clone()).collect();
+            if idle.is_empty() {
+                break;
+            }
+
+            // New VMs first, so the pool only shrinks back once they are ready
+            let batch = upgrade_batch(vms.len(), pool.desired_count, batch_size, idle.len());
+            let provisioned = match batch {
+                0 => ProvisionResult::new(&pool.id, None),
+                n => self.provision_with_progress(&pool.id, &ProvisionOptions::new(n), on_progress)?,
+            };
+            if let Some(failed) = provisioned.failures().next() {
+                let reason = format!(
+                    "{} failed to provision: {}",
+                    failed.name,
+                    failed.error.as_deref().unwrap_or("unknown error")
+                );
+                return self.pause_upgrade(pool, result, reason);
+            }
+            for id in provisioned.created_ids() {
+                let vm = self.db.get_vm(&id)?
+                    .ok_or_else(|| Error::VMNotFound(id.clone()))?;
+                let outcome = self.prepare_vm_with(&vm, on_progress, cancel);
+                on_progress(&Progress::finished(&vm.name, "prepare", &outcome));
+                match outcome {
+                    Ok(()) => result.created.push(vm.name),
+                    Err(_) if cancel.is_cancelled() => return Err(Error::Cancelled),
+                    Err(e) => {
+                        let reason = format!("{} failed readiness: {}", vm.name, e);
+                        return self.pause_upgrade(pool, result, reason);
+                    }
+                }
+            }
+
+            let vms = self.db.list_vms_by_pool(&pool.id)?;
+            let retired_before = result.replaced.len();
+            for old in pick_retirees(&vms, target, pool.warm_count, batch_size.min(idle.len())) {
+                if self.is_busy(&old.id) {
+                    continue;
+                }
+                let _busy = self.mark_busy(&old.id);
+                // Acquired since the pool was listed
+                if self.db.get_vm(&old.id)?.is_none_or(|v| v.is_leased()) {
+                    continue;
+                }
+                self.delete_vm(&old.id)?;
+                on_progress(&Progress::finished(&old.name, "retire", &Ok(())));
+                result.replaced.push(old.name.clone());
+            }
+            // Another round would only add VMs
+            if result.replaced.len() == retired_before {
+                return self.pause_upgrade(pool, result, "no outdated VM could be retired".to_string());
+            }
+        }
+
+        tracing::info!(
+            pool = %pool.name,
+            created = result.created.len(),
+            replaced = result.replaced.len(),
+            pending = result.pending.len(),
+            "Pool upgrade finished"
+        );
+        Ok(result)
+    }
+
+    fn pause_upgrade(&self, pool: &VMPool, mut result: UpgradeResult, reason: String) -> Result<UpgradeResult> {
+        self.record_event(Event::new(EventKind::UpgradePaused, format!(
+            "Upgrade of {} to v{} paused: {}",
+            pool.name, result.target_version, reason
+        )).for_pool(pool))?;
+        result.paused = Some(reason);
+        Ok(result)
+    }
+
+    /// Boot a VM, create checkpoint, and save state (makes it ready for fast resume)
+    pub fn prepare_vm(&self, vm_id: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        let job = self.start_job(JobKind::Prepare, &vm.name);
+        let result = self.prepare_vm_with(&vm, &|_| {}, &job.cancel);
+        job.finish(&result);
+        result
+    }
+
+    fn prepare_vm_with(&self, vm: &VM, on_progress: ProgressCallback<'_>, cancel: &CancellationToken) -> Result<()> {
+        let _busy = self.mark_busy(&vm.id);
+
+        // Whatever a cancelled operation left behind must not end up in the checkpoint
+        if vm.needs_recycle {
+            if let Err(e) = self.reset_vm_with(vm, cancel) {
+                tracing::warn!(vm = %vm.name, error = %e, "No clean checkpoint to recycle from, preparing as is");
+            }
+        }
+
+        let mut steps = OperationSteps::new(self, OperationKind::Prepare, vm.clone())
+            .with_progress(on_progress)
+            .with_cancel(cancel);
+        let result = self.run_journaled(&mut steps);
+        self.after_cancel(vm, JobKind::Prepare, cancel, result)?;
+
+        if vm.needs_recycle {
+            self.db.update_vm_recycle(&vm.id, false)?;
+        }
+        tracing::info!(vm = %vm.name, "VM ready for fast resume");
+        Ok(())
+    }
+
+    // ===== VM Operations =====
+
+    /// List all VMs
+    pub fn list_vms(&self) -> Result<Vec<VM>> {
+        self.db.list_vms()
+    }
+
+    /// Get VM by name
+    pub fn get_vm(&self, name: &str) -> Result<Option<VM>> {
+        self.db.get_vm_by_name(name)
+    }
+
+    /// Resume a saved VM (fast, 2-5 seconds). The IP is None when the
+    /// template's probes don't report one (heartbeat or script only).
+    pub fn resume_vm(&self, vm_id: &str) -> Result<Option<String>> {
+        runtime::block_on(self.resume_vm_async(vm_id))
+    }
+
+    /// Async `resume_vm`. Cancelling its job turns the VM off for recycling;
+    /// if the future is merely dropped the VM may be left Running, and the
+    /// health monitor and reconciler pick it up from there.
+    pub async fn resume_vm_async(&self, vm_id: &str) -> Result<Option<String>> {
+        self.resume_vm_with(vm_id, |vm| async move { self.start_saved_vm(&vm).await }).await
+    }
+
+    /// `resume_vm_async` with the Hyper-V part supplied by the caller
+    async fn resume_vm_with<F>(&self, vm_id: &str, start: impl FnOnce(VM) -> F) -> Result<Option<String>>
+    where
+        F: Future<Output = Result<Option<String>>>,
+    {
+        if self.is_shutting_down() {
+            return Err(Error::ShuttingDown);
+        }
+        let _busy = self.mark_busy(vm_id);
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        if vm.state != VMState::Saved {
+            return Err(Error::InvalidState {
+                current: vm.state.to_string(),
+                expected: "Saved".to_string(),
+            });
+        }
+
+        self.run_vm_job_async(JobKind::Resume, &vm, start(vm.clone())).await
+    }
+
+    async fn start_saved_vm(&self, vm: &VM) -> Result<Option<String>> {
+        let vm_id = vm.id.as_str();
+        let start = std::time::Instant::now();
+        tracing::info!(vm = %vm.name, "Resuming VM");
+
+        HyperV::start_vm_async(&vm.name).await?;
+        self.db.update_vm_state(vm_id, VMState::Running)?;
+        self.db.update_vm_resumed(vm_id)?;
+
+        // Wait for ready
+        let ip = self.wait_until_ready_async(vm, Duration::from_secs(30)).await?;
+        self.db.update_vm_ip(vm_id, ip.as_deref())?;
+
+        let elapsed = start.elapsed();
+        tracing::info!(vm = %vm.name, elapsed_ms = elapsed.as_millis(), ip = ip.as_deref().unwrap_or("-"), "VM resumed");
+
+        Ok(ip)
+    }
+
+    /// Save VM state (for fast resume later)
+    pub fn save_vm(&self, vm_id: &str) -> Result<()> {
+        runtime::block_on(self.save_vm_async(vm_id))
+    }
+
+    pub async fn save_vm_async(&self, vm_id: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        if vm.state != VMState::Running {
+            return Err(Error::InvalidState {
+                current: vm.state.to_string(),
+                expected: "Running".to_string(),
+            });
+        }
+
+        tracing::info!(vm = %vm.name, "Saving VM state");
+        HyperV::save_vm_async(&vm.name).await?;
+        self.db.update_vm_state(vm_id, VMState::Saved)?;
+        self.db.release_vm_lease(vm_id)?;
+
+        Ok(())
+    }
+
+    /// Reset VM to clean checkpoint
+    pub fn reset_vm(&self, vm_id: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        let job = self.start_job(JobKind::Reset, &vm.name);
+        let result = self.reset_vm_with(&vm, &job.cancel);
+        let result = self.after_cancel(&vm, JobKind::Reset, &job.cancel, result);
+        job.finish(&result);
+        result
+    }
+
+    fn reset_vm_with(&self, vm: &VM, cancel: &CancellationToken) -> Result<()> {
+        let _busy = self.mark_busy(&vm.id);
+        tracing::info!(vm = %vm.name, "Resetting VM to clean checkpoint");
+        self.run_journaled(&mut OperationSteps::new(self, OperationKind::Reset, vm.clone()).with_cancel(cancel))
+    }
+
+    /// Stop VM
+    pub fn stop_vm(&self, vm_id: &str, force: bool) -> Result<()> {
+        runtime::block_on(self.stop_vm_async(vm_id, force))
+    }
+
+    pub async fn stop_vm_async(&self, vm_id: &str, force: bool) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        tracing::info!(vm = %vm.name, force = force, "Stopping VM");
+
+        if force {
+            HyperV::turn_off_vm_async(&vm.name).await?;
+        } else {
+            HyperV::stop_vm_async(&vm.name, true).await?;
+        }
+
+        self.db.update_vm_state(vm_id, VMState::Off)?;
+        Ok(())
+    }
+
+    /// Delete VM completely
+    pub fn delete_vm(&self, vm_id: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        tracing::info!(vm = %vm.name, "Deleting VM");
+        self.run_journaled(&mut OperationSteps::new(self, OperationKind::Delete, vm))
+    }
+
+    /// Run a PowerShell script inside a running VM via PowerShell Direct
+    pub fn exec_vm(&self, vm_id: &str, script: &str, timeout: Duration) -> Result<GuestOutput> {
+        runtime::block_on(self.exec_vm_async(vm_id, script, timeout))
+    }
+
+    /// Async `exec_vm`; dropping the future kills the guest session
+    pub async fn exec_vm_async(&self, vm_id: &str, script: &str, timeout: Duration) -> Result<GuestOutput> {
+        let vm = self.get_running_vm(vm_id)?;
+        let credential = self.guest_credential(&vm)?;
+
+        self.db.touch_vm(vm_id)?;
+        tracing::info!(vm = %vm.name, "Executing script in guest");
+        let run = HyperV::invoke_in_guest_async(&vm.name, &credential, script, timeout);
+        let output = self.run_vm_job_async(JobKind::Exec, &vm, run).await?;
+        tracing::info!(vm = %vm.name, exit_code = output.exit_code, "Guest script finished");
+
+        Ok(output)
+    }
+
+    /// Copy a host file into a running VM
+    pub fn push_file(&self, vm_id: &str, host_path: &Path, guest_path: &str) -> Result<FileTransfer> {
+        let vm = self.get_running_vm(vm_id)?;
+
+        let size = std::fs::metadata(host_path)?.len();
+        self.check_transfer_size(size)?;
+        let (size_bytes, sha256) = checksum::sha256_file(host_path)?;
+
+        self.db.touch_vm(vm_id)?;
+        tracing::info!(vm = %vm.name, guest_path = %guest_path, size_bytes, "Copying file into guest");
+        let source = host_path.to_string_lossy();
+        runtime::block_on(self.run_transfer_job_async(&vm, HyperV::copy_to_guest_async(&vm.name, &source, guest_path)))?;
+
+        Ok(FileTransfer {
+            vm_name: vm.name,
+            direction: TransferDirection::ToGuest,
+            guest_path: guest_path.to_string(),
+            size_bytes,
+            sha256,
+        })
+    }
+
+    /// Copy a file out of a running VM to the host
+    pub fn pull_file(&self, vm_id: &str, guest_path: &str, host_path: &Path) -> Result<FileTransfer> {
+        let vm = self.get_running_vm(vm_id)?;
+        let credential = self.guest_credential(&vm)?;
+
+        self.db.touch_vm(vm_id)?;
+        let destination = host_path.to_string_lossy();
+        let copy = async {
+            // Refuse before copying, then again after in case the file grew meanwhile
+            self.check_transfer_size(HyperV::guest_file_size_async(&vm.name, &credential, guest_path).await?)?;
+            tracing::info!(vm = %vm.name, guest_path = %guest_path, "Copying file out of guest");
+            HyperV::copy_from_guest_async(&vm.name, &credential, guest_path, &destination).await
+        };
+        let copied = runtime::block_on(self.run_transfer_job_async(&vm, copy))
+            .and_then(|()| self.check_transfer_size(std::fs::metadata(host_path)?.len()));
+        if let Err(e) = copied {
+            let _ = std::fs::remove_file(host_path);
+            return Err(e);
+        }
+        let (size_bytes, sha256) = checksum::sha256_file(host_path)?;
+
+        Ok(FileTransfer {
+            vm_name: vm.name,
+            direction: TransferDirection::FromGuest,
+            guest_path: guest_path.to_string(),
+            size_bytes,
+            sha256,
+        })
+    }
+
+    /// Run a file copy as a cancellable job, killing it after `transfer_timeout`
+    async fn run_transfer_job_async(&self, vm: &VM, copy: impl Future<Output = Result<()>>) -> Result<()> {
+        let job = self.start_job(JobKind::Transfer, &vm.name);
+        let timed = async {
+            tokio::time::timeout(self.config.transfer_timeout, copy)
+                .await
+                .map_err(|_| Error::Timeout)?
+        };
+        let result = until_cancelled(&job.cancel, timed).await;
+        job.finish(&result);
+        result
+    }
+
+    /// Capture the VM console as PNG (host-side, no guest agent involved)
+    pub fn screenshot(&self, vm_id: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+        runtime::block_on(self.screenshot_async(vm_id, width, height))
+    }
+
+    pub async fn screenshot_async(&self, vm_id: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+        hyperv::check_screenshot_size(width, height)?;
+        let vm = self.get_running_vm(vm_id)?;
+        let raw = HyperV::capture_screenshot_async(&vm.name, width, height).await?;
+        hyperv::rgb565_to_png(&raw, width, height)
+    }
+
+    /// Fresh path for staging API uploads/downloads on the host
+    pub fn staging_path(&self) -> Result<PathBuf> {
+        let dir = self.config.vm_storage_path.join(".transfers");
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir.join(uuid::Uuid::new_v4().to_string()))
+    }
+
+    /// Reject transfers larger than the configured limit
+    pub fn check_transfer_size(&self, size: u64) -> Result<()> {
+        if size > self.config.max_transfer_bytes {
+            return Err(Error::FileTooLarge {
+                size,
+                limit: self.config.max_transfer_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn get_running_vm(&self, vm_id: &str) -> Result<VM> {
         let vm = self.db.get_vm(vm_id)?
             .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
 
-        HyperV::open_console(&vm.name)
+        if vm.state != VMState::Running {
+            return Err(Error::InvalidState {
+                current: vm.state.to_string(),
+                expected: "Running".to_string(),
+            });
+        }
+        Ok(vm)
+    }
+
+    /// Readiness probes for a VM, from its template (defaults if it has none)
+    pub fn readiness_probes(&self, vm: &VM) -> Result<(Vec<ReadinessProbe>, Option<GuestCredential>)> {
+        let template = match &vm.template_id {
+            Some(id) => self.db.get_template(id)?,
+            None => None,
+        };
+        Ok(match template {
+            Some(t) => (t.probes(), t.guest_credential),
+            None => (ReadinessProbe::defaults(), None),
+        })
+    }
+
+    fn wait_until_ready(&self, vm: &VM, timeout: Duration, cancel: &CancellationToken) -> Result<Option<String>> {
+        runtime::block_on(until_cancelled(cancel, self.wait_until_ready_async(vm, timeout)))
+    }
+
+    async fn wait_until_ready_async(&self, vm: &VM, timeout: Duration) -> Result<Option<String>> {
+        let (probes, credential) = self.readiness_probes(vm)?;
+        HyperV::wait_for_probes_async(&vm.name, &probes, credential.as_ref(), timeout).await
+    }
+
+    fn guest_credential(&self, vm: &VM) -> Result<GuestCredential> {
+        let template_id = vm.template_id.clone()
+            .ok_or_else(|| Error::NoGuestCredential(vm.name.clone()))?;
+        let template = self.db.get_template(&template_id)?
+            .ok_or(Error::TemplateNotFound(template_id))?;
+        template.guest_credential
+            .ok_or(Error::NoGuestCredential(template.name))
+    }
+
+    /// Open VM console
+    pub fn open_console(&self, vm_id: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        HyperV::open_console(&vm.name)
+    }
+
+    // ===== Shutdown and restart =====
+
+    /// Refuse new acquires and resumes; in-flight work carries on
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.cancel();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.is_cancelled()
+    }
+
+    /// Cancelled by `begin_shutdown`; background monitors stop on it
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutting_down.clone()
+    }
+
+    /// Persist lease state before exit. Leased VMs keep running and their
+    /// leases stay in the DB for `reattach_leases` on the next start.
+    /// Returns the number of leases left running.
+    pub fn shutdown(&self) -> Result<usize> {
+        self.begin_shutdown();
+
+        // Console recordings only live in memory; stopping saves their timelines
+        let recording: Vec<String> = self.recorders.lock().unwrap().keys().cloned().collect();
+        for vm_id in recording {
+            self.stop_recording(&vm_id)?;
+        }
+
+        // An acquire cut off by the drain never handed its VM out
+        let released = self.db.release_acquire_claims()?;
+        if released > 0 {
+            tracing::info!(count = released, "Released claims of interrupted acquires");
+        }
+
+        // Clients can't heartbeat while the server is down; their idle time
+        // counts from here rather than from their last call
+        let leases: Vec<VM> = self.db.list_vms()?.into_iter().filter(|v| v.state == VMState::Running).collect();
+        for vm in &leases {
+            self.db.touch_vm(&vm.id)?;
+        }
+        Ok(leases.len())
+    }
+
+    /// After a restart, pick up leases (Running VMs) that outlived the previous server
+    pub fn reattach_leases(&self) -> Result<Vec<String>> {
+        let host = HostSnapshot::capture(&self.config.vm_storage_path)?;
+        self.reattach_leases_with(&host)
+    }
+
+    /// `reattach_leases` against an already captured host snapshot
+    pub fn reattach_leases_with(&self, host: &HostSnapshot) -> Result<Vec<String>> {
+        let mut reattached = Vec::new();
+        for vm in self.db.list_vms()?.into_iter().filter(|v| v.state == VMState::Running) {
+            let actual = host.vms.iter()
+                .find(|h| h.name == vm.name)
+                .map(|h| VMState::from_hyperv_state(h.state));
+
+            if actual != Some(VMState::Running) {
+                let state = actual.unwrap_or(VMState::Error);
+                if let Some(agent_id) = &vm.current_agent_id {
+                    self.db.fail_agent(agent_id, &format!("VM {} was {} after a server restart", vm.name, state))?;
+                }
+                self.db.release_vm_lease(&vm.id)?;
+                self.db.update_vm_ip(&vm.id, None)?;
+                self.db.update_vm_state(&vm.id, state)?;
+                self.record_event(Event::new(EventKind::LeaseLost, format!("Found {} after restart", state)).for_vm(&vm))?;
+                continue;
+            }
+
+            let ip = host.addresses.iter()
+                .find(|a| a.vm_name == vm.name)
+                .and_then(|a| a.ipv4.clone());
+            if ip.is_some() && ip != vm.ip_address {
+                self.db.update_vm_ip(&vm.id, ip.as_deref())?;
+            }
+            if let Some(interval) = self.config.record_interval {
+                if let Err(e) = self.start_recording(&vm.id, interval) {
+                    tracing::warn!(vm = %vm.name, error = %e, "Failed to restart console recording");
+                }
+            }
+            self.record_event(Event::new(EventKind::LeaseReattached, "Still running after restart").for_vm(&vm))?;
+            reattached.push(vm.id);
+        }
+        Ok(reattached)
+    }
+
+    // ===== Jobs =====
+
+    /// Running and recently finished jobs, oldest first
+    pub fn list_jobs(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.lock().unwrap().values().map(|e| e.job.clone()).collect();
+        jobs.sort_by_key(|j| j.started_at);
+        jobs
+    }
+
+    pub fn get_job(&self, job_id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(job_id).map(|e| e.job.clone())
+    }
+
+    /// Ask a running job to stop. It finishes as Cancelled once its current
+    /// step notices, leaving its VM turned off and marked for recycling.
+    pub fn cancel_job(&self, job_id: &str) -> Result<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        let entry = jobs.get(job_id)
+            .ok_or_else(|| Error::JobNotFound(job_id.to_string()))?;
+        if !entry.job.is_finished() {
+            tracing::info!(job = %job_id, kind = %entry.job.kind, target = %entry.job.target, "Cancelling job");
+            entry.cancel.cancel();
+        }
+        Ok(entry.job.clone())
+    }
+
+    /// Cancel every running job; returns how many had not been cancelled yet
+    pub fn cancel_all_jobs(&self) -> usize {
+        let jobs = self.jobs.lock().unwrap();
+        let mut cancelled = 0;
+        for entry in jobs.values().filter(|e| !e.job.is_finished() && !e.cancel.is_cancelled()) {
+            entry.cancel.cancel();
+            cancelled += 1;
+        }
+        cancelled
+    }
+
+    fn start_job(&self, kind: JobKind, target: &str) -> JobGuard<'_> {
+        let job = Job::new(kind, target);
+        let id = job.id.clone();
+        let cancel = CancellationToken::new();
+        self.jobs.lock().unwrap().insert(id.clone(), JobEntry { job, cancel: cancel.clone() });
+        JobGuard { jobs: &self.jobs, id, cancel }
+    }
+
+    /// Run `fut` as a cancellable job on `vm`
+    async fn run_vm_job_async<T>(&self, kind: JobKind, vm: &VM, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let job = self.start_job(kind, &vm.name);
+        let result = until_cancelled(&job.cancel, fut).await;
+        let result = match result {
+            Err(_) if job.cancel.is_cancelled() => {
+                if let Err(e) = self.turn_off_for_recycle(vm, kind).await {
+                    tracing::warn!(vm = %vm.name, error = %e, "Failed to clean up after cancellation");
+                }
+                Err(Error::Cancelled)
+            }
+            other => other,
+        };
+        job.finish(&result);
+        result
+    }
+
+    /// If `cancel` fired, a failed operation leaves its VM turned off and
+    /// marked for recycling, and reports Cancelled
+    fn after_cancel(&self, vm: &VM, kind: JobKind, cancel: &CancellationToken, result: Result<()>) -> Result<()> {
+        match result {
+            Err(_) if cancel.is_cancelled() => {
+                if let Err(e) = runtime::block_on(self.turn_off_for_recycle(vm, kind)) {
+                    tracing::warn!(vm = %vm.name, error = %e, "Failed to clean up after cancellation");
+                }
+                Err(Error::Cancelled)
+            }
+            other => other,
+        }
+    }
+
+    async fn turn_off_for_recycle(&self, vm: &VM, kind: JobKind) -> Result<()> {
+        // A cancelled provision already rolled its VM back out of existence
+        let Some(vm) = self.db.get_vm(&vm.id)? else { return Ok(()) };
+
+        tracing::warn!(vm = %vm.name, kind = %kind, "Operation cancelled, turning VM off for recycling");
+        if let Err(e) = HyperV::turn_off_vm_async(&vm.name).await {
+            tracing::debug!(vm = %vm.name, error = %e, "Turn off after cancellation failed");
+        }
+        self.stop_recording(&vm.id)?;
+        if let Some(agent_id) = &vm.current_agent_id {
+            self.db.fail_agent(agent_id, &format!("{} on VM {} was cancelled", kind, vm.name))?;
+        }
+        self.db.update_vm_state(&vm.id, VMState::Off)?;
+        self.db.update_vm_ip(&vm.id, None)?;
+        self.db.release_vm_lease(&vm.id)?;
+        self.db.update_vm_recycle(&vm.id, true)?;
+        self.record_event(
+            Event::new(EventKind::OperationCancelled, format!("{} cancelled; turned off for recycling", kind)).for_vm(&vm),
+        )?;
+        Ok(())
+    }
+
+    // ===== Agent/Scheduling Operations =====
+
+    /// Acquire a VM from pool (resumes saved VM)
+    pub fn acquire_vm(&self, pool_id: &str) -> Result<VM> {
+        runtime::block_on(self.acquire_vm_async(pool_id))
+    }
+
+    pub async fn acquire_vm_async(&self, pool_id: &str) -> Result<VM> {
+        if self.is_shutting_down() {
+            return Err(Error::ShuttingDown);
+        }
+        // Concurrent acquires can find the same VM; only the one whose claim lands resumes it
+        let lease_id = format!("lease-{}", uuid::Uuid::new_v4());
+        let vm = loop {
+            let vm = self.db.find_available_vm_in_pool(pool_id)?
+                .ok_or(Error::NoVMAvailable)?;
+            if self.db.claim_vm(&vm.id, &lease_id)? {
+                break vm;
+            }
+        };
+        let claim = ClaimGuard { db: &self.db, vm_id: vm.id.clone(), lease_id };
+
+        self.resume_vm_async(&vm.id).await?;
+        claim.into_lease()?;
+
+        if let Some(interval) = self.config.record_interval {
+            if let Err(e) = self.start_recording(&vm.id, interval) {
+                tracing::warn!(vm = %vm.name, error = %e, "Failed to start console recording");
+            }
+        }
+
+        // Refresh VM info
+        self.db.get_vm(&vm.id)?
+            .ok_or_else(|| Error::VMNotFound(vm.id.clone()))
+    }
+
+    /// Release VM back to pool (ephemeral VMs are destroyed instead)
+    pub fn release_vm(&self, vm_id: &str, reset: bool) -> Result<()> {
+        let _busy = self.mark_busy(vm_id);
+        self.stop_recording(vm_id)?;
+
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+        if vm.ephemeral {
+            tracing::info!(vm = %vm.name, "Destroying released ephemeral VM");
+            return self.delete_vm(vm_id);
+        }
+
+        let pool = match &vm.pool_id {
+            Some(pool_id) => self.db.get_pool(pool_id)?,
+            None => None,
+        };
+        if let Some(pool) = &pool {
+            let disk_bytes = self.update_disk_usage(&vm, Some(pool), vm.saved_state_bytes)?;
+            if disk_bytes.is_some_and(|b| pool.disk_over_limit(b)) {
+                tracing::info!(vm = %vm.name, disk_bytes, "Disk over the pool limit, rebuilding on release");
+                self.db.release_vm_lease(vm_id)?;
+                let created = self.rebuild_vm(&vm, &pool.id)?;
+                self.record_event(
+                    Event::new(EventKind::VMRebuilt, format!(
+                        "Disk grew to {}MB; replaced by {}",
+                        disk_bytes.unwrap_or_default() >> 20,
+                        created.join(", ")
+                    )).for_vm(&vm),
+                )?;
+                return Ok(());
+            }
+        }
+
+        if reset {
+            self.reset_vm(vm_id)?;
+            // Re-prepare after reset
+            self.prepare_vm(vm_id)?;
+        } else {
+            self.save_vm(vm_id)?;
+        }
+
+        self.db.release_vm_lease(vm_id)?;
+        Ok(())
+    }
+
+    /// Record client activity on a leased VM so it isn't reclaimed as idle
+    pub fn heartbeat_vm(&self, vm_id: &str) -> Result<VM> {
+        let vm = self.get_running_vm(vm_id)?;
+        self.db.touch_vm(vm_id)?;
+        Ok(vm)
+    }
+
+    /// Running VMs past their pool's idle timeout, with the action to take
+    pub fn idle_vms(&self, now: chrono::DateTime<chrono::Utc>) -> Result<Vec<(VM, IdleAction, Duration)>> {
+        let mut idle = Vec::new();
+        for pool in self.db.list_pools()? {
+            let Some(timeout) = pool.idle_timeout() else {
+                continue;
+            };
+            for vm in self.db.list_vms_by_pool(&pool.id)? {
+                if vm.state != VMState::Running || self.is_busy(&vm.id) {
+                    continue;
+                }
+                let idle_for = vm.idle_for(now);
+                if idle_for >= timeout {
+                    idle.push((vm, pool.idle_action, idle_for));
+                }
+            }
+        }
+        Ok(idle)
+    }
+
+    /// Save or reset Running VMs that have been idle past their pool's threshold
+    pub fn reclaim_idle(&self) -> Result<Vec<String>> {
+        let mut reclaimed = Vec::new();
+        for (vm, action, idle_for) in self.idle_vms(chrono::Utc::now())? {
+            tracing::info!(vm = %vm.name, idle_secs = idle_for.as_secs(), action = %action, "Reclaiming idle VM");
+
+            if let Err(e) = self.release_vm(&vm.id, action == IdleAction::Reset) {
+                tracing::warn!(vm = %vm.name, error = %e, "Failed to reclaim idle VM");
+                continue;
+            }
+            if let Some(agent_id) = &vm.current_agent_id {
+                self.db.fail_agent(agent_id, &format!("Lease reclaimed after {}s idle", idle_for.as_secs()))?;
+            }
+            self.record_event(
+                Event::new(
+                    EventKind::VMIdleReclaimed,
+                    format!("Idle for {}s, {}", idle_for.as_secs(), match action {
+                        IdleAction::Save => "saved",
+                        IdleAction::Reset => "reset to clean checkpoint",
+                    }),
+                ).for_vm(&vm),
+            )?;
+            reclaimed.push(vm.id);
+        }
+        Ok(reclaimed)
+    }
+
+    // ===== Disk Usage =====
+
+    /// Measure every VM's disk and saved-state files. Records a
+    /// DiskLimitExceeded event when a VM first grows past its pool's limit;
+    /// returns the ids of all VMs over the limit (rebuilt on their next release).
+    pub fn check_disk_usage(&self) -> Result<Vec<String>> {
+        let saved_states = HyperV::list_saved_state_sizes()
+            .inspect_err(|e| tracing::warn!(error = %e, "Couldn't measure saved-state files"))
+            .ok();
+        let pools = self.db.list_pools()?;
+
+        let mut over = Vec::new();
+        for vm in self.db.list_vms()? {
+            let pool = vm.pool_id.as_ref().and_then(|id| pools.iter().find(|p| &p.id == id));
+            let saved_state_bytes = match &saved_states {
+                Some(sizes) => sizes.iter().find(|s| s.vm_name == vm.name).map(|s| s.bytes),
+                None => vm.saved_state_bytes,
+            };
+            let disk_bytes = self.update_disk_usage(&vm, pool, saved_state_bytes)?;
+            if pool.is_some_and(|p| disk_bytes.is_some_and(|b| p.disk_over_limit(b))) {
+                over.push(vm.id);
+            }
+        }
+        Ok(over)
+    }
+
+    /// Store a fresh measurement of a VM's disk files, alerting if it just went over the limit
+    fn update_disk_usage(&self, vm: &VM, pool: Option<&VMPool>, saved_state_bytes: Option<u64>) -> Result<Option<u64>> {
+        let disk_bytes = match own_disk_bytes(vm) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                tracing::warn!(vm = %vm.name, error = %e, "Couldn't measure disk files");
+                vm.disk_bytes
+            }
+        };
+        self.db.update_vm_disk_usage(&vm.id, disk_bytes, saved_state_bytes)?;
+
+        let Some(pool) = pool else {
+            return Ok(disk_bytes);
+        };
+        let was_over = vm.disk_bytes.is_some_and(|b| pool.disk_over_limit(b));
+        if let (Some(bytes), false) = (disk_bytes, was_over) {
+            if pool.disk_over_limit(bytes) {
+                self.record_event(Event::new(EventKind::DiskLimitExceeded, format!(
+                    "Disk is {}MB, pool {} allows {}MB; rebuilding on release",
+                    bytes >> 20,
+                    pool.name,
+                    pool.max_disk_bytes.unwrap_or_default() >> 20
+                )).for_vm(vm))?;
+            }
+        }
+        Ok(disk_bytes)
+    }
+
+    // ===== Ephemeral VMs =====
+
+    /// Create a one-shot VM from a template and cold-boot it. It is destroyed
+    /// on release or once its TTL passes; a failed boot destroys it at once.
+    pub fn create_ephemeral(&self, options: &EphemeralOptions) -> Result<VM> {
+        if self.is_shutting_down() {
+            return Err(Error::ShuttingDown);
+        }
+        if options.ttl.is_zero() {
+            return Err(Error::InvalidArgument("ephemeral VM TTL must be greater than zero".to_string()));
+        }
+        let template = self.get_template(&options.template)?
+            .ok_or_else(|| Error::TemplateNotFound(options.template.clone()))?;
+        self.ensure_template_intact(&template)?;
+
+        let vm = self.ephemeral_vm(&template, options);
+        let _busy = self.mark_busy(&vm.id);
+        let job = self.start_job(JobKind::Provision, &vm.name);
+        let result = self.boot_ephemeral(&template, &vm, &job.cancel);
+        job.finish(&result);
+        result
+    }
+
+    fn ephemeral_vm(&self, template: &Template, options: &EphemeralOptions) -> VM {
+        let name = format!("eph-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        let vhdx_path = self.config.vm_storage_path.join(&name).join("disk.vhdx");
+        let mut vm = VM::new(
+            name,
+            vhdx_path,
+            options.memory_mb.unwrap_or(template.memory_mb),
+            options.cpu_count.unwrap_or(template.cpu_count),
+        );
+        vm.template_id = Some(template.id.clone());
+        vm.template_version = Some(template.current_version);
+        vm.gpu_enabled = template.gpu_enabled;
+        vm.ephemeral = true;
+        vm.expires_at = chrono::Duration::from_std(options.ttl).ok()
+            .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl));
+        vm
+    }
+
+    fn boot_ephemeral(&self, template: &Template, vm: &VM, cancel: &CancellationToken) -> Result<VM> {
+        // A failed provision is undone by its journal
+        let mut steps = OperationSteps::new(self, OperationKind::Provision, vm.clone())
+            .with_parent(template.vhdx_path.clone())
+            .with_cancel(cancel);
+        self.run_journaled(&mut steps)?;
+
+        if let Err(e) = self.cold_boot(vm, cancel) {
+            tracing::warn!(vm = %vm.name, error = %e, "Ephemeral VM failed to boot, destroying it");
+            if let Err(delete_err) = self.delete_vm(&vm.id) {
+                tracing::error!(vm = %vm.name, error = %delete_err, "Failed to destroy ephemeral VM");
+            }
+            return Err(e);
+        }
+
+        if let Some(interval) = self.config.record_interval {
+            if let Err(e) = self.start_recording(&vm.id, interval) {
+                tracing::warn!(vm = %vm.name, error = %e, "Failed to start console recording");
+            }
+        }
+        self.db.get_vm(&vm.id)?
+            .ok_or_else(|| Error::VMNotFound(vm.id.clone()))
+    }
+
+    fn cold_boot(&self, vm: &VM, cancel: &CancellationToken) -> Result<()> {
+        tracing::info!(vm = %vm.name, "Cold-booting ephemeral VM");
+        HyperV::start_vm(&vm.name)?;
+        self.db.update_vm_state(&vm.id, VMState::Running)?;
+        self.db.update_vm_resumed(&vm.id)?;
+
+        // Heartbeat and script probes don't need an IP
+        let ip = self.wait_until_ready(vm, self.config.ready_timeout, cancel)?;
+        self.db.update_vm_ip(&vm.id, ip.as_deref())?;
+        Ok(())
+    }
+
+    /// Ephemeral VMs past their TTL that aren't mid-operation
+    pub fn expired_vms(&self, now: chrono::DateTime<chrono::Utc>) -> Result<Vec<VM>> {
+        Ok(self.db.list_vms()?
+            .into_iter()
+            .filter(|vm| vm.is_expired(now) && !self.is_busy(&vm.id))
+            .collect())
+    }
+
+    /// Destroy ephemeral VMs whose TTL has passed, failing any agent still holding one
+    pub fn reap_expired(&self) -> Result<Vec<String>> {
+        let mut reaped = Vec::new();
+        for vm in self.expired_vms(chrono::Utc::now())? {
+            tracing::info!(vm = %vm.name, "Ephemeral VM expired");
+
+            let _busy = self.mark_busy(&vm.id);
+            self.stop_recording(&vm.id)?;
+            if let Err(e) = self.delete_vm(&vm.id) {
+                tracing::warn!(vm = %vm.name, error = %e, "Failed to destroy expired VM");
+                continue;
+            }
+            if let Some(agent_id) = &vm.current_agent_id {
+                self.db.fail_agent(agent_id, &format!("Ephemeral VM {} expired", vm.name))?;
+            }
+            self.record_event(Event::new(EventKind::EphemeralExpired, "TTL passed, destroyed").for_vm(&vm))?;
+            reaped.push(vm.id);
+        }
+        Ok(reaped)
+    }
+
+    /// Start sampling a running VM's console into a timeline
+    pub fn start_recording(&self, vm_id: &str, interval: Duration) -> Result<()> {
+        let vm = self.get_running_vm(vm_id)?;
+
+        let mut recorders = self.recorders.lock().unwrap();
+        if recorders.contains_key(vm_id) {
+            return Err(Error::Other(format!("VM {} is already being recorded", vm.name)));
+        }
+
+        let dir = self.config.vm_storage_path
+            .join(&vm.name)
+            .join("recordings")
+            .join(format!(
+                "{}-{}",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+                &uuid::Uuid::new_v4().simple().to_string()[..8]
+            ));
+
+        tracing::info!(vm = %vm.name, interval_ms = interval.as_millis() as u64, "Starting console recording");
+        recorders.insert(vm_id.to_string(), ScreenRecorder::start_vm(&vm.name, &dir, interval)?);
+        Ok(())
+    }
+
+    /// Stop recording a VM; attaches the timeline to the VM's current agent, if any
+    pub fn stop_recording(&self, vm_id: &str) -> Result<Option<ScreenTimeline>> {
+        let recorder = self.recorders.lock().unwrap().remove(vm_id);
+        let Some(recorder) = recorder else {
+            return Ok(None);
+        };
+
+        let timeline = recorder.stop()?;
+        tracing::info!(
+            vm = %timeline.vm_name,
+            frames = timeline.frames.len(),
+            samples = timeline.samples,
+            "Console recording stopped"
+        );
+
+        let agent = match self.db.get_vm(vm_id)?.and_then(|vm| vm.current_agent_id) {
+            Some(agent_id) => self.db.get_agent(&agent_id)?,
+            None => None,
+        };
+        if let Some(agent) = agent {
+            let mut result = agent.result.unwrap_or(AgentResult {
+                success: agent.status == AgentStatus::Completed,
+                output: serde_json::Value::Null,
+                screenshots: vec![],
+                duration_seconds: timeline.duration_seconds(),
+                timeline: None,
+            });
+            result.screenshots.extend(
+                timeline.frames.iter().map(|f| f.path.to_string_lossy().to_string()),
+            );
+            result.timeline = Some(timeline.clone());
+            self.db.update_agent_result(&agent.id, &result)?;
+        }
+
+        Ok(Some(timeline))
+    }
+
+    /// Whether a VM's console is currently being recorded
+    pub fn is_recording(&self, vm_id: &str) -> bool {
+        self.recorders.lock().unwrap().contains_key(vm_id)
+    }
+
+    /// Whether a lifecycle operation (prepare, resume, reset, release) is running on a VM
+    pub fn is_busy(&self, vm_id: &str) -> bool {
+        self.busy.lock().unwrap().contains_key(vm_id)
+    }
+
+    fn mark_busy(&self, vm_id: &str) -> BusyGuard<'_> {
+        *self.busy.lock().unwrap().entry(vm_id.to_string()).or_insert(0) += 1;
+        BusyGuard {
+            busy: &self.busy,
+            vm_id: vm_id.to_string(),
+        }
+    }
+
+    // ===== Operation Journal =====
+
+    fn run_journaled(&self, steps: &mut OperationSteps<'_>) -> Result<()> {
+        let mut op = steps.operation()?;
+        Journal::new(&self.db).run(&mut op, steps)
+    }
+
+    /// Roll back or forward every operation a previous process left unfinished
+    pub fn recover_operations(&self) -> Result<Vec<Operation>> {
+        let journal = Journal::new(&self.db);
+        let mut recovered = Vec::new();
+
+        for mut op in journal.pending()? {
+            let params: OperationParams = serde_json::from_value(op.params.clone())?;
+            let _busy = self.mark_busy(&params.vm.id);
+            let mut steps = OperationSteps::new(self, op.kind, params.vm);
+            steps.parent = params.parent;
+            steps.output = params.output;
+
+            let status = journal.recover(&mut op, &mut steps)?;
+            tracing::info!(op = %op.id, kind = %op.kind, vm = %op.vm_name, status = %status, "Recovered interrupted operation");
+            recovered.push(op);
+        }
+
+        // A key claimed by a run that died would otherwise answer "in progress" forever
+        let mut in_flight = HashSet::new();
+        for op in journal.pending()?.into_iter().filter(|op| op.kind == OperationKind::Provision) {
+            let params: OperationParams = serde_json::from_value(op.params)?;
+            in_flight.extend(params.vm.pool_id);
+        }
+        for (key, pool_id) in self.db.list_unfinished_provision_requests()? {
+            if !in_flight.contains(&pool_id) {
+                tracing::info!(key = %key, pool = %pool_id, "Releasing idempotency key of an interrupted provision");
+                self.db.delete_provision_request(&key)?;
+            }
+        }
+
+        Ok(recovered)
+    }
+
+    /// Journaled operations, oldest first
+    pub fn list_operations(&self, status: Option<OperationStatus>) -> Result<Vec<Operation>> {
+        self.db.list_operations(status)
+    }
+
+    // ===== Health & Events =====
+
+    /// Append to the event log
+    pub fn record_event(&self, event: Event) -> Result<i64> {
+        tracing::info!(
+            kind = %event.kind,
+            vm = event.vm_name.as_deref().unwrap_or("-"),
+            "{}",
+            event.message
+        );
+        self.db.insert_event(&event)
+    }
+
+    /// List events, oldest first
+    pub fn list_events(&self, filter: &EventFilter) -> Result<Vec<Event>> {
+        self.db.list_events(filter)
+    }
+
+    /// Mark a VM healthy; emits a recovery event if it was unhealthy
+    pub fn mark_healthy(&self, vm_id: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        if vm.health == VMHealth::Unhealthy {
+            self.record_event(Event::new(EventKind::VMRecovered, "Health checks passing again").for_vm(&vm))?;
+        }
+        if vm.health != VMHealth::Healthy {
+            self.db.update_vm_health(vm_id, VMHealth::Healthy)?;
+        }
+        Ok(())
+    }
+
+    /// Mark a VM unhealthy and apply its pool's remediation policy
+    pub fn mark_unhealthy(&self, vm_id: &str, reason: &str) -> Result<()> {
+        let vm = self.db.get_vm(vm_id)?
+            .ok_or_else(|| Error::VMNotFound(vm_id.to_string()))?;
+
+        if vm.health == VMHealth::Unhealthy {
+            return Ok(());
+        }
+        self.db.update_vm_health(vm_id, VMHealth::Unhealthy)?;
+        self.record_event(Event::new(EventKind::VMUnhealthy, reason).for_vm(&vm))?;
+
+        let policy = match &vm.pool_id {
+            Some(pool_id) => self.db.get_pool(pool_id)?.map(|p| p.remediation).unwrap_or_default(),
+            None => RemediationPolicy::Notify,
+        };
+
+        if let Err(e) = self.remediate(&vm, policy, reason) {
+            self.record_event(
+                Event::new(EventKind::RemediationFailed, format!("{} failed: {}", policy, e)).for_vm(&vm),
+            )?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn remediate(&self, vm: &VM, policy: RemediationPolicy, reason: &str) -> Result<()> {
+        match policy {
+            RemediationPolicy::Notify => Ok(()),
+            RemediationPolicy::Reset => {
+                self.evict_holder(vm, reason)?;
+                self.reset_vm(&vm.id)?;
+                self.prepare_vm(&vm.id)?;
+                self.record_event(Event::new(EventKind::VMReset, "Reset to clean checkpoint").for_vm(vm))?;
+                Ok(())
+            }
+            RemediationPolicy::Rebuild => {
+                let pool_id = vm.pool_id.clone()
+                    .ok_or_else(|| Error::Other(format!("VM {} has no pool to rebuild into", vm.name)))?;
+                self.evict_holder(vm, reason)?;
+                let created = self.rebuild_vm(vm, &pool_id)?;
+                self.record_event(
+                    Event::new(EventKind::VMRebuilt, format!("Replaced by {}", created.join(", "))).for_vm(vm),
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Delete a pool VM and provision and prepare a replacement; returns the new VM's id
+    fn rebuild_vm(&self, vm: &VM, pool_id: &str) -> Result<Vec<String>> {
+        self.delete_vm(&vm.id)?;
+        let created = self.provision_pool(pool_id, 1)?;
+        for id in &created {
+            self.prepare_vm(id)?;
+        }
+        Ok(created)
+    }
+
+    /// Fail the agent leasing a VM so its holder stops using it
+    fn evict_holder(&self, vm: &VM, reason: &str) -> Result<()> {
+        self.stop_recording(&vm.id)?;
+        if let Some(agent_id) = &vm.current_agent_id {
+            self.db.fail_agent(agent_id, &format!("VM {} unhealthy: {}", vm.name, reason))?;
+            self.db.release_vm_lease(&vm.id)?;
+        }
+        Ok(())
+    }
+
+    /// Sync DB VM states with Hyper-V (marks missing VMs as Error)
+    pub fn reconcile(&self) -> Result<()> {
+        self.reconcile_with(&ReconcileOptions::default()).map(|_| ())
+    }
+
+    /// Compare the DB against Hyper-V and the storage directory without changing anything
+    pub fn drift_report(&self) -> Result<DriftReport> {
+        self.reconcile_with(&ReconcileOptions { dry_run: true, ..Default::default() })
+    }
+
+    /// Detect drift and repair what `options` allows
+    pub fn reconcile_with(&self, options: &ReconcileOptions) -> Result<DriftReport> {
+        let host = HostSnapshot::capture(&self.config.vm_storage_path)?;
+        self.reconcile_snapshot(&host, options)
+    }
+
+    /// Reconcile against an already captured host snapshot.
+    ///
+    /// State, missing VMs and IPs are always synced; adopting unknown VMs and
+    /// deleting orphans are opt-in. Broken parents and missing checkpoints are
+    /// only reported.
+    pub fn reconcile_snapshot(&self, host: &HostSnapshot, options: &ReconcileOptions) -> Result<DriftReport> {
+        let db_vms = self.db.list_vms()?;
+        let pools = self.db.list_pools()?;
+        let templates = self.db.list_templates()?;
+        let mut versions = Vec::new();
+        for template in &templates {
+            versions.extend(self.db.list_template_versions(&template.id)?);
+        }
+        let busy: HashSet<String> = self.busy.lock().unwrap().keys().cloned().collect();
+
+        // Half-provisioned VMs have a disk and a Hyper-V VM but no row yet
+        let in_flight: HashSet<String> = self.db.list_operations(Some(OperationStatus::Running))?
+            .into_iter()
+            .map(|op| op.vm_name)
+            .collect();
+
+        let mut items = reconcile::detect_drift(&db_vms, &pools, &templates, &versions, host, &busy, |p| p.exists());
+        items.retain(|item| {
+            let name = match item {
+                Drift::OrphanDirectory { path } => path.file_name().and_then(|n| n.to_str()),
+                other => other.vm_name(),
+            };
+            !name.is_some_and(|n| in_flight.contains(n))
+        });
+        let mut report = DriftReport::new(items);
+        if options.dry_run {
+            return Ok(report);
+        }
+
+        for item in &report.items {
+            match self.repair_drift(item, &db_vms, &pools, options) {
+                Ok(Some(action)) => {
+                    tracing::info!(drift = %item, action = %action, "Reconciled drift");
+                    report.actions.push(action);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(drift = %item, error = %e, "Failed to reconcile drift");
+                    report.actions.push(format!("failed to repair {}: {}", item, e));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn repair_drift(
+        &self,
+        item: &Drift,
+        db_vms: &[VM],
+        pools: &[VMPool],
+        options: &ReconcileOptions,
+    ) -> Result<Option<String>> {
+        let find_vm = |name: &str| db_vms.iter().find(|v| v.name == name);
+
+        match item {
+            Drift::StateMismatch { vm_name, actual_state, .. } => {
+                let Some(vm) = find_vm(vm_name) else { return Ok(None) };
+                self.db.update_vm_state(&vm.id, *actual_state)?;
+                Ok(Some(format!("{}: state set to {}", vm_name, actual_state)))
+            }
+            Drift::MissingFromHyperV { vm_name } => {
+                let Some(vm) = find_vm(vm_name) else { return Ok(None) };
+                if vm.state == VMState::Error {
+                    return Ok(None);
+                }
+                self.db.update_vm_state(&vm.id, VMState::Error)?;
+                Ok(Some(format!("{}: marked Error", vm_name)))
+            }
+            Drift::IpMismatch { vm_name, actual_ip, .. } => {
+                let Some(vm) = find_vm(vm_name) else { return Ok(None) };
+                self.db.update_vm_ip(&vm.id, actual_ip.as_deref())?;
+                Ok(Some(format!("{}: IP set to {}", vm_name, actual_ip.as_deref().unwrap_or("none"))))
+            }
+            Drift::UnknownVM { vm_name, pool_name, vhdx_path } => {
+                if options.adopt {
+                    let pool = pools.iter().find(|p| &p.name == pool_name)
+                        .ok_or_else(|| Error::PoolNotFound(pool_name.clone()))?;
+                    self.adopt_vm(vm_name, pool, vhdx_path.clone())?;
+                    Ok(Some(format!("{}: adopted into pool {}", vm_name, pool_name)))
+                } else if options.delete_orphans {
+                    let _ = HyperV::turn_off_vm(vm_name);
+                    HyperV::remove_vm(vm_name)?;
+                    let dir = self.config.vm_storage_path.join(vm_name);
+                    if dir.exists() {
+                        std::fs::remove_dir_all(&dir)?;
+                    }
+                    Ok(Some(format!("{}: removed from Hyper-V", vm_name)))
+                } else {
+                    Ok(None)
+                }
+            }
+            Drift::OrphanDirectory { path } if options.delete_orphans => {
+                std::fs::remove_dir_all(path)?;
+                Ok(Some(format!("{}: deleted", path.display())))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Insert a Hyper-V VM that follows a pool's naming scheme into the DB
+    fn adopt_vm(&self, vm_name: &str, pool: &VMPool, vhdx_path: Option<PathBuf>) -> Result<VM> {
+        let template = self.pool_template(pool)?;
+        let info = HyperV::get_vm(vm_name)?
+            .ok_or_else(|| Error::VMNotFound(vm_name.to_string()))?;
+
+        let vhdx_path = vhdx_path
+            .unwrap_or_else(|| self.config.vm_storage_path.join(vm_name).join("disk.vhdx"));
+        let mut vm = VM::new(vm_name.to_string(), vhdx_path, template.memory_mb, template.cpu_count);
+        vm.template_id = Some(template.id.clone());
+        vm.template_version = Some(template.current_version);
+        vm.pool_id = Some(pool.id.clone());
+        vm.gpu_enabled = template.gpu_enabled;
+        vm.state = VMState::from_hyperv_state(info.state);
+
+        self.db.insert_vm(&vm)?;
+        Ok(vm)
+    }
+}
+
+/// Cascading deletes stop before touching anything if an agent holds one of the VMs
+fn refuse_leased(vms: &[VM]) -> Result<()> {
+    match vms.iter().find(|v| v.is_leased()) {
+        Some(vm) => Err(Error::InvalidState {
+            current: format!("{} leased", vm.name),
+            expected: "released".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Clear the write permission bits so nothing modifies a template by accident
+fn set_read_only(path: &Path) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if !permissions.readonly() {
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// Build output, written to a file as it happens and echoed to the caller
+struct BuildLog<'a> {
+    file: std::fs::File,
+    echo: &'a (dyn Fn(&str) + Sync),
+}
+
+impl<'a> BuildLog<'a> {
+    fn create(path: &Path, echo: &'a (dyn Fn(&str) + Sync)) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(Self {
+            file: std::fs::File::create(path)?,
+            echo,
+        })
+    }
+
+    fn line(&mut self, text: &str) {
+        use std::io::Write;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let _ = writeln!(self.file, "{} {}", chrono::Utc::now().format("%H:%M:%S"), line);
+            (self.echo)(line);
+        }
+    }
+}
+
+fn boot_build_vm(vm_name: &str, timeout: Duration, log: &mut BuildLog<'_>, cancel: &CancellationToken) -> Result<()> {
+    log.line("Booting");
+    runtime::block_on(until_cancelled(cancel, async {
+        HyperV::start_vm_async(vm_name).await?;
+        HyperV::wait_for_heartbeat_async(vm_name, timeout).await
+    }))
+}
+
+/// Merge a copy of `disk` into `output` with `merge`, so the source disk is
+/// never opened by the merge. Parents below an active disk don't change while
+/// it exists, so a copy of it alone is a stable snapshot of the chain.
+fn merge_copy(disk: &Path, output: &Path, merge: impl FnOnce(&Path, &Path) -> Result<()>) -> Result<()> {
+    let extension = disk.extension().and_then(|e| e.to_str()).unwrap_or("vhdx");
+    let copy = output.with_extension(format!("capture.{}", extension));
+    std::fs::copy(disk, &copy)?;
+    let merged = merge(&copy, output);
+    std::fs::remove_file(&copy)?;
+    merged
+}
+
+/// Size of the disk files in a VM's own directory: its differencing disk
+/// and the `.avhdx` children its checkpoints added
+fn own_disk_bytes(vm: &VM) -> std::io::Result<u64> {
+    let Some(dir) = vm.vhdx_path.parent().filter(|d| d.is_dir()) else {
+        return Ok(0);
+    };
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_disk = path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("vhdx") || e.eq_ignore_ascii_case("avhdx"));
+        if is_disk {
+            total += std::fs::metadata(&path)?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Run `f` over `items` on up to `workers` threads. Results keep the input
+/// order; items not started before `stop` was set are None.
+fn fan_out<T: Sync, R: Send>(
+    items: &[T],
+    workers: usize,
+    stop: &AtomicBool,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<Option<R>> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(i) else { break };
+                let result = f(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+/// Race `fut` against `cancel`. Losing drops the future, which kills any
+/// PowerShell it was waiting on.
+async fn until_cancelled<T>(cancel: &CancellationToken, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::select! {
+        result = fut => result,
+        _ = cancel.cancelled() => Err(Error::Cancelled),
+    }
+}
+
+/// What an operation needs to be resumed or undone after a restart
+#[derive(Serialize, Deserialize)]
+struct OperationParams {
+    /// The VM as it was when the operation started (the record to insert, for provisioning)
+    vm: VM,
+    /// Differencing disk parent (provisioning and builds)
+    parent: Option<PathBuf>,
+    /// Merged disk a build writes
+    #[serde(default)]
+    output: Option<PathBuf>,
+}
+
+/// Hyper-V, filesystem and DB steps of provision, prepare, reset, delete and
+/// build. The guest work of a build runs in [`BuildSteps`]; everything else,
+/// and every undo, is here so recovery can tear a build VM down.
+struct OperationSteps<'a> {
+    orch: &'a Orchestrator,
+    kind: OperationKind,
+    vm: VM,
+    parent: Option<PathBuf>,
+    output: Option<PathBuf>,
+    progress: Option<ProgressCallback<'a>>,
+    /// Checked before each step and while waiting; undo steps ignore it
+    cancel: CancellationToken,
+}
+
+impl<'a> OperationSteps<'a> {
+    fn new(orch: &'a Orchestrator, kind: OperationKind, vm: VM) -> Self {
+        Self { orch, kind, vm, parent: None, output: None, progress: None, cancel: CancellationToken::new() }
+    }
+
+    fn with_parent(mut self, parent: PathBuf) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    fn with_output(mut self, output: PathBuf) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    fn with_progress(mut self, progress: ProgressCallback<'a>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    fn with_cancel(mut self, cancel: &CancellationToken) -> Self {
+        self.cancel = cancel.clone();
+        self
+    }
+
+    fn operation(&self) -> Result<Operation> {
+        let params = serde_json::to_value(OperationParams {
+            vm: self.vm.clone(),
+            parent: self.parent.clone(),
+            output: self.output.clone(),
+        })?;
+        Ok(Operation::new(self.kind, &self.vm.name, params).with_vm_id(&self.vm.id))
+    }
+
+    /// Current Hyper-V state, None if the VM doesn't exist
+    fn hyperv_state(&self) -> Result<Option<VMState>> {
+        Ok(HyperV::get_vm(&self.vm.name)?.map(|info| VMState::from_hyperv_state(info.state)))
+    }
+
+    fn vm_dir(&self) -> Option<&Path> {
+        self.vm.vhdx_path.parent()
+    }
+}
+
+impl StepExecutor for OperationSteps<'_> {
+    fn steps(&self) -> Vec<&'static str> {
+        match self.kind {
+            OperationKind::Provision => vec!["create_disk", "create_vm", "configure", "insert_record"],
+            OperationKind::Prepare => vec!["start", "wait_ready", "checkpoint", "save"],
+            OperationKind::Reset => vec!["stop", "restore_checkpoint", "update_record"],
+            OperationKind::Delete => vec!["stop", "remove_vm", "delete_files", "delete_record"],
+            OperationKind::Build => vec!["create_disk", "create_vm", "configure", "build", "remove_vm"],
+        }
+    }
+
+    fn run(&mut self, step: &str) -> Result<()> {
+        let db = &self.orch.db;
+        let name = self.vm.name.clone();
+        let id = self.vm.id.clone();
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if let Some(progress) = self.progress {
+            progress(&Progress::running(&name, step));
+        }
+
+        match (self.kind, step) {
+            (OperationKind::Provision | OperationKind::Build, "create_disk") => {
+                let parent = self.parent.as_ref()
+                    .ok_or_else(|| Error::Other(format!("{} has no parent disk", self.kind)))?;
+                if let Some(dir) = self.vm_dir() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                tracing::info!(vm = %name, "Creating differencing disk");
+                vhdx::create_differencing(parent, &self.vm.vhdx_path)?;
+            }
+            (OperationKind::Provision | OperationKind::Build, "create_vm") => {
+                tracing::info!(vm = %name, "Creating VM");
+                let created = HyperV::create_vm(&name, self.vm.vhdx_path.to_str().unwrap(), self.vm.memory_mb, self.vm.cpu_count);
+                if let Err(e) = created {
+                    // Undoing create_vm needs Hyper-V, which may be what just failed, so the
+                    // disk written natively by create_disk is removed here rather than left behind
+                    if let Err(undo) = self.undo("create_disk") {
+                        tracing::warn!(vm = %name, error = %undo, "Failed to remove disk after create_vm failed");
+                    }
+                    return Err(e);
+                }
+            }
+            (OperationKind::Provision, "configure") => {
+                HyperV::set_network_adapter(&name, &self.orch.config.switch_name)?;
+
+                // Enable enhanced session
+                let _ = HyperV::enable_enhanced_session(&name);
+
+                // Guest Service Interface is needed for Copy-VMFile
+                if let Err(e) = HyperV::enable_guest_services(&name) {
+                    tracing::warn!(vm = %name, error = %e, "Failed to enable guest services");
+                }
+
+                if self.vm.gpu_enabled {
+                    let _ = HyperV::add_gpu(&name);
+                }
+            }
+            (OperationKind::Provision, "insert_record") => {
+                if db.get_vm(&id)?.is_none() {
+                    db.insert_vm(&self.vm)?;
+                }
+            }
+
+            (OperationKind::Prepare, "start") => {
+                tracing::info!(vm = %name, "Starting VM for first boot");
+                if self.hyperv_state()? != Some(VMState::Running) {
+                    HyperV::start_vm(&name)?;
+                }
+                db.update_vm_state(&id, VMState::Running)?;
+            }
+            (OperationKind::Prepare, "wait_ready") => {
+                tracing::info!(vm = %name, "Waiting for VM to be ready");
+                let ip = self.orch.wait_until_ready(&self.vm, self.orch.config.ready_timeout, &self.cancel)?;
+                db.update_vm_ip(&id, ip.as_deref())?;
+
+                // Checkpointing mid first-boot captures a busy guest; wait for it to go quiet
+                let timeout = self.orch.config.settle_timeout;
+                let settle = HyperV::wait_for_settle_async(&name, hyperv::SETTLE_CPU_PERCENT, timeout);
+                if !timeout.is_zero() && !runtime::block_on(until_cancelled(&self.cancel, settle))? {
+                    tracing::warn!(vm = %name, timeout_secs = timeout.as_secs(), "Guest still busy, checkpointing anyway");
+                }
+            }
+            (OperationKind::Prepare, "checkpoint") => {
+                tracing::info!(vm = %name, "Creating clean checkpoint");
+                HyperV::remove_checkpoint(&name, reconcile::CLEAN_CHECKPOINT)?;
+                HyperV::create_checkpoint(&name, reconcile::CLEAN_CHECKPOINT)?;
+            }
+            (OperationKind::Prepare, "save") => {
+                tracing::info!(vm = %name, "Saving VM state");
+                HyperV::save_vm(&name)?;
+                db.update_vm_state(&id, VMState::Saved)?;
+            }
+
+            (OperationKind::Reset, "stop") => {
+                if self.hyperv_state()? == Some(VMState::Running) {
+                    HyperV::turn_off_vm(&name)?;
+                }
+            }
+            (OperationKind::Reset, "restore_checkpoint") => {
+                HyperV::restore_checkpoint(&name, reconcile::CLEAN_CHECKPOINT)?;
+            }
+            (OperationKind::Reset, "update_record") => {
+                db.update_vm_state(&id, VMState::Off)?;
+                db.release_vm_lease(&id)?;
+                db.update_vm_ip(&id, None)?;
+                db.update_vm_health(&id, VMHealth::Unknown)?;
+            }
+
+            (OperationKind::Delete, "stop") => {
+                if matches!(self.hyperv_state()?, Some(VMState::Running | VMState::Saved)) {
+                    let _ = HyperV::turn_off_vm(&name);
+                }
+            }
+            (OperationKind::Delete, "remove_vm") => {
+                if self.hyperv_state()?.is_some() {
+                    HyperV::remove_vm(&name)?;
+                }
+            }
+            (OperationKind::Delete, "delete_files") => {
+                if self.vm.vhdx_path.exists() {
+                    std::fs::remove_file(&self.vm.vhdx_path)?;
+                }
+                if let Some(dir) = self.vm_dir() {
+                    let _ = std::fs::remove_dir_all(dir);
+                }
+            }
+            (OperationKind::Delete, "delete_record") => {
+                db.delete_vm(&id)?;
+            }
+
+            (OperationKind::Build, "configure") => {
+                HyperV::set_network_adapter(&name, &self.orch.config.switch_name)?;
+                HyperV::enable_guest_services(&name)?;
+            }
+            (OperationKind::Build, "remove_vm") => {
+                tracing::info!(vm = %name, "Removing build VM");
+                self.undo("create_vm")?;
+                self.undo("create_disk")?;
+            }
+
+            (kind, step) => return Err(Error::Other(format!("Unknown {} step: {}", kind, step))),
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self, step: &str) -> Result<()> {
+        let db = &self.orch.db;
+        let name = self.vm.name.clone();
+        let id = self.vm.id.clone();
+
+        match (self.kind, step) {
+            (OperationKind::Provision | OperationKind::Build, "create_disk") => {
+                if self.vm.vhdx_path.exists() {
+                    std::fs::remove_file(&self.vm.vhdx_path)?;
+                }
+                if let Some(dir) = self.vm_dir().filter(|d| d.exists()) {
+                    std::fs::remove_dir_all(dir)?;
+                }
+            }
+            (OperationKind::Provision | OperationKind::Build, "create_vm") => {
+                if let Some(state) = self.hyperv_state()? {
+                    if state != VMState::Off {
+                        HyperV::turn_off_vm(&name)?;
+                    }
+                    HyperV::remove_vm(&name)?;
+                }
+            }
+            (OperationKind::Provision, "insert_record") => {
+                db.delete_vm(&id)?;
+            }
+            (OperationKind::Build, "build") => {
+                // Not registered yet, so nothing else knows about it
+                if let Some(output) = self.output.as_ref().filter(|o| o.exists()) {
+                    std::fs::remove_file(output)?;
+                }
+            }
+
+            (OperationKind::Prepare, "start") => {
+                if self.hyperv_state()? == Some(VMState::Running) {
+                    HyperV::turn_off_vm(&name)?;
+                }
+                db.update_vm_state(&id, VMState::Off)?;
+                db.update_vm_ip(&id, None)?;
+            }
+            (OperationKind::Prepare, "checkpoint") => {
+                HyperV::remove_checkpoint(&name, reconcile::CLEAN_CHECKPOINT)?;
+            }
+            (OperationKind::Prepare, "save") => {
+                // Leave it Off so the "start" undo has nothing to do
+                let saved = self.hyperv_state()? == Some(VMState::Saved);
+                if saved {
+                    HyperV::remove_saved_state(&name)?;
+                }
+            }
+
+            // Configuration goes with the VM; roll-forward kinds are never undone
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A template build: the guest steps and merge, with the build VM's lifecycle
+/// and all undo left to the `Build` arms of [`OperationSteps`]
+struct BuildSteps<'a, 'l> {
+    inner: OperationSteps<'a>,
+    spec: &'a BuildSpec,
+    credential: Option<&'a GuestCredential>,
+    log: &'a mut BuildLog<'l>,
+}
+
+impl StepExecutor for BuildSteps<'_, '_> {
+    fn steps(&self) -> Vec<&'static str> {
+        self.inner.steps()
+    }
+
+    fn run(&mut self, step: &str) -> Result<()> {
+        if step != "build" {
+            return self.inner.run(step);
+        }
+        let output = self.inner.output.as_deref()
+            .ok_or_else(|| Error::Other("build has no output disk".to_string()))?;
+        self.inner.orch.run_build(self.spec, &self.inner.vm, output, self.credential, self.log, &self.inner.cancel)
+    }
+
+    fn undo(&mut self, step: &str) -> Result<()> {
+        self.inner.undo(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Template, VMPool};
+    use crate::vhdx::DiskType;
+    use tempfile::TempDir;
+
+    fn setup_test_orchestrator() -> (Orchestrator, TempDir) {
+        let tmp = TempDir::new().unwrap();
+        let config = OrchestratorConfig {
+            vm_storage_path: tmp.path().join("vms"),
+            db_path: tmp.path().join("test.db"),
+            switch_name: "Default Switch".to_string(),
+            ready_timeout: Duration::from_secs(5),
+            max_transfer_bytes: 1024,
+            record_interval: None,
+            max_parallel: 1,
+            settle_timeout: Duration::ZERO,
+            transfer_timeout: Duration::from_millis(100),
+        };
+        let orch = Orchestrator::with_config(config).unwrap();
+        (orch, tmp)
+    }
+
+    const TEMPLATE_SIZE: u64 = 64 << 30;
+
+    fn write_template_image(path: &Path) {
+        crate::vhdx::fixture::Image::dynamic(TEMPLATE_SIZE).write(path).unwrap();
+    }
+
+    #[test]
+    fn test_orchestrator_config_default() {
+        let config = OrchestratorConfig::default();
+        assert_eq!(config.switch_name, "Default Switch");
+        assert_eq!(config.ready_timeout, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_create_pool_without_template() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        
+        let pool = VMPool::new("test-pool", "nonexistent-template");
+        let result = orch.create_pool(pool);
+        
+        assert!(result.is_err());
+        assert!(matches!(result.unwrap_err(), Error::TemplateNotFound(_)));
+    }
+
+    #[test]
+    fn test_list_empty_templates() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let templates = orch.list_templates().unwrap();
+        assert!(templates.is_empty());
+    }
+
+    #[test]
+    fn test_list_empty_pools() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let pools = orch.list_pools().unwrap();
+        assert!(pools.is_empty());
+    }
+
+    #[test]
+    fn test_list_empty_vms() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let vms = orch.list_vms().unwrap();
+        assert!(vms.is_empty());
+    }
+
+    #[test]
+    fn test_get_nonexistent_template() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let result = orch.get_template("nonexistent").unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_get_nonexistent_vm() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let result = orch.get_vm("nonexistent").unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_pool_status_empty() {
+        let (orch, tmp) = setup_test_orchestrator();
+        
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        
+        let pool = VMPool::new("test-pool", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+        
+        let status = orch.get_pool_status(&pool.id).unwrap();
+        assert_eq!(status.total_vms, 0);
+        assert_eq!(status.running_vms, 0);
+        assert_eq!(status.saved_vms, 0);
+    }
+
+    #[test]
+    fn test_register_template_with_fake_vhdx() {
+        let (orch, tmp) = setup_test_orchestrator();
+        
+        // A text file named .vhdx is not a disk image
+        let vhdx_path = tmp.path().join("test.vhdx");
+        std::fs::write(&vhdx_path, "fake vhdx content").unwrap();
+        
+        let template = Template::new("win11", &vhdx_path);
+        let result = orch.register_template(template);
+        
+        assert!(matches!(result, Err(Error::InvalidVhdx(_))));
+        assert!(orch.list_templates().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_register_template_records_disk_info() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("win11.vhdx");
+        write_template_image(&vhdx_path);
+
+        orch.register_template(Template::new("win11", &vhdx_path)).unwrap();
+        let template = orch.get_template("win11").unwrap().unwrap();
+        assert_eq!(template.virtual_size_bytes, Some(TEMPLATE_SIZE));
+        assert_eq!(template.disk_type, Some(DiskType::Dynamic));
+    }
+
+    #[test]
+    fn test_register_template_rejects_pending_log() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("win11.vhdx");
+        let mut image = crate::vhdx::fixture::Image::dynamic(TEMPLATE_SIZE);
+        image.log_guid = uuid::Uuid::new_v4();
+        image.write(&vhdx_path).unwrap();
+
+        let result = orch.register_template(Template::new("win11", &vhdx_path));
+        assert!(matches!(result, Err(Error::InvalidVhdx(ref reason)) if reason.contains("unreplayed log")));
+        assert!(orch.list_templates().unwrap().is_empty());
+        assert!(!std::fs::metadata(&vhdx_path).unwrap().permissions().readonly());
+    }
+
+    #[test]
+    fn test_register_template_missing_vhdx() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        
+        let template = Template::new("missing", r"C:\nonexistent\path.vhdx");
+        let result = orch.register_template(template);
+        
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_template_versions() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let v1_path = tmp.path().join("win11-v1.vhdx");
+        write_template_image(&v1_path);
+        orch.register_template(Template::new("win11", &v1_path)).unwrap();
+        let versions = orch.list_template_versions("win11").unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].sha256.as_ref().unwrap().len(), 64);
+
+        // Added versions wait for promotion
+        let v2_path = tmp.path().join("win11-v2.vhdx");
+        write_template_image(&v2_path);
+        let v2 = orch.add_template_version("win11", &v2_path, Some("patched".into())).unwrap();
+        assert_eq!(v2.version, 2);
+        assert_eq!(orch.get_template("win11").unwrap().unwrap().current_version, 1);
+        assert!(orch.add_template_version("win11", tmp.path().join("missing.vhdx"), None).is_err());
+        let err = orch.add_template_version("win11", &v1_path, None).unwrap_err();
+        assert!(err.to_string().contains("already win11 v1"));
+
+        let promoted = orch.promote_template_version("win11", 2).unwrap();
+        assert_eq!(promoted.vhdx_path, v2_path);
+        let stored = orch.get_template("win11").unwrap().unwrap();
+        assert_eq!((stored.current_version, stored.vhdx_path), (2, v2_path));
+        assert!(matches!(
+            orch.promote_template_version("win11", 7),
+            Err(Error::TemplateVersionNotFound { version: 7, .. })
+        ));
+
+        let back = orch.rollback_template("win11").unwrap();
+        assert_eq!((back.current_version, back.vhdx_path), (1, v1_path));
+        assert!(orch.rollback_template("win11").is_err());
+        assert_eq!(orch.list_template_versions("win11").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_pinned_pool_provisions_its_version() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let v1_path = tmp.path().join("win11-v1.vhdx");
+        write_template_image(&v1_path);
+        let template = Template::new("win11", &v1_path);
+        orch.register_template(template.clone()).unwrap();
+        let v2_path = tmp.path().join("win11-v2.vhdx");
+        write_template_image(&v2_path);
+        orch.add_template_version("win11", &v2_path, None).unwrap();
+        orch.promote_template_version("win11", 2).unwrap();
+
+        let missing = VMPool::new("old", &template.id).with_template_version(3);
+        assert!(matches!(orch.create_pool(missing), Err(Error::TemplateVersionNotFound { .. })));
+        let pinned = VMPool::new("pinned", &template.id).with_template_version(1);
+        orch.create_pool(pinned.clone()).unwrap();
+        let latest = VMPool::new("latest", &template.id);
+        orch.create_pool(latest.clone()).unwrap();
+        assert_eq!(orch.pool_template(&pinned).unwrap().vhdx_path, v1_path);
+        assert_eq!(orch.pool_template(&latest).unwrap().vhdx_path, v2_path);
+
+        // Without Hyper-V the VM fails after its disk is written; the journal records its parent
+        orch.provision(&pinned.id, &ProvisionOptions::new(1)).unwrap();
+        let op = orch.list_operations(None).unwrap().into_iter().find(|op| op.vm_name == "pinned-0").unwrap();
+        let params: OperationParams = serde_json::from_value(op.params).unwrap();
+        assert_eq!(params.parent, Some(v1_path));
+    }
+
+    #[test]
+    fn test_upgrade_pool_pauses_when_new_vms_fail() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let v1_path = tmp.path().join("win11-v1.vhdx");
+        write_template_image(&v1_path);
+        let template = Template::new("win11", &v1_path);
+        orch.register_template(template.clone()).unwrap();
+        let v2_path = tmp.path().join("win11-v2.vhdx");
+        write_template_image(&v2_path);
+        orch.add_template_version("win11", &v2_path, None).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+
+        for (i, leased) in [false, true].into_iter().enumerate() {
+            let mut vm = VM::new(format!("agents-{}", i), tmp.path().join("vms").join("disk.vhdx"), 4096, 2);
+            vm.pool_id = Some(pool.id.clone());
+            vm.template_id = Some(template.id.clone());
+            vm.template_version = Some(1);
+            vm.state = VMState::Saved;
+            if leased {
+                vm.current_agent_id = Some("agent-1".to_string());
+            }
+            orch.db().insert_vm(&vm).unwrap();
+        }
+
+        // Already on v1: nothing to replace
+        let result = orch.upgrade_pool(&pool.id, &UpgradeOptions::new(Some(1)), &|_| {}).unwrap();
+        assert!(result.is_complete());
+        assert!(result.created.is_empty());
+        assert!(matches!(
+            orch.upgrade_pool(&pool.id, &UpgradeOptions::new(Some(9)), &|_| {}),
+            Err(Error::TemplateVersionNotFound { version: 9, .. })
+        ));
+
+        // Without Hyper-V the first new VM fails, so nothing old is touched
+        let result = orch.upgrade_pool(&pool.id, &UpgradeOptions::new(Some(2)), &|_| {}).unwrap();
+        assert_eq!(result.target_version, 2);
+        assert!(result.paused.as_ref().unwrap().contains("failed to provision"));
+        assert!(result.replaced.is_empty());
+        assert_eq!(result.pending, ["agents-1"]);
+        let old: Vec<VM> = orch.db().list_vms_by_pool(&pool.id).unwrap();
+        assert_eq!(old.len(), 2);
+        assert_eq!(orch.db().get_pool(&pool.id).unwrap().unwrap().template_version, Some(2));
+
+        let events = orch.list_events(&EventFilter { kind: Some(EventKind::UpgradePaused), ..Default::default() }).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pool_id.as_deref(), Some(pool.id.as_str()));
+    }
+
+    #[test]
+    fn test_upgrade_pool_pauses_without_progress() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let v1_path = tmp.path().join("win11-v1.vhdx");
+        write_template_image(&v1_path);
+        let template = Template::new("win11", &v1_path);
+        orch.register_template(template.clone()).unwrap();
+        let v2_path = tmp.path().join("win11-v2.vhdx");
+        write_template_image(&v2_path);
+        orch.add_template_version("win11", &v2_path, None).unwrap();
+        let pool = VMPool::new("agents", &template.id).with_count(1).with_warm_count(2);
+        orch.create_pool(pool.clone()).unwrap();
+        for i in 0..2 {
+            let mut vm = VM::new(format!("agents-{}", i), tmp.path().join("vms").join("disk.vhdx"), 4096, 2);
+            vm.pool_id = Some(pool.id.clone());
+            vm.template_version = Some(1);
+            vm.state = VMState::Saved;
+            orch.db().insert_vm(&vm).unwrap();
+        }
+
+        // Already one batch over the desired count, and the warm count keeps both old VMs
+        let result = orch.upgrade_pool(&pool.id, &UpgradeOptions::new(Some(2)), &|_| {}).unwrap();
+        assert!(result.paused.as_ref().unwrap().contains("could be retired"));
+        assert!(result.created.is_empty() && result.replaced.is_empty());
+        assert_eq!(orch.db().list_vms_by_pool(&pool.id).unwrap().len(), 2);
+        assert!(orch.list_operations(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_build_template_cleans_up_on_failure() {
+        let (orch, tmp) = setup_test_orchestrator();
+        let base = tmp.path().join("base.vhdx");
+        write_template_image(&base);
+
+        // Guest steps need a login from the spec or an existing template
+        let scripted = BuildSpec::parse(&format!(
+            "template: win11-dev\nbase: {}\nsteps: [{{ script: 'echo hi' }}]",
+            base.display()
+        )).unwrap();
+        assert!(matches!(orch.build_template(&scripted, &|_| {}), Err(Error::NoGuestCredential(_))));
+
+        // Without Hyper-V the build VM can't be created; nothing is left behind
+        let spec = BuildSpec::parse(&format!("template: win11-dev\nbase: {}\nsteps: [reboot]", base.display())).unwrap();
+        let lines = Mutex::new(Vec::new());
+        assert!(orch.build_template(&spec, &|l| lines.lock().unwrap().push(l.to_string())).is_err());
+
+        let output = spec.output_path(1);
+        assert!(!output.exists());
+        let log = std::fs::read_to_string(build_log_path(&output)).unwrap();
+        assert!(log.contains("Build failed"));
+        assert!(lines.lock().unwrap().iter().any(|l| l.starts_with("Build failed")));
+        assert_eq!(std::fs::read_dir(tmp.path().join("vms")).unwrap().count(), 0);
+        assert!(orch.get_template("win11-dev").unwrap().is_none());
+        assert!(orch.list_jobs().iter().any(|j| j.kind == JobKind::Build));
+    }
+
+    #[test]
+    fn test_capture_template_checks_source() {
+        let (orch, tmp) = setup_test_orchestrator();
+        let base = tmp.path().join("win11.vhdx");
+        write_template_image(&base);
+        let template = Template::new("win11", &base)
+            .with_guest_credential("Administrator", "s3cret");
+        orch.register_template(template.clone()).unwrap();
+
+        let mut vm = VM::new("agents-0".to_string(), tmp.path().join("vms").join("agents-0").join("disk.vhdx"), 4096, 2);
+        vm.template_id = Some(template.id.clone());
+        vm.state = VMState::Running;
+        vm.current_agent_id = Some("agent-1".to_string());
+        orch.db().insert_vm(&vm).unwrap();
+
+        assert!(orch.capture_template(&vm.id, "win11", &CaptureOptions::new()).is_err());
+        assert!(matches!(
+            orch.capture_template(&vm.id, "custom", &CaptureOptions::new()),
+            Err(Error::InvalidState { .. })
+        ));
+        // A copy can only be taken of a disk the VM isn't writing to
+        assert!(matches!(
+            orch.capture_template(&vm.id, "custom", &CaptureOptions::new().keep_source()),
+            Err(Error::InvalidState { .. })
+        ));
+        assert!(matches!(
+            orch.capture_template("missing", "custom", &CaptureOptions::new()),
+            Err(Error::VMNotFound(_))
+        ));
+
+        // A Saved VM's disk is only crash-consistent
+        orch.db().update_vm_state(&vm.id, VMState::Saved).unwrap();
+        orch.db().update_vm_agent(&vm.id, None).unwrap();
+        assert!(matches!(
+            orch.capture_template(&vm.id, "custom", &CaptureOptions::new().keep_source()),
+            Err(Error::InvalidState { .. })
+        ));
+
+        // Without Hyper-V the merge fails; nothing is registered or left behind
+        orch.db().update_vm_state(&vm.id, VMState::Off).unwrap();
+        assert!(orch.capture_template(&vm.id, "custom", &CaptureOptions::new().keep_source()).is_err());
+        assert!(orch.get_template("custom").unwrap().is_none());
+        assert!(!tmp.path().join("custom.vhdx").exists());
+        assert!(!orch.is_busy(&vm.id));
+        assert!(orch.list_jobs().iter().any(|j| j.kind == JobKind::Capture && j.status == JobStatus::Failed));
+    }
+
+    #[test]
+    fn test_merge_copy_leaves_source_untouched() {
+        let tmp = TempDir::new().unwrap();
+        let disk = tmp.path().join("disk.avhdx");
+        write_template_image(&disk);
+        let original = std::fs::read(&disk).unwrap();
+        let output = tmp.path().join("custom.vhdx");
+
+        merge_copy(&disk, &output, |from, to| {
+            assert_ne!(from, disk.as_path());
+            // Even a merge that writes to its input only touches the copy
+            std::fs::write(from, b"scribbled")?;
+            std::fs::copy(from, to)?;
+            Ok(())
+        }).unwrap();
+        assert_eq!(std::fs::read(&disk).unwrap(), original);
+        assert_eq!(std::fs::read(&output).unwrap(), b"scribbled");
+        assert!(!output.with_extension("capture.avhdx").exists());
+
+        // A failed merge still removes the copy
+        std::fs::remove_file(&output).unwrap();
+        assert!(merge_copy(&disk, &output, |_, _| Err(Error::PowerShell("no Hyper-V".to_string()))).is_err());
+        assert_eq!(std::fs::read(&disk).unwrap(), original);
+        assert!(!output.with_extension("capture.avhdx").exists());
+    }
+
+    #[test]
+    fn test_template_checksum_guards_provisioning() {
+        let (orch, tmp) = setup_test_orchestrator();
+        let path = tmp.path().join("win11.vhdx");
+        write_template_image(&path);
+
+        let hashed = AtomicUsize::new(0);
+        let template = Template::new("win11", &path);
+        orch.register_template_with_progress(template.clone(), &|_, _| { hashed.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        assert!(hashed.load(Ordering::SeqCst) > 0);
+        assert!(std::fs::metadata(&path).unwrap().permissions().readonly());
+
+        let check = orch.verify_template("win11", None, &|_, _| {}).unwrap();
+        assert!(check.is_intact() && check.read_only);
+        assert_eq!(Some(check.actual_size), std::fs::metadata(&path).ok().map(|m| m.len()));
+
+        // A write to the image (after lifting the protection) is caught
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        let check = orch.verify_template("win11", Some(1), &|_, _| {}).unwrap();
+        assert!(!check.is_intact() && !check.read_only);
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+        assert!(matches!(
+            orch.provision_pool(&pool.id, 1),
+            Err(Error::TemplateChecksumMismatch { version: 1, .. })
+        ));
+        assert!(orch.db().list_vms_by_pool(&pool.id).unwrap().is_empty());
+        assert!(matches!(
+            orch.verify_template("win11", Some(4), &|_, _| {}),
+            Err(Error::TemplateVersionNotFound { version: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn test_unrecorded_checksum_is_unverified() {
+        let (orch, tmp) = setup_test_orchestrator();
+        let path = tmp.path().join("legacy.vhdx");
+        write_template_image(&path);
+
+        // Registered before checksums were kept
+        let template = Template::new("legacy", &path);
+        orch.db().insert_template(&template).unwrap();
+        orch.db().insert_template_version(&TemplateVersion::new(&template.id, 1, &path)).unwrap();
+
+        let check = orch.verify_template("legacy", None, &|_, _| {}).unwrap();
+        assert!(check.is_intact() && !check.is_verified());
+        let events = orch.list_events(&EventFilter { kind: Some(EventKind::TemplateChecksumRecorded), ..Default::default() }).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].message.contains("legacy v1"));
+
+        // The recorded hash is what later checks compare with
+        let check = orch.verify_template("legacy", None, &|_, _| {}).unwrap();
+        assert!(check.is_verified());
+        let events = orch.list_events(&EventFilter { kind: Some(EventKind::TemplateChecksumRecorded), ..Default::default() }).unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_delete_checks_dependents() {
+        let (orch, tmp) = setup_test_orchestrator();
+        let path = tmp.path().join("win11.vhdx");
+        write_template_image(&path);
+        let template = Template::new("win11", &path);
+        orch.register_template(template.clone()).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+        let empty = VMPool::new("spare", &template.id);
+        orch.create_pool(empty.clone()).unwrap();
+
+        let mut vm = VM::new("agents-0".to_string(), tmp.path().join("vms").join("agents-0").join("disk.vhdx"), 4096, 2);
+        vm.pool_id = Some(pool.id.clone());
+        vm.template_id = Some(template.id.clone());
+        vm.current_agent_id = Some("agent-1".to_string());
+        orch.db().insert_vm(&vm).unwrap();
+
+        match orch.delete_template("win11", false) {
+            Err(Error::InUse { dependents, .. }) => assert_eq!(dependents, ["pool agents", "pool spare"]),
+            other => panic!("expected InUse, got {:?}", other),
+        }
+        assert!(matches!(orch.delete_pool(&pool.id, false), Err(Error::InUse { .. })));
+        // Cascading stops before anything is deleted while an agent holds a VM
+        assert!(matches!(orch.delete_template("win11", true), Err(Error::InvalidState { .. })));
+        assert!(matches!(orch.delete_pool(&pool.id, true), Err(Error::InvalidState { .. })));
+        assert_eq!(orch.list_pools().unwrap().len(), 2);
+        assert!(orch.db().get_vm(&vm.id).unwrap().is_some());
+
+        orch.delete_pool(&empty.id, false).unwrap();
+        assert!(matches!(orch.delete_pool(&empty.id, false), Err(Error::PoolNotFound(_))));
+
+        orch.db().delete_vm(&vm.id).unwrap();
+        orch.delete_template("win11", true).unwrap();
+        assert!(orch.get_template("win11").unwrap().is_none());
+        assert!(orch.list_pools().unwrap().is_empty());
+        assert!(orch.db().list_template_versions(&template.id).unwrap().is_empty());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_exec_requires_running_vm() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("exec-vm".to_string(), PathBuf::from(r"C:\vms\exec.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+
+        let result = orch.exec_vm(&vm.id, "hostname", Duration::from_secs(5));
+        assert!(matches!(result.unwrap_err(), Error::InvalidState { .. }));
+    }
+
+    #[test]
+    fn test_exec_without_guest_credential() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let template = Template::new("no-creds", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+
+        let mut vm = VM::new("exec-vm".to_string(), PathBuf::from(r"C:\vms\exec.vhdx"), 4096, 2);
+        vm.template_id = Some(template.id.clone());
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+
+        let result = orch.exec_vm(&vm.id, "hostname", Duration::from_secs(5));
+        assert!(matches!(result.unwrap_err(), Error::NoGuestCredential(_)));
+    }
+
+    #[test]
+    fn test_readiness_probes_from_template() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let probes = vec![
+            "heartbeat".parse().unwrap(),
+            "http:9000/ready@45".parse().unwrap(),
+        ];
+        let template = Template::new("headless", &vhdx_path)
+            .with_probes(probes.clone())
+            .with_guest_credential("admin", "pw");
+        orch.register_template(template.clone()).unwrap();
+
+        let mut vm = VM::new("probe-vm".to_string(), PathBuf::from(r"C:\vms\probe.vhdx"), 4096, 2);
+        vm.template_id = Some(template.id.clone());
+        let (loaded, credential) = orch.readiness_probes(&vm).unwrap();
+        assert_eq!(loaded, probes);
+        assert!(credential.is_some());
+
+        // VMs without a template fall back to the RDP check
+        let bare = VM::new("bare-vm".to_string(), PathBuf::from(r"C:\vms\bare.vhdx"), 4096, 2);
+        let (loaded, credential) = orch.readiness_probes(&bare).unwrap();
+        assert_eq!(loaded, ReadinessProbe::defaults());
+        assert!(credential.is_none());
+    }
+
+    #[test]
+    fn test_busy_guard_nests() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let outer = orch.mark_busy("vm-1");
+        {
+            let _inner = orch.mark_busy("vm-1");
+            assert!(orch.is_busy("vm-1"));
+        }
+        assert!(orch.is_busy("vm-1"));
+        drop(outer);
+        assert!(!orch.is_busy("vm-1"));
+    }
+
+    #[test]
+    fn test_health_transitions_emit_events() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+
+        let mut vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\a.vhdx"), 4096, 2);
+        vm.pool_id = Some(pool.id.clone());
+        vm.current_agent_id = Some("agent-1".to_string());
+        orch.db().insert_vm(&vm).unwrap();
+
+        // Notify policy: flag it, keep the lease
+        orch.mark_unhealthy(&vm.id, "heartbeat lost").unwrap();
+        orch.mark_unhealthy(&vm.id, "heartbeat lost").unwrap();
+        let loaded = orch.db().get_vm(&vm.id).unwrap().unwrap();
+        assert_eq!(loaded.health, VMHealth::Unhealthy);
+        assert_eq!(loaded.current_agent_id.as_deref(), Some("agent-1"));
+        assert_eq!(orch.get_pool_status(&pool.id).unwrap().unhealthy_vms, 1);
+
+        orch.mark_healthy(&vm.id).unwrap();
+        orch.mark_healthy(&vm.id).unwrap();
+
+        let events = orch.list_events(&EventFilter::default()).unwrap();
+        let kinds: Vec<_> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![EventKind::VMUnhealthy, EventKind::VMRecovered]);
+        assert_eq!(events[0].agent_id.as_deref(), Some("agent-1"));
+        assert_eq!(events[0].message, "heartbeat lost");
+    }
+
+    #[test]
+    fn test_idle_vms() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        let pool = VMPool::new("agents", &template.id).with_idle_timeout(600, IdleAction::Save);
+        orch.create_pool(pool.clone()).unwrap();
+        let no_timeout = VMPool::new("forever", &template.id);
+        orch.create_pool(no_timeout.clone()).unwrap();
+
+        let later = chrono::Utc::now() + chrono::Duration::seconds(1200);
+        let insert = |name: &str, pool_id: &str, state: VMState, activity: Option<chrono::DateTime<chrono::Utc>>| {
+            let mut vm = VM::new(name.to_string(), PathBuf::from(r"C:\vms\x.vhdx"), 4096, 2);
+            vm.pool_id = Some(pool_id.to_string());
+            vm.state = state;
+            vm.last_activity_at = activity;
+            orch.db().insert_vm(&vm).unwrap();
+            vm
+        };
+        let running = insert("agents-0", &pool.id, VMState::Running, None);
+        insert("agents-1", &pool.id, VMState::Running, Some(later - chrono::Duration::seconds(30)));
+        insert("agents-2", &pool.id, VMState::Saved, None);
+        insert("forever-0", &no_timeout.id, VMState::Running, None);
+
+        let idle = orch.idle_vms(later).unwrap();
+        assert_eq!(idle.len(), 1);
+        assert_eq!(idle[0].0.id, running.id);
+        assert_eq!(idle[0].1, IdleAction::Save);
+        assert!(idle[0].2 > Duration::from_secs(1190));
+
+        // Busy VMs are left alone
+        let _busy = orch.mark_busy(&running.id);
+        assert!(orch.idle_vms(later).unwrap().is_empty());
+
+        let status = orch.get_pool_status(&pool.id).unwrap();
+        assert_eq!(status.idle_vms, 2);
+        assert_eq!(status.reclaimed_vms, 0);
+    }
+
+    #[test]
+    fn test_heartbeat_requires_running_vm() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("hb-vm".to_string(), PathBuf::from(r"C:\vms\hb.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        assert!(matches!(orch.heartbeat_vm(&vm.id).unwrap_err(), Error::InvalidState { .. }));
+
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+        orch.heartbeat_vm(&vm.id).unwrap();
+        assert!(orch.db().get_vm(&vm.id).unwrap().unwrap().last_activity_at.is_some());
+    }
+
+    #[test]
+    fn test_push_file_over_limit() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("cp-vm".to_string(), PathBuf::from(r"C:\vms\cp.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+
+        let big = tmp.path().join("big.bin");
+        std::fs::write(&big, vec![0u8; 2048]).unwrap();
+
+        let result = orch.push_file(&vm.id, &big, r"C:\Temp\big.bin");
+        assert!(matches!(result.unwrap_err(), Error::FileTooLarge { size: 2048, limit: 1024 }));
+    }
+
+    #[test]
+    fn test_staging_path_is_unique() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let a = orch.staging_path().unwrap();
+        let b = orch.staging_path().unwrap();
+        assert_ne!(a, b);
+        assert!(a.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn test_stop_recording_when_idle() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        assert!(!orch.is_recording("vm-none"));
+        assert!(orch.stop_recording("vm-none").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_start_recording_requires_running_vm() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("rec-vm".to_string(), PathBuf::from(r"C:\vms\rec.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+
+        let result = orch.start_recording(&vm.id, Duration::from_secs(1));
+        assert!(matches!(result.unwrap_err(), Error::InvalidState { .. }));
+        assert!(!orch.is_recording(&vm.id));
     }
 
-    // ===== Agent/Scheduling Operations =====
+    #[test]
+    fn test_stop_recording_attaches_timeline_to_agent() {
+        let (orch, _tmp) = setup_test_orchestrator();
 
-    /// Acquire a VM from pool (resumes saved VM)
-    pub fn acquire_vm(&self, pool_id: &str) -> Result<VM> {
-        let vm = self.db.find_available_vm_in_pool(pool_id)?
-            .ok_or(Error::NoVMAvailable)?;
+        let agent = Agent::new("agent", Task::new("workflow"));
+        orch.db().insert_agent(&agent).unwrap();
 
-        self.resume_vm(&vm.id)?;
+        let vm = VM::new("rec-vm".to_string(), PathBuf::from(r"C:\vms\rec.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+        orch.db().update_vm_agent(&vm.id, Some(&agent.id)).unwrap();
 
-        // Refresh VM info
-        self.db.get_vm(&vm.id)?
-            .ok_or_else(|| Error::VMNotFound(vm.id.clone()))
+        orch.start_recording(&vm.id, Duration::from_millis(10)).unwrap();
+        assert!(orch.is_recording(&vm.id));
+
+        let timeline = orch.stop_recording(&vm.id).unwrap().unwrap();
+        assert_eq!(timeline.vm_name, "rec-vm");
+        assert!(timeline.directory.join("timeline.json").exists());
+
+        let result = orch.db().get_agent(&agent.id).unwrap().unwrap().result.unwrap();
+        assert!(!result.success);
+        assert_eq!(result.timeline.unwrap().vm_name, "rec-vm");
+
+        // A second recording in the same second gets its own directory
+        orch.start_recording(&vm.id, Duration::from_millis(10)).unwrap();
+        let again = orch.stop_recording(&vm.id).unwrap().unwrap();
+        assert_ne!(again.directory, timeline.directory);
     }
 
-    /// Release VM back to pool
-    pub fn release_vm(&self, vm_id: &str, reset: bool) -> Result<()> {
-        if reset {
-            self.reset_vm(vm_id)?;
-            // Re-prepare after reset
-            self.prepare_vm(vm_id)?;
-        } else {
-            self.save_vm(vm_id)?;
-        }
+    #[tokio::test]
+    async fn test_dropped_resume_releases_vm() {
+        let (orch, _tmp) = setup_test_orchestrator();
 
-        self.db.update_vm_agent(vm_id, None)?;
-        Ok(())
+        let vm = VM::new("resume-vm".to_string(), PathBuf::from(r"C:\vms\resume.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Saved).unwrap();
+
+        // Hyper-V never answers, so the resume is still in flight when dropped
+        let never_started = tokio::sync::Notify::new();
+        let mut resume = Box::pin(orch.resume_vm_with(&vm.id, |_| async {
+            never_started.notified().await;
+            Ok(Some("10.0.0.5".to_string()))
+        }));
+        assert!(futures_util::poll!(&mut resume).is_pending());
+        assert!(orch.is_busy(&vm.id));
+        assert_eq!(orch.list_jobs()[0].status, JobStatus::Running);
+
+        drop(resume);
+        assert!(!orch.is_busy(&vm.id));
+        assert_eq!(orch.list_jobs()[0].status, JobStatus::Cancelled);
     }
 
-    /// Sync DB state with actual Hyper-V state
-    pub fn reconcile(&self) -> Result<()> {
-        let hyperv_vms = HyperV::list_vms()?;
-        let db_vms = self.db.list_vms()?;
+    #[test]
+    fn test_cancel_job() {
+        let (orch, _tmp) = setup_test_orchestrator();
 
-        for db_vm in db_vms {
-            if let Some(hv_vm) = hyperv_vms.iter().find(|v| v.name == db_vm.name) {
-                let actual_state = VMState::from_hyperv_state(hv_vm.state);
-                if db_vm.state != actual_state {
-                    tracing::info!(
-                        vm = %db_vm.name,
-                        db_state = %db_vm.state,
-                        actual_state = %actual_state,
-                        "Reconciling VM state"
-                    );
-                    self.db.update_vm_state(&db_vm.id, actual_state)?;
-                }
-            } else {
-                tracing::warn!(vm = %db_vm.name, "VM not found in Hyper-V");
-                self.db.update_vm_state(&db_vm.id, VMState::Error)?;
-            }
-        }
+        let job = orch.start_job(JobKind::Prepare, "agents-0");
+        assert_eq!(orch.list_jobs().len(), 1);
+        assert_eq!(orch.cancel_all_jobs(), 1);
+        // Already asked to stop
+        assert_eq!(orch.cancel_all_jobs(), 0);
+        assert!(job.cancel.is_cancelled());
+        assert_eq!(orch.get_job(&job.id).unwrap().status, JobStatus::Running);
 
-        Ok(())
+        job.finish::<()>(&Err(Error::Timeout));
+        assert_eq!(orch.get_job(&job.id).unwrap().status, JobStatus::Cancelled);
+
+        assert!(matches!(orch.cancel_job("job-missing"), Err(Error::JobNotFound(_))));
+
+        // Dropped without finishing, as when an API client goes away
+        let id = orch.start_job(JobKind::Resume, "agents-1").id.clone();
+        assert_eq!(orch.get_job(&id).unwrap().status, JobStatus::Cancelled);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::models::{Template, VMPool, VM, VMState};
-    use tempfile::TempDir;
+    #[tokio::test]
+    async fn test_transfer_job_times_out_and_cancels() {
+        let (orch, _tmp) = setup_test_orchestrator();
 
-    fn setup_test_orchestrator() -> (Orchestrator, TempDir) {
-        let tmp = TempDir::new().unwrap();
-        let config = OrchestratorConfig {
-            vm_storage_path: tmp.path().join("vms"),
-            db_path: tmp.path().join("test.db"),
-            switch_name: "Default Switch".to_string(),
-            ready_timeout: Duration::from_secs(5),
+        let vm = VM::new("cp-vm".to_string(), PathBuf::from(r"C:\vms\cp.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+
+        let hung = orch.run_transfer_job_async(&vm, std::future::pending()).await;
+        assert!(matches!(hung, Err(Error::Timeout)));
+        assert_eq!(orch.list_jobs()[0].kind, JobKind::Transfer);
+        assert_eq!(orch.list_jobs()[0].status, JobStatus::Failed);
+
+        let cancel = async {
+            while orch.cancel_all_jobs() == 0 {
+                tokio::task::yield_now().await;
+            }
         };
-        let orch = Orchestrator::with_config(config).unwrap();
-        (orch, tmp)
+        let (result, ()) = tokio::join!(orch.run_transfer_job_async(&vm, std::future::pending()), cancel);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        // Only the copy is stopped; the guest is left running
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().state, VMState::Running);
     }
 
-    #[test]
-    fn test_orchestrator_config_default() {
-        let config = OrchestratorConfig::default();
-        assert_eq!(config.switch_name, "Default Switch");
-        assert_eq!(config.ready_timeout, Duration::from_secs(120));
+    #[tokio::test]
+    async fn test_cancelled_job_turns_vm_off_for_recycling() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("stuck-vm".to_string(), PathBuf::from(r"C:\vms\stuck.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+
+        let never_ready = std::future::pending::<Result<String>>();
+        let cancel = async {
+            while orch.cancel_all_jobs() == 0 {
+                tokio::task::yield_now().await;
+            }
+        };
+        let (result, ()) = tokio::join!(orch.run_vm_job_async(JobKind::Resume, &vm, never_ready), cancel);
+        assert!(matches!(result, Err(Error::Cancelled)));
+
+        let stored = orch.db().get_vm(&vm.id).unwrap().unwrap();
+        assert_eq!(stored.state, VMState::Off);
+        assert!(stored.needs_recycle);
+        assert_eq!(orch.list_jobs()[0].status, JobStatus::Cancelled);
+
+        let events = orch.list_events(&EventFilter::default()).unwrap();
+        assert_eq!(events[0].kind, EventKind::OperationCancelled);
     }
 
     #[test]
-    fn test_create_pool_without_template() {
+    fn test_cancelled_prepare_stops_before_next_step() {
         let (orch, _tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("prep-vm".to_string(), PathBuf::from(r"C:\vms\prep.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let started = Mutex::new(Vec::new());
+        let result = orch.prepare_vm_with(&vm, &|p| started.lock().unwrap().push(p.step.clone()), &cancel);
+
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(started.lock().unwrap().is_empty());
+        assert!(orch.db().get_vm(&vm.id).unwrap().unwrap().needs_recycle);
+        assert!(!orch.is_busy(&vm.id));
+    }
+
+    #[test]
+    fn test_acquire_from_empty_pool() {
+        let (orch, tmp) = setup_test_orchestrator();
         
-        let pool = VMPool::new("test-pool", "nonexistent-template");
-        let result = orch.create_pool(pool);
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        
+        let pool = VMPool::new("empty-pool", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
         
+        let result = orch.acquire_vm(&pool.id);
         assert!(result.is_err());
-        assert!(matches!(result.unwrap_err(), Error::TemplateNotFound(_)));
+        assert!(matches!(result.unwrap_err(), Error::NoVMAvailable));
     }
 
     #[test]
-    fn test_list_empty_templates() {
-        let (orch, _tmp) = setup_test_orchestrator();
-        let templates = orch.list_templates().unwrap();
-        assert!(templates.is_empty());
+    fn test_acquire_claims_vm() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+        let mut vm = VM::new("agents-0".to_string(), tmp.path().join("vms").join("disk.vhdx"), 4096, 2);
+        vm.pool_id = Some(pool.id.clone());
+        vm.state = VMState::Saved;
+        orch.db().insert_vm(&vm).unwrap();
+
+        // Another acquire got there first
+        assert!(orch.db().claim_vm(&vm.id, "acquire-other").unwrap());
+        assert!(!orch.db().claim_vm(&vm.id, "acquire-mine").unwrap());
+        assert!(matches!(orch.acquire_vm(&pool.id), Err(Error::NoVMAvailable)));
+        orch.db().release_vm_claim(&vm.id, "acquire-other").unwrap();
+
+        // Without Hyper-V the resume fails, and the claim goes with it
+        assert!(orch.acquire_vm(&pool.id).is_err());
+        assert!(!orch.db().get_vm(&vm.id).unwrap().unwrap().is_leased());
     }
 
     #[test]
-    fn test_list_empty_pools() {
-        let (orch, _tmp) = setup_test_orchestrator();
-        let pools = orch.list_pools().unwrap();
-        assert!(pools.is_empty());
+    fn test_reconcile_snapshot() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let mut vm = VM::new("sync-vm".to_string(), tmp.path().join("sync.vhdx"), 4096, 2);
+        vm.state = VMState::Saved;
+        orch.db().insert_vm(&vm).unwrap();
+        let gone = VM::new("gone-vm".to_string(), tmp.path().join("gone.vhdx"), 4096, 2);
+        orch.db().insert_vm(&gone).unwrap();
+
+        let orphan = tmp.path().join("vms").join("stale-3");
+        std::fs::create_dir_all(&orphan).unwrap();
+
+        let host = HostSnapshot {
+            vms: vec![crate::hyperv::HyperVInfo {
+                name: "sync-vm".to_string(),
+                state: 3,
+                memory_assigned: None,
+                uptime: None,
+                id: None,
+            }],
+            checkpoints: vec![crate::hyperv::CheckpointInfo {
+                vm_name: "sync-vm".to_string(),
+                name: reconcile::CLEAN_CHECKPOINT.to_string(),
+            }],
+            directories: vec![orphan.clone()],
+            ..Default::default()
+        };
+
+        // Dry run reports everything, changes nothing
+        let dry = ReconcileOptions { dry_run: true, ..Default::default() };
+        let report = orch.reconcile_snapshot(&host, &dry).unwrap();
+        assert_eq!(report.items.len(), 3);
+        assert!(report.actions.is_empty());
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().state, VMState::Saved);
+
+        // Orphans are kept unless deletion is asked for
+        let report = orch.reconcile_snapshot(&host, &ReconcileOptions::default()).unwrap();
+        assert_eq!(report.actions.len(), 2);
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().state, VMState::Running);
+        assert_eq!(orch.db().get_vm(&gone.id).unwrap().unwrap().state, VMState::Error);
+        assert!(orphan.exists());
+
+        let purge = ReconcileOptions { delete_orphans: true, ..Default::default() };
+        let report = orch.reconcile_snapshot(&host, &purge).unwrap();
+        assert_eq!(report.items.len(), 2);
+        assert!(!orphan.exists());
     }
 
     #[test]
-    fn test_list_empty_vms() {
-        let (orch, _tmp) = setup_test_orchestrator();
-        let vms = orch.list_vms().unwrap();
-        assert!(vms.is_empty());
+    fn test_recover_interrupted_delete() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("doomed".to_string(), tmp.path().join("doomed.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+
+        // Crashed after the Hyper-V and file steps, before the row was deleted
+        let steps = OperationSteps::new(&orch, OperationKind::Delete, vm.clone());
+        let mut op = steps.operation().unwrap();
+        op.completed_steps = vec!["stop".to_string(), "remove_vm".to_string(), "delete_files".to_string()];
+        op.current_step = Some("delete_record".to_string());
+        orch.db().insert_operation(&op).unwrap();
+
+        // The reconciler leaves it alone while it's in flight
+        let host = HostSnapshot::default();
+        let report = orch.reconcile_snapshot(&host, &ReconcileOptions { dry_run: true, ..Default::default() }).unwrap();
+        assert!(report.is_clean());
+
+        let recovered = orch.recover_operations().unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].status, OperationStatus::Completed);
+        assert!(orch.db().get_vm(&vm.id).unwrap().is_none());
+        assert!(orch.list_operations(Some(OperationStatus::Running)).unwrap().is_empty());
     }
 
     #[test]
-    fn test_get_nonexistent_template() {
-        let (orch, _tmp) = setup_test_orchestrator();
-        let result = orch.get_template("nonexistent").unwrap();
-        assert!(result.is_none());
+    fn test_recover_interrupted_provision() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vm_dir = tmp.path().join("vms").join("agents-0");
+        std::fs::create_dir_all(&vm_dir).unwrap();
+        std::fs::write(vm_dir.join("disk.vhdx"), "partial").unwrap();
+        let vm = VM::new("agents-0".to_string(), vm_dir.join("disk.vhdx"), 4096, 2);
+
+        // Crashed while creating the disk: only the disk is rolled back, no Hyper-V calls
+        let steps = OperationSteps::new(&orch, OperationKind::Provision, vm)
+            .with_parent(tmp.path().join("template.vhdx"));
+        let mut op = steps.operation().unwrap();
+        op.current_step = Some("create_disk".to_string());
+        orch.db().insert_operation(&op).unwrap();
+
+        let recovered = orch.recover_operations().unwrap();
+        assert_eq!(recovered[0].status, OperationStatus::RolledBack);
+        assert!(!vm_dir.exists());
     }
 
     #[test]
-    fn test_get_nonexistent_vm() {
-        let (orch, _tmp) = setup_test_orchestrator();
-        let result = orch.get_vm("nonexistent").unwrap();
-        assert!(result.is_none());
+    fn test_recover_interrupted_build() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vm_dir = tmp.path().join("vms").join("build-1a2b3c4d");
+        std::fs::create_dir_all(&vm_dir).unwrap();
+        std::fs::write(vm_dir.join("disk.vhdx"), "partial").unwrap();
+        let vm = VM::new("build-1a2b3c4d".to_string(), vm_dir.join("disk.vhdx"), 4096, 2);
+
+        let steps = OperationSteps::new(&orch, OperationKind::Build, vm)
+            .with_parent(tmp.path().join("base.vhdx"))
+            .with_output(tmp.path().join("win11-dev-v1.vhdx"));
+        let mut op = steps.operation().unwrap();
+        op.current_step = Some("create_disk".to_string());
+        orch.db().insert_operation(&op).unwrap();
+
+        // The build directory has no VM row, but isn't an orphan while the build is in flight
+        let host = HostSnapshot { directories: vec![vm_dir.clone()], ..Default::default() };
+        let report = orch.reconcile_snapshot(&host, &ReconcileOptions { dry_run: true, ..Default::default() }).unwrap();
+        assert!(report.is_clean());
+
+        let recovered = orch.recover_operations().unwrap();
+        assert_eq!(recovered[0].kind, OperationKind::Build);
+        assert_eq!(recovered[0].status, OperationStatus::RolledBack);
+        assert!(!vm_dir.exists());
     }
 
     #[test]
-    fn test_pool_status_empty() {
+    fn test_provision_reports_each_vm() {
         let (orch, tmp) = setup_test_orchestrator();
-        
-        // Create a fake template file
+
         let vhdx_path = tmp.path().join("template.vhdx");
-        std::fs::write(&vhdx_path, "fake").unwrap();
-        
+        write_template_image(&vhdx_path);
         let template = Template::new("test", &vhdx_path);
         orch.register_template(template.clone()).unwrap();
-        
-        let pool = VMPool::new("test-pool", &template.id);
+        let pool = VMPool::new("agents", &template.id);
         orch.create_pool(pool.clone()).unwrap();
-        
-        let status = orch.get_pool_status(&pool.id).unwrap();
-        assert_eq!(status.total_vms, 0);
-        assert_eq!(status.running_vms, 0);
-        assert_eq!(status.saved_vms, 0);
+
+        // Without Hyper-V every VM fails at create_vm, and its disk is rolled back
+        let result = orch.provision(&pool.id, &ProvisionOptions::new(2)).unwrap();
+        let names: Vec<&str> = result.vms.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["agents-0", "agents-1"]);
+        assert!(result.vms.iter().all(|v| v.outcome == ProvisionOutcome::Failed && v.error.is_some()));
+        assert!(!tmp.path().join("vms").join("agents-0").exists());
+
+        // Atomic batches stop at the first failure; names keep moving forward
+        let result = orch.provision(&pool.id, &ProvisionOptions::new(3).atomic()).unwrap();
+        assert_eq!(result.vms.len(), 1);
+        assert_eq!(result.vms[0].name, "agents-2");
+        assert!(orch.provision_pool(&pool.id, 1).is_err());
+        assert!(orch.list_vms().unwrap().is_empty());
     }
 
     #[test]
-    fn test_register_template_with_fake_vhdx() {
+    fn test_provision_idempotency_key() {
         let (orch, tmp) = setup_test_orchestrator();
-        
-        // Create a fake VHDX file
-        let vhdx_path = tmp.path().join("test.vhdx");
-        std::fs::write(&vhdx_path, "fake vhdx content").unwrap();
-        
-        let template = Template::new("win11", &vhdx_path);
-        let result = orch.register_template(template);
-        
-        assert!(result.is_ok());
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+        let other = VMPool::new("others", &template.id);
+        orch.create_pool(other.clone()).unwrap();
+
+        let options = ProvisionOptions::new(1).with_idempotency_key("req-1");
+        let first = orch.provision(&pool.id, &options).unwrap();
+        let again = orch.provision(&pool.id, &options).unwrap();
+        assert_eq!(first, again);
+        assert_eq!(orch.db().allocate_vm_index(&pool.id).unwrap(), 1);
+
+        let err = orch.provision(&other.id, &options).unwrap_err();
+        assert!(matches!(err, Error::IdempotencyConflict(_)));
+
+        // A key being worked on elsewhere isn't run twice
+        orch.db().insert_provision_request("req-2", &pool.id).unwrap();
+        let err = orch.provision(&pool.id, &ProvisionOptions::new(1).with_idempotency_key("req-2")).unwrap_err();
+        assert!(err.to_string().contains("in progress"));
+
+        // Unless the run holding it died: recovery frees the key for a retry
+        orch.recover_operations().unwrap();
+        let retried = orch.provision(&pool.id, &ProvisionOptions::new(1).with_idempotency_key("req-2")).unwrap();
+        assert_eq!(retried.idempotency_key.as_deref(), Some("req-2"));
     }
 
     #[test]
-    fn test_register_template_missing_vhdx() {
-        let (orch, _tmp) = setup_test_orchestrator();
-        
-        let template = Template::new("missing", r"C:\nonexistent\path.vhdx");
-        let result = orch.register_template(template);
-        
-        assert!(result.is_err());
+    fn test_cancelled_provision_keeps_partial_result() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx_path = tmp.path().join("template.vhdx");
+        write_template_image(&vhdx_path);
+        let template = Template::new("test", &vhdx_path);
+        orch.register_template(template.clone()).unwrap();
+        let pool = VMPool::new("agents", &template.id);
+        orch.create_pool(pool.clone()).unwrap();
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let options = ProvisionOptions::new(3).with_idempotency_key("req-1");
+        let result = orch.provision_idempotent(&pool, &template, &options, &|_| {}, &cancel).unwrap();
+        let names: Vec<&str> = result.vms.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["agents-0", "agents-1", "agents-2"]);
+        assert!(result.vms.iter().all(|v| v.outcome == ProvisionOutcome::Cancelled));
+        assert!(!tmp.path().join("vms").join("agents-0").exists());
+
+        // A retry with the key gets the same per-VM answer
+        let (_, stored) = orch.db().get_provision_request("req-1").unwrap().unwrap();
+        assert_eq!(stored, Some(result));
     }
 
     #[test]
-    fn test_acquire_from_empty_pool() {
+    fn test_fan_out_bounds_workers_and_keeps_order() {
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let items: Vec<usize> = (0..12).collect();
+
+        let results = fan_out(&items, 3, &AtomicBool::new(false), |&i| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            active.fetch_sub(1, Ordering::SeqCst);
+            i * 2
+        });
+
+        assert_eq!(results, (0..12).map(|i| Some(i * 2)).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        assert!(peak.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn test_fan_out_stops_taking_work() {
+        let stop = AtomicBool::new(false);
+        let items: Vec<usize> = (0..5).collect();
+
+        let results = fan_out(&items, 1, &stop, |&i| {
+            if i == 1 {
+                stop.store(true, Ordering::SeqCst);
+            }
+            i
+        });
+        assert_eq!(results, vec![Some(0), Some(1), None, None, None]);
+    }
+
+    #[test]
+    fn test_prepare_pool_reports_progress() {
         let (orch, tmp) = setup_test_orchestrator();
-        
+
         let vhdx_path = tmp.path().join("template.vhdx");
-        std::fs::write(&vhdx_path, "fake").unwrap();
-        
+        write_template_image(&vhdx_path);
         let template = Template::new("test", &vhdx_path);
         orch.register_template(template.clone()).unwrap();
-        
-        let pool = VMPool::new("empty-pool", &template.id);
+        let pool = VMPool::new("agents", &template.id);
         orch.create_pool(pool.clone()).unwrap();
-        
-        let result = orch.acquire_vm(&pool.id);
-        assert!(result.is_err());
-        assert!(matches!(result.unwrap_err(), Error::NoVMAvailable));
+
+        let mut vm = VM::new("agents-0".to_string(), tmp.path().join("a.vhdx"), 4096, 2);
+        vm.pool_id = Some(pool.id.clone());
+        orch.db().insert_vm(&vm).unwrap();
+
+        let seen = Mutex::new(Vec::new());
+        let prepared = orch.prepare_pool(&pool.id, &|p| seen.lock().unwrap().push(p.clone())).unwrap();
+
+        // No Hyper-V here: the boot fails, is rolled back, and is reported
+        assert_eq!(prepared.len(), 1);
+        assert!(prepared[0].error.is_some());
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.first().unwrap().status, ProgressStatus::Running);
+        assert_eq!(seen.last().unwrap().step, "prepare");
+        assert_eq!(seen.last().unwrap().status, ProgressStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_refuses_new_leases() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let vm = VM::new("leased-vm".to_string(), PathBuf::from(r"C:\vms\leased.vhdx"), 4096, 2);
+        orch.db().insert_vm(&vm).unwrap();
+        orch.db().update_vm_state(&vm.id, VMState::Running).unwrap();
+
+        // Claimed by an acquire the drain cut off
+        let claimed = VM::new("claimed-vm".to_string(), PathBuf::from(r"C:\vms\claimed.vhdx"), 4096, 2);
+        orch.db().insert_vm(&claimed).unwrap();
+        assert!(orch.db().claim_vm(&claimed.id, "acquire-interrupted").unwrap());
+
+        let monitors = orch.shutdown_token();
+        orch.begin_shutdown();
+        assert!(orch.is_shutting_down() && monitors.is_cancelled());
+        assert!(matches!(orch.acquire_vm_async("agents").await, Err(Error::ShuttingDown)));
+        assert!(matches!(orch.resume_vm_async(&vm.id).await, Err(Error::ShuttingDown)));
+
+        // The lease stays in the DB for the next start, its idle clock restarted
+        assert_eq!(orch.shutdown().unwrap(), 1);
+        let kept = orch.db().get_vm(&vm.id).unwrap().unwrap();
+        assert_eq!(kept.state, VMState::Running);
+        assert!(kept.last_activity_at.is_some());
+        assert!(!orch.db().get_vm(&claimed.id).unwrap().unwrap().is_leased());
+    }
+
+    #[test]
+    fn test_reattach_leases() {
+        let (orch, _tmp) = setup_test_orchestrator();
+
+        let agent = Agent::new("agent", Task::new("workflow"));
+        orch.db().insert_agent(&agent).unwrap();
+
+        let kept = VM::new("kept-vm".to_string(), PathBuf::from(r"C:\vms\kept.vhdx"), 4096, 2);
+        orch.db().insert_vm(&kept).unwrap();
+        orch.db().update_vm_state(&kept.id, VMState::Running).unwrap();
+        let lost = VM::new("lost-vm".to_string(), PathBuf::from(r"C:\vms\lost.vhdx"), 4096, 2);
+        orch.db().insert_vm(&lost).unwrap();
+        orch.db().update_vm_state(&lost.id, VMState::Running).unwrap();
+        orch.db().update_vm_agent(&lost.id, Some(&agent.id)).unwrap();
+        orch.db().update_vm_ip(&lost.id, Some("10.0.0.9")).unwrap();
+
+        // Only kept-vm survived the restart; lost-vm was turned off
+        let host = HostSnapshot {
+            vms: vec![
+                crate::hyperv::HyperVInfo { name: "kept-vm".to_string(), state: 3, memory_assigned: None, uptime: None, id: None },
+                crate::hyperv::HyperVInfo { name: "lost-vm".to_string(), state: 2, memory_assigned: None, uptime: None, id: None },
+            ],
+            addresses: vec![crate::hyperv::VMAddressInfo {
+                vm_name: "kept-vm".to_string(),
+                ipv4: Some("10.0.0.5".to_string()),
+            }],
+            ..Default::default()
+        };
+
+        let reattached = orch.reattach_leases_with(&host).unwrap();
+        assert_eq!(reattached, vec![kept.id.clone()]);
+        assert_eq!(orch.db().get_vm(&kept.id).unwrap().unwrap().ip_address.as_deref(), Some("10.0.0.5"));
+
+        let stored = orch.db().get_vm(&lost.id).unwrap().unwrap();
+        assert_eq!(stored.state, VMState::Off);
+        assert!(stored.current_agent_id.is_none());
+        assert!(stored.ip_address.is_none());
+        assert_eq!(orch.db().get_agent(&agent.id).unwrap().unwrap().status, AgentStatus::Failed);
+
+        let kinds: Vec<EventKind> = orch.list_events(&EventFilter::default()).unwrap()
+            .into_iter().map(|e| e.kind).collect();
+        assert!(kinds.contains(&EventKind::LeaseReattached));
+        assert!(kinds.contains(&EventKind::LeaseLost));
+    }
+
+    #[test]
+    fn test_ephemeral_vm_from_template() {
+        let (orch, tmp) = setup_test_orchestrator();
+
+        let vhdx = tmp.path().join("base.vhdx");
+        write_template_image(&vhdx);
+        orch.register_template(Template::new("base", &vhdx).with_memory(4096).with_cpus(2)).unwrap();
+        let template = orch.get_template("base").unwrap().unwrap();
+
+        let options = EphemeralOptions::new("base", Duration::from_secs(600)).with_memory(8192);
+        let vm = orch.ephemeral_vm(&template, &options);
+        assert!(vm.name.starts_with("eph-"));
+        assert!(vm.ephemeral && vm.pool_id.is_none());
+        assert_eq!((vm.memory_mb, vm.cpu_count), (8192, 2));
+        assert!(vm.vhdx_path.starts_with(tmp.path().join("vms").join(&vm.name)));
+        assert!(!vm.is_expired(chrono::Utc::now()));
+
+        let missing = EphemeralOptions::new("nope", Duration::from_secs(600));
+        assert!(matches!(orch.create_ephemeral(&missing), Err(Error::TemplateNotFound(_))));
+        let no_ttl = EphemeralOptions::new("base", Duration::ZERO);
+        assert!(matches!(orch.create_ephemeral(&no_ttl), Err(Error::InvalidArgument(_))));
+
+        orch.begin_shutdown();
+        assert!(matches!(orch.create_ephemeral(&options), Err(Error::ShuttingDown)));
+    }
+
+    #[test]
+    fn test_expired_vms() {
+        let (orch, _tmp) = setup_test_orchestrator();
+        let now = chrono::Utc::now();
+
+        let mut expired = VM::new("eph-old".to_string(), PathBuf::from(r"C:\vms\old.vhdx"), 4096, 2);
+        expired.ephemeral = true;
+        expired.expires_at = Some(now - chrono::Duration::seconds(1));
+        orch.db().insert_vm(&expired).unwrap();
+
+        let mut fresh = VM::new("eph-new".to_string(), PathBuf::from(r"C:\vms\new.vhdx"), 4096, 2);
+        fresh.ephemeral = true;
+        fresh.expires_at = Some(now + chrono::Duration::seconds(600));
+        orch.db().insert_vm(&fresh).unwrap();
+
+        let pooled = VM::new("agents-0".to_string(), PathBuf::from(r"C:\vms\agents-0.vhdx"), 4096, 2);
+        orch.db().insert_vm(&pooled).unwrap();
+
+        let names: Vec<String> = orch.expired_vms(now).unwrap().into_iter().map(|v| v.name).collect();
+        assert_eq!(names, vec!["eph-old"]);
+
+        // Left alone while something else is working on it
+        let _busy = orch.mark_busy(&expired.id);
+        assert!(orch.expired_vms(now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_disk_usage_limit() {
+        let (orch, tmp) = setup_test_orchestrator();
+        let template = Template::new("test", tmp.path().join("template.vhdx"));
+        orch.db().insert_template(&template).unwrap();
+        let pool = VMPool::new("agents", &template.id).with_disk_limit(3 * 1024 * 1024);
+        orch.create_pool(pool.clone()).unwrap();
+
+        let vm_dir = tmp.path().join("vms").join("agents-0");
+        std::fs::create_dir_all(&vm_dir).unwrap();
+        let mut vm = VM::new("agents-0".to_string(), vm_dir.join("disk.vhdx"), 4096, 2);
+        vm.pool_id = Some(pool.id.clone());
+        orch.db().insert_vm(&vm).unwrap();
+
+        std::fs::write(vm_dir.join("disk.vhdx"), vec![0u8; 2 * 1024 * 1024]).unwrap();
+        std::fs::write(vm_dir.join("notes.txt"), vec![0u8; 4 * 1024 * 1024]).unwrap();
+        assert!(orch.check_disk_usage().unwrap().is_empty());
+        assert_eq!(orch.db().get_vm(&vm.id).unwrap().unwrap().disk_bytes, Some(2 * 1024 * 1024));
+
+        // A checkpoint child pushes it over
+        std::fs::write(vm_dir.join("disk_1F2E.avhdx"), vec![0u8; 2 * 1024 * 1024]).unwrap();
+        assert_eq!(orch.check_disk_usage().unwrap(), vec![vm.id.clone()]);
+        assert_eq!(orch.check_disk_usage().unwrap(), vec![vm.id.clone()]);
+
+        // Alerted once, not on every pass
+        let alerts = orch.list_events(&EventFilter { kind: Some(EventKind::DiskLimitExceeded), ..Default::default() }).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].vm_name.as_deref(), Some("agents-0"));
+
+        let status = orch.get_pool_status(&pool.id).unwrap();
+        assert_eq!(status.disk_bytes, 4 * 1024 * 1024);
+        assert_eq!(status.over_disk_limit_vms, 1);
     }
 }
@@ -0,0 +1,399 @@
+//! Drift detection between the database, Hyper-V and the VM storage directory
+
+use crate::hyperv::{CheckpointInfo, HyperV, HyperVInfo, VMAddressInfo, VMDiskInfo};
+use crate::models::*;
+use crate::vhdx::DiskChain;
+use crate::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Checkpoint every prepared VM is expected to have
+pub const CLEAN_CHECKPOINT: &str = "clean";
+
+/// Everything the reconciler looks at on the host, captured up front
+#[derive(Debug, Clone, Default)]
+pub struct HostSnapshot {
+    pub vms: Vec<HyperVInfo>,
+    pub checkpoints: Vec<CheckpointInfo>,
+    pub disks: Vec<VMDiskInfo>,
+    pub addresses: Vec<VMAddressInfo>,
+    /// Parent chains of the disks in `disks`, walked from the VHDX files
+    pub chains: Vec<DiskChain>,
+    /// Directories directly under the VM storage path
+    pub directories: Vec<PathBuf>,
+}
+
+impl HostSnapshot {
+    /// Query Hyper-V and list the storage directory
+    pub fn capture(storage_path: &Path) -> Result<Self> {
+        let mut directories = Vec::new();
+        if storage_path.exists() {
+            for entry in std::fs::read_dir(storage_path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    directories.push(entry.path());
+                }
+            }
+        }
+
+        let disks = HyperV::list_vm_disks()?;
+        let chains = disks.iter().map(|d| DiskChain::walk(&d.path)).collect();
+        Ok(Self {
+            vms: HyperV::list_vms()?,
+            checkpoints: HyperV::list_checkpoints()?,
+            disks,
+            addresses: HyperV::list_vm_addresses()?,
+            chains,
+            directories,
+        })
+    }
+
+    fn vm(&self, name: &str) -> Option<&HyperVInfo> {
+        self.vms.iter().find(|v| v.name == name)
+    }
+
+    fn disk(&self, vm_name: &str) -> Option<&VMDiskInfo> {
+        self.disks.iter().find(|d| d.vm_name == vm_name)
+    }
+
+    fn chain(&self, path: &str) -> Option<&DiskChain> {
+        self.chains.iter().find(|c| same_path(&c.path.to_string_lossy(), Path::new(path)))
+    }
+}
+
+/// Compare DB records against a host snapshot.
+///
+/// `versions` lists every template version, so VMs provisioned from an
+/// older version are checked against that version's disk.
+///
+/// VMs in `busy` are mid-operation: their state, IP and checkpoints are not
+/// judged, but their directories are never reported as orphans.
+pub fn detect_drift(
+    db_vms: &[VM],
+    pools: &[VMPool],
+    templates: &[Template],
+    versions: &[TemplateVersion],
+    host: &HostSnapshot,
+    busy: &HashSet<String>,
+    path_exists: impl Fn(&Path) -> bool,
+) -> Vec<Drift> {
+    let mut drift = Vec::new();
+
+    for vm in db_vms {
+        let Some(info) = host.vm(&vm.name) else {
+            drift.push(Drift::MissingFromHyperV { vm_name: vm.name.clone() });
+            continue;
+        };
+        if busy.contains(&vm.id) {
+            continue;
+        }
+
+        let actual_state = VMState::from_hyperv_state(info.state);
+        if actual_state != vm.state {
+            drift.push(Drift::StateMismatch {
+                vm_name: vm.name.clone(),
+                db_state: vm.state,
+                actual_state,
+            });
+        }
+
+        if matches!(actual_state, VMState::Saved | VMState::Running)
+            && !host.checkpoints.iter().any(|c| c.vm_name == vm.name && c.name == CLEAN_CHECKPOINT)
+        {
+            drift.push(Drift::MissingCheckpoint {
+                vm_name: vm.name.clone(),
+                checkpoint: CLEAN_CHECKPOINT.to_string(),
+            });
+        }
+
+        if actual_state == VMState::Running {
+            let actual_ip = host.addresses.iter()
+                .find(|a| a.vm_name == vm.name)
+                .and_then(|a| a.ipv4.clone());
+            if actual_ip.is_some() && actual_ip != vm.ip_address {
+                drift.push(Drift::IpMismatch {
+                    vm_name: vm.name.clone(),
+                    db_ip: vm.ip_address.clone(),
+                    actual_ip,
+                });
+            }
+        }
+
+        let template = vm.template_id.as_ref()
+            .and_then(|id| templates.iter().find(|t| &t.id == id));
+        if let (Some(template), Some(disk)) = (template, host.disk(&vm.name)) {
+            let expected = match vm.template_version {
+                Some(v) if v != template.current_version => versions.iter()
+                    .find(|tv| tv.template_id == template.id && tv.version == v)
+                    .map(|tv| &tv.vhdx_path)
+                    .unwrap_or(&template.vhdx_path),
+                _ => &template.vhdx_path,
+            };
+            let parent = disk.parent_path.as_deref().filter(|p| !p.is_empty());
+            let broken = match parent {
+                Some(p) => !same_path(p, expected) || !path_exists(Path::new(p)),
+                None => true,
+            };
+            if broken {
+                drift.push(Drift::BrokenParent {
+                    vm_name: vm.name.clone(),
+                    vhdx_path: PathBuf::from(&disk.path),
+                    expected_parent: Some(expected.clone()),
+                    actual_parent: parent.map(PathBuf::from),
+                });
+                continue;
+            }
+        }
+
+        // Hyper-V only reports the immediate parent; the files tell the rest
+        let chain = host.disk(&vm.name).and_then(|d| host.chain(&d.path));
+        if let Some(problem) = chain.and_then(|c| c.problem.as_ref()) {
+            drift.push(Drift::BrokenChain {
+                vm_name: vm.name.clone(),
+                vhdx_path: chain.map(|c| c.path.clone()).unwrap_or_default(),
+                problem: problem.to_string(),
+            });
+        }
+    }
+
+    let known: HashSet<&str> = db_vms.iter().map(|v| v.name.as_str()).collect();
+    for info in &host.vms {
+        if known.contains(info.name.as_str()) {
+            continue;
+        }
+        if let Some(pool) = pools.iter().find(|p| is_pool_vm_name(&p.name, &info.name)) {
+            drift.push(Drift::UnknownVM {
+                vm_name: info.name.clone(),
+                pool_name: pool.name.clone(),
+                vhdx_path: host.disk(&info.name).map(|d| PathBuf::from(&d.path)),
+            });
+        }
+    }
+
+    for dir in &host.directories {
+        let Some(name) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        // Hidden directories hold our own staging data
+        if name.starts_with('.') || known.contains(name) || host.vm(name).is_some() {
+            continue;
+        }
+        drift.push(Drift::OrphanDirectory { path: dir.clone() });
+    }
+
+    drift
+}
+
+/// Whether a VM name follows the `{pool}-{index}` scheme used by `provision_pool`
+pub fn is_pool_vm_name(pool_name: &str, vm_name: &str) -> bool {
+    vm_name
+        .strip_prefix(pool_name)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .is_some_and(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Windows paths compare case-insensitively and accept either separator
+fn same_path(a: &str, b: &Path) -> bool {
+    let norm = |s: &str| s.replace('/', "\\").trim_end_matches('\\').to_lowercase();
+    norm(a) == norm(&b.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hv(name: &str, state: i32) -> HyperVInfo {
+        HyperVInfo {
+            name: name.to_string(),
+            state,
+            memory_assigned: None,
+            uptime: None,
+            id: None,
+        }
+    }
+
+    fn checkpoint(vm: &str) -> CheckpointInfo {
+        CheckpointInfo { vm_name: vm.to_string(), name: CLEAN_CHECKPOINT.to_string() }
+    }
+
+    fn disk(vm: &str, parent: Option<&str>) -> VMDiskInfo {
+        VMDiskInfo {
+            vm_name: vm.to_string(),
+            path: format!(r"C:\VMs\{}\disk.vhdx", vm),
+            parent_path: parent.map(String::from),
+        }
+    }
+
+    fn setup() -> (Template, VMPool, VM) {
+        let template = Template::new("win11", r"C:\Templates\win11.vhdx");
+        let pool = VMPool::new("agents", &template.id);
+        let mut vm = VM::new("agents-0".to_string(), PathBuf::from(r"C:\VMs\agents-0\disk.vhdx"), 4096, 2);
+        vm.template_id = Some(template.id.clone());
+        vm.pool_id = Some(pool.id.clone());
+        vm.state = VMState::Saved;
+        (template, pool, vm)
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let (template, pool, vm) = setup();
+        let host = HostSnapshot {
+            vms: vec![hv("agents-0", 6)],
+            checkpoints: vec![checkpoint("agents-0")],
+            disks: vec![disk("agents-0", Some(r"c:/templates/WIN11.vhdx"))],
+            directories: vec![PathBuf::from("vms/agents-0"), PathBuf::from("vms/.transfers")],
+            ..Default::default()
+        };
+
+        let drift = detect_drift(&[vm], &[pool], &[template], &[], &host, &HashSet::new(), |_| true);
+        assert!(drift.is_empty(), "{:?}", drift);
+    }
+
+    #[test]
+    fn test_state_checkpoint_and_ip_drift() {
+        let (template, pool, mut vm) = setup();
+        vm.ip_address = Some("172.20.0.5".to_string());
+        let host = HostSnapshot {
+            vms: vec![hv("agents-0", 3)],
+            addresses: vec![VMAddressInfo { vm_name: "agents-0".to_string(), ipv4: Some("172.20.0.9".to_string()) }],
+            ..Default::default()
+        };
+
+        let drift = detect_drift(std::slice::from_ref(&vm), std::slice::from_ref(&pool), std::slice::from_ref(&template), &[], &host, &HashSet::new(), |_| true);
+        assert_eq!(drift, vec![
+            Drift::StateMismatch { vm_name: "agents-0".to_string(), db_state: VMState::Saved, actual_state: VMState::Running },
+            Drift::MissingCheckpoint { vm_name: "agents-0".to_string(), checkpoint: "clean".to_string() },
+            Drift::IpMismatch {
+                vm_name: "agents-0".to_string(),
+                db_ip: Some("172.20.0.5".to_string()),
+                actual_ip: Some("172.20.0.9".to_string()),
+            },
+        ]);
+
+        // Busy VMs are left alone, but not reported missing either
+        let busy = HashSet::from([vm.id.clone()]);
+        assert!(detect_drift(&[vm], &[pool], &[template], &[], &host, &busy, |_| true).is_empty());
+    }
+
+    #[test]
+    fn test_broken_parent() {
+        let (template, pool, vm) = setup();
+        let mut host = HostSnapshot {
+            vms: vec![hv("agents-0", 6)],
+            checkpoints: vec![checkpoint("agents-0")],
+            disks: vec![disk("agents-0", Some(r"C:\Templates\win11.vhdx"))],
+            ..Default::default()
+        };
+
+        // Template VHDX moved away
+        let drift = detect_drift(std::slice::from_ref(&vm), std::slice::from_ref(&pool), std::slice::from_ref(&template), &[], &host, &HashSet::new(), |_| false);
+        assert!(matches!(&drift[..], [Drift::BrokenParent { actual_parent: Some(_), .. }]));
+
+        // Disk points at some other parent
+        host.disks = vec![disk("agents-0", Some(r"D:\old\win11.vhdx"))];
+        let drift = detect_drift(std::slice::from_ref(&vm), std::slice::from_ref(&pool), std::slice::from_ref(&template), &[], &host, &HashSet::new(), |_| true);
+        assert_eq!(drift.len(), 1);
+
+        // Not a differencing disk at all
+        host.disks = vec![disk("agents-0", Some(""))];
+        let drift = detect_drift(&[vm], &[pool], &[template], &[], &host, &HashSet::new(), |_| true);
+        assert!(matches!(&drift[..], [Drift::BrokenParent { actual_parent: None, .. }]));
+    }
+
+    #[test]
+    fn test_parent_of_older_version() {
+        let (mut template, pool, mut vm) = setup();
+        let v1 = TemplateVersion::current_of(&template);
+        let v2 = TemplateVersion::new(&template.id, 2, r"C:\Templates\win11-v2.vhdx");
+        template = template.at_version(&v2);
+        vm.template_version = Some(1);
+        let host = HostSnapshot {
+            vms: vec![hv("agents-0", 6)],
+            checkpoints: vec![checkpoint("agents-0")],
+            disks: vec![disk("agents-0", Some(r"C:\Templates\win11.vhdx"))],
+            ..Default::default()
+        };
+
+        let versions = [v1, v2];
+        let drift = detect_drift(std::slice::from_ref(&vm), std::slice::from_ref(&pool), std::slice::from_ref(&template), &versions, &host, &HashSet::new(), |_| true);
+        assert!(drift.is_empty(), "{:?}", drift);
+
+        // Provisioned from v2 but still on v1's disk
+        vm.template_version = Some(2);
+        let drift = detect_drift(&[vm], &[pool], &[template], &versions, &host, &HashSet::new(), |_| true);
+        assert!(matches!(&drift[..], [Drift::BrokenParent { expected_parent: Some(p), .. }] if p == Path::new(r"C:\Templates\win11-v2.vhdx")));
+    }
+
+    #[test]
+    fn test_broken_chain() {
+        let (template, pool, vm) = setup();
+        let tmp = tempfile::TempDir::new().unwrap();
+        let disk_path = tmp.path().join("disk.vhdx");
+        crate::vhdx::fixture::Image::differencing(1 << 30, uuid::Uuid::new_v4(), "win11.vhdx", r"C:\Templates\win11.vhdx")
+            .write(&disk_path)
+            .unwrap();
+        let mut host = HostSnapshot {
+            vms: vec![hv("agents-0", 6)],
+            checkpoints: vec![checkpoint("agents-0")],
+            disks: vec![VMDiskInfo {
+                vm_name: "agents-0".to_string(),
+                path: disk_path.display().to_string(),
+                parent_path: Some(r"C:\Templates\win11.vhdx".to_string()),
+            }],
+            chains: vec![DiskChain::walk(&disk_path)],
+            ..Default::default()
+        };
+
+        // Hyper-V's view is fine, but the parent isn't where the locator says
+        let drift = detect_drift(std::slice::from_ref(&vm), std::slice::from_ref(&pool), std::slice::from_ref(&template), &[], &host, &HashSet::new(), |_| true);
+        assert!(matches!(&drift[..], [Drift::BrokenChain { vhdx_path, problem, .. }] if *vhdx_path == disk_path && problem.contains("parent not found")));
+
+        // A broken parent is reported once, not twice
+        host.disks[0].parent_path = Some(r"D:\old\win11.vhdx".to_string());
+        let drift = detect_drift(&[vm], &[pool], &[template], &[], &host, &HashSet::new(), |_| true);
+        assert!(matches!(&drift[..], [Drift::BrokenParent { .. }]));
+    }
+
+    #[test]
+    fn test_unknown_vms_and_orphans() {
+        let (template, pool, vm) = setup();
+        let host = HostSnapshot {
+            vms: vec![hv("agents-0", 2), hv("agents-7", 2), hv("unrelated", 3), hv("agents-x", 2)],
+            disks: vec![disk("agents-7", None)],
+            directories: vec![
+                PathBuf::from("vms/agents-0"),
+                PathBuf::from("vms/agents-7"),
+                PathBuf::from("vms/agents-3"),
+            ],
+            ..Default::default()
+        };
+        let mut off = vm;
+        off.state = VMState::Off;
+
+        let drift = detect_drift(&[off], &[pool], &[template], &[], &host, &HashSet::new(), |_| true);
+        assert_eq!(drift, vec![
+            Drift::UnknownVM {
+                vm_name: "agents-7".to_string(),
+                pool_name: "agents".to_string(),
+                vhdx_path: Some(PathBuf::from(r"C:\VMs\agents-7\disk.vhdx")),
+            },
+            Drift::OrphanDirectory { path: PathBuf::from("vms/agents-3") },
+        ]);
+    }
+
+    #[test]
+    fn test_missing_from_hyperv() {
+        let (template, pool, vm) = setup();
+        let drift = detect_drift(&[vm], &[pool], &[template], &[], &HostSnapshot::default(), &HashSet::new(), |_| true);
+        assert_eq!(drift, vec![Drift::MissingFromHyperV { vm_name: "agents-0".to_string() }]);
+    }
+
+    #[test]
+    fn test_is_pool_vm_name() {
+        assert!(is_pool_vm_name("agents", "agents-0"));
+        assert!(is_pool_vm_name("agents", "agents-12"));
+        assert!(!is_pool_vm_name("agents", "agents-"));
+        assert!(!is_pool_vm_name("agents", "agents-big-1"));
+        assert!(!is_pool_vm_name("agents", "agentsx-1"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Per-lease console recorder
+//!
+//! Samples the VM console on a background thread, drops frames identical to
+//! the previous one, and writes the rest as PNGs plus a `timeline.json`.
+
+use crate::hyperv;
+use crate::models::{ScreenTimeline, TimelineFrame};
+use crate::{Error, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Frame size used for recordings
+pub const RECORD_WIDTH: u32 = 1024;
+pub const RECORD_HEIGHT: u32 = 768;
+
+/// A running recorder; call `stop` to finish and collect the timeline
+pub struct ScreenRecorder {
+    vm_name: String,
+    stop_tx: Sender<()>,
+    handle: JoinHandle<ScreenTimeline>,
+}
+
+impl ScreenRecorder {
+    /// Start sampling `capture` (raw RGB565 frames) every `interval`
+    pub fn start<F>(
+        vm_name: &str,
+        directory: &Path,
+        interval: Duration,
+        (width, height): (u32, u32),
+        mut capture: F,
+    ) -> Result<Self>
+    where
+        F: FnMut() -> Result<Vec<u8>> + Send + 'static,
+    {
+        std::fs::create_dir_all(directory)?;
+
+        let mut timeline = ScreenTimeline::new(vm_name, directory, interval.as_millis() as u64);
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+        let handle = std::thread::spawn(move || {
+            let mut last_hash = None;
+
+            loop {
+                match capture() {
+                    Ok(raw) => {
+                        timeline.samples += 1;
+                        let hash = frame_hash(&raw);
+                        if last_hash != Some(hash) {
+                            match write_frame(&timeline.directory, timeline.frames.len(), &raw, width, height) {
+                                Ok(path) => {
+                                    timeline.frames.push(TimelineFrame {
+                                        captured_at: chrono::Utc::now(),
+                                        path,
+                                    });
+                                    last_hash = Some(hash);
+                                }
+                                Err(e) => tracing::warn!(vm = %timeline.vm_name, error = %e, "Failed to write frame"),
+                            }
+                        }
+                    }
+                    Err(e) => tracing::debug!(vm = %timeline.vm_name, error = %e, "Frame capture failed"),
+                }
+
+                // Sleep until the next sample, waking early on stop (or orchestrator drop)
+                match stop_rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+
+            timeline.ended_at = Some(chrono::Utc::now());
+            if let Err(e) = write_timeline(&timeline) {
+                tracing::warn!(vm = %timeline.vm_name, error = %e, "Failed to write timeline.json");
+            }
+            timeline
+        });
+
+        Ok(Self { vm_name: vm_name.to_string(), stop_tx, handle })
+    }
+
+    /// Start recording a Hyper-V VM's console
+    pub fn start_vm(vm_name: &str, directory: &Path, interval: Duration) -> Result<Self> {
+        let name = vm_name.to_string();
+        Self::start(vm_name, directory, interval, (RECORD_WIDTH, RECORD_HEIGHT), move || {
+            hyperv::HyperV::capture_screenshot(&name, RECORD_WIDTH, RECORD_HEIGHT)
+        })
+    }
+
+    /// Stop sampling and return the finished timeline
+    pub fn stop(self) -> Result<ScreenTimeline> {
+        let _ = self.stop_tx.send(());
+        self.handle.join()
+            .map_err(|_| Error::Other(format!("Console recorder for {} panicked", self.vm_name)))
+    }
+}
+
+fn frame_hash(raw: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    raw.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn write_frame(dir: &Path, index: usize, raw: &[u8], width: u32, height: u32) -> Result<PathBuf> {
+    let png = hyperv::rgb565_to_png(raw, width, height)?;
+    let path = dir.join(format!("frame-{:05}.png", index));
+    std::fs::write(&path, png)?;
+    Ok(path)
+}
+
+fn write_timeline(timeline: &ScreenTimeline) -> Result<()> {
+    let json = serde_json::to_string_pretty(timeline)?;
+    std::fs::write(timeline.directory.join("timeline.json"), json)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn solid(value: u16) -> Vec<u8> {
+        (0..4).flat_map(|_| value.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_recorder_dedups_unchanged_frames() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let script = [solid(0), solid(0), solid(0xffff), solid(0xffff), solid(0)];
+        let calls = Arc::new(Mutex::new(0usize));
+
+        let counter = calls.clone();
+        let recorder = ScreenRecorder::start("vm", tmp.path(), Duration::from_millis(5), (2, 2), move || {
+            let mut n = counter.lock().unwrap();
+            let frame = script[(*n).min(script.len() - 1)].clone();
+            *n += 1;
+            Ok(frame)
+        })
+        .unwrap();
+
+        // Wait until every scripted frame has been captured
+        while *calls.lock().unwrap() < 6 {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let timeline = recorder.stop().unwrap();
+
+        assert_eq!(timeline.frames.len(), 3);
+        assert!(timeline.samples >= 5);
+        assert!(timeline.ended_at.is_some());
+        assert!(timeline.frames.iter().all(|f| f.path.exists()));
+        assert!(tmp.path().join("timeline.json").exists());
+    }
+
+    #[test]
+    fn test_recorder_survives_capture_errors() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let recorder = ScreenRecorder::start("vm", tmp.path(), Duration::from_millis(5), (2, 2), || {
+            Err(crate::Error::Timeout)
+        })
+        .unwrap();
+
+        std::thread::sleep(Duration::from_millis(20));
+        let timeline = recorder.stop().unwrap();
+        assert!(timeline.frames.is_empty());
+        assert_eq!(timeline.samples, 0);
+    }
+
+    #[test]
+    fn test_recorder_panic_is_an_error() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let recorder = ScreenRecorder::start("vm", tmp.path(), Duration::from_millis(5), (2, 2), || {
+            panic!("capture blew up")
+        })
+        .unwrap();
+
+        let e = recorder.stop().unwrap_err();
+        assert!(e.to_string().contains("vm"));
+    }
+}
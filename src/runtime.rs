@@ -0,0 +1,48 @@
+//! Bridge from the synchronous API to the async one
+
+use std::future::Future;
+use tokio::runtime::{Builder, Handle, RuntimeFlavor};
+
+/// Run a future to completion from synchronous code.
+///
+/// Works from plain threads (a throwaway current-thread runtime), from
+/// `spawn_blocking` threads and from inside a multi-threaded runtime such as
+/// `#[tokio::main]` (via `block_in_place`). Async callers should await the
+/// `*_async` methods instead; calling this from a current-thread runtime panics.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(fut))
+        }
+        _ => Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build tokio runtime")
+            .block_on(fut),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn slow_add(a: u32, b: u32) -> u32 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        a + b
+    }
+
+    #[test]
+    fn test_block_on_plain_thread() {
+        assert_eq!(block_on(slow_add(1, 2)), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_block_on_inside_runtime() {
+        // Directly on a worker, as the CLI does under #[tokio::main]
+        assert_eq!(block_on(slow_add(2, 3)), 5);
+        // From a blocking task, as API handlers do for long sync operations
+        let sum = tokio::task::spawn_blocking(|| block_on(slow_add(3, 4))).await.unwrap();
+        assert_eq!(sum, 7);
+    }
+}
@@ -0,0 +1,133 @@
+//! At-rest protection for secrets kept in the state DB
+//!
+//! On Windows, secrets are encrypted with DPAPI in the scope of the account
+//! that writes them (normally the service account), so neither other local
+//! users nor a copy of the DB taken off the host can read them. Run the CLI as
+//! the service account when it writes to the same DB. Elsewhere (tests,
+//! development builds) they are stored as given.
+//!
+//! Values written before protection existed are read back unchanged and
+//! re-protected by the next migration.
+
+use crate::{Error, Result};
+
+/// Prefix of a DPAPI-protected value: `dpapi:<base64 blob>`
+const DPAPI_PREFIX: &str = "dpapi:";
+
+/// Whether a stored value is already protected
+pub fn is_protected(stored: &str) -> bool {
+    stored.starts_with(DPAPI_PREFIX)
+}
+
+/// Protect a secret for storage
+#[cfg(windows)]
+pub fn protect(plain: &str) -> Result<String> {
+    use base64::Engine;
+    let blob = dpapi::protect(plain.as_bytes())?;
+    Ok(format!("{}{}", DPAPI_PREFIX, base64::engine::general_purpose::STANDARD.encode(blob)))
+}
+
+#[cfg(not(windows))]
+pub fn protect(plain: &str) -> Result<String> {
+    Ok(plain.to_string())
+}
+
+/// Recover a secret written by `protect` (or stored before protection existed)
+pub fn unprotect(stored: &str) -> Result<String> {
+    let Some(encoded) = stored.strip_prefix(DPAPI_PREFIX) else {
+        return Ok(stored.to_string());
+    };
+    unprotect_dpapi(encoded)
+}
+
+#[cfg(windows)]
+fn unprotect_dpapi(encoded: &str) -> Result<String> {
+    use base64::Engine;
+    let blob = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| Error::Other(format!("Corrupt protected secret: {}", e)))?;
+    String::from_utf8(dpapi::unprotect(&blob)?)
+        .map_err(|_| Error::Other("Protected secret is not UTF-8".to_string()))
+}
+
+#[cfg(not(windows))]
+fn unprotect_dpapi(_encoded: &str) -> Result<String> {
+    Err(Error::Other("DPAPI-protected secrets can only be read on Windows".to_string()))
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use crate::{Error, Result};
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+
+    pub fn protect(data: &[u8]) -> Result<Vec<u8>> {
+        call(data, |input, output| unsafe {
+            CryptProtectData(
+                input,
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        })
+    }
+
+    pub fn unprotect(data: &[u8]) -> Result<Vec<u8>> {
+        call(data, |input, output| unsafe {
+            CryptUnprotectData(
+                input,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        })
+    }
+
+    /// Run a DPAPI call and copy out (then free) the blob it allocated
+    fn call(data: &[u8], f: impl FnOnce(*const CRYPT_INTEGER_BLOB, *mut CRYPT_INTEGER_BLOB) -> i32) -> Result<Vec<u8>> {
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+        if f(&input, &mut output) == 0 {
+            return Err(Error::Other(format!("DPAPI failed: {}", std::io::Error::last_os_error())));
+        }
+        // SAFETY: on success DPAPI returns a LocalAlloc'd buffer of cbData bytes
+        let bytes = unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec() };
+        unsafe { LocalFree(output.pbData as _) };
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let stored = protect("s3cret").unwrap();
+        assert_eq!(unprotect(&stored).unwrap(), "s3cret");
+        assert_eq!(is_protected(&stored), cfg!(windows));
+        if cfg!(windows) {
+            assert!(!stored.contains("s3cret"));
+        }
+    }
+
+    #[test]
+    fn test_plain_values_read_back() {
+        assert_eq!(unprotect("legacy-password").unwrap(), "legacy-password");
+        assert!(!is_protected("legacy-password"));
+    }
+}
@@ -0,0 +1,229 @@
+//! Differencing-disk chains, followed through parent locators
+
+use super::{inspect, DiskType, VhdxInfo};
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+
+/// Deeper than any chain we create; also stops parent loops
+const MAX_DEPTH: usize = 16;
+
+/// One disk of a chain, child first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainLink {
+    pub path: PathBuf,
+    pub disk_type: DiskType,
+    pub virtual_size: u64,
+    /// What children of this disk record as their parent linkage
+    pub data_write_guid: Uuid,
+}
+
+/// Why a chain stops before reaching a disk without a parent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainProblem {
+    /// Missing, unreadable or not a valid VHDX
+    Unreadable { path: PathBuf, reason: String },
+    /// None of the locator's paths exist
+    ParentMissing { child: PathBuf, locations: Vec<PathBuf> },
+    /// The parent was written to (or replaced) after the child was created
+    LinkageMismatch { child: PathBuf, parent: PathBuf, expected: Option<Uuid>, found: Uuid },
+    TooDeep,
+}
+
+impl std::fmt::Display for ChainProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainProblem::Unreadable { path, reason } => write!(f, "{}: {}", path.display(), reason),
+            ChainProblem::ParentMissing { child, locations } => write!(
+                f,
+                "{}: parent not found at {}",
+                child.display(),
+                locations.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(" or ")
+            ),
+            ChainProblem::LinkageMismatch { child, parent, expected, found } => write!(
+                f,
+                "{}: parent {} has linkage {}, child expects {}",
+                child.display(),
+                parent.display(),
+                found,
+                expected.map(|g| g.to_string()).unwrap_or_else(|| "none".to_string())
+            ),
+            ChainProblem::TooDeep => write!(f, "chain is deeper than {} disks", MAX_DEPTH),
+        }
+    }
+}
+
+/// A disk and its ancestors, as far as they could be followed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskChain {
+    /// Disk the walk started from
+    pub path: PathBuf,
+    pub links: Vec<ChainLink>,
+    pub problem: Option<ChainProblem>,
+}
+
+impl DiskChain {
+    /// Follow parent locators from `path` to the base disk
+    pub fn walk(path: impl AsRef<Path>) -> Self {
+        let mut chain = Self {
+            path: path.as_ref().to_path_buf(),
+            links: Vec::new(),
+            problem: None,
+        };
+
+        let mut current = chain.path.clone();
+        let mut expected_linkage: Option<(PathBuf, Option<Uuid>)> = None;
+        loop {
+            if chain.links.len() == MAX_DEPTH {
+                chain.problem = Some(ChainProblem::TooDeep);
+                break;
+            }
+            let info = match inspect(&current) {
+                Ok(info) => info,
+                Err(e) => {
+                    chain.problem = Some(ChainProblem::Unreadable { path: current, reason: e.to_string() });
+                    break;
+                }
+            };
+
+            if let Some((child, expected)) = expected_linkage.take() {
+                if expected != Some(info.header.data_write_guid) {
+                    chain.problem = Some(ChainProblem::LinkageMismatch {
+                        child,
+                        parent: current,
+                        expected,
+                        found: info.header.data_write_guid,
+                    });
+                    break;
+                }
+            }
+
+            chain.links.push(ChainLink {
+                path: current.clone(),
+                disk_type: info.disk_type,
+                virtual_size: info.virtual_size,
+                data_write_guid: info.header.data_write_guid,
+            });
+
+            let Some(locator) = info.parent_locator.as_ref() else {
+                break;
+            };
+            let locations = parent_locations(&current, &info);
+            let Some(parent) = locations.iter().find(|p| p.is_file()).cloned() else {
+                chain.problem = Some(ChainProblem::ParentMissing { child: current, locations });
+                break;
+            };
+            expected_linkage = Some((current, locator.parent_linkage()));
+            current = parent;
+        }
+        chain
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.problem.is_some()
+    }
+
+    /// The disk without a parent, if the walk got that far
+    pub fn base(&self) -> Option<&ChainLink> {
+        self.links.last().filter(|_| !self.is_broken())
+    }
+}
+
+/// Where a child's locator says the parent is, in the order Hyper-V tries them
+fn parent_locations(child: &Path, info: &VhdxInfo) -> Vec<PathBuf> {
+    let Some(locator) = &info.parent_locator else {
+        return vec![];
+    };
+    let mut locations = Vec::new();
+    if let Some(relative) = locator.relative_path() {
+        let dir = child.parent().unwrap_or(Path::new(""));
+        locations.push(normalize(&dir.join(relative.replace('\\', std::path::MAIN_SEPARATOR_STR))));
+    }
+    if let Some(absolute) = locator.absolute_win32_path() {
+        locations.push(PathBuf::from(absolute));
+    }
+    locations
+}
+
+/// Resolve `.` and `..` without touching the filesystem
+pub(super) fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(out.components().next_back(), Some(Component::Normal(_))) => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhdx::fixture::Image;
+    use crate::vhdx::format::MB;
+
+    fn child_of(parent: &Image, relative: &str) -> Image {
+        Image::differencing(parent.virtual_size, parent.data_write_guid, relative, r"C:\Templates\win11.vhdx")
+    }
+
+    #[test]
+    fn test_walk_chain() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let base = Image::dynamic(1024 * MB);
+        base.write(tmp.path().join("win11.vhdx")).unwrap();
+        std::fs::create_dir(tmp.path().join("agents-0")).unwrap();
+        let disk = tmp.path().join("agents-0").join("disk.vhdx");
+        child_of(&base, r"..\.\win11.vhdx").write(&disk).unwrap();
+
+        let chain = DiskChain::walk(&disk);
+        assert!(!chain.is_broken(), "{:?}", chain.problem);
+        assert_eq!(chain.links.len(), 2);
+        assert_eq!(chain.links[0].disk_type, DiskType::Differencing);
+        let base_link = chain.base().unwrap();
+        assert_eq!(base_link.path, tmp.path().join("win11.vhdx"));
+        assert_eq!(base_link.data_write_guid, base.data_write_guid);
+    }
+
+    #[test]
+    fn test_broken_chains() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let base = Image::dynamic(1024 * MB);
+        let disk = tmp.path().join("disk.vhdx");
+        child_of(&base, "win11.vhdx").write(&disk).unwrap();
+
+        // Template moved away
+        let chain = DiskChain::walk(&disk);
+        assert!(matches!(&chain.problem, Some(ChainProblem::ParentMissing { locations, .. }) if locations.len() == 2));
+        assert_eq!(chain.links.len(), 1);
+        assert!(chain.base().is_none());
+
+        // Template replaced by a different image at the same path
+        Image::dynamic(1024 * MB).write(tmp.path().join("win11.vhdx")).unwrap();
+        let chain = DiskChain::walk(&disk);
+        assert!(matches!(&chain.problem, Some(ChainProblem::LinkageMismatch { expected: Some(g), .. }) if *g == base.data_write_guid));
+
+        // Template overwritten with garbage
+        std::fs::write(tmp.path().join("win11.vhdx"), "not a disk").unwrap();
+        let chain = DiskChain::walk(&disk);
+        assert!(matches!(chain.problem, Some(ChainProblem::Unreadable { .. })));
+    }
+
+    #[test]
+    fn test_parent_loop() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let mut image = Image::differencing(1024 * MB, Uuid::nil(), "loop.vhdx", r"C:\loop.vhdx");
+        image.data_write_guid = Uuid::nil();
+        image.write(tmp.path().join("loop.vhdx")).unwrap();
+
+        assert_eq!(DiskChain::walk(tmp.path().join("loop.vhdx")).problem, Some(ChainProblem::TooDeep));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("a/b/../c/./d")), PathBuf::from("a/c/d"));
+        assert_eq!(normalize(Path::new("../x")), PathBuf::from("../x"));
+    }
+}
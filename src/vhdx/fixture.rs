@@ -0,0 +1,136 @@
+//! Minimal VHDX images for tests
+
+use super::format::*;
+use std::path::Path;
+use uuid::Uuid;
+
+/// A small but well-formed VHDX: headers, log, metadata and BAT regions, no data blocks
+pub struct Image {
+    pub virtual_size: u64,
+    pub block_size: u32,
+    /// Set the HasParent flag even without a locator
+    pub has_parent_flag: bool,
+    pub parent: Option<Vec<(String, String)>>,
+    /// Written to both headers; children name it as their parent linkage
+    pub data_write_guid: Uuid,
+    /// Non-nil marks a log Hyper-V hasn't replayed yet
+    pub log_guid: Uuid,
+}
+
+impl Image {
+    pub fn dynamic(virtual_size: u64) -> Self {
+        Self {
+            virtual_size,
+            block_size: 32 * MB as u32,
+            has_parent_flag: false,
+            parent: None,
+            data_write_guid: Uuid::new_v4(),
+            log_guid: Uuid::nil(),
+        }
+    }
+
+    pub fn differencing(virtual_size: u64, linkage: Uuid, relative: &str, absolute: &str) -> Self {
+        let mut image = Self::dynamic(virtual_size);
+        image.block_size = 2 * MB as u32;
+        image.parent = Some(vec![
+            ("parent_linkage".to_string(), format!("{{{}}}", linkage)),
+            ("relative_path".to_string(), relative.to_string()),
+            ("absolute_win32_path".to_string(), absolute.to_string()),
+        ]);
+        image
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.build())
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut file = vec![0u8; 4 * MB as usize];
+
+        file[..8].copy_from_slice(FILE_SIGNATURE);
+        put_utf16(&mut file, 8, "hyperv-kube tests");
+
+        for (i, offset) in HEADER_OFFSETS.iter().enumerate() {
+            let h = &mut file[*offset as usize..][..HEADER_SIZE];
+            h[..4].copy_from_slice(HEADER_SIGNATURE);
+            h[8..16].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+            h[16..32].copy_from_slice(&Uuid::new_v4().to_bytes_le());
+            h[32..48].copy_from_slice(&self.data_write_guid.to_bytes_le());
+            h[48..64].copy_from_slice(&self.log_guid.to_bytes_le());
+            h[66..68].copy_from_slice(&1u16.to_le_bytes());
+            h[68..72].copy_from_slice(&(MB as u32).to_le_bytes());
+            h[72..80].copy_from_slice(&MB.to_le_bytes());
+            let crc = crc32c(h);
+            h[4..8].copy_from_slice(&crc.to_le_bytes());
+        }
+
+        for offset in REGION_TABLE_OFFSETS {
+            let t = &mut file[offset as usize..][..REGION_TABLE_SIZE];
+            t[..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+            t[8..12].copy_from_slice(&2u32.to_le_bytes());
+            for (i, (guid, at)) in [(BAT_REGION, 3 * MB), (METADATA_REGION, 2 * MB)].iter().enumerate() {
+                let e = &mut t[16 + i * REGION_ENTRY_SIZE..][..REGION_ENTRY_SIZE];
+                e[..16].copy_from_slice(&guid.to_bytes_le());
+                e[16..24].copy_from_slice(&at.to_le_bytes());
+                e[24..28].copy_from_slice(&(MB as u32).to_le_bytes());
+                e[28..32].copy_from_slice(&1u32.to_le_bytes());
+            }
+            let crc = crc32c(t);
+            t[4..8].copy_from_slice(&crc.to_le_bytes());
+        }
+
+        let flags = if self.has_parent_flag || self.parent.is_some() { HAS_PARENT } else { 0 };
+        let mut params = self.block_size.to_le_bytes().to_vec();
+        params.extend_from_slice(&flags.to_le_bytes());
+        let mut items = vec![
+            (FILE_PARAMETERS, METADATA_IS_REQUIRED, params),
+            (VIRTUAL_DISK_SIZE, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, self.virtual_size.to_le_bytes().to_vec()),
+            (VIRTUAL_DISK_ID, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, Uuid::new_v4().to_bytes_le().to_vec()),
+            (LOGICAL_SECTOR_SIZE, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, 512u32.to_le_bytes().to_vec()),
+            (PHYSICAL_SECTOR_SIZE, METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED, 4096u32.to_le_bytes().to_vec()),
+        ];
+        if let Some(entries) = &self.parent {
+            items.push((PARENT_LOCATOR, METADATA_IS_REQUIRED, locator(entries)));
+        }
+
+        let region = &mut file[2 * MB as usize..][..MB as usize];
+        region[..8].copy_from_slice(METADATA_SIGNATURE);
+        region[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+        let mut data_at = METADATA_TABLE_SIZE;
+        for (i, (id, flags, data)) in items.iter().enumerate() {
+            let e = &mut region[32 + i * METADATA_ENTRY_SIZE..][..METADATA_ENTRY_SIZE];
+            e[..16].copy_from_slice(&id.to_bytes_le());
+            e[16..20].copy_from_slice(&(data_at as u32).to_le_bytes());
+            e[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            e[24..28].copy_from_slice(&flags.to_le_bytes());
+            region[data_at..data_at + data.len()].copy_from_slice(data);
+            data_at += data.len().next_multiple_of(8);
+        }
+        file
+    }
+}
+
+fn put_utf16(buf: &mut [u8], offset: usize, s: &str) -> usize {
+    let bytes: Vec<u8> = s.encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+    buf[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    bytes.len()
+}
+
+fn locator(entries: &[(String, String)]) -> Vec<u8> {
+    let mut data = vec![0u8; 4096];
+    data[..16].copy_from_slice(&VHDX_PARENT_LOCATOR_TYPE.to_bytes_le());
+    data[18..20].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    let mut at = 20 + entries.len() * 12;
+    for (i, (key, value)) in entries.iter().enumerate() {
+        let key_len = put_utf16(&mut data, at, key);
+        let value_len = put_utf16(&mut data, at + key_len, value);
+        let e = &mut data[20 + i * 12..][..12];
+        e[..4].copy_from_slice(&(at as u32).to_le_bytes());
+        e[4..8].copy_from_slice(&((at + key_len) as u32).to_le_bytes());
+        e[8..10].copy_from_slice(&(key_len as u16).to_le_bytes());
+        e[10..12].copy_from_slice(&(value_len as u16).to_le_bytes());
+        at += key_len + value_len;
+    }
+    data.truncate(at);
+    data
+}
@@ -0,0 +1,125 @@
+//! On-disk layout constants from the VHDX specification
+
+use uuid::Uuid;
+
+pub const KB: u64 = 1024;
+pub const MB: u64 = 1024 * KB;
+
+pub const FILE_SIGNATURE: &[u8; 8] = b"vhdxfile";
+pub const HEADER_SIGNATURE: &[u8; 4] = b"head";
+pub const REGION_TABLE_SIGNATURE: &[u8; 4] = b"regi";
+pub const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+
+/// The two copies of the header
+pub const HEADER_OFFSETS: [u64; 2] = [64 * KB, 128 * KB];
+pub const HEADER_SIZE: usize = 4 * KB as usize;
+/// The two copies of the region table
+pub const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KB, 256 * KB];
+pub const REGION_TABLE_SIZE: usize = 64 * KB as usize;
+pub const REGION_ENTRY_SIZE: usize = 32;
+/// The metadata table sits at the start of the metadata region
+pub const METADATA_TABLE_SIZE: usize = 64 * KB as usize;
+pub const METADATA_ENTRY_SIZE: usize = 32;
+/// Spec limit on region and metadata table entries
+pub const MAX_TABLE_ENTRIES: usize = 2047;
+
+pub const MIN_BLOCK_SIZE: u32 = MB as u32;
+pub const MAX_BLOCK_SIZE: u32 = 256 * MB as u32;
+pub const MAX_VIRTUAL_SIZE: u64 = 64 * 1024 * 1024 * MB;
+
+// Region GUIDs
+pub const BAT_REGION: Uuid = Uuid::from_u128(0x2dc27766_f623_4200_9d64_115e9bfd4a08);
+pub const METADATA_REGION: Uuid = Uuid::from_u128(0x8b7ca206_4790_4b9a_b8fe_575f050f886e);
+
+// Metadata item GUIDs
+pub const FILE_PARAMETERS: Uuid = Uuid::from_u128(0xcaa16737_fa36_4d43_b3b6_33f0aa44e76b);
+pub const VIRTUAL_DISK_SIZE: Uuid = Uuid::from_u128(0x2fa54224_cd1b_4876_b211_5dbed83bf4b8);
+pub const VIRTUAL_DISK_ID: Uuid = Uuid::from_u128(0xbeca12ab_b2e6_4523_93ef_c309e000c746);
+pub const LOGICAL_SECTOR_SIZE: Uuid = Uuid::from_u128(0x8141bf1d_a96f_4709_ba47_f233a8faab5f);
+pub const PHYSICAL_SECTOR_SIZE: Uuid = Uuid::from_u128(0xcda348c7_445d_4471_9cc9_e9885251c556);
+pub const PARENT_LOCATOR: Uuid = Uuid::from_u128(0xa8d35f2d_b30b_454d_abf7_d3d84834ab0c);
+
+/// Locator type of a VHDX parent (the only kind Hyper-V writes)
+pub const VHDX_PARENT_LOCATOR_TYPE: Uuid = Uuid::from_u128(0xb04aefb7_d19e_4a81_b789_25b8e9445913);
+
+// File parameter flags
+pub const LEAVE_BLOCKS_ALLOCATED: u32 = 0x1;
+pub const HAS_PARENT: u32 = 0x2;
+
+// Metadata entry flags
+pub const METADATA_IS_USER: u32 = 0x1;
+pub const METADATA_IS_VIRTUAL_DISK: u32 = 0x2;
+pub const METADATA_IS_REQUIRED: u32 = 0x4;
+
+pub fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// GUIDs are stored in the Windows mixed-endian layout
+pub fn guid_at(buf: &[u8], offset: usize) -> Uuid {
+    Uuid::from_bytes_le(buf[offset..offset + 16].try_into().unwrap())
+}
+
+pub fn utf16_le(buf: &[u8]) -> Option<String> {
+    let units: Vec<u16> = buf.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16(&units).ok()
+}
+
+/// CRC-32C (Castagnoli), as used for VHDX header and table checksums
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Checksum of a structure whose own 4-byte checksum field sits at `field`
+pub fn checksum_without_field(buf: &[u8], field: usize) -> u32 {
+    let mut copy = buf.to_vec();
+    copy[field..field + 4].fill(0);
+    crc32c(&copy)
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn test_guid_layout() {
+        let mut buf = BAT_REGION.to_bytes_le().to_vec();
+        assert_eq!(&buf[..4], &[0x66, 0x77, 0xc2, 0x2d]);
+        assert_eq!(guid_at(&buf, 0), BAT_REGION);
+
+        buf.extend_from_slice(&[0x61, 0x00, 0x62, 0x00]);
+        assert_eq!(utf16_le(&buf[16..]).as_deref(), Some("ab"));
+    }
+}
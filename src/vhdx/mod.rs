@@ -0,0 +1,46 @@
+//! Pure-Rust VHDX reading, so templates and disk chains can be checked
+//! without Hyper-V
+
+mod chain;
+pub mod format;
+mod parser;
+mod writer;
+
+#[cfg(test)]
+pub(crate) mod fixture;
+
+pub use chain::*;
+pub use parser::*;
+pub use writer::*;
+
+use serde::{Deserialize, Serialize};
+
+/// How a VHDX allocates its blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskType {
+    /// All blocks allocated up front
+    Fixed,
+    /// Blocks allocated on first write
+    Dynamic,
+    /// Unwritten blocks read from a parent disk
+    Differencing,
+}
+
+impl std::fmt::Display for DiskType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::str::FromStr for DiskType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Fixed" => Ok(DiskType::Fixed),
+            "Dynamic" => Ok(DiskType::Dynamic),
+            "Differencing" => Ok(DiskType::Differencing),
+            _ => Err(format!("Unknown disk type: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! VHDX header, region table and metadata parsing
+
+use super::format::*;
+use super::DiskType;
+use crate::{Error, Result};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use uuid::Uuid;
+
+/// The current of the two VHDX headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhdxHeader {
+    pub sequence_number: u64,
+    pub file_write_guid: Uuid,
+    pub data_write_guid: Uuid,
+    /// Non-nil when the log holds entries that must be replayed before use
+    pub log_guid: Uuid,
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+}
+
+/// An entry of the region table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionEntry {
+    pub guid: Uuid,
+    pub file_offset: u64,
+    pub length: u32,
+    pub required: bool,
+}
+
+/// Key/value pairs describing where a differencing disk's parent lives
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParentLocator {
+    pub entries: Vec<(String, String)>,
+}
+
+impl ParentLocator {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Data write GUID the parent had when this child was created
+    pub fn parent_linkage(&self) -> Option<Uuid> {
+        self.get("parent_linkage").and_then(|v| Uuid::parse_str(v.trim_matches(|c| c == '{' || c == '}')).ok())
+    }
+
+    pub fn relative_path(&self) -> Option<&str> {
+        self.get("relative_path")
+    }
+
+    pub fn absolute_win32_path(&self) -> Option<&str> {
+        self.get("absolute_win32_path")
+    }
+
+    pub fn volume_path(&self) -> Option<&str> {
+        self.get("volume_path")
+    }
+}
+
+/// Everything registration and chain checks need from a VHDX file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhdxInfo {
+    /// Application that created the file
+    pub creator: String,
+    pub header: VhdxHeader,
+    pub regions: Vec<RegionEntry>,
+    pub virtual_size: u64,
+    pub block_size: u32,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub disk_type: DiskType,
+    pub virtual_disk_id: Uuid,
+    pub parent_locator: Option<ParentLocator>,
+    pub file_size: u64,
+}
+
+impl VhdxInfo {
+    /// Parse and validate the structures at the start of a VHDX file
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let file_size = reader.seek(SeekFrom::End(0))?;
+
+        let identifier = read_at(reader, 0, 64 * KB as usize)?;
+        if &identifier[..8] != FILE_SIGNATURE {
+            return Err(invalid("missing vhdxfile signature"));
+        }
+        let creator = utf16_le(&identifier[8..520]).unwrap_or_default()
+            .trim_end_matches('\0')
+            .to_string();
+
+        let header = read_header(reader)?;
+        if header.version != 1 {
+            return Err(invalid(format!("unsupported version {}", header.version)));
+        }
+
+        let regions = read_region_table(reader, file_size)?;
+        for region in &regions {
+            if region.required && region.guid != BAT_REGION && region.guid != METADATA_REGION {
+                return Err(invalid(format!("unsupported required region {}", region.guid)));
+            }
+        }
+        if !regions.iter().any(|r| r.guid == BAT_REGION) {
+            return Err(invalid("no BAT region"));
+        }
+        let metadata = regions.iter().find(|r| r.guid == METADATA_REGION)
+            .ok_or_else(|| invalid("no metadata region"))?;
+
+        let region = read_at(reader, metadata.file_offset, metadata.length as usize)?;
+        let items = Metadata::parse(&region)?;
+
+        let params = items.require(FILE_PARAMETERS, 8)?;
+        let block_size = u32_at(params, 0);
+        let flags = u32_at(params, 4);
+        let virtual_size = u64_at(items.require(VIRTUAL_DISK_SIZE, 8)?, 0);
+        let virtual_disk_id = guid_at(items.require(VIRTUAL_DISK_ID, 16)?, 0);
+        let logical_sector_size = u32_at(items.require(LOGICAL_SECTOR_SIZE, 4)?, 0);
+        let physical_sector_size = u32_at(items.require(PHYSICAL_SECTOR_SIZE, 4)?, 0);
+
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(invalid(format!("bad block size {}", block_size)));
+        }
+        if !matches!(logical_sector_size, 512 | 4096) {
+            return Err(invalid(format!("bad logical sector size {}", logical_sector_size)));
+        }
+        if !matches!(physical_sector_size, 512 | 4096) {
+            return Err(invalid(format!("bad physical sector size {}", physical_sector_size)));
+        }
+        if virtual_size == 0
+            || virtual_size > MAX_VIRTUAL_SIZE
+            || !virtual_size.is_multiple_of(logical_sector_size as u64)
+        {
+            return Err(invalid(format!("bad virtual size {}", virtual_size)));
+        }
+
+        let disk_type = if flags & HAS_PARENT != 0 {
+            DiskType::Differencing
+        } else if flags & LEAVE_BLOCKS_ALLOCATED != 0 {
+            DiskType::Fixed
+        } else {
+            DiskType::Dynamic
+        };
+        let parent_locator = match items.get(PARENT_LOCATOR) {
+            Some(data) if disk_type == DiskType::Differencing => Some(parse_parent_locator(data)?),
+            None if disk_type == DiskType::Differencing => {
+                return Err(invalid("differencing disk without a parent locator"));
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            creator,
+            header,
+            regions,
+            virtual_size,
+            block_size,
+            logical_sector_size,
+            physical_sector_size,
+            disk_type,
+            virtual_disk_id,
+            parent_locator,
+            file_size,
+        })
+    }
+
+    pub fn region(&self, guid: Uuid) -> Option<&RegionEntry> {
+        self.regions.iter().find(|r| r.guid == guid)
+    }
+
+    /// Hyper-V replays the log when it next opens the file
+    pub fn has_pending_log(&self) -> bool {
+        !self.header.log_guid.is_nil()
+    }
+}
+
+/// Parse a VHDX file on disk; errors name the file
+pub fn inspect(path: impl AsRef<Path>) -> Result<VhdxInfo> {
+    let path = path.as_ref();
+    let mut file = std::fs::File::open(path)?;
+    VhdxInfo::read(&mut file).map_err(|e| match e {
+        Error::InvalidVhdx(reason) => Error::InvalidVhdx(format!("{}: {}", path.display(), reason)),
+        e => e,
+    })
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidVhdx(reason.into())
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => invalid(format!("truncated at offset {}", offset)),
+        _ => e.into(),
+    })?;
+    Ok(buf)
+}
+
+/// The valid header with the highest sequence number
+fn read_header<R: Read + Seek>(reader: &mut R) -> Result<VhdxHeader> {
+    let mut current: Option<VhdxHeader> = None;
+    for offset in HEADER_OFFSETS {
+        let buf = read_at(reader, offset, HEADER_SIZE)?;
+        let Some(header) = parse_header(&buf) else {
+            tracing::debug!(offset, "Skipping invalid VHDX header");
+            continue;
+        };
+        if current.as_ref().is_none_or(|c| header.sequence_number > c.sequence_number) {
+            current = Some(header);
+        }
+    }
+    current.ok_or_else(|| invalid("both headers are corrupt"))
+}
+
+fn parse_header(buf: &[u8]) -> Option<VhdxHeader> {
+    if &buf[..4] != HEADER_SIGNATURE || checksum_without_field(buf, 4) != u32_at(buf, 4) {
+        return None;
+    }
+    Some(VhdxHeader {
+        sequence_number: u64_at(buf, 8),
+        file_write_guid: guid_at(buf, 16),
+        data_write_guid: guid_at(buf, 32),
+        log_guid: guid_at(buf, 48),
+        log_version: u16_at(buf, 64),
+        version: u16_at(buf, 66),
+        log_length: u32_at(buf, 68),
+        log_offset: u64_at(buf, 72),
+    })
+}
+
+/// The first region table copy with a good checksum
+fn read_region_table<R: Read + Seek>(reader: &mut R, file_size: u64) -> Result<Vec<RegionEntry>> {
+    for offset in REGION_TABLE_OFFSETS {
+        let buf = read_at(reader, offset, REGION_TABLE_SIZE)?;
+        if &buf[..4] != REGION_TABLE_SIGNATURE || checksum_without_field(&buf, 4) != u32_at(&buf, 4) {
+            tracing::debug!(offset, "Skipping invalid VHDX region table");
+            continue;
+        }
+        let count = u32_at(&buf, 8) as usize;
+        if count > MAX_TABLE_ENTRIES {
+            return Err(invalid(format!("{} region table entries", count)));
+        }
+
+        let mut regions = Vec::with_capacity(count);
+        for i in 0..count {
+            let entry = &buf[16 + i * REGION_ENTRY_SIZE..][..REGION_ENTRY_SIZE];
+            let region = RegionEntry {
+                guid: guid_at(entry, 0),
+                file_offset: u64_at(entry, 16),
+                length: u32_at(entry, 24),
+                required: u32_at(entry, 28) & 1 != 0,
+            };
+            let end = region.file_offset.checked_add(region.length as u64);
+            if region.file_offset < MB
+                || !region.file_offset.is_multiple_of(MB)
+                || !(region.length as u64).is_multiple_of(MB)
+                || end.is_none_or(|end| end > file_size)
+            {
+                return Err(invalid(format!(
+                    "region {} at {}+{} is outside the file",
+                    region.guid, region.file_offset, region.length
+                )));
+            }
+            regions.push(region);
+        }
+        return Ok(regions);
+    }
+    Err(invalid("both region tables are corrupt"))
+}
+
+/// Metadata items, as slices of the metadata region
+struct Metadata<'a> {
+    items: Vec<(Uuid, &'a [u8])>,
+}
+
+impl<'a> Metadata<'a> {
+    fn parse(region: &'a [u8]) -> Result<Self> {
+        if region.len() < METADATA_TABLE_SIZE || &region[..8] != METADATA_SIGNATURE {
+            return Err(invalid("missing metadata table"));
+        }
+        let count = u16_at(region, 10) as usize;
+        if count > MAX_TABLE_ENTRIES {
+            return Err(invalid(format!("{} metadata entries", count)));
+        }
+
+        let mut items = Vec::with_capacity(count);
+        for i in 0..count {
+            let entry = &region[32 + i * METADATA_ENTRY_SIZE..][..METADATA_ENTRY_SIZE];
+            let id = guid_at(entry, 0);
+            let offset = u32_at(entry, 16) as usize;
+            let length = u32_at(entry, 20) as usize;
+            let flags = u32_at(entry, 24);
+
+            let known = [
+                FILE_PARAMETERS,
+                VIRTUAL_DISK_SIZE,
+                VIRTUAL_DISK_ID,
+                LOGICAL_SECTOR_SIZE,
+                PHYSICAL_SECTOR_SIZE,
+                PARENT_LOCATOR,
+            ];
+            if flags & METADATA_IS_REQUIRED != 0 && !known.contains(&id) {
+                return Err(invalid(format!("unsupported required metadata item {}", id)));
+            }
+            if length > 0 && (offset < METADATA_TABLE_SIZE || offset + length > region.len()) {
+                return Err(invalid(format!("metadata item {} is outside the region", id)));
+            }
+            items.push((id, &region[offset..offset + length]));
+        }
+        Ok(Self { items })
+    }
+
+    fn get(&self, id: Uuid) -> Option<&'a [u8]> {
+        self.items.iter().find(|(item, _)| *item == id).map(|(_, data)| *data)
+    }
+
+    fn require(&self, id: Uuid, min_len: usize) -> Result<&'a [u8]> {
+        self.get(id)
+            .filter(|data| data.len() >= min_len)
+            .ok_or_else(|| invalid(format!("missing metadata item {}", id)))
+    }
+}
+
+fn parse_parent_locator(data: &[u8]) -> Result<ParentLocator> {
+    if data.len() < 20 || guid_at(data, 0) != VHDX_PARENT_LOCATOR_TYPE {
+        return Err(invalid("unsupported parent locator type"));
+    }
+    let count = u16_at(data, 18) as usize;
+    if data.len() < 20 + count * 12 {
+        return Err(invalid("truncated parent locator"));
+    }
+
+    let text = |offset: usize, len: usize| {
+        data.get(offset..offset + len)
+            .and_then(utf16_le)
+            .ok_or_else(|| invalid("bad parent locator entry"))
+    };
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = &data[20 + i * 12..][..12];
+        let key = text(u32_at(entry, 0) as usize, u16_at(entry, 8) as usize)?;
+        let value = text(u32_at(entry, 4) as usize, u16_at(entry, 10) as usize)?;
+        entries.push((key, value));
+    }
+    Ok(ParentLocator { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhdx::fixture::Image;
+    use std::io::Cursor;
+
+    const GB: u64 = 1024 * MB;
+
+    #[test]
+    fn test_read_dynamic_disk() {
+        let bytes = Image::dynamic(127 * 1024 * MB).build();
+        let info = VhdxInfo::read(&mut Cursor::new(&bytes)).unwrap();
+
+        assert_eq!(info.disk_type, DiskType::Dynamic);
+        assert_eq!(info.virtual_size, 127 * 1024 * MB);
+        assert_eq!(info.block_size, 32 * MB as u32);
+        assert_eq!((info.logical_sector_size, info.physical_sector_size), (512, 4096));
+        assert_eq!(info.creator, "hyperv-kube tests");
+        assert_eq!(info.header.sequence_number, 2);
+        assert!(info.region(BAT_REGION).is_some());
+        assert!(info.parent_locator.is_none());
+        assert!(!info.has_pending_log());
+    }
+
+    #[test]
+    fn test_read_differencing_disk() {
+        let linkage = Uuid::new_v4();
+        let bytes = Image::differencing(64 * 1024 * MB, linkage, r"..\template.vhdx", r"C:\Templates\template.vhdx").build();
+        let info = VhdxInfo::read(&mut Cursor::new(&bytes)).unwrap();
+
+        assert_eq!(info.disk_type, DiskType::Differencing);
+        let locator = info.parent_locator.unwrap();
+        assert_eq!(locator.parent_linkage(), Some(linkage));
+        assert_eq!(locator.relative_path(), Some(r"..\template.vhdx"));
+        assert_eq!(locator.absolute_win32_path(), Some(r"C:\Templates\template.vhdx"));
+    }
+
+    #[test]
+    fn test_falls_back_to_good_header_and_region_table() {
+        let mut bytes = Image::dynamic(GB).build();
+        // Corrupt the newer header and the first region table
+        bytes[HEADER_OFFSETS[1] as usize + 100] ^= 0xff;
+        bytes[REGION_TABLE_OFFSETS[0] as usize + 100] ^= 0xff;
+
+        let info = VhdxInfo::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(info.header.sequence_number, 1);
+        assert_eq!(info.virtual_size, GB);
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let err = |bytes: Vec<u8>| VhdxInfo::read(&mut Cursor::new(&bytes)).unwrap_err().to_string();
+
+        assert!(err(b"fake vhdx content".to_vec()).contains("truncated"));
+        assert!(err(vec![0u8; 4 * MB as usize]).contains("signature"));
+
+        let mut bytes = Image::dynamic(GB).build();
+        for offset in HEADER_OFFSETS {
+            bytes[offset as usize + 8] ^= 0xff;
+        }
+        assert!(err(bytes).contains("both headers"));
+
+        let mut bytes = Image::dynamic(GB).build();
+        bytes.truncate(3 * MB as usize);
+        assert!(err(bytes).contains("outside the file"));
+
+        // An offset near the top of the range mustn't wrap around the end check
+        let mut bytes = Image::dynamic(GB).build();
+        for offset in REGION_TABLE_OFFSETS {
+            let table = &mut bytes[offset as usize..][..REGION_TABLE_SIZE];
+            table[16 + 16..][..8].copy_from_slice(&(u64::MAX / MB * MB).to_le_bytes());
+            let crc = checksum_without_field(table, 4);
+            table[4..8].copy_from_slice(&crc.to_le_bytes());
+        }
+        assert!(err(bytes).contains("outside the file"));
+
+        let mut image = Image::dynamic(GB);
+        image.block_size = 3 * MB as u32;
+        assert!(err(image.build()).contains("block size"));
+
+        let mut image = Image::dynamic(GB);
+        image.has_parent_flag = true;
+        assert!(err(image.build()).contains("parent locator"));
+    }
+
+    #[test]
+    fn test_inspect_names_the_file() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("test.vhdx");
+        std::fs::write(&path, "fake vhdx content").unwrap();
+
+        let e = inspect(&path).unwrap_err();
+        assert!(matches!(e, Error::InvalidVhdx(_)));
+        assert!(e.to_string().contains("test.vhdx"));
+    }
+}
@@ -0,0 +1,453 @@
+//! Creating empty dynamic and differencing VHDX files without `New-VHD`
+
+use super::chain::normalize;
+use super::format::*;
+use super::{inspect, ParentLocator};
+use crate::Result;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Component, Path};
+use uuid::Uuid;
+
+/// Block size `New-VHD` picks for dynamic disks
+pub const DYNAMIC_BLOCK_SIZE: u32 = 32 * MB as u32;
+/// Block size `New-VHD` picks for differencing disks
+pub const DIFFERENCING_BLOCK_SIZE: u32 = 2 * MB as u32;
+
+// Same layout as Hyper-V: log, metadata and BAT regions on 1MB boundaries
+const LOG_OFFSET: u64 = MB;
+const LOG_LENGTH: u32 = MB as u32;
+const METADATA_OFFSET: u64 = 2 * MB;
+const METADATA_LENGTH: u32 = MB as u32;
+const BAT_OFFSET: u64 = 3 * MB;
+
+/// Parameters of a new VHDX file with no data blocks allocated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhdxBuilder {
+    pub virtual_size: u64,
+    pub block_size: u32,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    /// Makes the disk a differencing child of the located parent
+    pub parent: Option<ParentLocator>,
+    /// Children of this disk record it as their parent linkage
+    pub data_write_guid: Uuid,
+    pub creator: String,
+}
+
+impl VhdxBuilder {
+    pub fn dynamic(virtual_size: u64) -> Self {
+        Self {
+            virtual_size,
+            block_size: DYNAMIC_BLOCK_SIZE,
+            logical_sector_size: 512,
+            physical_sector_size: 4096,
+            parent: None,
+            data_write_guid: Uuid::new_v4(),
+            creator: format!("hyperv-kube {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// A child of `parent` that will live at `child`; geometry comes from the parent
+    pub fn differencing(parent: impl AsRef<Path>, child: impl AsRef<Path>) -> Result<Self> {
+        let info = inspect(&parent)?;
+        let parent = normalize(&std::path::absolute(parent)?);
+        let child = normalize(&std::path::absolute(child)?);
+
+        let mut entries = vec![("parent_linkage".to_string(), format!("{{{}}}", info.header.data_write_guid))];
+        if let Some(relative) = child.parent().and_then(|dir| relative_path(dir, &parent)) {
+            entries.push(("relative_path".to_string(), relative));
+        }
+        entries.push(("absolute_win32_path".to_string(), parent.display().to_string()));
+
+        Ok(Self {
+            block_size: DIFFERENCING_BLOCK_SIZE,
+            logical_sector_size: info.logical_sector_size,
+            physical_sector_size: info.physical_sector_size,
+            parent: Some(ParentLocator { entries }),
+            ..Self::dynamic(info.virtual_size)
+        })
+    }
+
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Write the file; fails if `path` already exists
+    pub fn create(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.validate()?;
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let written = file.write_all(&self.structures())
+            .and_then(|_| file.set_len(self.file_size()))
+            .and_then(|_| file.sync_all());
+        if let Err(e) = written {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |what: String| Err(crate::Error::InvalidVhdx(what));
+        if !self.block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return invalid(format!("bad block size {}", self.block_size));
+        }
+        if !matches!(self.logical_sector_size, 512 | 4096) || !matches!(self.physical_sector_size, 512 | 4096) {
+            return invalid(format!("bad sector sizes {}/{}", self.logical_sector_size, self.physical_sector_size));
+        }
+        if self.virtual_size == 0
+            || self.virtual_size > MAX_VIRTUAL_SIZE
+            || !self.virtual_size.is_multiple_of(self.logical_sector_size as u64)
+        {
+            return invalid(format!("bad virtual size {}", self.virtual_size));
+        }
+        Ok(())
+    }
+
+    /// Entries in the BAT, including the sector bitmap entries interleaved every chunk
+    fn bat_entries(&self) -> u64 {
+        let chunk_ratio = (1u64 << 23) * self.logical_sector_size as u64 / self.block_size as u64;
+        let data_blocks = self.virtual_size.div_ceil(self.block_size as u64);
+        if self.parent.is_some() {
+            data_blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+        } else {
+            data_blocks + data_blocks.saturating_sub(1) / chunk_ratio
+        }
+    }
+
+    fn bat_length(&self) -> u64 {
+        (self.bat_entries() * 8).next_multiple_of(MB)
+    }
+
+    fn file_size(&self) -> u64 {
+        BAT_OFFSET + self.bat_length()
+    }
+
+    /// The whole file in memory, BAT included; for small disks in tests
+    #[cfg(test)]
+    pub(crate) fn image(&self) -> Vec<u8> {
+        let mut bytes = self.structures();
+        bytes.resize(self.file_size() as usize, 0);
+        bytes
+    }
+
+    /// Everything before the BAT, which starts out all zeroes (no blocks present)
+    fn structures(&self) -> Vec<u8> {
+        let mut file = vec![0u8; BAT_OFFSET as usize];
+
+        file[..8].copy_from_slice(FILE_SIGNATURE);
+        put_utf16(&mut file[8..8 + 512], &self.creator);
+
+        // Both headers current, the second one newer, as after a clean close
+        let file_write_guid = Uuid::new_v4();
+        for (i, offset) in HEADER_OFFSETS.iter().enumerate() {
+            let h = &mut file[*offset as usize..][..HEADER_SIZE];
+            h[..4].copy_from_slice(HEADER_SIGNATURE);
+            h[8..16].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+            h[16..32].copy_from_slice(&file_write_guid.to_bytes_le());
+            h[32..48].copy_from_slice(&self.data_write_guid.to_bytes_le());
+            h[66..68].copy_from_slice(&1u16.to_le_bytes());
+            h[68..72].copy_from_slice(&LOG_LENGTH.to_le_bytes());
+            h[72..80].copy_from_slice(&LOG_OFFSET.to_le_bytes());
+            let crc = crc32c(h);
+            h[4..8].copy_from_slice(&crc.to_le_bytes());
+        }
+
+        let regions = [
+            (BAT_REGION, BAT_OFFSET, self.bat_length() as u32),
+            (METADATA_REGION, METADATA_OFFSET, METADATA_LENGTH),
+        ];
+        for offset in REGION_TABLE_OFFSETS {
+            let t = &mut file[offset as usize..][..REGION_TABLE_SIZE];
+            t[..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+            t[8..12].copy_from_slice(&(regions.len() as u32).to_le_bytes());
+            for (i, (guid, at, length)) in regions.iter().enumerate() {
+                let e = &mut t[16 + i * REGION_ENTRY_SIZE..][..REGION_ENTRY_SIZE];
+                e[..16].copy_from_slice(&guid.to_bytes_le());
+                e[16..24].copy_from_slice(&at.to_le_bytes());
+                e[24..28].copy_from_slice(&length.to_le_bytes());
+                e[28..32].copy_from_slice(&1u32.to_le_bytes());
+            }
+            let crc = crc32c(t);
+            t[4..8].copy_from_slice(&crc.to_le_bytes());
+        }
+
+        let flags = if self.parent.is_some() { HAS_PARENT } else { 0 };
+        let mut params = self.block_size.to_le_bytes().to_vec();
+        params.extend_from_slice(&flags.to_le_bytes());
+        let disk = METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED;
+        let mut items = vec![
+            (FILE_PARAMETERS, METADATA_IS_REQUIRED, params),
+            (VIRTUAL_DISK_SIZE, disk, self.virtual_size.to_le_bytes().to_vec()),
+            (VIRTUAL_DISK_ID, disk, Uuid::new_v4().to_bytes_le().to_vec()),
+            (LOGICAL_SECTOR_SIZE, disk, self.logical_sector_size.to_le_bytes().to_vec()),
+            (PHYSICAL_SECTOR_SIZE, disk, self.physical_sector_size.to_le_bytes().to_vec()),
+        ];
+        if let Some(locator) = &self.parent {
+            items.push((PARENT_LOCATOR, METADATA_IS_REQUIRED, locator_bytes(locator)));
+        }
+
+        let region = &mut file[METADATA_OFFSET as usize..][..METADATA_LENGTH as usize];
+        region[..8].copy_from_slice(METADATA_SIGNATURE);
+        region[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+        let mut data_at = METADATA_TABLE_SIZE;
+        for (i, (id, flags, data)) in items.iter().enumerate() {
+            let e = &mut region[32 + i * METADATA_ENTRY_SIZE..][..METADATA_ENTRY_SIZE];
+            e[..16].copy_from_slice(&id.to_bytes_le());
+            e[16..20].copy_from_slice(&(data_at as u32).to_le_bytes());
+            e[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            e[24..28].copy_from_slice(&flags.to_le_bytes());
+            region[data_at..data_at + data.len()].copy_from_slice(data);
+            data_at += data.len().next_multiple_of(8);
+        }
+        file
+    }
+}
+
+/// Create an empty dynamic disk
+pub fn create_dynamic(path: impl AsRef<Path>, virtual_size: u64) -> Result<()> {
+    VhdxBuilder::dynamic(virtual_size).create(path)
+}
+
+/// Create a differencing disk at `child` on top of `parent`
+pub fn create_differencing(parent: impl AsRef<Path>, child: impl AsRef<Path>) -> Result<()> {
+    VhdxBuilder::differencing(parent, &child)?.create(&child)
+}
+
+/// Write `s` as UTF-16LE, cut at a character boundary if it doesn't fit `buf`
+fn put_utf16(buf: &mut [u8], s: &str) -> usize {
+    let mut len = 0;
+    for c in s.chars() {
+        let mut units = [0u16; 2];
+        let units = c.encode_utf16(&mut units);
+        if len + units.len() * 2 > buf.len() {
+            break;
+        }
+        for unit in units.iter() {
+            buf[len..len + 2].copy_from_slice(&unit.to_le_bytes());
+            len += 2;
+        }
+    }
+    len
+}
+
+fn locator_bytes(locator: &ParentLocator) -> Vec<u8> {
+    let entries = &locator.entries;
+    let size = 20 + entries.len() * 12 + entries.iter().map(|(k, v)| (k.len() + v.len()) * 2).sum::<usize>();
+    let mut data = vec![0u8; size];
+    data[..16].copy_from_slice(&VHDX_PARENT_LOCATOR_TYPE.to_bytes_le());
+    data[18..20].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    let mut at = 20 + entries.len() * 12;
+    for (i, (key, value)) in entries.iter().enumerate() {
+        let key_len = put_utf16(&mut data[at..], key);
+        let value_len = put_utf16(&mut data[at + key_len..], value);
+        let e = &mut data[20 + i * 12..][..12];
+        e[..4].copy_from_slice(&(at as u32).to_le_bytes());
+        e[4..8].copy_from_slice(&((at + key_len) as u32).to_le_bytes());
+        e[8..10].copy_from_slice(&(key_len as u16).to_le_bytes());
+        e[10..12].copy_from_slice(&(value_len as u16).to_le_bytes());
+        at += key_len + value_len;
+    }
+    data.truncate(at);
+    data
+}
+
+/// `to` relative to the directory `from`, in Windows form; `None` across drives
+fn relative_path(from: &Path, to: &Path) -> Option<String> {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    if from.first() != to.first() {
+        return None;
+    }
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = if common == from.len() { vec![".".to_string()] } else { vec!["..".to_string(); from.len() - common] };
+    parts.extend(to[common..].iter().map(|c| c.as_os_str().to_string_lossy().into_owned()));
+    Some(parts.join("\\"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhdx::fixture::Image;
+    use crate::vhdx::{DiskChain, DiskType, VhdxInfo};
+    use std::io::Cursor;
+
+    const GB: u64 = 1024 * MB;
+
+    #[test]
+    fn test_dynamic_round_trip() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("base.vhdx");
+        let builder = VhdxBuilder::dynamic(127 * GB);
+        builder.create(&path).unwrap();
+
+        let info = inspect(&path).unwrap();
+        assert_eq!(info.disk_type, DiskType::Dynamic);
+        assert_eq!(info.virtual_size, 127 * GB);
+        assert_eq!(info.block_size, DYNAMIC_BLOCK_SIZE);
+        assert_eq!((info.logical_sector_size, info.physical_sector_size), (512, 4096));
+        assert_eq!(info.header.data_write_guid, builder.data_write_guid);
+        assert!(info.creator.starts_with("hyperv-kube"));
+        assert!(!info.has_pending_log());
+        assert_eq!(info.region(BAT_REGION).unwrap().length, MB as u32);
+        assert_eq!(info.file_size, 4 * MB);
+
+        // Never overwrites
+        assert!(create_dynamic(&path, GB).is_err());
+        assert_eq!(inspect(&path).unwrap().virtual_size, 127 * GB);
+    }
+
+    #[test]
+    fn test_differencing_round_trip() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let template = tmp.path().join("templates").join("win11.vhdx");
+        let child = tmp.path().join("vms").join("agents-0").join("disk.vhdx");
+        std::fs::create_dir_all(template.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(child.parent().unwrap()).unwrap();
+        VhdxBuilder::dynamic(64 * GB).with_block_size(MB as u32).create(&template).unwrap();
+        create_differencing(&template, &child).unwrap();
+
+        let info = inspect(&child).unwrap();
+        assert_eq!(info.disk_type, DiskType::Differencing);
+        assert_eq!(info.virtual_size, 64 * GB);
+        assert_eq!(info.block_size, DIFFERENCING_BLOCK_SIZE);
+        let locator = info.parent_locator.unwrap();
+        assert_eq!(locator.parent_linkage(), Some(inspect(&template).unwrap().header.data_write_guid));
+        assert_eq!(locator.relative_path(), Some(r"..\..\templates\win11.vhdx"));
+        assert_eq!(locator.absolute_win32_path(), Some(template.to_str().unwrap()));
+
+        let chain = DiskChain::walk(&child);
+        assert!(!chain.is_broken(), "{:?}", chain.problem);
+        assert_eq!(chain.base().unwrap().path, template);
+    }
+
+    #[test]
+    fn test_bat_size() {
+        // 64TB differencing: 16384 chunks of 2048 blocks plus a sector bitmap each
+        let mut builder = VhdxBuilder::dynamic(MAX_VIRTUAL_SIZE).with_block_size(DIFFERENCING_BLOCK_SIZE);
+        builder.parent = Some(ParentLocator::default());
+        assert_eq!(builder.bat_entries(), 16384 * 2049);
+        assert_eq!(builder.bat_length(), 257 * MB);
+
+        // Dynamic disks only count the bitmap entries between data blocks
+        let builder = VhdxBuilder::dynamic(8 * 1024 * GB).with_block_size(MB as u32);
+        assert_eq!(builder.bat_entries(), 8 * 1024 * 1024 + 2047);
+
+        let image = VhdxBuilder::dynamic(GB).image();
+        let info = VhdxInfo::read(&mut Cursor::new(&image)).unwrap();
+        assert_eq!(info.file_size, image.len() as u64);
+    }
+
+    #[test]
+    fn test_rejects_bad_geometry() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let err = |builder: VhdxBuilder| builder.create(tmp.path().join("bad.vhdx")).unwrap_err().to_string();
+
+        assert!(err(VhdxBuilder::dynamic(GB + 1)).contains("virtual size"));
+        assert!(err(VhdxBuilder::dynamic(0)).contains("virtual size"));
+        assert!(err(VhdxBuilder::dynamic(GB).with_block_size(3 * MB as u32)).contains("block size"));
+        assert!(!tmp.path().join("bad.vhdx").exists());
+
+        // The parent must be a readable VHDX
+        std::fs::write(tmp.path().join("fake.vhdx"), "fake").unwrap();
+        assert!(create_differencing(tmp.path().join("fake.vhdx"), tmp.path().join("child.vhdx")).is_err());
+    }
+
+    /// Random per-file GUIDs cleared, so two images with the same shape compare equal
+    fn shape(image: &[u8]) -> VhdxInfo {
+        let mut info = VhdxInfo::read(&mut Cursor::new(image)).unwrap();
+        info.header.file_write_guid = Uuid::nil();
+        info.virtual_disk_id = Uuid::nil();
+        info
+    }
+
+    #[test]
+    fn test_matches_hand_written_fixture() {
+        let same_as = |image: &Image| VhdxBuilder {
+            block_size: image.block_size,
+            parent: image.parent.clone().map(|entries| ParentLocator { entries }),
+            data_write_guid: image.data_write_guid,
+            creator: "hyperv-kube tests".to_string(),
+            ..VhdxBuilder::dynamic(image.virtual_size)
+        };
+
+        let base = Image::dynamic(GB);
+        let child = Image::differencing(GB, base.data_write_guid, r"..\base.vhdx", r"C:\vms\base.vhdx");
+        for image in [base, child] {
+            let (expected, actual) = (image.build(), same_as(&image).image());
+            assert_eq!(shape(&actual), shape(&expected));
+            for offset in REGION_TABLE_OFFSETS {
+                let table = offset as usize..offset as usize + REGION_TABLE_SIZE;
+                assert_eq!(actual[table.clone()], expected[table]);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs disks made by New-VHD; run testdata/vhdx/make-fixtures.ps1 on a Hyper-V host"]
+    fn test_matches_new_vhd() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("vhdx");
+        let reference = inspect(data.join("dynamic.vhdx")).unwrap();
+        let reference_child = inspect(data.join("differencing.vhdx")).unwrap();
+
+        // Same layout-independent fields as New-VHD -Dynamic; offsets and GUIDs may differ
+        let same_shape = |ours: &VhdxInfo, theirs: &VhdxInfo| {
+            assert_eq!(ours.disk_type, theirs.disk_type);
+            assert_eq!(ours.virtual_size, theirs.virtual_size);
+            assert_eq!(ours.block_size, theirs.block_size);
+            assert_eq!((ours.logical_sector_size, ours.physical_sector_size), (theirs.logical_sector_size, theirs.physical_sector_size));
+            assert_eq!(ours.header.version, theirs.header.version);
+            assert_eq!(ours.header.log_version, theirs.header.log_version);
+            assert_eq!(ours.header.log_length, theirs.header.log_length);
+            assert_eq!(ours.has_pending_log(), theirs.has_pending_log());
+            let regions = |info: &VhdxInfo| {
+                let mut regions: Vec<(Uuid, bool)> = info.regions.iter().map(|r| (r.guid, r.required)).collect();
+                regions.sort();
+                regions
+            };
+            assert_eq!(regions(ours), regions(theirs));
+        };
+
+        let tmp = tempfile::TempDir::new().unwrap();
+        let base = tmp.path().join("dynamic.vhdx");
+        let child = tmp.path().join("differencing.vhdx");
+        VhdxBuilder::dynamic(reference.virtual_size).create(&base).unwrap();
+        std::fs::copy(data.join("dynamic.vhdx"), tmp.path().join("parent.vhdx")).unwrap();
+        create_differencing(tmp.path().join("parent.vhdx"), &child).unwrap();
+        same_shape(&inspect(&base).unwrap(), &reference);
+        same_shape(&inspect(&child).unwrap(), &reference_child);
+
+        // Our locator keys are a subset of New-VHD's, with the same linkage and relative form
+        let ours = inspect(&child).unwrap().parent_locator.unwrap();
+        let theirs = reference_child.parent_locator.unwrap();
+        assert!(ours.entries.iter().all(|(key, _)| theirs.get(key).is_some()), "{:?} vs {:?}", ours, theirs);
+        assert_eq!(ours.parent_linkage(), theirs.parent_linkage());
+        assert_eq!(theirs.parent_linkage(), Some(reference.header.data_write_guid));
+        assert_eq!(ours.relative_path(), Some(r".\parent.vhdx"));
+        assert_eq!(theirs.relative_path(), Some(r".\dynamic.vhdx"));
+    }
+
+    #[test]
+    fn test_long_creator_is_truncated() {
+        let mut builder = VhdxBuilder::dynamic(GB);
+        builder.creator = "é".repeat(300);
+        let info = VhdxInfo::read(&mut Cursor::new(builder.image())).unwrap();
+        assert_eq!(info.creator, "é".repeat(256));
+
+        // A surrogate pair is never split
+        builder.creator = format!("x{}", "\u{1F600}".repeat(200));
+        let info = VhdxInfo::read(&mut Cursor::new(builder.image())).unwrap();
+        assert_eq!(info.creator, format!("x{}", "\u{1F600}".repeat(127)));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let rel = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(rel("/vms/agents-0", "/vms/agents-0/base.vhdx").as_deref(), Some(r".\base.vhdx"));
+        assert_eq!(rel("/vms/agents-0", "/templates/win11.vhdx").as_deref(), Some(r"..\..\templates\win11.vhdx"));
+        assert_eq!(rel("vms", "/templates/win11.vhdx"), None);
+    }
+}
@@ -0,0 +1,9 @@
+# Regenerates the reference disks used by vhdx::writer's test_matches_new_vhd.
+# Run on a Hyper-V host from this directory; never mount the results, since
+# that changes their data write GUID and breaks the differencing linkage.
+$ErrorActionPreference = 'Stop'
+
+Remove-Item -ErrorAction SilentlyContinue dynamic.vhdx, differencing.vhdx
+New-VHD -Path (Join-Path $PWD 'dynamic.vhdx') -SizeBytes 1GB -Dynamic | Out-Null
+New-VHD -Path (Join-Path $PWD 'differencing.vhdx') -ParentPath (Join-Path $PWD 'dynamic.vhdx') -Differencing | Out-Null
+Get-Item dynamic.vhdx, differencing.vhdx | Select-Object Name, Length